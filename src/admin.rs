@@ -0,0 +1,794 @@
+//! A minimal HTTP admin API.
+//!
+//! Only the few routes needed to operate dnsr are implemented on top of a
+//! blocking listener to avoid pulling a web framework in the dependencies.
+//! Requests are handled one at a time and every connection is closed after
+//! its response.
+//!
+//! Routes:
+//!
+//! - `GET /zones/removed`: lists the zones removed from the configuration which
+//!   are still retained, one `<apex> <seconds left>` per line,
+//! - `POST /zones/<apex>/restore`: serves a retained zone again and provisions
+//!   its domain, with a new key returned as for `POST /keys` if its key was
+//!   deleted, so the route requires the token,
+//! - `GET /zones/<apex>/journal`: lists the retained changes of a zone, the
+//!   oldest first,
+//! - `GET /zones/<apex>/journal/<serial>`: lists the changes of a zone since
+//!   `serial`, as an incremental transfer would send them, or answers 404 if
+//!   the journal does not reach back to it,
+//! - `POST /keys`: creates a key and the zone of its domain, the body holds the
+//!   `key` name, the `domain` and the fields of a domain entry of the
+//!   configuration. The secret is only returned in this response, as a BIND
+//!   `key` statement, so the route requires the token,
+//! - `GET /domains/<domain>/onboarding`: the challenge name, key and server
+//!   an ACME client needs to issue the certificates of a domain, wildcards
+//!   included, and whether the challenge zone is ready,
+//! - `GET /capture.pcap`: dumps the wire capture in the pcap format, when the
+//!   capture is enabled. It requires the token,
+//! - `GET /health`: answers `ok`, or `degraded` followed by the keys which
+//!   could not be loaded,
+//! - `GET /metrics`: the last metrics report, when the `admin` sink of the
+//!   metrics is enabled,
+//! - `PUT /metrics/config`: replaces the `metrics` section of the
+//!   configuration, the body holds its fields,
+//! - `GET /role`: answers `primary` or `standby`,
+//! - `POST /promote`: promotes a standby to primary, enabling the updates.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use domain::base::iana::Class;
+use domain::base::Rtype;
+use domain::rdata::ZoneRecordData;
+use domain::tsig::{Algorithm, KeyName};
+use domain::zonetree::types::StoredName;
+use serde::Deserialize;
+
+use crate::config::{AdminConfig, WebhookEvent, DNS_PORT};
+use crate::dname::DomainName;
+use crate::error;
+use crate::error::{ErrorKind, Result};
+use crate::key::{build_zones, DomainInfo, KeyFile, Keys, SystemClock, TryInto};
+use crate::report::MetricsReporter;
+use crate::service::Dnsr;
+
+const MAX_HEADER_LINES: usize = 64;
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+pub struct AdminServer {
+    dnsr: Arc<Dnsr>,
+    reporter: Arc<MetricsReporter>,
+    config: AdminConfig,
+}
+
+impl AdminServer {
+    pub fn new(dnsr: Arc<Dnsr>, reporter: Arc<MetricsReporter>, config: &AdminConfig) -> Self {
+        Self {
+            dnsr,
+            reporter,
+            config: config.clone(),
+        }
+    }
+
+    /// Serves the API until the listener fails.
+    pub fn run(&self) -> Result<()> {
+        // The routes restore, disable and provision the zones and keys
+        if self.config.token().is_none() {
+            if !self.config.is_loopback() {
+                return Err(
+                    error!(Admin => "a token is required to listen on {}", self.config.listen()),
+                );
+            }
+            log::warn!(target: "admin", "admin api listening on {} without a token, every local user can use it", self.config.listen());
+        }
+
+        let listener = TcpListener::bind(self.config.listen())?;
+        log::info!(target: "admin", "admin api listening on {}", self.config.listen());
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::error!(target: "admin", "failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.handle(stream) {
+                log::error!(target: "admin", "failed to handle request: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(self.config.timeout()))?;
+        stream.set_write_timeout(Some(self.config.timeout()))?;
+        let mut reader = BufReader::new(stream);
+
+        let response = match Request::read(&mut reader) {
+            Ok(request) if !self.authorized(&request) => Response::new(401, "unauthorized"),
+            Ok(request) => {
+                log::info!(target: "admin", "{} {}", request.method, request.path);
+                self.route(&request)
+            }
+            Err(e) => {
+                log::debug!(target: "admin", "invalid request: {}", e);
+                Response::new(400, "bad request")
+            }
+        };
+
+        response.write(reader.get_mut())
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(token) = self.config.token() else {
+            return true;
+        };
+
+        request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| {
+                ring::constant_time::verify_slices_are_equal(value.as_bytes(), token.as_bytes())
+                    .is_ok()
+            })
+    }
+
+    fn route(&self, request: &Request) -> Response {
+        let segments = request
+            .path
+            .trim_matches('/')
+            .split('/')
+            .collect::<Vec<_>>();
+
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["zones", "removed"]) => self.removed_zones(),
+            ("POST", ["zones", apex, "restore"]) => self.restore_zone(apex),
+            ("GET", ["zones", apex, "journal"]) => self.journal(apex, None),
+            ("GET", ["zones", apex, "journal", serial]) => match serial.parse() {
+                Ok(serial) => self.journal(apex, Some(serial)),
+                Err(_) => Response::new(400, "invalid serial"),
+            },
+            ("POST", ["keys"]) => self.provision_key(&request.body),
+            ("GET", ["domains", domain, "onboarding"]) => self.onboarding(domain),
+            ("GET", ["capture.pcap"]) => self.capture(),
+            ("GET", ["health"]) => self.health(),
+            ("GET", ["metrics"]) => self.metrics(),
+            ("PUT", ["metrics", "config"]) => self.set_metrics_config(&request.body),
+            ("GET", ["role"]) => self.role(),
+            ("POST", ["promote"]) => self.promote(),
+            _ => Response::new(404, "not found"),
+        }
+    }
+
+    fn removed_zones(&self) -> Response {
+        let retention = self.dnsr.config.removed_zone_retention();
+        self.dnsr.purge_removed_zones();
+
+        let body = self
+            .dnsr
+            .zones
+            .disabled_zones()
+            .into_iter()
+            .map(|(name, elapsed)| {
+                format!("{} {}\n", name, retention.saturating_sub(elapsed).as_secs())
+            })
+            .collect::<String>();
+        Response::new(200, body)
+    }
+
+    /// Serves a retained zone again along with the other retained zone of
+    /// its domain. The domain is provisioned again so that its zones keep
+    /// being served and updated, with a new key if its key was deleted.
+    fn restore_zone(&self, apex: &str) -> Response {
+        // The response holds the secret of the key generated again, if any
+        if let Some(response) = self.require_token() {
+            return response;
+        }
+        if self.dnsr.is_standby() {
+            return Response::new(409, "standby instance");
+        }
+
+        let Ok(apex): Result<StoredName> = apex.try_into_t() else {
+            return Response::new(400, "invalid zone name");
+        };
+
+        if self.dnsr.zones.apex_names().contains(&apex) {
+            return Response::new(409, "zone is already served");
+        }
+
+        self.dnsr.purge_removed_zones();
+        let domain = DomainName::from_name(&apex);
+        let retained = self.dnsr.retained.lock().unwrap().get(&domain).cloned();
+        let Some((key, info)) = retained else {
+            return Response::new(404, "zone is not retained");
+        };
+
+        let secret = if self.dnsr.keystore.read().unwrap().is_loaded(&key) {
+            None
+        } else {
+            // The secret is generated without holding the keystore lock
+            let secrets = self.dnsr.keystore.read().unwrap().secrets();
+            match secrets.generate(&key) {
+                Ok((generated, secret)) => {
+                    self.dnsr.keystore.write().unwrap().insert_key(generated);
+                    Some(secret)
+                }
+                Err(e) if e.kind == ErrorKind::TSIGFileAlreadyExist => {
+                    return Response::new(409, "key file already exists")
+                }
+                Err(e) => return Response::new(500, e.to_string()),
+            }
+        };
+
+        let mut restored = Vec::new();
+        for (name, _) in self.dnsr.zones.disabled_zones() {
+            if DomainName::from_name(&name) != domain {
+                continue;
+            }
+            if let Err(e) = self.dnsr.zones.restore_zone(&name) {
+                self.roll_back_restore(&key, secret.is_some(), &restored);
+                return Response::new(500, e.to_string());
+            }
+            restored.push(name);
+        }
+
+        let saved = {
+            let mut provisioned = self.dnsr.provisioned.write().unwrap();
+            let mut updated = provisioned.clone();
+            updated.insert(key.clone(), domain.clone(), info);
+            let saved = save_provisioned(&self.dnsr, &updated);
+            if saved.is_ok() {
+                *provisioned = updated;
+            }
+            saved
+        };
+        if let Err(e) = saved {
+            log::error!(target: "admin", "failed to save the provisioned keys: {}", e);
+            self.roll_back_restore(&key, secret.is_some(), &restored);
+            return Response::new(500, e.to_string());
+        }
+        self.dnsr.retained.lock().unwrap().remove(&domain);
+
+        if let Some(webhooks) = &self.dnsr.webhooks {
+            for apex in &restored {
+                webhooks.send(WebhookEvent::ZoneAdded, apex);
+            }
+        }
+        log::info!(target: "admin", "restored the zones of {} with key {}", domain, key);
+
+        match secret {
+            Some(secret) => Response::new(200, key_statement(&key, &secret)),
+            None => Response::new(200, "restored"),
+        }
+    }
+
+    /// Lists the changes of the zone `apex`, only those since `serial` if set.
+    fn journal(&self, apex: &str, serial: Option<u32>) -> Response {
+        let Ok(apex): Result<StoredName> = apex.try_into_t() else {
+            return Response::new(400, "invalid zone name");
+        };
+
+        if !self.dnsr.zones.apex_names().contains(&apex) {
+            return Response::new(404, "unknown zone");
+        }
+
+        let entries = match serial {
+            Some(serial) => match self.dnsr.journal.entries_since(&apex, serial) {
+                Some(entries) => entries,
+                None => return Response::new(404, "serial not in the journal"),
+            },
+            None => self.dnsr.journal.entries(&apex),
+        };
+        let body = entries.iter().map(ToString::to_string).collect::<String>();
+        Response::new(200, body)
+    }
+
+    /// Gathers what an ACME client needs to issue the certificates of
+    /// `domain` through the DNS-01 challenge.
+    fn onboarding(&self, domain: &str) -> Response {
+        // The wildcard certificates are validated on the challenge name of
+        // their base domain
+        let domain = domain.strip_prefix("*.").unwrap_or(domain);
+        let Ok(domain) = DomainName::from_str(domain) else {
+            return Response::new(400, "invalid domain name");
+        };
+
+        let found = {
+            let provisioned = self.dnsr.provisioned.read().unwrap();
+            self.dnsr
+                .config
+                .keys
+                .find_domain(&domain)
+                .or_else(|| provisioned.find_domain(&domain))
+                .map(|(key, info)| (key.clone(), info.mname().trim_end_matches('.').to_string()))
+        };
+        let Some((key, server)) = found else {
+            return Response::new(404, "unknown domain");
+        };
+        let apex = match domain.challenge_apex() {
+            Ok(apex) => apex,
+            Err(e) => return Response::new(500, e.to_string()),
+        };
+
+        // The imported keys may use another algorithm than the generated ones
+        let algorithm = KeyName::try_from(&key)
+            .ok()
+            .and_then(|name| self.dnsr.keystore.read().unwrap().find_key(&name))
+            .map_or(Algorithm::Sha512, |k| k.algorithm());
+
+        let body = format!(
+            "challenge {}.\nkey {}\nalgorithm {}\nserver {}\nport {}\nstatus {}\n",
+            apex,
+            key,
+            algorithm,
+            server,
+            DNS_PORT,
+            self.challenge_status(&key, &apex)
+        );
+        Response::new(200, body)
+    }
+
+    /// Reports whether the challenge zone `apex` can be updated with `key`,
+    /// along with its serial and the challenge records already published.
+    fn challenge_status(&self, key: &KeyFile, apex: &StoredName) -> String {
+        let unavailable = KeyName::try_from(key).ok().and_then(|name| {
+            let keystore = self.dnsr.keystore.read().unwrap();
+            keystore.unavailable_reason(&name).map(ToString::to_string)
+        });
+        if let Some(reason) = unavailable {
+            return format!("key unavailable: {}", reason);
+        }
+        if !self.dnsr.zones.apex_names().contains(apex) {
+            return "zone not served".to_string();
+        }
+
+        let records = self.dnsr.zones.records(apex);
+        let serial = records
+            .iter()
+            .filter(|((rtype, _), _)| *rtype == Rtype::SOA)
+            .find_map(|(_, data)| match data.first()? {
+                ZoneRecordData::Soa(soa) => Some(soa.serial()),
+                _ => None,
+            });
+        let txt = records
+            .iter()
+            .filter(|((rtype, _), _)| *rtype == Rtype::TXT)
+            .map(|(_, data)| data.len())
+            .sum::<usize>();
+
+        let serial = serial.map_or("unknown".to_string(), |serial| serial.to_string());
+        match txt {
+            0 => format!("ready serial {}", serial),
+            txt => format!("published serial {} txt {}", serial, txt),
+        }
+    }
+
+    fn capture(&self) -> Response {
+        // The captured exchanges include the signed updates
+        if let Some(response) = self.require_token() {
+            return response;
+        }
+        match &self.dnsr.capture {
+            Some(capture) => Response::new(200, capture.to_pcap())
+                .with_content_type("application/vnd.tcpdump.pcap"),
+            None => Response::new(404, "capture is not enabled"),
+        }
+    }
+
+    /// Reports whether the server is degraded, i.e. some keys are unavailable
+    /// and their signed requests are refused while the plain queries are
+    /// still answered.
+    fn health(&self) -> Response {
+        let keystore = self.dnsr.keystore.read().unwrap();
+        if !keystore.is_degraded() {
+            return Response::new(200, "ok");
+        }
+
+        let body = keystore
+            .unavailable_keys()
+            .map(|(name, reason)| format!("key {} unavailable: {}\n", name, reason))
+            .collect::<String>();
+        Response::new(200, format!("degraded\n{}", body))
+    }
+
+    fn metrics(&self) -> Response {
+        match self.reporter.last_report() {
+            Some(report) => Response::new(200, report),
+            None => Response::new(404, "the admin metrics sink is not enabled"),
+        }
+    }
+
+    fn set_metrics_config(&self, body: &[u8]) -> Response {
+        match serde_yaml::from_slice(body) {
+            Ok(config) => {
+                self.reporter.set_config(config);
+                Response::new(200, "updated")
+            }
+            Err(e) => Response::new(400, e.to_string()),
+        }
+    }
+
+    fn role(&self) -> Response {
+        if self.dnsr.is_standby() {
+            Response::new(200, "standby")
+        } else {
+            Response::new(200, "primary")
+        }
+    }
+
+    fn promote(&self) -> Response {
+        if !self.dnsr.promote() {
+            return Response::new(409, "already primary");
+        }
+        log::warn!(target: "admin", "promoted to primary, the updates are now accepted");
+        Response::new(200, "promoted")
+    }
+
+    /// Refuses the routes exposing secrets when the API has no token, even
+    /// on a loopback address.
+    fn require_token(&self) -> Option<Response> {
+        self.config
+            .token()
+            .is_none()
+            .then(|| Response::new(403, "this route requires the admin token"))
+    }
+
+    fn provision_key(&self, body: &[u8]) -> Response {
+        // The response holds the secret of the new key
+        if let Some(response) = self.require_token() {
+            return response;
+        }
+        if self.dnsr.is_standby() {
+            return Response::new(409, "standby instance");
+        }
+
+        let new_key: NewKey = match serde_yaml::from_slice(body) {
+            Ok(new_key) => new_key,
+            Err(e) => return Response::new(400, e.to_string()),
+        };
+
+        let key_exists = self.dnsr.config.keys.contains_key(&new_key.key)
+            || self
+                .dnsr
+                .provisioned
+                .read()
+                .unwrap()
+                .contains_key(&new_key.key);
+        if key_exists {
+            return Response::new(409, "key already exists");
+        }
+
+        // Build the zones first so that an invalid entry leaves no key file behind
+        let zones = match build_zones(&new_key.domain, &new_key.info, &SystemClock) {
+            Ok(zones) => zones,
+            Err(e) => return Response::new(400, e.to_string()),
+        };
+        let apex_names = self.dnsr.zones.apex_names();
+        if zones
+            .iter()
+            .any(|zone| apex_names.contains(zone.apex_name()))
+        {
+            return Response::new(409, "domain already served");
+        }
+
+        // The secret is generated without holding the keystore lock
+        let secrets = self.dnsr.keystore.read().unwrap().secrets();
+        let generated = secrets.generate(&new_key.key);
+        let (key, secret) = match generated {
+            Ok(generated) => generated,
+            Err(e) if e.kind == ErrorKind::TSIGFileAlreadyExist => {
+                return Response::new(409, "key file already exists")
+            }
+            Err(e) => return Response::new(500, e.to_string()),
+        };
+
+        self.dnsr.keystore.write().unwrap().insert_key(key);
+        let mut inserted = Vec::new();
+        for zone in zones {
+            let apex = zone.apex_name().clone();
+            if let Err(e) = self.dnsr.zones.insert_zone(zone) {
+                self.roll_back_key(&new_key.key, &inserted);
+                return Response::new(500, e.to_string());
+            }
+            inserted.push(apex);
+        }
+
+        // The key is only provisioned once saved, it would be lost on restart
+        // otherwise
+        let saved = {
+            let mut provisioned = self.dnsr.provisioned.write().unwrap();
+            let mut updated = provisioned.clone();
+            updated.insert(new_key.key.clone(), new_key.domain, new_key.info);
+            let saved = save_provisioned(&self.dnsr, &updated);
+            if saved.is_ok() {
+                *provisioned = updated;
+            }
+            saved
+        };
+        if let Err(e) = saved {
+            log::error!(target: "admin", "failed to save the provisioned keys: {}", e);
+            self.roll_back_key(&new_key.key, &inserted);
+            return Response::new(500, e.to_string());
+        }
+        if let Some(webhooks) = &self.dnsr.webhooks {
+            for apex in &inserted {
+                webhooks.send(WebhookEvent::ZoneAdded, apex);
+            }
+        }
+        log::info!(target: "admin", "provisioned key {}", new_key.key);
+
+        Response::new(201, key_statement(&new_key.key, &secret))
+    }
+
+    /// Removes the zones `inserted` and the key of a failed provisioning,
+    /// along with its secret.
+    fn roll_back_key(&self, key: &KeyFile, inserted: &[StoredName]) {
+        for apex in inserted {
+            if let Err(e) = self.dnsr.zones.remove_zone(apex, Class::IN) {
+                log::error!(target: "admin", "failed to remove the zone {} of key {}: {}", apex, key, e);
+            }
+        }
+        self.remove_key(key);
+    }
+
+    /// Disables the zones `restored` of a failed restoration again, and
+    /// removes the key if it was `generated` for it.
+    fn roll_back_restore(&self, key: &KeyFile, generated: bool, restored: &[StoredName]) {
+        for apex in restored {
+            if let Err(e) = self.dnsr.zones.disable_zone(apex) {
+                log::error!(target: "admin", "failed to disable the zone {} again: {}", apex, e);
+            }
+        }
+        if generated {
+            self.remove_key(key);
+        }
+    }
+
+    /// Removes `key` from the keystore along with its secret.
+    fn remove_key(&self, key: &KeyFile) {
+        let removed = self.dnsr.keystore.write().unwrap().remove_key(key);
+        let secrets = self.dnsr.keystore.read().unwrap().secrets();
+        if let Err(e) = removed.and_then(|_| secrets.delete(key)) {
+            log::error!(target: "admin", "failed to remove the key {}: {}", key, e);
+        }
+    }
+}
+
+/// Returns the BIND `key` statement of `key`, of secret `secret`.
+fn key_statement(key: &KeyFile, secret: &str) -> String {
+    format!(
+        "key \"{}\" {{\n\talgorithm hmac-sha512;\n\tsecret \"{}\";\n}};\n",
+        key, secret
+    )
+}
+
+/// Writes the provisioned keys to a temporary file renamed over the previous one.
+fn save_provisioned(dnsr: &Dnsr, provisioned: &Keys) -> Result<()> {
+    let path = dnsr.config.provisioned_keys_path();
+    let tmp = path.with_extension("yml.tmp");
+
+    std::fs::write(&tmp, serde_yaml::to_string(provisioned)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct NewKey {
+    key: KeyFile,
+    domain: DomainName,
+    #[serde(flatten)]
+    info: DomainInfo,
+}
+
+/// A request of the admin API, also read by the cert-manager solver.
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn read<R>(reader: &mut R) -> Result<Self>
+    where
+        R: BufRead,
+    {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return Err(error!(Admin => "invalid request line {:?}", line));
+        };
+        let method = method.to_string();
+        let path = path.to_string();
+
+        let mut headers = Vec::new();
+        loop {
+            if headers.len() > MAX_HEADER_LINES {
+                return Err(error!(Admin => "too many headers"));
+            }
+
+            line.clear();
+            reader.read_line(&mut line)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+
+            let Some((name, value)) = header.split_once(':') else {
+                return Err(error!(Admin => "invalid header {:?}", header));
+            };
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+
+        let mut request = Self {
+            method,
+            path,
+            headers,
+            body: Vec::new(),
+        };
+
+        let len = request
+            .header("content-length")
+            .map(str::parse::<usize>)
+            .transpose()
+            .map_err(|e| error!(Admin => "invalid content length: {}", e))?
+            .unwrap_or(0);
+        if len > MAX_BODY_LEN {
+            return Err(error!(Admin => "body too large"));
+        }
+        request.body.resize(len, 0);
+        reader.read_exact(&mut request.body)?;
+
+        Ok(request)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new<B>(status: u16, body: B) -> Self
+    where
+        B: Into<Vec<u8>>,
+    {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: body.into(),
+        }
+    }
+
+    pub fn with_content_type(mut self, content_type: &'static str) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    pub fn write<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            409 => "Conflict",
+            _ => "Internal Server Error",
+        };
+
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len(),
+        )?;
+        writer.write_all(&self.body)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use domain::tsig::Key;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::key::build_zone;
+    use crate::service::middleware::Stats;
+
+    const CONFIG: &str = "
+admin:
+  listen: 127.0.0.1:0
+  token: change-me
+keys:
+  key1:
+    example.fr:
+      mname: ns-acme.example.fr.
+      rname: postmaster.example.fr.
+";
+
+    fn server() -> (AdminServer, Arc<Dnsr>) {
+        let config = Arc::new(Config::try_from(&CONFIG.as_bytes().to_vec()).unwrap());
+        let dnsr = Arc::new(Dnsr::from(config.clone()));
+        for (name, info) in dnsr.config.keys.domains() {
+            let zone = build_zone(name, info, &SystemClock).unwrap();
+            dnsr.zones.insert_zone(zone).unwrap();
+        }
+        let reporter = Arc::new(MetricsReporter::new(
+            Stats::new_shared(),
+            config.metrics_config(),
+        ));
+        let server = AdminServer::new(dnsr.clone(), reporter, config.admin_config().unwrap());
+        (server, dnsr)
+    }
+
+    fn send(server: &AdminServer, method: &str, path: &str) -> Response {
+        let request = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+        server.route(&Request::read(&mut request.as_bytes()).unwrap())
+    }
+
+    fn get(server: &AdminServer, path: &str) -> Response {
+        send(server, "GET", path)
+    }
+
+    #[test]
+    fn onboarding_reports_the_key_algorithm() {
+        let (server, dnsr) = server();
+
+        // An imported key keeps its own algorithm
+        let rng = ring::rand::SystemRandom::new();
+        let name = KeyName::from_str("key1").unwrap();
+        let (key, _) = Key::generate(Algorithm::Sha256, &rng, name, None, None).unwrap();
+        dnsr.keystore.write().unwrap().insert_key(key);
+
+        let response = get(&server, "/domains/*.example.fr/onboarding");
+        assert_eq!(response.status, 200);
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.starts_with("challenge _acme-challenge.example.fr.\nkey key1\n"));
+        assert!(body.contains("\nalgorithm hmac-sha256\n"));
+        assert!(body.contains("\nstatus ready serial "));
+
+        let response = get(&server, "/domains/example.com/onboarding");
+        assert_eq!(response.status, 404);
+    }
+
+    #[test]
+    fn only_the_zones_of_retained_domains_are_restored() {
+        let (server, dnsr) = server();
+        let apex = StoredName::bytes_from_str("_acme-challenge.example.fr").unwrap();
+
+        let response = send(&server, "POST", "/zones/_acme-challenge.example.fr/restore");
+        assert_eq!(response.status, 409);
+
+        // A zone disabled without the entry of its domain would be served
+        // without being tracked by the watcher nor updatable
+        dnsr.zones.disable_zone(&apex).unwrap();
+        let response = send(&server, "POST", "/zones/_acme-challenge.example.fr/restore");
+        assert_eq!(response.status, 404);
+        assert!(!dnsr.zones.apex_names().contains(&apex));
+    }
+}
@@ -0,0 +1,90 @@
+//! The networks whose requests are blocked.
+//!
+//! The blocked networks are listed in the configuration and in an optional
+//! file, one network per line with `#` starting a comment. Both are reloaded
+//! by the watcher along with the configuration so that an abusive client can
+//! be blocked without restarting the server.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::cidr::Cidr;
+use crate::config::{BlocklistAction, BlocklistConfig};
+use crate::error;
+use crate::error::Result;
+
+#[derive(Debug, Default)]
+pub struct Blocklist(RwLock<Entries>);
+
+#[derive(Debug, Default)]
+struct Entries {
+    networks: Vec<Cidr>,
+    action: BlocklistAction,
+}
+
+impl Blocklist {
+    /// Replaces the blocked networks with the ones of `config`, a missing
+    /// section blocks nothing. They are left unchanged if the file cannot be
+    /// loaded.
+    pub fn reload(&self, config: Option<&BlocklistConfig>) -> Result<()> {
+        let mut networks = Vec::new();
+        if let Some(config) = config {
+            networks.extend_from_slice(config.networks());
+            if let Some(path) = config.file() {
+                networks.extend(read_file(path)?);
+            }
+        }
+        let action = config.map(BlocklistConfig::action).unwrap_or_default();
+
+        let mut entries = self.0.write().unwrap();
+        if entries.networks != networks {
+            log::info!(target: "blocklist", "{} networks blocked", networks.len());
+        }
+        *entries = Entries { networks, action };
+        Ok(())
+    }
+
+    /// Returns the action applied to the requests of `addr`, `None` if it is
+    /// not blocked.
+    pub fn check(&self, addr: IpAddr) -> Option<BlocklistAction> {
+        let entries = self.0.read().unwrap();
+        entries
+            .networks
+            .iter()
+            .any(|network| network.contains(addr))
+            .then_some(entries.action)
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<Cidr>> {
+    let content = std::fs::read_to_string(path)?;
+    parse(&content)
+        .map_err(|e| error!(Cidr => "invalid blocklist file {}: {}", path.display(), e.text()))
+}
+
+fn parse(content: &str) -> Result<Vec<Cidr>> {
+    content
+        .lines()
+        .map(|line| line.split_once('#').map_or(line, |(line, _)| line).trim())
+        .filter(|line| !line.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_file_lists_a_network_per_line() {
+        let content = "# abusive resolvers\n192.0.2.0/24\n\n2001:db8::1 # single address\n";
+        let networks = parse(content).unwrap();
+        assert_eq!(networks.len(), 2);
+        assert!(networks[0].contains("192.0.2.42".parse().unwrap()));
+        assert!(networks[1].contains("2001:db8::1".parse().unwrap()));
+        assert!(!networks[1].contains("2001:db8::2".parse().unwrap()));
+
+        assert!(parse("192.0.2.0/33\n").is_err());
+    }
+}
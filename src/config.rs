@@ -1,66 +1,1566 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use domain::base::Ttl;
+use domain::zonetree::types::StoredName;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 
+use crate::cidr::Cidr;
+use crate::dname::DomainName;
+use crate::error;
 use crate::error::Result;
-use crate::key::Keys;
+use crate::geo::GeoInfo;
+use crate::key::{KeyFile, Keys, TryInto};
+use crate::serial::SerialPolicy;
 
 pub const TSIG_PATH: &str = "/etc/dnsr/keys";
 pub const BASE_CONFIG_FILE: &str = "/etc/dnsr/config.yml";
+pub const TOML_CONFIG_FILE: &str = "/etc/dnsr/config.toml";
+pub const PROVISIONED_KEYS_FILE: &str = "/etc/dnsr/provisioned.yml";
+pub const DNS_PORT: u16 = 53;
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
     log: Option<LogConfig>,
+    metrics: Option<MetricsConfig>,
+    redis: Option<RedisConfig>,
+    s3: Option<S3Config>,
+    vault: Option<VaultConfig>,
+    serial_policy: Option<SerialPolicy>,
+    removed_zone_retention: Option<u64>,
+    reload_debounce: Option<u64>,
+    prune_orphaned_key_files: Option<bool>,
+    standby: Option<bool>,
+    update_forwarding: Option<UpdateForwardingConfig>,
+    refuse_out_of_zone: Option<bool>,
+    admin: Option<AdminConfig>,
+    cert_manager: Option<CertManagerConfig>,
+    import_keys: Option<Vec<PathBuf>>,
+    secondaries: Option<Vec<SecondaryConfig>>,
+    webhooks: Option<Vec<WebhookConfig>>,
+    alerts: Option<AlertConfig>,
+    journal: Option<JournalConfig>,
+    client_id_option: Option<u16>,
+    acl: Option<AclConfig>,
+    geoip: Option<GeoIpConfig>,
+    capture: Option<CaptureConfig>,
+    telemetry: Option<TelemetryConfig>,
+    cache_flush: Option<CacheFlushConfig>,
+    edns: Option<EdnsConfig>,
+    tsig: Option<TsigConfig>,
+    stats_zone: Option<StatsZoneConfig>,
+    catalog: Option<CatalogConfig>,
+    chaos: Option<ChaosConfig>,
+    udp_workers: Option<UdpWorkersConfig>,
+    workers: Option<WorkersConfig>,
+    limits: Option<LimitsConfig>,
+    rate_limit: Option<RateLimitConfig>,
+    update_rate_limit: Option<UpdateRateLimitConfig>,
+    blocklist: Option<BlocklistConfig>,
+    challenge_expiry: Option<ChallengeExpiryConfig>,
+    include: Option<Vec<PathBuf>>,
+    zones_dir: Option<PathBuf>,
+    #[cfg(feature = "kubernetes")]
+    kubernetes: Option<KubernetesConfig>,
+    kv: Option<KvConfig>,
 
+    /// The domains of every key, they may all come from the included files,
+    /// the Kubernetes ConfigMaps or the key value store
+    #[serde(default)]
     pub keys: Keys,
 }
 
 impl Config {
+    /// The path of the configuration file, the TOML file is only used in
+    /// place of the YAML one if the latter does not exist.
     pub fn config_file_path() -> String {
-        std::env::var("DNSR_CONFIG").unwrap_or(BASE_CONFIG_FILE.into())
+        std::env::var("DNSR_CONFIG").unwrap_or_else(|_| {
+            if !Path::new(BASE_CONFIG_FILE).exists() && Path::new(TOML_CONFIG_FILE).exists() {
+                TOML_CONFIG_FILE.into()
+            } else {
+                BASE_CONFIG_FILE.into()
+            }
+        })
+    }
+
+    /// Parses the content of a configuration file in `format`, its includes
+    /// are not merged.
+    pub fn parse(bytes: &[u8], format: ConfigFormat) -> Result<Self> {
+        Self::from_value(parse_value(bytes, format)?)
+    }
+
+    /// Loads the configuration file `path` and merges the fragments it
+    /// includes, the formats are detected from the extensions.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut value = read_value(path)?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        let includes = resolve_includes(&mut value, base)?;
+
+        for include in includes {
+            let fragment = read_value(&include).map_err(
+                |e| error!(Include => "invalid included file {}: {}", include.display(), e.text()),
+            )?;
+            if fragment.get("include").is_some() {
+                return Err(error!(Include => "nested include in {}", include.display()));
+            }
+            merge_fragment(&mut value, fragment);
+        }
+
+        #[cfg(feature = "kubernetes")]
+        if let Some(kubernetes) = value.get("kubernetes") {
+            let kubernetes: KubernetesConfig = serde_yaml::from_value(kubernetes.clone())?;
+            for fragment in crate::kubernetes::fragments(&kubernetes)? {
+                merge_fragment(&mut value, fragment);
+            }
+        }
+
+        if let Some(kv) = value.get("kv") {
+            let kv: KvConfig = serde_yaml::from_value(kv.clone())?;
+            for fragment in crate::kv::fragments(&kv)? {
+                merge_fragment(&mut value, fragment);
+            }
+        }
+
+        Self::from_value(value)
+    }
+
+    fn from_value(mut value: serde_yaml::Value) -> Result<Self> {
+        value.apply_merge()?;
+        apply_domain_defaults(&mut value);
+
+        Ok(serde_yaml::from_value(value)?)
+    }
+
+    /// The fragments merged into the configuration, the relative paths are
+    /// resolved from the directory of the configuration file.
+    pub fn includes(&self) -> &[PathBuf] {
+        self.include.as_deref().unwrap_or_default()
     }
 
     pub fn tsig_path(&self) -> &Path {
         Path::new(TSIG_PATH)
     }
 
-    pub fn log_config(&self) -> LogConfig {
-        self.log.unwrap_or_default()
+    /// The file storing the keys and domains provisioned through the admin API.
+    pub fn provisioned_keys_path(&self) -> &Path {
+        Path::new(PROVISIONED_KEYS_FILE)
+    }
+
+    /// The folder of the zone files served along with the zones of the keys.
+    pub fn zones_dir(&self) -> Option<&Path> {
+        self.zones_dir.as_deref()
+    }
+
+    /// The BIND key files whose keys are loaded in the keystore.
+    pub fn import_keys(&self) -> &[PathBuf] {
+        self.import_keys.as_deref().unwrap_or_default()
+    }
+
+    /// The secondaries notified of the updated zones.
+    pub fn secondaries(&self) -> &[SecondaryConfig] {
+        self.secondaries.as_deref().unwrap_or_default()
+    }
+
+    /// The urls the events of the zones and keys are posted to.
+    pub fn webhooks(&self) -> &[WebhookConfig] {
+        self.webhooks.as_deref().unwrap_or_default()
+    }
+
+    /// Returns whether `key` is the key of a secondary at `addr`, its signed
+    /// transfers are served without the key handling the zone.
+    pub fn is_secondary_key(&self, addr: IpAddr, key: &KeyFile) -> bool {
+        self.secondaries().iter().any(|secondary| {
+            secondary.address.ip().to_canonical() == addr.to_canonical()
+                && secondary.key.as_ref() == Some(key)
+        })
+    }
+
+    pub fn log_config(&self) -> LogConfig {
+        self.log.clone().unwrap_or_default()
+    }
+
+    pub fn metrics_config(&self) -> MetricsConfig {
+        self.metrics.clone().unwrap_or_default()
+    }
+
+    pub fn serial_policy(&self) -> SerialPolicy {
+        self.serial_policy.unwrap_or_default()
+    }
+
+    /// How long a zone removed from the configuration is retained in a disabled state.
+    pub fn removed_zone_retention(&self) -> Duration {
+        Duration::from_secs(self.removed_zone_retention.unwrap_or(86400))
+    }
+
+    /// How long the watched files must be left unchanged before they are
+    /// reloaded, a burst of changes is reloaded once.
+    pub fn reload_debounce(&self) -> Duration {
+        Duration::from_millis(self.reload_debounce.unwrap_or(500))
+    }
+
+    /// The private EDNS option code carrying the client correlation id of updates.
+    /// Whether the key files matching no configured key are deleted instead
+    /// of only being reported.
+    pub fn prune_orphaned_key_files(&self) -> bool {
+        self.prune_orphaned_key_files.unwrap_or(false)
+    }
+
+    /// Whether the instance starts as a read-only replica, until promoted.
+    pub fn standby(&self) -> bool {
+        self.standby.unwrap_or(false)
+    }
+
+    /// The primary the updates received by a standby are forwarded to, they
+    /// are refused if none.
+    pub fn update_forwarding_config(&self) -> Option<UpdateForwardingConfig> {
+        self.update_forwarding
+    }
+
+    /// Whether the queries of names under none of the zones are refused
+    /// instead of answered NXDOMAIN.
+    pub fn refuse_out_of_zone(&self) -> bool {
+        self.refuse_out_of_zone.unwrap_or(true)
+    }
+
+    pub fn client_id_option(&self) -> u16 {
+        self.client_id_option.unwrap_or(65001)
+    }
+
+    pub fn alert_config(&self) -> AlertConfig {
+        self.alerts.unwrap_or_default()
+    }
+
+    pub fn journal_config(&self) -> JournalConfig {
+        self.journal.unwrap_or_default()
+    }
+
+    pub fn acl_config(&self) -> AclConfig {
+        self.acl.clone().unwrap_or_default()
+    }
+
+    pub fn geoip_config(&self) -> Option<&GeoIpConfig> {
+        self.geoip.as_ref()
+    }
+
+    pub fn capture_config(&self) -> Option<CaptureConfig> {
+        self.capture
+    }
+
+    pub fn telemetry_config(&self) -> Option<&TelemetryConfig> {
+        self.telemetry.as_ref()
+    }
+
+    pub fn cache_flush_config(&self) -> Option<&CacheFlushConfig> {
+        self.cache_flush.as_ref()
+    }
+
+    pub fn edns_config(&self) -> EdnsConfig {
+        self.edns.unwrap_or_default()
+    }
+
+    pub fn tsig_config(&self) -> TsigConfig {
+        self.tsig.unwrap_or_default()
+    }
+
+    pub fn stats_zone_config(&self) -> Option<&StatsZoneConfig> {
+        self.stats_zone.as_ref()
+    }
+
+    pub fn catalog_config(&self) -> Option<&CatalogConfig> {
+        self.catalog.as_ref()
+    }
+
+    pub fn chaos_config(&self) -> Option<&ChaosConfig> {
+        self.chaos.as_ref()
+    }
+
+    pub fn limits_config(&self) -> LimitsConfig {
+        self.limits.unwrap_or_default()
+    }
+
+    pub fn rate_limit_config(&self) -> Option<RateLimitConfig> {
+        self.rate_limit
+    }
+
+    pub fn update_rate_limit_config(&self) -> Option<UpdateRateLimitConfig> {
+        self.update_rate_limit
+    }
+
+    pub fn blocklist_config(&self) -> Option<&BlocklistConfig> {
+        self.blocklist.as_ref()
+    }
+
+    pub fn challenge_expiry_config(&self) -> Option<ChallengeExpiryConfig> {
+        self.challenge_expiry
+    }
+
+    /// The UDP workers, a fixed `workers.udp` count disables their scaling.
+    pub fn udp_workers_config(&self) -> UdpWorkersConfig {
+        let mut config = self.udp_workers.unwrap_or_default();
+        if let Some(udp) = self.workers_config().udp() {
+            config.min = Some(udp);
+            config.max = Some(udp);
+        }
+        config
+    }
+
+    pub fn workers_config(&self) -> WorkersConfig {
+        self.workers.unwrap_or_default()
+    }
+
+    #[cfg(feature = "kubernetes")]
+    pub fn kubernetes_config(&self) -> Option<&KubernetesConfig> {
+        self.kubernetes.as_ref()
+    }
+
+    pub fn kv_config(&self) -> Option<&KvConfig> {
+        self.kv.as_ref()
+    }
+
+    pub fn admin_config(&self) -> Option<&AdminConfig> {
+        self.admin.as_ref()
+    }
+
+    pub fn cert_manager_config(&self) -> Option<&CertManagerConfig> {
+        self.cert_manager.as_ref()
+    }
+
+    pub fn redis_config(&self) -> Option<&RedisConfig> {
+        self.redis.as_ref()
+    }
+
+    pub fn s3_config(&self) -> Option<&S3Config> {
+        self.s3.as_ref()
+    }
+
+    pub fn vault_config(&self) -> Option<&VaultConfig> {
+        self.vault.as_ref()
+    }
+}
+
+impl TryFrom<&Vec<u8>> for Config {
+    type Error = crate::error::Error;
+
+    fn try_from(value: &Vec<u8>) -> Result<Self> {
+        Self::parse(value, ConfigFormat::Yaml)
+    }
+}
+
+/// The format of a configuration file, both are parsed into the same schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// Detects the format of the file `path` from its extension, YAML unless
+    /// it is `toml`.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+}
+
+fn parse_value(bytes: &[u8], format: ConfigFormat) -> Result<serde_yaml::Value> {
+    Ok(match format {
+        ConfigFormat::Yaml => serde_yaml::from_slice(bytes)?,
+        ConfigFormat::Toml => toml::from_str(std::str::from_utf8(bytes)?)?,
+    })
+}
+
+/// Parses the JSON `body` of a response of the Kubernetes, cert-manager, KV or
+/// Vault APIs. JSON is a subset of YAML, the YAML parser of the configuration
+/// reads it as well and spares a JSON dependency.
+pub fn parse_json<T: DeserializeOwned>(body: impl AsRef<[u8]>) -> serde_yaml::Result<T> {
+    serde_yaml::from_slice(body.as_ref())
+}
+
+fn read_value(path: &Path) -> Result<serde_yaml::Value> {
+    parse_value(&std::fs::read(path)?, ConfigFormat::from_path(path))
+}
+
+/// Resolves the paths of the `include` sequence of `config` from `base` in
+/// place and returns them.
+fn resolve_includes(config: &mut serde_yaml::Value, base: &Path) -> Result<Vec<PathBuf>> {
+    let Some(include) = config.get_mut("include") else {
+        return Ok(Vec::new());
+    };
+    let Some(paths) = include.as_sequence_mut() else {
+        return Err(error!(Include => "include must be a list of paths"));
+    };
+
+    let mut includes = Vec::with_capacity(paths.len());
+    for path in paths.iter_mut() {
+        let Some(relative) = path.as_str() else {
+            return Err(error!(Include => "invalid include path {:?}", path));
+        };
+        let resolved = base.join(relative);
+        *path = resolved.to_string_lossy().into_owned().into();
+        includes.push(resolved);
+    }
+    Ok(includes)
+}
+
+/// Merges the mappings of `fragment` into `config` recursively, e.g. the
+/// domains of a key spread over several files. The other values already set
+/// in `config` take precedence.
+fn merge_fragment(config: &mut serde_yaml::Value, fragment: serde_yaml::Value) {
+    if config.is_null() {
+        *config = fragment;
+        return;
+    }
+    let (Some(config), serde_yaml::Value::Mapping(fragment)) = (config.as_mapping_mut(), fragment)
+    else {
+        return;
+    };
+
+    for (key, value) in fragment {
+        match config.get_mut(&key) {
+            Some(existing) => merge_fragment(existing, value),
+            None => {
+                config.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Merges the `defaults` mapping into every domain entry of the `keys` mapping,
+/// the fields set on an entry take precedence.
+fn apply_domain_defaults(config: &mut serde_yaml::Value) {
+    let Some(defaults) = config.get("defaults").and_then(|d| d.as_mapping()).cloned() else {
+        return;
+    };
+    let Some(keys) = config.get_mut("keys").and_then(|k| k.as_mapping_mut()) else {
+        return;
+    };
+
+    for domains in keys.values_mut().filter_map(|d| d.as_mapping_mut()) {
+        for entry in domains.values_mut() {
+            // A domain without any field only uses the defaults
+            if entry.is_null() {
+                *entry = serde_yaml::Mapping::new().into();
+            }
+
+            if let Some(entry) = entry.as_mapping_mut() {
+                for (field, value) in &defaults {
+                    if !entry.contains_key(field) {
+                        entry.insert(field.clone(), value.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct LogConfig {
+    #[serde(deserialize_with = "de_opt_level_filter")]
+    level: Option<log::LevelFilter>,
+    enable_metrics: Option<bool>,
+    enable_thread_id: Option<bool>,
+    stderr: Option<bool>,
+    syslog: Option<SyslogConfig>,
+    file: Option<LogFileConfig>,
+}
+
+impl LogConfig {
+    pub fn level(&self) -> log::LevelFilter {
+        self.level.unwrap_or(log::LevelFilter::Info)
+    }
+
+    pub fn enable_metrics(&self) -> bool {
+        self.enable_metrics.unwrap_or(true)
+    }
+
+    pub fn enable_thread_id(&self) -> bool {
+        self.enable_thread_id.unwrap_or(false)
+    }
+
+    pub fn stderr(&self) -> bool {
+        self.stderr.unwrap_or(false)
+    }
+
+    /// The syslog output, used instead of stdout/stderr when set.
+    pub fn syslog(&self) -> Option<&SyslogConfig> {
+        self.syslog.as_ref()
+    }
+
+    /// The log file, used instead of stdout/stderr when set.
+    pub fn file(&self) -> Option<&LogFileConfig> {
+        self.file.as_ref()
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct MetricsConfig {
+    interval: Option<u64>,
+    sinks: Option<Vec<MetricsSink>>,
+    verbosity: Option<MetricsVerbosity>,
+}
+
+impl MetricsConfig {
+    /// The interval between two metrics reports.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(5).max(1))
+    }
+
+    /// Whether the reports are sent to `sink`, only logged by default.
+    pub fn has_sink(&self, sink: MetricsSink) -> bool {
+        match &self.sinks {
+            Some(sinks) => sinks.contains(&sink),
+            None => sink == MetricsSink::Log,
+        }
+    }
+
+    pub fn verbosity(&self) -> MetricsVerbosity {
+        self.verbosity.unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsSink {
+    /// The `metrics` target of the logs
+    Log,
+    /// The `GET /metrics` route of the admin API
+    Admin,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsVerbosity {
+    /// Only the request counters and latencies
+    Summary,
+    /// The counters of every update failure reason and zone as well
+    #[default]
+    Full,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct LogFileConfig {
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_age: Option<u64>,
+    retention: Option<usize>,
+}
+
+impl LogFileConfig {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The size in bytes above which the file is rotated.
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    /// The age above which the file is rotated.
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age.map(Duration::from_secs)
+    }
+
+    /// The number of rotated files kept.
+    pub fn retention(&self) -> usize {
+        self.retention.unwrap_or(7)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SyslogConfig {
+    address: Option<String>,
+    facility: Option<String>,
+    app_name: Option<String>,
+}
+
+impl SyslogConfig {
+    /// The local socket path, or a `udp://host:port` or `tcp://host:port` server.
+    pub fn address(&self) -> &str {
+        self.address.as_deref().unwrap_or("/dev/log")
+    }
+
+    pub fn facility(&self) -> &str {
+        self.facility.as_deref().unwrap_or("daemon")
+    }
+
+    pub fn app_name(&self) -> &str {
+        self.app_name.as_deref().unwrap_or("dnsr")
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct AlertConfig {
+    window: Option<u64>,
+    factor: Option<f64>,
+    min_updates: Option<u32>,
+    min_records: Option<usize>,
+}
+
+impl AlertConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window.unwrap_or(60).max(1))
+    }
+
+    /// How many times above its baseline a zone activity is reported.
+    pub fn factor(&self) -> f64 {
+        self.factor.unwrap_or(5.0)
+    }
+
+    pub fn min_updates(&self) -> u32 {
+        self.min_updates.unwrap_or(10)
+    }
+
+    pub fn min_records(&self) -> usize {
+        self.min_records.unwrap_or(20)
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct JournalConfig {
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+}
+
+impl JournalConfig {
+    /// The number of changes retained per zone, 0 disables the journal.
+    pub fn max_entries(&self) -> usize {
+        self.max_entries.unwrap_or(100)
+    }
+
+    /// The size of the record data retained per zone, in bytes.
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes.unwrap_or(1024 * 1024)
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct AclConfig {
+    #[serde(flatten)]
+    global: AccessList,
+    #[serde(default)]
+    zones: HashMap<DomainName, AccessList>,
+}
+
+impl AclConfig {
+    /// Returns whether `addr`, tagged with `geo` if known, may query the zone
+    /// of `domain`, the global list is checked before the list of the zone.
+    pub fn allows(&self, addr: IpAddr, geo: Option<&GeoInfo>, domain: Option<&DomainName>) -> bool {
+        self.global.allows(addr, geo)
+            && domain
+                .and_then(|domain| self.zones.get(domain))
+                .map_or(true, |list| list.allows(addr, geo))
+    }
+
+    /// Returns whether the transfers of the zone of `domain` to `addr` are
+    /// sent one record per message, for the old clients expecting it.
+    pub fn single_record_transfer(&self, addr: IpAddr, domain: &DomainName) -> bool {
+        self.global.single_record_transfer(addr)
+            || self
+                .zones
+                .get(domain)
+                .is_some_and(|list| list.single_record_transfer(addr))
+    }
+
+    /// Returns the bounds of the TTLs of the transfers of the zone of `domain`
+    /// to `addr`, the bounds of the zone take precedence over the global ones.
+    pub fn transfer_ttl(&self, addr: IpAddr, domain: &DomainName) -> Option<TransferTtl> {
+        self.zones
+            .get(domain)
+            .and_then(|list| list.transfer_ttl(addr))
+            .or_else(|| self.global.transfer_ttl(addr))
+            .cloned()
+    }
+
+    /// Returns whether `addr` may transfer the zone of `domain`, both the
+    /// global and the zone transfer policies must allow it. `signed` tells
+    /// whether the request carries a valid TSIG signature.
+    pub fn allows_transfer<F>(&self, addr: IpAddr, domain: &DomainName, signed: F) -> bool
+    where
+        F: FnOnce() -> bool,
+    {
+        let policies = self.global.transfer.iter().chain(
+            self.zones
+                .get(domain)
+                .and_then(|list| list.transfer.as_ref()),
+        );
+
+        let mut tsig = false;
+        for policy in policies {
+            if !policy.allows(addr) {
+                return false;
+            }
+            tsig |= policy.tsig;
+        }
+        !tsig || signed()
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct AccessList {
+    #[serde(default)]
+    allow: Vec<Cidr>,
+    #[serde(default)]
+    deny: Vec<Cidr>,
+    /// The countries denied, by their ISO 3166 code
+    #[serde(default)]
+    deny_countries: Vec<String>,
+    /// The autonomous systems denied
+    #[serde(default)]
+    deny_asns: Vec<u32>,
+    #[serde(default)]
+    single_record_transfer: Vec<Cidr>,
+    #[serde(default)]
+    transfer_ttl: Vec<TransferTtl>,
+    transfer: Option<TransferPolicy>,
+}
+
+impl AccessList {
+    /// A denied network, country or autonomous system takes precedence over an
+    /// allowed network, every address is allowed when the allow list is empty.
+    /// The countries and autonomous systems only apply to the tagged addresses.
+    pub fn allows(&self, addr: IpAddr, geo: Option<&GeoInfo>) -> bool {
+        let denied_geo = geo.is_some_and(|geo| {
+            self.deny_asns.contains(&geo.asn)
+                || self
+                    .deny_countries
+                    .iter()
+                    .any(|country| country.eq_ignore_ascii_case(&geo.country))
+        });
+
+        !denied_geo
+            && !self.deny.iter().any(|cidr| cidr.contains(addr))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr)))
+    }
+
+    pub fn single_record_transfer(&self, addr: IpAddr) -> bool {
+        self.single_record_transfer
+            .iter()
+            .any(|cidr| cidr.contains(addr))
+    }
+
+    /// Returns the first TTL bounds matching `addr`.
+    pub fn transfer_ttl(&self, addr: IpAddr) -> Option<&TransferTtl> {
+        self.transfer_ttl
+            .iter()
+            .find(|ttl| ttl.clients.iter().any(|cidr| cidr.contains(addr)))
+    }
+}
+
+/// The bounds of the TTLs sent in the transfers to some clients.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TransferTtl {
+    clients: Vec<Cidr>,
+    min: Option<u32>,
+    max: Option<u32>,
+}
+
+impl TransferTtl {
+    pub fn clamp(&self, ttl: Ttl) -> Ttl {
+        let mut secs = ttl.as_secs();
+        if let Some(min) = self.min {
+            secs = secs.max(min);
+        }
+        if let Some(max) = self.max {
+            secs = secs.min(max);
+        }
+        Ttl::from_secs(secs)
+    }
+}
+
+/// Who may transfer a zone, every client may if no policy is configured.
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct TransferPolicy {
+    #[serde(default)]
+    allow: Vec<Cidr>,
+    #[serde(default)]
+    tsig: bool,
+}
+
+impl TransferPolicy {
+    /// Every address is allowed when the allow list is empty.
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr))
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct CaptureConfig {
+    size: Option<usize>,
+}
+
+impl CaptureConfig {
+    /// The number of exchanges kept in memory.
+    pub fn size(&self) -> usize {
+        self.size.unwrap_or(1000).max(1)
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct EdnsConfig {
+    udp_payload_size: Option<u16>,
+    padding_block_size: Option<u16>,
+}
+
+impl EdnsConfig {
+    /// The largest UDP response, advertised in the OPT record of the responses.
+    ///
+    /// The default follows the DNS flag day 2020 recommendation, the value is
+    /// kept between the 512 bytes of RFC 1035 and 4096 bytes.
+    pub fn udp_payload_size(&self) -> u16 {
+        self.udp_payload_size.unwrap_or(1232).clamp(512, 4096)
+    }
+
+    /// The block size the responses are padded to, `None` to disable padding.
+    ///
+    /// RFC 8467 recommends padding the responses to a multiple of 468 bytes.
+    pub fn padding_block_size(&self) -> Option<u16> {
+        self.padding_block_size.filter(|size| *size > 0)
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct TsigConfig {
+    fudge: Option<u16>,
+}
+
+impl TsigConfig {
+    /// The clock skew in seconds accepted between the signing time of a
+    /// request and the server clock, 300 as recommended by RFC 8945.
+    pub fn fudge(&self) -> u16 {
+        self.fudge.unwrap_or(300)
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct UdpWorkersConfig {
+    min: Option<usize>,
+    max: Option<usize>,
+    queue_delay: Option<u64>,
+    interval: Option<u64>,
+    batch: Option<usize>,
+}
+
+impl UdpWorkersConfig {
+    /// The number of workers started and kept at least.
+    pub fn min(&self) -> usize {
+        self.min.unwrap_or(1).max(1)
+    }
+
+    /// The number of workers never exceeded, one per core by default.
+    pub fn max(&self) -> usize {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        self.max.unwrap_or(cores).max(self.min())
+    }
+
+    /// The mean queue delay of the UDP requests above which a worker is added.
+    pub fn queue_delay(&self) -> Duration {
+        Duration::from_millis(self.queue_delay.unwrap_or(10))
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(5).max(1))
+    }
+
+    /// The number of datagrams read and written per syscall, the datagrams
+    /// are handled one by one if not set.
+    pub fn batch(&self) -> Option<usize> {
+        self.batch.filter(|batch| *batch > 1)
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct WorkersConfig {
+    udp: Option<usize>,
+    tokio_threads: Option<usize>,
+    blocking_threads: Option<usize>,
+}
+
+impl WorkersConfig {
+    /// The fixed number of UDP workers, scaled on the load if not set.
+    pub fn udp(&self) -> Option<usize> {
+        self.udp.map(|udp| udp.max(1))
+    }
+
+    /// The number of threads of the runtime, one per core if not set.
+    pub fn tokio_threads(&self) -> Option<usize> {
+        self.tokio_threads.map(|threads| threads.max(1))
+    }
+
+    /// The largest number of threads of the blocking pool, e.g. running the
+    /// zone transfers, 512 if not set.
+    pub fn blocking_threads(&self) -> Option<usize> {
+        self.blocking_threads.map(|threads| threads.max(1))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CacheFlushConfig {
+    targets: Vec<FlushTarget>,
+    timeout: Option<u64>,
+    retries: Option<u32>,
+    backoff: Option<u64>,
+}
+
+impl CacheFlushConfig {
+    /// The resolvers the updated names are flushed from.
+    pub fn targets(&self) -> &[FlushTarget] {
+        &self.targets
+    }
+
+    /// The timeout of a flush in seconds.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(2).max(1))
+    }
+
+    /// The number of times a failed flush is tried again, none by default.
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(0)
+    }
+
+    /// The wait before the first retry of a flush in seconds, doubled on
+    /// every retry, 1 second by default.
+    pub fn backoff(&self) -> Duration {
+        Duration::from_secs(self.backoff.unwrap_or(1))
+    }
+}
+
+/// A resolver and the way to flush a name from its cache.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FlushTarget {
+    /// A DNS NOTIFY of the zone, which wipes it from the cache of the
+    /// resolvers allowing it, e.g. `allow-notify-for` of PowerDNS Recursor
+    Notify(std::net::SocketAddr),
+    /// The control socket of a Knot Resolver instance
+    Kresd(PathBuf),
+    /// A command run with the name as its last argument, e.g.
+    /// `[unbound-control, flush_zone]`
+    Command(Vec<String>),
+    /// An url the name is posted to as `{"name": "<name>"}`
+    Http(String),
+}
+
+impl std::fmt::Display for FlushTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlushTarget::Notify(addr) => write!(f, "notify {}", addr),
+            FlushTarget::Kresd(path) => write!(f, "kresd {}", path.display()),
+            FlushTarget::Command(argv) => write!(f, "command {}", argv.join(" ")),
+            FlushTarget::Http(url) => write!(f, "http {}", url),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct LimitsConfig {
+    max_open_files: Option<u64>,
+    memory_warning: Option<u64>,
+    max_zones: Option<usize>,
+}
+
+impl LimitsConfig {
+    /// The soft limit of open file descriptors requested at startup, the
+    /// inherited limit is kept by default.
+    pub fn max_open_files(&self) -> Option<u64> {
+        self.max_open_files
+    }
+
+    /// The resident memory in bytes above which a warning is logged.
+    pub fn memory_warning(&self) -> Option<u64> {
+        self.memory_warning.map(|mib| mib * 1024 * 1024)
+    }
+
+    /// The largest number of zones served, unlimited by default.
+    pub fn max_zones(&self) -> Option<usize> {
+        self.max_zones
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct RateLimitConfig {
+    qps: Option<u32>,
+    burst: Option<u32>,
+    action: Option<RateLimitAction>,
+}
+
+impl RateLimitConfig {
+    /// The sustained rate of UDP requests of a client prefix per second.
+    pub fn qps(&self) -> u32 {
+        self.qps.unwrap_or(20).max(1)
+    }
+
+    /// The largest burst of requests of a client prefix answered at once,
+    /// twice the rate by default.
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or(2 * self.qps()).max(1)
+    }
+
+    pub fn action(&self) -> RateLimitAction {
+        self.action.unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    /// An empty truncated response, the legitimate clients retry over TCP
+    #[default]
+    Truncate,
+    /// No response at all
+    Drop,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct UpdateRateLimitConfig {
+    per_key: Option<u32>,
+    per_zone: Option<u32>,
+}
+
+impl UpdateRateLimitConfig {
+    /// The sustained rate of updates signed by a key per minute.
+    pub fn per_key(&self) -> u32 {
+        self.per_key.unwrap_or(60).max(1)
+    }
+
+    /// The sustained rate of updates of a zone per minute.
+    pub fn per_zone(&self) -> u32 {
+        self.per_zone.unwrap_or(20).max(1)
     }
 }
 
-impl TryFrom<&Vec<u8>> for Config {
-    type Error = crate::error::Error;
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct ChallengeExpiryConfig {
+    after: Option<u64>,
+    interval: Option<u64>,
+}
 
-    fn try_from(value: &Vec<u8>) -> Result<Self> {
-        Ok(serde_yaml::from_slice(value)?)
+impl ChallengeExpiryConfig {
+    /// How long a challenge record is served before it is removed, one hour
+    /// by default.
+    pub fn after(&self) -> Duration {
+        Duration::from_secs(self.after.unwrap_or(3600))
+    }
+
+    /// The interval between two sweeps of the challenge zones, one minute by
+    /// default.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(60).max(1))
     }
 }
 
-#[derive(Deserialize, Default, Clone, Copy, Debug)]
-pub struct LogConfig {
-    #[serde(deserialize_with = "de_opt_level_filter")]
-    level: Option<log::LevelFilter>,
-    enable_metrics: Option<bool>,
-    enable_thread_id: Option<bool>,
-    stderr: Option<bool>,
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct UpdateForwardingConfig {
+    primary: SocketAddr,
+    timeout: Option<u64>,
+    retries: Option<u32>,
+    backoff: Option<u64>,
 }
 
-impl LogConfig {
-    pub fn level(&self) -> log::LevelFilter {
-        self.level.unwrap_or(log::LevelFilter::Info)
+impl UpdateForwardingConfig {
+    pub fn primary(&self) -> SocketAddr {
+        self.primary
     }
 
-    pub fn enable_metrics(&self) -> bool {
-        self.enable_metrics.unwrap_or(true)
+    /// The time waited for the response of the primary, 5 seconds by default.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(5))
     }
 
-    pub fn enable_thread_id(&self) -> bool {
-        self.enable_thread_id.unwrap_or(false)
+    /// The number of times an update is forwarded again when the primary
+    /// cannot be reached, none by default.
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(0)
     }
 
-    pub fn stderr(&self) -> bool {
-        self.stderr.unwrap_or(false)
+    /// The wait before the first retry of a forwarding in seconds, doubled on
+    /// every retry, 1 second by default.
+    pub fn backoff(&self) -> Duration {
+        Duration::from_secs(self.backoff.unwrap_or(1))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SecondaryConfig {
+    address: SocketAddr,
+    key: Option<KeyFile>,
+    timeout: Option<u64>,
+    retries: Option<u32>,
+    backoff: Option<u64>,
+}
+
+impl SecondaryConfig {
+    /// The address the NOTIFY messages are sent to.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The TSIG key signing the messages exchanged with the secondary.
+    pub fn key(&self) -> Option<&KeyFile> {
+        self.key.as_ref()
+    }
+
+    /// The time waited for the acknowledgement of a NOTIFY in seconds, 2
+    /// seconds by default.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(2).max(1))
+    }
+
+    /// The number of times an unacknowledged NOTIFY is sent again, 3 by
+    /// default.
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(3)
+    }
+
+    /// The wait before the first retry of a NOTIFY in seconds, doubled on
+    /// every retry, 1 second by default.
+    pub fn backoff(&self) -> Duration {
+        Duration::from_secs(self.backoff.unwrap_or(1))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    url: String,
+    #[serde(default)]
+    events: Vec<WebhookEvent>,
+    timeout: Option<u64>,
+}
+
+impl WebhookConfig {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns whether the events of `kind` are posted to the url, every
+    /// event is if none is listed.
+    pub fn accepts(&self, kind: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+
+    /// The timeout of a post in seconds, 5 seconds by default.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(5))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    ZoneAdded,
+    ZoneRemoved,
+    RecordsUpdated,
+    KeyRotated,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct BlocklistConfig {
+    #[serde(default)]
+    networks: Vec<Cidr>,
+    file: Option<PathBuf>,
+    action: Option<BlocklistAction>,
+}
+
+impl BlocklistConfig {
+    pub fn networks(&self) -> &[Cidr] {
+        &self.networks
+    }
+
+    /// The file listing more blocked networks, one per line.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    pub fn action(&self) -> BlocklistAction {
+        self.action.unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlocklistAction {
+    /// No response at all
+    #[default]
+    Drop,
+    /// A REFUSED response
+    Refuse,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StatsZoneConfig {
+    instance: DomainName,
+}
+
+impl StatsZoneConfig {
+    /// The apex of the control zone exposing the counters.
+    pub fn apex(&self) -> Result<StoredName> {
+        format!("_stats.{}", self.instance).as_bytes().try_into_t()
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct GeoIpConfig {
+    path: PathBuf,
+}
+
+impl GeoIpConfig {
+    /// The database of the address ranges, see `crate::geo`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CatalogConfig {
+    name: DomainName,
+}
+
+impl CatalogConfig {
+    /// The apex of the catalog zone.
+    pub fn apex(&self) -> Result<StoredName> {
+        self.name.apex()
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChaosConfig {
+    allow: Option<Vec<Cidr>>,
+}
+
+impl ChaosConfig {
+    /// Returns whether `addr` may query the debug tree, only the loopback
+    /// addresses by default.
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        match &self.allow {
+            Some(allow) => allow.iter().any(|cidr| cidr.contains(addr)),
+            None => addr.to_canonical().is_loopback(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct TelemetryConfig {
+    endpoint: String,
+    service_name: Option<String>,
+    sample_ratio: Option<f64>,
+    interval: Option<u64>,
+    timeout: Option<u64>,
+}
+
+impl TelemetryConfig {
+    /// The base url of the OTLP/HTTP collector.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn service_name(&self) -> &str {
+        self.service_name.as_deref().unwrap_or("dnsr")
+    }
+
+    /// The ratio of the requests traced.
+    pub fn sample_ratio(&self) -> f64 {
+        self.sample_ratio.unwrap_or(1.0)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(5).max(1))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(5))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AdminConfig {
+    listen: Option<String>,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+impl AdminConfig {
+    pub fn listen(&self) -> &str {
+        self.listen.as_deref().unwrap_or("127.0.0.1:8080")
+    }
+
+    /// The bearer token required on every request. The API is only open
+    /// without it when it listens on a loopback address.
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    /// Returns whether the API only listens on loopback addresses.
+    pub fn is_loopback(&self) -> bool {
+        self.listen()
+            .to_socket_addrs()
+            .is_ok_and(|mut addrs| addrs.all(|addr| addr.ip().is_loopback()))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(5))
+    }
+}
+
+#[cfg(feature = "kubernetes")]
+#[derive(Deserialize, Clone, Debug)]
+pub struct KubernetesConfig {
+    namespace: Option<String>,
+    label_selector: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[cfg(feature = "kubernetes")]
+impl KubernetesConfig {
+    /// The namespace of the ConfigMaps, the namespace of the pod if not set.
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// The label selector of the ConfigMaps holding the domains.
+    pub fn label_selector(&self) -> &str {
+        self.label_selector.as_deref().unwrap_or("dnsr.io/keys")
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(5))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct KvConfig {
+    backend: KvBackend,
+    url: String,
+    prefix: Option<String>,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+impl KvConfig {
+    pub fn backend(&self) -> KvBackend {
+        self.backend
+    }
+
+    /// The url of the HTTP API of the server, e.g. `http://127.0.0.1:8500`.
+    pub fn url(&self) -> &str {
+        self.url.trim_end_matches('/')
+    }
+
+    /// The prefix of the keys holding the fragments, `dnsr/` by default.
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or("dnsr/")
+    }
+
+    /// The ACL token of Consul or the authentication token of etcd.
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(5))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KvBackend {
+    Etcd,
+    Consul,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CertManagerConfig {
+    listen: Option<String>,
+    group_name: String,
+    solver_name: Option<String>,
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+    ttl: Option<u32>,
+    timeout: Option<u64>,
+}
+
+impl CertManagerConfig {
+    pub fn listen(&self) -> &str {
+        self.listen.as_deref().unwrap_or("0.0.0.0:8443")
+    }
+
+    /// The API group of the solver, the `groupName` of the issuers.
+    pub fn group_name(&self) -> &str {
+        &self.group_name
+    }
+
+    /// The name of the solver, the `solverName` of the issuers.
+    pub fn solver_name(&self) -> &str {
+        self.solver_name.as_deref().unwrap_or("dnsr")
+    }
+
+    /// The PEM file of the certificate chain served over TLS.
+    pub fn cert(&self) -> &Path {
+        &self.cert
+    }
+
+    /// The PEM file of the private key of the certificate.
+    pub fn key(&self) -> &Path {
+        &self.key
+    }
+
+    /// The PEM file of the CA signing the client certificates of the
+    /// kube-apiserver, its requestheader CA. The solver does not start
+    /// without it.
+    pub fn client_ca(&self) -> Option<&Path> {
+        self.client_ca.as_deref()
+    }
+
+    /// The TTL of the presented challenge records, 60 seconds by default.
+    pub fn ttl(&self) -> Ttl {
+        Ttl::from_secs(self.ttl.unwrap_or(60))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(5))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RedisConfig {
+    url: String,
+    prefix: Option<String>,
+    sync_interval: Option<u64>,
+    timeout: Option<u64>,
+}
+
+impl RedisConfig {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or("dnsr")
+    }
+
+    pub fn sync_interval(&self) -> Duration {
+        Duration::from_secs(self.sync_interval.unwrap_or(5))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout.unwrap_or(500))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct VaultConfig {
+    url: String,
+    token: Option<String>,
+    mount: Option<String>,
+    path: Option<String>,
+    interval: Option<u64>,
+    timeout: Option<u64>,
+}
+
+impl VaultConfig {
+    pub fn url(&self) -> &str {
+        self.url.trim_end_matches('/')
+    }
+
+    pub fn token(&self) -> String {
+        self.token
+            .clone()
+            .or_else(|| std::env::var("VAULT_TOKEN").ok())
+            .unwrap_or_default()
+    }
+
+    /// The mount of the KV version 2 secrets engine, `secret` by default.
+    pub fn mount(&self) -> &str {
+        self.mount.as_deref().unwrap_or("secret").trim_matches('/')
+    }
+
+    /// The path of the secrets in the engine, `dnsr/keys` by default.
+    pub fn path(&self) -> &str {
+        self.path
+            .as_deref()
+            .unwrap_or("dnsr/keys")
+            .trim_matches('/')
+    }
+
+    /// The interval between two checks of the rotation of the secrets, five
+    /// minutes by default.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(300).max(1))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(5))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct S3Config {
+    endpoint: String,
+    region: Option<String>,
+    bucket: String,
+    key: Option<String>,
+    access_key_id: Option<String>,
+    secret_access_key: Option<String>,
+    server_side_encryption: Option<String>,
+    kms_key_id: Option<String>,
+    interval: Option<u64>,
+    timeout: Option<u64>,
+}
+
+impl S3Config {
+    pub fn endpoint(&self) -> &str {
+        self.endpoint.trim_end_matches('/')
+    }
+
+    pub fn region(&self) -> &str {
+        self.region.as_deref().unwrap_or("us-east-1")
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub fn key(&self) -> &str {
+        self.key.as_deref().unwrap_or("dnsr/snapshot")
+    }
+
+    pub fn access_key_id(&self) -> String {
+        self.access_key_id
+            .clone()
+            .or_else(|| std::env::var("AWS_ACCESS_KEY_ID").ok())
+            .unwrap_or_default()
+    }
+
+    pub fn secret_access_key(&self) -> String {
+        self.secret_access_key
+            .clone()
+            .or_else(|| std::env::var("AWS_SECRET_ACCESS_KEY").ok())
+            .unwrap_or_default()
+    }
+
+    /// The server side encryption algorithm, `none` disables the encryption.
+    pub fn server_side_encryption(&self) -> Option<&str> {
+        match self.server_side_encryption.as_deref() {
+            Some("none") => None,
+            Some(sse) => Some(sse),
+            None => Some("AES256"),
+        }
+    }
+
+    pub fn kms_key_id(&self) -> Option<&str> {
+        self.kms_key_id.as_deref()
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(60))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(10))
     }
 }
 
@@ -94,3 +1594,322 @@ enum LevelFilter {
     Debug,
     Trace,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domain_defaults_are_merged() {
+        let config = "
+defaults: &defaults
+  mname: ns-acme.example.fr.
+  rname: postmaster.example.fr.
+keys:
+  key1:
+    example.fr:
+    sub.example.fr:
+      rname: hostmaster.example.fr.
+  key2:
+    another-example.fr:
+      <<: *defaults
+      mname: ns-acme.another-example.fr.
+";
+        let config = Config::try_from(&config.as_bytes().to_vec()).unwrap();
+        let expected = serde_yaml::from_str::<Keys>(
+            "
+key1:
+  example.fr: { mname: ns-acme.example.fr., rname: postmaster.example.fr. }
+  sub.example.fr: { mname: ns-acme.example.fr., rname: hostmaster.example.fr. }
+key2:
+  another-example.fr: { mname: ns-acme.another-example.fr., rname: postmaster.example.fr. }
+",
+        )
+        .unwrap();
+
+        assert_eq!(*config.keys, *expected);
+    }
+
+    #[test]
+    fn toml_configs_share_the_yaml_schema() {
+        let config = r#"
+refuse_out_of_zone = false
+
+[defaults]
+mname = "ns-acme.example.fr."
+rname = "postmaster.example.fr."
+
+[log]
+level = "debug"
+
+[keys.key1."example.fr"]
+
+[keys.key1."sub.example.fr"]
+rname = "hostmaster.example.fr."
+"#;
+        let config = Config::parse(config.as_bytes(), ConfigFormat::Toml).unwrap();
+        let expected = serde_yaml::from_str::<Keys>(
+            "
+key1:
+  example.fr: { mname: ns-acme.example.fr., rname: postmaster.example.fr. }
+  sub.example.fr: { mname: ns-acme.example.fr., rname: hostmaster.example.fr. }
+",
+        )
+        .unwrap();
+
+        assert_eq!(*config.keys, *expected);
+        assert_eq!(config.log_config().level(), log::LevelFilter::Debug);
+        assert!(!config.refuse_out_of_zone());
+
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("/etc/dnsr/config.TOML")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("/etc/dnsr/config.yml")),
+            ConfigFormat::Yaml
+        );
+    }
+
+    #[test]
+    fn included_fragments_are_merged() {
+        let dir = std::env::temp_dir().join(format!("dnsr-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("customers")).unwrap();
+        std::fs::write(
+            dir.join("config.yml"),
+            "
+include: [customers/a.yml, customers/b.toml]
+refuse_out_of_zone: false
+defaults:
+  mname: ns-acme.example.fr.
+  rname: postmaster.example.fr.
+keys:
+  key1:
+    example.fr:
+",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("customers/a.yml"),
+            "
+refuse_out_of_zone: true
+keys:
+  key1:
+    customer-a.fr:
+",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("customers/b.toml"),
+            "[keys.key2.\"customer-b.fr\"]\nrname = \"hostmaster.customer-b.fr.\"\n",
+        )
+        .unwrap();
+
+        let config = Config::from_file(&dir.join("config.yml")).unwrap();
+        let expected = serde_yaml::from_str::<Keys>(
+            "
+key1:
+  example.fr: { mname: ns-acme.example.fr., rname: postmaster.example.fr. }
+  customer-a.fr: { mname: ns-acme.example.fr., rname: postmaster.example.fr. }
+key2:
+  customer-b.fr: { mname: ns-acme.example.fr., rname: hostmaster.customer-b.fr. }
+",
+        )
+        .unwrap();
+        assert_eq!(*config.keys, *expected);
+        // The including file takes precedence
+        assert!(!config.refuse_out_of_zone());
+        assert_eq!(config.includes()[0], dir.join("customers/a.yml"));
+
+        std::fs::write(dir.join("customers/a.yml"), "include: [c.yml]\n").unwrap();
+        assert!(Config::from_file(&dir.join("config.yml")).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn acl_checks_global_and_zone_lists() {
+        let acl: AclConfig = serde_yaml::from_str(
+            "
+allow: [10.0.0.0/8, 2001:db8::/32]
+deny: [10.1.0.0/16]
+deny_countries: [xx]
+zones:
+  example.fr:
+    allow: [10.2.0.0/16]
+    deny_asns: [64496]
+",
+        )
+        .unwrap();
+        let zone = serde_yaml::from_str::<DomainName>("example.fr").unwrap();
+        let addr = |addr: &str| addr.parse::<IpAddr>().unwrap();
+
+        assert!(acl.allows(addr("10.3.0.1"), None, None));
+        assert!(!acl.allows(addr("10.1.0.1"), None, None));
+        assert!(!acl.allows(addr("192.0.2.1"), None, None));
+        assert!(acl.allows(addr("2001:db8::1"), None, None));
+        assert!(acl.allows(addr("10.2.0.1"), None, Some(&zone)));
+        assert!(!acl.allows(addr("10.3.0.1"), None, Some(&zone)));
+
+        let geo = |country: &str, asn| GeoInfo {
+            country: country.into(),
+            asn,
+        };
+        assert!(!acl.allows(addr("10.3.0.1"), Some(&geo("XX", 1)), None));
+        assert!(acl.allows(addr("10.2.0.1"), Some(&geo("FR", 1)), Some(&zone)));
+        assert!(!acl.allows(addr("10.2.0.1"), Some(&geo("FR", 64496)), Some(&zone)));
+    }
+
+    #[test]
+    fn single_record_transfers_are_per_client_or_zone() {
+        let acl: AclConfig = serde_yaml::from_str(
+            "
+single_record_transfer: [192.0.2.1]
+zones:
+  example.fr:
+    single_record_transfer: [0.0.0.0/0]
+",
+        )
+        .unwrap();
+        let zone = serde_yaml::from_str::<DomainName>("example.fr").unwrap();
+        let other = serde_yaml::from_str::<DomainName>("example.com").unwrap();
+        let addr = |addr: &str| addr.parse::<IpAddr>().unwrap();
+
+        assert!(acl.single_record_transfer(addr("192.0.2.1"), &other));
+        assert!(!acl.single_record_transfer(addr("192.0.2.2"), &other));
+        assert!(acl.single_record_transfer(addr("192.0.2.2"), &zone));
+        assert!(!acl.single_record_transfer(addr("2001:db8::1"), &zone));
+    }
+
+    #[test]
+    fn transfer_ttls_are_clamped_per_client_or_zone() {
+        let acl: AclConfig = serde_yaml::from_str(
+            "
+transfer_ttl:
+  - { clients: [192.0.2.0/24], min: 300 }
+zones:
+  example.fr:
+    transfer_ttl:
+      - { clients: [192.0.2.1], max: 60 }
+",
+        )
+        .unwrap();
+        let zone = serde_yaml::from_str::<DomainName>("example.fr").unwrap();
+        let other = serde_yaml::from_str::<DomainName>("example.com").unwrap();
+        let addr = |addr: &str| addr.parse::<IpAddr>().unwrap();
+        let clamp = |addr, domain, ttl| {
+            acl.transfer_ttl(addr, domain)
+                .map(|bounds| bounds.clamp(Ttl::from_secs(ttl)).as_secs())
+        };
+
+        assert_eq!(clamp(addr("192.0.2.2"), &other, 10), Some(300));
+        assert_eq!(clamp(addr("192.0.2.2"), &other, 3600), Some(3600));
+        assert_eq!(clamp(addr("192.0.2.1"), &zone, 3600), Some(60));
+        assert_eq!(clamp(addr("192.0.2.2"), &zone, 10), Some(300));
+        assert_eq!(clamp(addr("198.51.100.1"), &zone, 10), None);
+    }
+
+    #[test]
+    fn transfers_check_the_address_and_the_signature() {
+        let acl: AclConfig = serde_yaml::from_str(
+            "
+transfer:
+  allow: [192.0.2.0/24]
+zones:
+  example.fr:
+    transfer:
+      tsig: true
+",
+        )
+        .unwrap();
+        let zone = serde_yaml::from_str::<DomainName>("example.fr").unwrap();
+        let other = serde_yaml::from_str::<DomainName>("example.com").unwrap();
+        let addr = |addr: &str| addr.parse::<IpAddr>().unwrap();
+
+        assert!(acl.allows_transfer(addr("192.0.2.1"), &other, || false));
+        assert!(!acl.allows_transfer(addr("198.51.100.1"), &other, || true));
+        assert!(acl.allows_transfer(addr("192.0.2.1"), &zone, || true));
+        assert!(!acl.allows_transfer(addr("192.0.2.1"), &zone, || false));
+        assert!(!acl.allows_transfer(addr("198.51.100.1"), &zone, || true));
+
+        let open = AclConfig::default();
+        assert!(open.allows_transfer(addr("198.51.100.1"), &zone, || false));
+    }
+
+    #[test]
+    fn metrics_are_only_logged_by_default() {
+        let config = MetricsConfig::default();
+        assert_eq!(config.interval(), Duration::from_secs(5));
+        assert!(config.has_sink(MetricsSink::Log));
+        assert!(!config.has_sink(MetricsSink::Admin));
+        assert_eq!(config.verbosity(), MetricsVerbosity::Full);
+
+        let config: MetricsConfig =
+            serde_yaml::from_str("{ interval: 30, sinks: [admin], verbosity: summary }").unwrap();
+        assert_eq!(config.interval(), Duration::from_secs(30));
+        assert!(!config.has_sink(MetricsSink::Log));
+        assert!(config.has_sink(MetricsSink::Admin));
+        assert_eq!(config.verbosity(), MetricsVerbosity::Summary);
+    }
+
+    #[test]
+    fn flush_targets_are_tagged_with_their_kind() {
+        let config: CacheFlushConfig = serde_yaml::from_str(
+            "
+targets:
+  - notify: 192.0.2.53:53
+  - kresd: /run/knot-resolver/control/1
+  - command: [unbound-control, flush_zone]
+  - http: https://resolver.example.net/flush
+",
+        )
+        .unwrap();
+        assert_eq!(
+            config.targets(),
+            [
+                FlushTarget::Notify("192.0.2.53:53".parse().unwrap()),
+                FlushTarget::Kresd("/run/knot-resolver/control/1".into()),
+                FlushTarget::Command(vec!["unbound-control".into(), "flush_zone".into()]),
+                FlushTarget::Http("https://resolver.example.net/flush".into()),
+            ]
+        );
+        assert_eq!(config.timeout(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn admin_listeners_are_loopback_or_not() {
+        let admin = |yaml: &str| serde_yaml::from_str::<AdminConfig>(yaml).unwrap();
+        assert!(admin("{}").is_loopback());
+        assert!(admin("{ listen: '[::1]:8080' }").is_loopback());
+        assert!(!admin("{ listen: '0.0.0.0:8080' }").is_loopback());
+        assert!(!admin("{ listen: '192.0.2.1:8080' }").is_loopback());
+    }
+
+    #[test]
+    fn fixed_udp_workers_are_not_scaled() {
+        let config = "
+udp_workers:
+  min: 2
+  max: 8
+";
+        let config = Config::try_from(&config.as_bytes().to_vec()).unwrap();
+        assert_eq!(config.udp_workers_config().min(), 2);
+        assert_eq!(config.udp_workers_config().max(), 8);
+        assert_eq!(config.workers_config().tokio_threads(), None);
+
+        let config = "
+udp_workers:
+  min: 2
+  max: 8
+workers:
+  udp: 4
+  tokio_threads: 2
+  blocking_threads: 16
+";
+        let config = Config::try_from(&config.as_bytes().to_vec()).unwrap();
+        assert_eq!(config.udp_workers_config().min(), 4);
+        assert_eq!(config.udp_workers_config().max(), 4);
+        assert_eq!(config.workers_config().tokio_threads(), Some(2));
+        assert_eq!(config.workers_config().blocking_threads(), Some(16));
+    }
+}
@@ -0,0 +1,150 @@
+//! The domain names of the configuration.
+//!
+//! dnsr serves the challenge zone `_acme-challenge.<domain>` of every domain of
+//! the configuration. The conversions between a domain and the names of its
+//! challenge zone are all done here.
+
+use std::str::FromStr;
+
+use bytes::BytesMut;
+use domain::base::ToName;
+use domain::zonetree::types::StoredName;
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+use crate::error::Result;
+use crate::key::TryInto;
+
+/// The label prepended to a domain to get the apex of its challenge zone.
+pub const CHALLENGE_PREFIX: &str = "_acme-challenge.";
+
+/// The longest domain, leaving room for the challenge prefix in a name of at
+/// most 253 characters.
+const MAX_LEN: usize = 253 - CHALLENGE_PREFIX.len();
+
+/// A domain name, in lower case and without the trailing dot.
+///
+/// The names of the configuration are validated as hostnames when parsed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DomainName(String);
+
+impl DomainName {
+    /// Returns the domain of `name`, i.e. `name` without its challenge prefix.
+    pub fn from_name<N>(name: &N) -> Self
+    where
+        N: ToName,
+    {
+        let name = normalize(&name.to_bytes().to_string());
+        match name.strip_prefix(CHALLENGE_PREFIX) {
+            Some(domain) => Self(domain.to_string()),
+            None => Self(name),
+        }
+    }
+
+    /// Returns the apex of the zone of the domain itself.
+    pub fn apex(&self) -> Result<StoredName> {
+        self.0.as_bytes().try_into_t()
+    }
+
+    /// Returns the apex of the challenge zone of the domain.
+    pub fn challenge_apex(&self) -> Result<StoredName> {
+        challenge_name(&self.0)
+    }
+}
+
+/// Returns the challenge name of the host `name`.
+pub fn challenge_name(name: &str) -> Result<StoredName> {
+    let mut owner = BytesMut::with_capacity(CHALLENGE_PREFIX.len() + name.len());
+    owner.extend_from_slice(CHALLENGE_PREFIX.as_bytes());
+    owner.extend_from_slice(name.as_bytes());
+
+    owner.freeze().try_into_t()
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl FromStr for DomainName {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = normalize(s.trim());
+        if name.is_empty() || name.len() > MAX_LEN {
+            return Err(error!(DomainStr => "invalid domain name length: {}", s));
+        }
+
+        let valid_label = |label: &str| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        };
+        if !name.split('.').all(valid_label) {
+            return Err(error!(DomainStr => "invalid hostname: {}", s));
+        }
+
+        Ok(Self(name))
+    }
+}
+
+impl TryFrom<String> for DomainName {
+    type Error = error::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<DomainName> for String {
+    fn from(value: DomainName) -> Self {
+        value.0
+    }
+}
+
+impl std::fmt::Display for DomainName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use domain::base::Name;
+
+    use super::*;
+
+    #[test]
+    fn domain_names_are_validated_and_normalized() {
+        let name = |s: &str| DomainName::from_str(s).map(|d| d.to_string()).ok();
+
+        assert_eq!(name("Example.FR."), Some("example.fr".to_string()));
+        assert_eq!(
+            name("xn--bcher-kva.example"),
+            Some("xn--bcher-kva.example".to_string())
+        );
+        assert_eq!(name(""), None);
+        assert_eq!(name("."), None);
+        assert_eq!(name("exa mple.fr"), None);
+        assert_eq!(name("example..fr"), None);
+        assert_eq!(name("-example.fr"), None);
+        assert_eq!(name("_acme-challenge.example.fr"), None);
+        assert_eq!(name(&format!("{}.fr", "a".repeat(64))), None);
+    }
+
+    #[test]
+    fn challenge_prefix_is_stripped_and_added() {
+        let apex = Name::<Bytes>::from_str("_acme-challenge.Example.fr.").unwrap();
+        let domain = DomainName::from_name(&apex);
+
+        assert_eq!(domain, DomainName::from_str("example.fr").unwrap());
+        assert_eq!(
+            domain.challenge_apex().unwrap().to_string(),
+            "_acme-challenge.example.fr"
+        );
+    }
+}
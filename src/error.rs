@@ -21,17 +21,42 @@ pub enum ErrorKind {
     PushError,
     OctsetShortBuffer,
     Base64,
+    Store,
+    Ingest,
+    Export,
+    Admin,
+    Cidr,
+    Telemetry,
+    Geo,
+    Limit,
+    Flush,
+    Toml,
+    Include,
+    Forward,
+    Solver,
+    Kubernetes,
+    Kv,
+    Vault,
+    Sig0,
+    Policy,
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Error {
+    /// The message of the error, without its code.
+    pub fn text(&self) -> String {
         match &self.message {
-            Some(message) => write!(f, "{}", message),
-            None => self.kind.fmt(f),
+            Some(message) => message.clone(),
+            None => self.kind.to_string(),
         }
     }
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.kind.code(), self.text())
+    }
+}
+
 impl std::fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use ErrorKind::*;
@@ -50,10 +75,177 @@ impl std::fmt::Display for ErrorKind {
             Utf8 => write!(f, "utf8 error"),
             PushError => write!(f, "tsig push error"),
             OctsetShortBuffer => write!(f, "octset short buffer error"),
+            Store => write!(f, "zone store error"),
+            Ingest => write!(f, "ingest error"),
+            Export => write!(f, "export error"),
+            Admin => write!(f, "admin api error"),
+            Cidr => write!(f, "invalid cidr"),
+            Telemetry => write!(f, "telemetry error"),
+            Geo => write!(f, "geoip error"),
+            Limit => write!(f, "resource limit error"),
+            Flush => write!(f, "cache flush error"),
+            Toml => write!(f, "toml error"),
+            Include => write!(f, "config include error"),
+            Forward => write!(f, "update forwarding error"),
+            Solver => write!(f, "cert-manager solver error"),
+            Kubernetes => write!(f, "kubernetes source error"),
+            Kv => write!(f, "key value source error"),
+            Vault => write!(f, "vault error"),
+            Sig0 => write!(f, "sig(0) error"),
+            Policy => write!(f, "update policy error"),
+        }
+    }
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 31] = {
+        use ErrorKind::*;
+
+        [
+            Notify,
+            SerdeYaml,
+            DomainStr,
+            DomainZone,
+            Io,
+            TSIGFileAlreadyExist,
+            TSIGFileNotFound,
+            TSIGKey,
+            RingUnspecified,
+            Utf8,
+            PushError,
+            OctsetShortBuffer,
+            Base64,
+            Store,
+            Ingest,
+            Export,
+            Admin,
+            Cidr,
+            Telemetry,
+            Geo,
+            Limit,
+            Flush,
+            Toml,
+            Include,
+            Forward,
+            Solver,
+            Kubernetes,
+            Kv,
+            Vault,
+            Sig0,
+            Policy,
+        ]
+    };
+
+    /// The stable code of the kind, in the logs, the admin API responses and
+    /// the extended DNS errors.
+    ///
+    /// A code is never reused nor renumbered, a new kind takes the next code.
+    pub fn code(&self) -> &'static str {
+        use ErrorKind::*;
+
+        match self {
+            Notify => "E001",
+            SerdeYaml => "E002",
+            DomainStr => "E003",
+            DomainZone => "E004",
+            Io => "E005",
+            TSIGFileAlreadyExist => "E006",
+            TSIGFileNotFound => "E007",
+            TSIGKey => "E008",
+            RingUnspecified => "E009",
+            Utf8 => "E010",
+            PushError => "E011",
+            OctsetShortBuffer => "E012",
+            Base64 => "E013",
+            Store => "E014",
+            Ingest => "E015",
+            Export => "E016",
+            Admin => "E017",
+            Cidr => "E018",
+            Telemetry => "E019",
+            Geo => "E020",
+            Limit => "E021",
+            Flush => "E022",
+            Toml => "E023",
+            Include => "E024",
+            Forward => "E025",
+            Solver => "E026",
+            Kubernetes => "E027",
+            Kv => "E028",
+            Vault => "E029",
+            Sig0 => "E030",
+            Policy => "E031",
+        }
+    }
+
+    /// Returns the kind of the code `code`, case insensitive.
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.code().eq_ignore_ascii_case(code))
+    }
+
+    /// What the errors of the kind mean and what to check, for `dnsr explain`.
+    pub fn explanation(&self) -> &'static str {
+        use ErrorKind::*;
+
+        match self {
+            Notify => "The watcher of the configuration file failed, check that the file and its directory still exist and are readable.",
+            SerdeYaml => "A YAML document could not be parsed, either the configuration file or the body of an admin API request.",
+            DomainStr => "A domain name is invalid, the names of the configuration must be hostnames.",
+            DomainZone => "A zone could not be added to or removed from the served zones, or a record is outside of its zone.",
+            Io => "A file or socket operation failed, check the paths of the configuration and the permissions of the user running dnsr.",
+            TSIGFileAlreadyExist => "The file of a new TSIG key already exists in the keys directory, the existing file is kept.",
+            TSIGFileNotFound => "The file of a TSIG key is missing from the keys directory, the signed requests of the key are refused until it is back.",
+            TSIGKey => "A TSIG key is invalid, either its file in the keys directory or a key of an imported BIND key file.",
+            RingUnspecified => "The cryptographic library failed, e.g. to generate a key or random bytes.",
+            Utf8 => "Some text, e.g. a key file, is not valid UTF-8.",
+            PushError => "A DNS message could not be built, its records would exceed its size limit.",
+            OctsetShortBuffer => "A buffer was too short to hold a DNS name or message.",
+            Base64 => "The secret of a TSIG key is not valid base64.",
+            Store => "The zone store failed or returned invalid records, check the redis and s3 sections and the reachability of their servers.",
+            Ingest => "The message read by the ingest subcommand is invalid.",
+            Export => "The export subcommand failed, check its arguments and the names of the exported zones.",
+            Admin => "An admin API request is malformed or too large, or the admin API listens on a non-loopback address without a token.",
+            Cidr => "A network of the configuration is not a valid address/prefix pair.",
+            Telemetry => "The traces could not be exported, check the endpoint of the telemetry section and the reachability of the collector.",
+            Geo => "The geoip database is invalid, see the format expected in the geoip section.",
+            Limit => "A limit of the limits section was reached, e.g. the maximum number of zones, the zone was not loaded.",
+            Flush => "A name could not be flushed from the cache of a downstream resolver, check the targets of the cache_flush section.",
+            Toml => "The TOML configuration file could not be parsed.",
+            Include => "A file included by the configuration could not be read or parsed, the paths are relative to the directory of the configuration file.",
+            Forward => "An update received by a standby could not be forwarded to the primary, check the update_forwarding section and that the primary is reachable over TCP.",
+            Solver => "The cert-manager solver could not be started or a challenge could not be presented, check the certificate, key and client CA of the cert_manager section and that the challenge name is served.",
+            Kubernetes => "The ConfigMaps holding the domains could not be listed or parsed, check the kubernetes section, the RBAC permissions of the service account and the YAML of every labeled ConfigMap.",
+            Kv => "The configuration fragments could not be read from etcd or Consul, check the kv section, the reachability of the server and the YAML of every key under the prefix.",
+            Vault => "A TSIG secret could not be read from or written to Vault, check the vault section, the token and its policy on the path of the secrets.",
+            Sig0 => "A SIG(0) signed update could not be verified, check the sig0_keys of its domain: the name, the algorithm and the public key of the signing key, and the clock of the client.",
+            Policy => "A rule of the update_policy of a domain could not be parsed, a rule is written `grant|deny <identity> <nametype> [<name>] [<types>]`.",
         }
     }
 }
 
+/// Describes the error code `code` for the `explain` subcommand, or lists
+/// every code if none. Returns `None` if the code is unknown.
+pub fn explain(code: Option<&str>) -> Option<String> {
+    let Some(code) = code else {
+        return Some(
+            ErrorKind::ALL
+                .iter()
+                .map(|kind| format!("{} {}\n", kind.code(), kind))
+                .collect(),
+        );
+    };
+
+    let kind = ErrorKind::from_code(code)?;
+    Some(format!(
+        "{} {}\n\n{}\n",
+        kind.code(),
+        kind,
+        kind.explanation()
+    ))
+}
+
 impl From<ErrorKind> for Error {
     fn from(value: ErrorKind) -> Self {
         Self {
@@ -81,6 +273,15 @@ impl From<notify::Error> for Error {
     }
 }
 
+impl From<toml::de::Error> for Error {
+    fn from(value: toml::de::Error) -> Self {
+        Self {
+            kind: ErrorKind::Toml,
+            message: Some(value.to_string()),
+        }
+    }
+}
+
 impl From<serde_yaml::Error> for Error {
     fn from(value: serde_yaml::Error) -> Self {
         Self {
@@ -203,3 +404,35 @@ mod macros {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn error_codes_are_unique_and_explained() {
+        let codes = ErrorKind::ALL
+            .iter()
+            .map(|kind| kind.code())
+            .collect::<HashSet<_>>();
+        assert_eq!(codes.len(), ErrorKind::ALL.len());
+
+        for kind in ErrorKind::ALL {
+            assert_eq!(ErrorKind::from_code(kind.code()), Some(kind));
+        }
+        assert_eq!(ErrorKind::from_code("e014"), Some(ErrorKind::Store));
+        assert_eq!(ErrorKind::from_code("E999"), None);
+
+        assert_eq!(
+            crate::error!(Store => "redis error: {}", "down").to_string(),
+            "[E014] redis error: down"
+        );
+        assert!(explain(Some("E014"))
+            .unwrap()
+            .starts_with("E014 zone store error\n"));
+        assert_eq!(explain(None).unwrap().lines().count(), ErrorKind::ALL.len());
+        assert!(explain(Some("E999")).is_none());
+    }
+}
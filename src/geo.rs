@@ -0,0 +1,124 @@
+//! The country and autonomous system of the clients.
+//!
+//! The addresses are looked up in a local database in the format of the
+//! ip2asn databases, one range per line with the tab separated fields
+//! `range_start`, `range_end`, `as_number`, `country_code` and
+//! `as_description`.
+//!
+//! The unrouted ranges, with the AS number 0 or the country `None`, are
+//! skipped.
+
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
+
+use crate::error;
+use crate::error::Result;
+
+/// The tags of a client address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeoInfo {
+    /// The ISO 3166 code of the country, e.g. `FR`
+    pub country: String,
+    pub asn: u32,
+}
+
+impl std::fmt::Display for GeoInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} AS{}", self.country, self.asn)
+    }
+}
+
+#[derive(Debug)]
+pub struct GeoDb {
+    /// The ranges sorted by their first address, the IPv4 addresses are mapped
+    /// in the IPv6 space
+    ranges: Vec<(u128, u128, GeoInfo)>,
+}
+
+impl GeoDb {
+    pub fn open<P>(path: &P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content).map_err(
+            |e| error!(Geo => "invalid geoip database {}: {}", path.as_ref().display(), e.text()),
+        )
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let mut ranges = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = || error!(Geo => "invalid line {}", i + 1);
+
+            let mut fields = line.split('\t');
+            let (Some(start), Some(end), Some(asn), Some(country)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            let start = start.parse::<IpAddr>().map_err(|_| invalid())?;
+            let end = end.parse::<IpAddr>().map_err(|_| invalid())?;
+            let asn = asn.parse::<u32>().map_err(|_| invalid())?;
+            if asn == 0 || country == "None" {
+                continue;
+            }
+
+            let info = GeoInfo {
+                country: country.to_ascii_uppercase(),
+                asn,
+            };
+            ranges.push((key(start), key(end), info));
+        }
+        ranges.sort_by_key(|(start, _, _)| *start);
+
+        Ok(Self { ranges })
+    }
+
+    /// Returns the tags of `addr`, `None` if it is in no range.
+    pub fn lookup(&self, addr: IpAddr) -> Option<&GeoInfo> {
+        let key = key(addr);
+        let index = self.ranges.partition_point(|(start, _, _)| *start <= key);
+        let (_, end, info) = self.ranges.get(index.checked_sub(1)?)?;
+        (key <= *end).then_some(info)
+    }
+}
+
+fn key(addr: IpAddr) -> u128 {
+    let addr = match addr.to_canonical() {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        IpAddr::V6(addr) => addr,
+    };
+    u128::from(Ipv6Addr::from(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_tagged_with_their_range() {
+        let db = GeoDb::parse(
+            "
+1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET
+1.0.1.0\t1.0.3.255\t0\tNone\tNot routed
+2.0.0.0\t2.15.255.255\t3215\tFR\tOrange
+2001:db8::\t2001:db8::ffff\t64496\tfr\tDocumentation
+",
+        )
+        .unwrap();
+        let lookup = |addr: &str| db.lookup(addr.parse().unwrap()).map(ToString::to_string);
+
+        assert_eq!(lookup("1.0.0.1").as_deref(), Some("US AS13335"));
+        assert_eq!(lookup("1.0.2.1"), None);
+        assert_eq!(lookup("2.1.2.3").as_deref(), Some("FR AS3215"));
+        assert_eq!(lookup("::ffff:2.1.2.3").as_deref(), Some("FR AS3215"));
+        assert_eq!(lookup("3.0.0.1"), None);
+        assert_eq!(lookup("2001:db8::1").as_deref(), Some("FR AS64496"));
+
+        assert!(GeoDb::parse("1.0.0.0\t1.0.0.255\tAS1\tUS\n").is_err());
+    }
+}
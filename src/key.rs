@@ -1,22 +1,31 @@
 use core::str;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
 
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use domain::base::iana::Class;
-use domain::base::{Name, Record, Serial, ToName, Ttl};
-use domain::rdata::Soa;
+use domain::base::rdata::{RecordData, UnknownRecordData};
+use domain::base::{Record, Rtype, Serial, ToName, Ttl};
+use domain::rdata::{Aaaa, Mx, Ns, Soa, Txt, ZoneRecordData, A};
 use domain::tsig::{Algorithm, Key, KeyName};
 use domain::zonetree::types::{StoredName, StoredRecord};
 use domain::zonetree::{Rrset, SharedRrset, Zone, ZoneBuilder};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::error::{ErrorKind, Result};
+use crate::cidr::Cidr;
+use crate::dname::{challenge_name, DomainName};
+use crate::error;
+use crate::error::{Error, ErrorKind, Result};
+use crate::policy::{self, UpdateRule};
+use crate::sig0::Sig0Key;
+use crate::vault::Vault;
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct Keys(HashMap<KeyFile, HashMap<DomainName, DomainInfo>>);
 
 impl Keys {
@@ -33,6 +42,17 @@ impl Keys {
         });
         domains
     }
+
+    /// Returns the key handling `domain` and the entry of the domain.
+    pub fn find_domain(&self, domain: &DomainName) -> Option<(&KeyFile, &DomainInfo)> {
+        self.0
+            .iter()
+            .find_map(|(key, domains)| domains.get(domain).map(|info| (key, info)))
+    }
+
+    pub fn insert(&mut self, key: KeyFile, name: DomainName, info: DomainInfo) {
+        self.0.entry(key).or_default().insert(name, info);
+    }
 }
 
 impl Deref for Keys {
@@ -43,23 +63,149 @@ impl Deref for Keys {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct DomainInfo {
     mname: String,
     rname: String,
+    /// The record types the key may update, every type if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allowed_types: Vec<String>,
+    /// The owner name patterns the key may update, every name if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allowed_names: Vec<String>,
+    /// The networks the key may update from, every address if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allowed_clients: Vec<Cidr>,
+    /// The public keys trusted to sign the updates with SIG(0) besides the key
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sig0_keys: Vec<Sig0Key>,
+    /// The BIND style rules checked against every record of an update, see
+    /// the `policy` module, every record allowed by the fields above if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    update_policy: Vec<UpdateRule>,
+    /// The zone of the domain itself, served along with the challenge zone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    domain_zone: Option<DomainZone>,
+    /// The static records of the challenge zone apex written `<type> <data>`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    records: Vec<String>,
+    /// The TTL of the generated records in seconds, one hour if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u32>,
+    /// The timers of the SOA in seconds, see `soa_rrset` for their defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expire: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    minimum: Option<u32>,
+}
+
+/// The template of the zone of a domain delegated as a whole to dnsr.
+///
+/// Only the records of the apex are served, the zone shares the SOA of the
+/// challenge zone and cannot be updated.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Default)]
+pub struct DomainZone {
+    /// The name servers of the zone, the mname if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ns: Vec<String>,
+    /// The records of the apex written `<type> <data>`, e.g. `A 192.0.2.1`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    records: Vec<String>,
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
-pub struct DomainName(String);
+impl DomainZone {
+    /// Returns the rrsets of the apex, NS included, with the TTL `ttl`.
+    fn rrsets(&self, mname: &str, ttl: Ttl) -> Result<Vec<SharedRrset>> {
+        let mut records = Vec::new();
+        if self.ns.is_empty() {
+            records.push(Ns::new(mname.try_into_t()?).into());
+        }
+        for ns in self.ns.iter() {
+            records.push(Ns::new(ns.try_into_t()?).into());
+        }
+        for record in self.records.iter() {
+            records.push(parse_template_record(record)?);
+        }
+
+        Ok(group_rrsets(records, ttl))
+    }
+}
 
-impl DomainName {
-    pub fn strip_prefix(self) -> Self {
-        if let Some(dname) = self.0.strip_prefix("_acme-challenge.") {
-            Self(dname.to_string())
-        } else {
-            self
+/// Groups the records of a same owner into rrsets of the TTL `ttl`, in the
+/// order of their first record.
+fn group_rrsets<I>(records: I, ttl: Ttl) -> Vec<SharedRrset>
+where
+    I: IntoIterator<Item = ZoneRecordData<Bytes, StoredName>>,
+{
+    let mut rrsets: Vec<Rrset> = Vec::new();
+    for data in records {
+        let rtype = data.rtype();
+        match rrsets.iter_mut().find(|rrset| rrset.rtype() == rtype) {
+            Some(rrset) => rrset.push_data(data),
+            None => {
+                let mut rrset = Rrset::new(rtype, ttl);
+                rrset.push_data(data);
+                rrsets.push(rrset);
+            }
         }
     }
+    rrsets.into_iter().map(Rrset::into_shared).collect()
+}
+
+/// Parses a record of a zone template, only the A, AAAA, MX, TXT, CAA and NS
+/// records are supported.
+fn parse_template_record(record: &str) -> Result<ZoneRecordData<Bytes, StoredName>> {
+    let invalid = || error!(DomainZone => "invalid template record: {}", record);
+
+    let (rtype, data) = record.trim().split_once(' ').ok_or_else(invalid)?;
+    let data = data.trim();
+    let data = match rtype.to_ascii_uppercase().as_str() {
+        "A" => A::new(data.parse().map_err(|_| invalid())?).into(),
+        "AAAA" => Aaaa::new(data.parse().map_err(|_| invalid())?).into(),
+        "MX" => {
+            let (preference, exchange) = data.split_once(' ').ok_or_else(invalid)?;
+            let preference = preference.parse().map_err(|_| invalid())?;
+            Mx::new(preference, exchange.trim().try_into_t()?).into()
+        }
+        "TXT" => Txt::<Bytes>::build_from_slice(data.as_bytes())
+            .map_err(|_| invalid())?
+            .into(),
+        "NS" => Ns::new(data.try_into_t()?).into(),
+        "CAA" => {
+            let wire = caa_wire(data).ok_or_else(invalid)?;
+            let data = UnknownRecordData::from_octets(Rtype::CAA, Bytes::from(wire))
+                .map_err(|_| invalid())?;
+            ZoneRecordData::Unknown(data)
+        }
+        _ => return Err(error!(DomainZone => "unsupported template record type: {}", record)),
+    };
+    Ok(data)
+}
+
+/// Encodes the CAA data `<flags> <tag> "<value>"` in the wire format of RFC
+/// 8659 section 4.1, the quotes of the value are optional.
+fn caa_wire(data: &str) -> Option<Vec<u8>> {
+    let (flags, rest) = data.split_once(' ')?;
+    let (tag, value) = rest.trim().split_once(' ')?;
+    let flags: u8 = flags.parse().ok()?;
+    let tag_len = u8::try_from(tag.len()).ok()?;
+    if tag.is_empty() || !tag.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+
+    let mut wire = vec![flags, tag_len];
+    wire.extend_from_slice(tag.to_ascii_lowercase().as_bytes());
+    wire.extend_from_slice(value.as_bytes());
+    Some(wire)
 }
 
 pub trait TryInto<T> {
@@ -68,30 +214,128 @@ pub trait TryInto<T> {
 
 impl TryInto<Vec<domain::zonetree::Zone>> for &HashMap<DomainName, DomainInfo> {
     fn try_into_t(self) -> Result<Vec<domain::zonetree::Zone>> {
-        self.iter().map(|d| d.try_into_t()).collect()
+        let mut zones = Vec::new();
+        for d in self.iter() {
+            zones.extend(d.try_into_t()?);
+        }
+        Ok(zones)
     }
 }
 
-impl TryFrom<&DomainInfo> for SharedRrset {
-    type Error = crate::error::Error;
+/// The source of the time used as serial for newly generated zones.
+///
+/// Injecting a fixed time makes the generated zones reproducible.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
 
-    fn try_from(value: &DomainInfo) -> std::result::Result<Self, Self::Error> {
-        let mut owner = BytesMut::with_capacity(16 + value.mname.len());
-        owner.extend_from_slice(b"_acme-challenge.");
-        owner.extend_from_slice(value.mname.as_bytes());
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl Clock for SystemTime {
+    fn now(&self) -> SystemTime {
+        *self
+    }
+}
+
+impl DomainInfo {
+    /// The primary name server of the zones of the domain.
+    pub fn mname(&self) -> &str {
+        &self.mname
+    }
+
+    /// Returns whether the key of this domain may update the `rtype` records.
+    pub fn allows_type(&self, rtype: Rtype) -> bool {
+        self.allowed_types.is_empty()
+            || self
+                .allowed_types
+                .iter()
+                .any(|t| Rtype::from_str(t).is_ok_and(|t| t == rtype))
+    }
+
+    /// Returns whether the key of this domain may update the records of `owner`.
+    pub fn allows_name<N>(&self, owner: &N) -> bool
+    where
+        N: ToName,
+    {
+        let owner = owner.to_bytes().to_string();
+        self.allowed_names.is_empty()
+            || self
+                .allowed_names
+                .iter()
+                .any(|pattern| matches_pattern(pattern, &owner))
+    }
 
+    /// The public keys trusted to sign the updates of the domain with SIG(0).
+    pub fn sig0_keys(&self) -> &[Sig0Key] {
+        &self.sig0_keys
+    }
+
+    /// Returns whether the update policy of the domain lets `signer` update
+    /// the `rtype` records of `owner` in `zone`.
+    pub fn allows_update<Z, O>(&self, signer: &str, zone: &Z, owner: &O, rtype: Rtype) -> bool
+    where
+        Z: ToName,
+        O: ToName,
+    {
+        self.update_policy.is_empty()
+            || policy::allows(
+                &self.update_policy,
+                signer,
+                &zone.to_bytes().to_string(),
+                &owner.to_bytes().to_string(),
+                rtype,
+            )
+    }
+
+    /// Returns whether the key of this domain may update from `addr`.
+    pub fn allows_client(&self, addr: IpAddr) -> bool {
+        self.allowed_clients.is_empty()
+            || self.allowed_clients.iter().any(|cidr| cidr.contains(addr))
+    }
+
+    /// The TTL of the generated records.
+    fn ttl(&self) -> Ttl {
+        self.ttl.map_or(Ttl::HOUR, Ttl::from_secs)
+    }
+
+    /// Returns the static rrsets of the challenge zone apex.
+    pub fn static_rrsets(&self) -> Result<Vec<SharedRrset>> {
+        let records = self
+            .records
+            .iter()
+            .map(|record| parse_template_record(record))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(group_rrsets(records, self.ttl()))
+    }
+
+    /// Builds the SOA of the zones of the domain, the timers default to a
+    /// refresh of 3 hours, a retry of 1 hour, an expire of 7 days and a
+    /// minimum of 1 hour.
+    pub fn soa_rrset<C>(&self, clock: &C) -> Result<SharedRrset>
+    where
+        C: Clock,
+    {
+        let timer = |value: Option<u32>, default: u32| Ttl::from_secs(value.unwrap_or(default));
+        let serial = Serial::from(crate::time::unix_secs(clock.now()) as u32);
         let record: StoredRecord = Record::new(
-            owner.freeze().try_into_t()?,
+            challenge_name(&self.mname)?,
             Class::IN,
-            Ttl::HOUR,
+            self.ttl(),
             Soa::new(
-                (&value.mname).try_into_t()?,
-                (&value.rname).try_into_t()?,
-                Serial::now(),
-                Ttl::from_secs(10800),
-                Ttl::HOUR,
-                Ttl::from_secs(605800),
-                Ttl::HOUR,
+                (&self.mname).try_into_t()?,
+                (&self.rname).try_into_t()?,
+                serial,
+                timer(self.refresh, 10800),
+                timer(self.retry, 3600),
+                timer(self.expire, 605800),
+                timer(self.minimum, 3600),
             )
             .into(),
         );
@@ -102,30 +346,91 @@ impl TryFrom<&DomainInfo> for SharedRrset {
     }
 }
 
-impl TryInto<Zone> for (&DomainName, &DomainInfo) {
-    fn try_into_t(self) -> Result<Zone> {
-        let (name, info) = self;
-        let mut builder = ZoneBuilder::new(name.try_into_t()?, Class::IN);
-        builder.insert_rrset(&name.try_into_t()?, info.try_into()?)?;
-        let zone = builder.build();
-        log::debug!(target: "zone", "new zone created: {:?}", zone);
-        Ok(zone)
+impl TryFrom<&DomainInfo> for SharedRrset {
+    type Error = crate::error::Error;
+
+    fn try_from(value: &DomainInfo) -> std::result::Result<Self, Self::Error> {
+        value.soa_rrset(&SystemClock)
     }
 }
 
-impl TryInto<StoredName> for &DomainName {
-    fn try_into_t(self) -> Result<StoredName> {
-        let mut owner = BytesMut::with_capacity(16 + self.0.len());
-        owner.extend_from_slice(b"_acme-challenge.");
-        owner.extend_from_slice(self.0.as_bytes());
+/// Matches a domain name against a pattern where `*` stands for any sequence of characters.
+///
+/// The comparison is case insensitive and ignores the trailing dot.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
 
-        owner.freeze().try_into_t()
+    rest.is_empty()
+}
+
+pub fn build_zone<C>(name: &DomainName, info: &DomainInfo, clock: &C) -> Result<Zone>
+where
+    C: Clock,
+{
+    let apex = name.challenge_apex()?;
+    let mut builder = ZoneBuilder::new(apex.clone(), Class::IN);
+    builder.insert_rrset(&apex, info.soa_rrset(clock)?)?;
+    for rrset in info.static_rrsets()? {
+        builder.insert_rrset(&apex, rrset)?;
     }
+    let zone = builder.build();
+    log::debug!(target: "zone", "new zone created: {:?}", zone);
+    Ok(zone)
 }
 
-impl From<&Name<Bytes>> for DomainName {
-    fn from(value: &Name<Bytes>) -> Self {
-        DomainName(value.to_string())
+/// Builds the zone of the domain itself, if the domain has a `domain_zone`.
+pub fn build_domain_zone<C>(name: &DomainName, info: &DomainInfo, clock: &C) -> Result<Option<Zone>>
+where
+    C: Clock,
+{
+    let Some(template) = &info.domain_zone else {
+        return Ok(None);
+    };
+
+    let apex = name.apex()?;
+    let mut builder = ZoneBuilder::new(apex.clone(), Class::IN);
+    builder.insert_rrset(&apex, info.soa_rrset(clock)?)?;
+    for rrset in template.rrsets(&info.mname, info.ttl())? {
+        builder.insert_rrset(&apex, rrset)?;
+    }
+    let zone = builder.build();
+    log::debug!(target: "zone", "new domain zone created: {:?}", zone);
+    Ok(Some(zone))
+}
+
+/// Builds the zones of a domain, its challenge zone and the zone of the domain
+/// itself if any.
+pub fn build_zones<C>(name: &DomainName, info: &DomainInfo, clock: &C) -> Result<Vec<Zone>>
+where
+    C: Clock,
+{
+    let mut zones = vec![build_zone(name, info, clock)?];
+    zones.extend(build_domain_zone(name, info, clock)?);
+    Ok(zones)
+}
+
+impl TryInto<Vec<Zone>> for (&DomainName, &DomainInfo) {
+    fn try_into_t(self) -> Result<Vec<Zone>> {
+        let (name, info) = self;
+        build_zones(name, info, &SystemClock)
     }
 }
 
@@ -139,7 +444,7 @@ where
     }
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
 pub struct KeyFile(String);
 
 impl KeyFile {
@@ -188,37 +493,186 @@ impl std::fmt::Display for KeyFile {
     }
 }
 
+/// The storage of the secrets of the keys, the key files or Vault.
+///
+/// It is cloned out of the keystore so that the secrets are generated, loaded
+/// and deleted without holding the keystore lock, Vault being a remote server.
+#[derive(Debug, Clone)]
+pub struct Secrets {
+    vault: Option<Arc<Vault>>,
+}
+
+impl Secrets {
+    /// Generates a new key and stores its secret, the secret is returned
+    /// encoded in base64.
+    pub fn generate(&self, key: &KeyFile) -> Result<(Key, String)> {
+        match &self.vault {
+            Some(vault) => vault.generate(key),
+            None => {
+                let k = key.generate_key_file()?;
+                let secret = std::fs::read_to_string(key.as_pathbuf())?;
+                Ok((k, secret))
+            }
+        }
+    }
+
+    pub fn load(&self, key: &KeyFile) -> Result<Key> {
+        match &self.vault {
+            Some(vault) => vault.load(key),
+            None => key.load_key(),
+        }
+    }
+
+    /// Generates `key`, or loads it if its secret already exists.
+    pub fn fetch(&self, key: &KeyFile) -> Result<Key> {
+        match self.generate(key) {
+            Ok((key, _)) => Ok(key),
+            Err(e) if e.kind == ErrorKind::TSIGFileAlreadyExist => {
+                log::info!(target: "tsig_file", "tsig key {} already exists - skipping", key);
+                self.load(key)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn delete(&self, key: &KeyFile) -> Result<()> {
+        match &self.vault {
+            Some(vault) => vault.delete(key),
+            None => key.delete_key_file(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct KeyStore {
     keys: HashMap<(KeyName, Algorithm), Arc<Key>>,
+    /// The keys whose file could not be loaded, with the reason
+    unavailable: HashMap<KeyName, Error>,
+    /// The keys imported from BIND key files, they have no key file
+    imported: HashSet<KeyName>,
+    /// The storage of the secrets of the keys
+    secrets: Secrets,
 }
 
 impl KeyStore {
-    pub fn new_shared() -> Arc<RwLock<Self>> {
+    pub fn new_shared(vault: Option<Vault>) -> Arc<RwLock<Self>> {
         Arc::new(RwLock::new(Self {
             keys: HashMap::new(),
+            unavailable: HashMap::new(),
+            imported: HashSet::new(),
+            secrets: Secrets {
+                vault: vault.map(Arc::new),
+            },
         }))
     }
 
-    pub fn remove_key(&mut self, key: &KeyFile) -> Result<()> {
-        if self.keys.remove(&key.try_into()?).is_some() {
-            key.delete_key_file()?;
-        }
-        Ok(())
+    /// Returns the Vault storing the secrets, if they are not stored in files.
+    pub fn vault(&self) -> Option<Arc<Vault>> {
+        self.secrets.vault.clone()
     }
 
-    pub fn add_key(&mut self, key: &KeyFile) -> Result<()> {
-        let k = match key.generate_key_file() {
-            Ok(key) => key,
-            Err(e) if e.kind == ErrorKind::TSIGFileAlreadyExist => {
-                log::info!(target: "tsig_file", "tsig key {} already exists - skipping", key);
-                key.load_key()?
+    /// Returns the storage of the secrets, to be used once the keystore lock
+    /// is released.
+    pub fn secrets(&self) -> Secrets {
+        self.secrets.clone()
+    }
+
+    /// Removes `key` whatever its algorithm and returns whether its secret
+    /// is to be deleted by the caller, the imported keys have none.
+    pub fn remove_key(&mut self, key: &KeyFile) -> Result<bool> {
+        let name: KeyName = key.try_into()?;
+        self.unavailable.remove(&name);
+        let loaded = self.keys.len();
+        self.keys.retain(|(n, _), _| n != &name);
+        Ok(self.keys.len() < loaded && !self.imported.contains(&name))
+    }
+
+    /// Returns whether a key named as `key` is loaded, e.g. imported from a
+    /// BIND key file.
+    pub fn is_loaded(&self, key: &KeyFile) -> bool {
+        KeyName::try_from(key).is_ok_and(|name| self.keys.keys().any(|(n, _)| n == &name))
+    }
+
+    /// Replaces `key` with the `loaded` one, fetched from its secret. The key
+    /// is unavailable with the reason it could not be loaded otherwise.
+    pub fn set_key(&mut self, key: &KeyFile, loaded: Result<Key>) -> Result<()> {
+        let name: KeyName = key.try_into()?;
+        self.keys.retain(|(n, _), _| n != &name);
+        match loaded {
+            Ok(k) => {
+                self.unavailable.remove(&name);
+                self.insert_key(k);
+                Ok(())
             }
-            Err(e) => return Err(e),
-        };
-        self.keys.insert(key.try_into()?, Arc::new(k));
+            Err(e) => {
+                self.unavailable.insert(name, e.clone());
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the unavailable keys to load again.
+    pub fn retried_keys(&self) -> Vec<KeyFile> {
+        self.unavailable
+            .iter()
+            // A key file deleted by an external tool is not generated again,
+            // the key is reloaded once its file is back
+            .filter(|(_, reason)| reason.kind != ErrorKind::TSIGFileNotFound)
+            .map(|(name, _)| KeyFile::from(name))
+            .collect()
+    }
+
+    /// Returns whether some keys could not be loaded, their signed requests
+    /// are refused while the other requests are still served.
+    pub fn is_degraded(&self) -> bool {
+        !self.unavailable.is_empty()
+    }
+
+    pub fn unavailable_keys(&self) -> impl Iterator<Item = (&KeyName, &Error)> {
+        self.unavailable.iter()
+    }
+
+    /// Returns why the key `name` could not be loaded, if it is unavailable.
+    pub fn unavailable_reason<N>(&self, name: &N) -> Option<&Error>
+    where
+        N: ToName,
+    {
+        self.unavailable
+            .iter()
+            .find(|(n, _)| n.name_eq(name))
+            .map(|(_, reason)| reason)
+    }
+
+    /// Loads every key of the BIND key file at `path`.
+    pub fn import_keys<P>(&mut self, path: &P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        for key in crate::tsig::load_bind_keys(path)? {
+            log::info!(target: "tsig_file", "imported tsig key {} from {}", key.name(), path.as_ref().display());
+            self.imported.insert(key.name().clone());
+            self.insert_key(key);
+        }
         Ok(())
     }
+
+    /// Returns whether `key` was imported from a BIND key file.
+    pub fn is_imported(&self, key: &KeyFile) -> bool {
+        KeyName::try_from(key).is_ok_and(|name| self.imported.contains(&name))
+    }
+
+    /// Returns the key `name`, whatever its algorithm.
+    pub fn find_key(&self, name: &KeyName) -> Option<Arc<Key>> {
+        self.keys
+            .iter()
+            .find(|((n, _), _)| n == name)
+            .map(|(_, key)| key.clone())
+    }
+
+    pub fn insert_key(&mut self, key: Key) {
+        self.keys
+            .insert((key.name().clone(), key.algorithm()), Arc::new(key));
+    }
 }
 
 impl domain::tsig::KeyStore for KeyStore {
@@ -239,3 +693,222 @@ impl Deref for KeyStore {
         &self.keys
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use domain::base::Name;
+
+    use super::*;
+
+    const CONFIG: &str = "
+key1:
+  example.fr:
+    mname: ns-acme.example.fr.
+    rname: postmaster.example.fr.
+";
+
+    fn fixed_clock() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1722353587)
+    }
+
+    #[test]
+    fn generated_soa_is_reproducible() {
+        let keys: Keys = serde_yaml::from_str(CONFIG).unwrap();
+        let (_, info) = keys.domains()[0];
+
+        let first = info.soa_rrset(&fixed_clock()).unwrap();
+        let second = info.soa_rrset(&fixed_clock()).unwrap();
+        assert_eq!(first.data(), second.data());
+
+        assert_eq!(first.rtype(), Rtype::SOA);
+        assert_eq!(first.ttl(), Ttl::HOUR);
+        let ZoneRecordData::Soa(soa) = &first.data()[0] else {
+            panic!("expected a SOA record");
+        };
+        assert_eq!(soa.mname().to_string(), "ns-acme.example.fr");
+        assert_eq!(soa.rname().to_string(), "postmaster.example.fr");
+        assert_eq!(soa.serial(), Serial::from(1722353587));
+        assert_eq!(soa.refresh(), Ttl::from_secs(10800));
+        assert_eq!(soa.retry(), Ttl::HOUR);
+        assert_eq!(soa.expire(), Ttl::from_secs(605800));
+        assert_eq!(soa.minimum(), Ttl::HOUR);
+    }
+
+    #[test]
+    fn soa_timers_are_overridden_per_domain() {
+        let keys: Keys = serde_yaml::from_str(
+            "
+key1:
+  example.fr:
+    mname: ns-acme.example.fr.
+    rname: postmaster.example.fr.
+    ttl: 60
+    refresh: 600
+    minimum: 30
+",
+        )
+        .unwrap();
+        let (_, info) = keys.domains()[0];
+
+        let rrset = info.soa_rrset(&fixed_clock()).unwrap();
+        assert_eq!(rrset.ttl(), Ttl::from_secs(60));
+        let ZoneRecordData::Soa(soa) = &rrset.data()[0] else {
+            panic!("expected a SOA record");
+        };
+        assert_eq!(soa.refresh(), Ttl::from_secs(600));
+        assert_eq!(soa.retry(), Ttl::HOUR);
+        assert_eq!(soa.expire(), Ttl::from_secs(605800));
+        assert_eq!(soa.minimum(), Ttl::from_secs(30));
+    }
+
+    #[test]
+    fn generated_zone_is_rooted_at_the_challenge_name() {
+        let keys: Keys = serde_yaml::from_str(CONFIG).unwrap();
+        let (name, info) = keys.domains()[0];
+
+        let zone = build_zone(name, info, &fixed_clock()).unwrap();
+        assert_eq!(zone.apex_name().to_string(), "_acme-challenge.example.fr");
+        assert_eq!(zone.class(), Class::IN);
+    }
+
+    #[test]
+    fn domain_zone_is_built_from_the_template() {
+        let keys: Keys = serde_yaml::from_str(
+            "
+key1:
+  example.fr:
+    mname: ns-acme.example.fr.
+    rname: postmaster.example.fr.
+    domain_zone:
+      records:
+        - A 192.0.2.1
+        - A 192.0.2.2
+        - MX 10 mail.example.fr.
+        - TXT v=spf1 mx -all
+",
+        )
+        .unwrap();
+        let (name, info) = keys.domains()[0];
+
+        let zones = build_zones(name, info, &fixed_clock()).unwrap();
+        let apexes = zones
+            .iter()
+            .map(|zone| zone.apex_name().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(apexes, ["_acme-challenge.example.fr", "example.fr"]);
+
+        let rrsets = info
+            .domain_zone
+            .as_ref()
+            .unwrap()
+            .rrsets(&info.mname, info.ttl())
+            .unwrap();
+        let rtypes = rrsets.iter().map(|r| r.rtype()).collect::<Vec<_>>();
+        assert_eq!(rtypes, [Rtype::NS, Rtype::A, Rtype::MX, Rtype::TXT]);
+        assert_eq!(rrsets[0].data()[0].to_string(), "ns-acme.example.fr.");
+        assert_eq!(rrsets[1].data().len(), 2);
+
+        assert!(parse_template_record("CNAME www.example.fr.").is_err());
+        assert!(parse_template_record("A 192.0.2").is_err());
+    }
+
+    #[test]
+    fn challenge_zone_carries_the_static_records() {
+        let keys: Keys = serde_yaml::from_str(
+            r#"
+key1:
+  example.fr:
+    mname: ns-acme.example.fr.
+    rname: postmaster.example.fr.
+    records:
+      - CAA 0 issue "letsencrypt.org"
+      - NS ns1.example.fr.
+      - NS ns2.example.fr.
+"#,
+        )
+        .unwrap();
+        let (name, info) = keys.domains()[0];
+
+        let rrsets = info.static_rrsets().unwrap();
+        let rtypes = rrsets.iter().map(|r| r.rtype()).collect::<Vec<_>>();
+        assert_eq!(rtypes, [Rtype::CAA, Rtype::NS]);
+        assert_eq!(rrsets[1].data().len(), 2);
+        let ZoneRecordData::Unknown(caa) = &rrsets[0].data()[0] else {
+            panic!("expected a CAA record");
+        };
+        assert_eq!(caa.data().as_ref(), b"\x00\x05issueletsencrypt.org");
+        assert!(build_zone(name, info, &fixed_clock()).is_ok());
+
+        assert!(parse_template_record("CAA 0 issue").is_err());
+        assert!(parse_template_record("CAA 256 issue \"ca.example\"").is_err());
+    }
+
+    #[test]
+    fn domain_zone_is_optional() {
+        let keys: Keys = serde_yaml::from_str(CONFIG).unwrap();
+        let (name, info) = keys.domains()[0];
+
+        assert!(build_domain_zone(name, info, &fixed_clock())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn name_patterns_match_wildcards() {
+        assert!(matches_pattern(
+            "_acme-challenge.*",
+            "_acme-challenge.example.fr"
+        ));
+        assert!(matches_pattern(
+            "*.example.fr.",
+            "_acme-challenge.Example.fr"
+        ));
+        assert!(matches_pattern(
+            "_acme-*.example.*",
+            "_acme-challenge.example.fr"
+        ));
+        assert!(matches_pattern("*", "example.fr"));
+        assert!(!matches_pattern("_acme-challenge.*", "www.example.fr"));
+        assert!(!matches_pattern("*.example.fr", "example.fr.evil.com"));
+        assert!(!matches_pattern("example.fr", "sub.example.fr"));
+    }
+
+    #[test]
+    fn update_policy_restricts_types_and_names() {
+        let keys: Keys = serde_yaml::from_str(
+            "
+key1:
+  example.fr:
+    mname: ns-acme.example.fr.
+    rname: postmaster.example.fr.
+    allowed_types: [TXT]
+    allowed_names: [_acme-challenge.*]
+",
+        )
+        .unwrap();
+        let (_, info) = keys.domains()[0];
+        let owner = Name::<Bytes>::from_str("_acme-challenge.example.fr.").unwrap();
+        let other = Name::<Bytes>::from_str("www.example.fr.").unwrap();
+
+        assert!(info.allows_type(Rtype::TXT));
+        assert!(!info.allows_type(Rtype::A));
+        assert!(info.allows_name(&owner));
+        assert!(!info.allows_name(&other));
+    }
+
+    #[test]
+    fn keys_are_removed_whatever_their_algorithm() {
+        let rng = ring::rand::SystemRandom::new();
+        let name = KeyName::from_str("key1").unwrap();
+        let (key, _) = Key::generate(Algorithm::Sha256, &rng, name.clone(), None, None).unwrap();
+
+        let keystore = KeyStore::new_shared(None);
+        let mut keystore = keystore.write().unwrap();
+        keystore.insert_key(key);
+        assert!(keystore.remove_key(&KeyFile::from(&name)).unwrap());
+        assert!(keystore.find_key(&name).is_none());
+        assert!(!keystore.remove_key(&KeyFile::from(&name)).unwrap());
+    }
+}
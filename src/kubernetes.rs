@@ -0,0 +1,172 @@
+//! The domains sourced from labeled Kubernetes ConfigMaps.
+//!
+//! Every entry of the ConfigMaps matching the label selector is a YAML
+//! fragment merged into the configuration like an included file, e.g. a
+//! `keys` mapping with the domains of a team, so that the domains are managed
+//! as Kubernetes resources instead of files mounted and rewritten in the pod.
+//! The ConfigMaps are listed with the service account of the pod, which must
+//! be allowed to list them, and polled by the watcher to reload the
+//! configuration once one of them changes.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::config::{parse_json, KubernetesConfig};
+use crate::error;
+use crate::error::Result;
+
+/// The directory of the credentials of the service account of the pod.
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+#[derive(Debug, Deserialize)]
+struct ConfigMapList {
+    items: Vec<ConfigMap>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigMap {
+    metadata: Metadata,
+    #[serde(default)]
+    data: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Metadata {
+    name: String,
+    resource_version: String,
+}
+
+/// Returns the fragments of the labeled ConfigMaps, ordered by the name of
+/// their ConfigMap and entry.
+pub fn fragments(config: &KubernetesConfig) -> Result<Vec<serde_yaml::Value>> {
+    let mut fragments = Vec::new();
+    for configmap in list(config)? {
+        for (entry, data) in configmap.data {
+            let fragment: serde_yaml::Value = serde_yaml::from_str(&data).map_err(|e| {
+                error!(Kubernetes => "invalid entry {} of configmap {}: {}", entry, configmap.metadata.name, e)
+            })?;
+            if fragment.get("include").is_some() {
+                return Err(
+                    error!(Kubernetes => "include in entry {} of configmap {}", entry, configmap.metadata.name),
+                );
+            }
+            fragments.push(fragment);
+        }
+    }
+    Ok(fragments)
+}
+
+/// Returns the version of the labeled ConfigMaps, it changes once one of them
+/// is created, modified or deleted.
+pub fn version(config: &KubernetesConfig) -> Result<String> {
+    Ok(list(config)?
+        .iter()
+        .map(|configmap| {
+            format!(
+                "{}:{}",
+                configmap.metadata.name, configmap.metadata.resource_version
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+fn list(config: &KubernetesConfig) -> Result<Vec<ConfigMap>> {
+    let host = std::env::var("KUBERNETES_SERVICE_HOST")
+        .map_err(|_| error!(Kubernetes => "not running in a kubernetes pod"))?;
+    let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or("443".into());
+    // An IPv6 address is bracketed in the url
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host
+    };
+    let namespace = match config.namespace() {
+        Some(namespace) => namespace.to_string(),
+        None => read_service_account("namespace")?,
+    };
+    let token = read_service_account("token")?;
+
+    let url = format!(
+        "https://{}:{}/api/v1/namespaces/{}/configmaps",
+        host, port, namespace
+    );
+    let body = agent(config)?
+        .get(&url)
+        .query("labelSelector", config.label_selector())
+        .set("authorization", &format!("Bearer {}", token))
+        .call()
+        .map_err(|e| match e {
+            ureq::Error::Status(code, _) => {
+                error!(Kubernetes => "listing the configmaps failed with status {}", code)
+            }
+            e => error!(Kubernetes => "listing the configmaps failed: {}", e),
+        })?
+        .into_string()?;
+    parse_list(&body)
+}
+
+fn parse_list(body: &str) -> Result<Vec<ConfigMap>> {
+    let list: ConfigMapList =
+        parse_json(body).map_err(|e| error!(Kubernetes => "invalid configmap list: {}", e))?;
+    let mut items = list.items;
+    items.sort_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+    Ok(items)
+}
+
+fn read_service_account(file: &str) -> Result<String> {
+    let path = format!("{}/{}", SERVICE_ACCOUNT, file);
+    let content = std::fs::read_to_string(&path)
+        .map_err(|e| error!(Kubernetes => "failed to read {}: {}", path, e))?;
+    Ok(content.trim().to_string())
+}
+
+/// Builds an agent trusting the certificate authority of the cluster.
+fn agent(config: &KubernetesConfig) -> Result<ureq::Agent> {
+    let path = format!("{}/ca.crt", SERVICE_ACCOUNT);
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(&path)?)) {
+        roots
+            .add(cert?)
+            .map_err(|e| error!(Kubernetes => "invalid certificate in {}: {}", path, e))?;
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| error!(Kubernetes => "tls error: {}", e))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(ureq::AgentBuilder::new()
+        .tls_config(Arc::new(tls))
+        .timeout(config.timeout())
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configmaps_are_ordered_by_name() {
+        let body = r#"{"kind":"ConfigMapList","apiVersion":"v1","metadata":{"resourceVersion":"812"},"items":[
+            {"metadata":{"name":"team-b","namespace":"dnsr","resourceVersion":"811"},"data":{"keys.yml":"keys:\n  key2:\n    another-example.fr: {}\n"}},
+            {"metadata":{"name":"team-a","namespace":"dnsr","resourceVersion":"42"}}
+        ]}"#;
+        let items = parse_list(body).unwrap();
+        let names = items
+            .iter()
+            .map(|item| item.metadata.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["team-a", "team-b"]);
+        assert!(items[0].data.is_empty());
+
+        let fragment: serde_yaml::Value = serde_yaml::from_str(&items[1].data["keys.yml"]).unwrap();
+        assert!(fragment["keys"]["key2"].get("another-example.fr").is_some());
+    }
+}
@@ -0,0 +1,221 @@
+//! The configuration sourced from etcd or Consul.
+//!
+//! Every key under the configured prefix holds a YAML fragment merged into the
+//! configuration like an included file, e.g. a `keys` mapping with the domains
+//! of a team, so that a fleet of instances converges on the same keys and
+//! domains without distributing the files. The keys are read through the HTTP
+//! API of the server and polled by the watcher to reload the configuration
+//! once one of them changes.
+
+use base64::Engine;
+use serde::Deserialize;
+
+use crate::config::{parse_json, KvBackend, KvConfig};
+use crate::error;
+use crate::error::Result;
+
+/// A key under the prefix, its value is `None` for a Consul folder.
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    key: String,
+    value: Option<Vec<u8>>,
+    revision: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulEntry {
+    key: String,
+    value: Option<String>,
+    modify_index: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct EtcdRange {
+    #[serde(default)]
+    kvs: Vec<EtcdEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EtcdEntry {
+    key: String,
+    #[serde(default)]
+    value: String,
+    mod_revision: String,
+}
+
+/// Returns the fragments of the keys under the prefix, ordered by key.
+pub fn fragments(config: &KvConfig) -> Result<Vec<serde_yaml::Value>> {
+    let mut fragments = Vec::new();
+    for entry in list(config)? {
+        let Some(value) = entry.value else {
+            continue;
+        };
+        let fragment: serde_yaml::Value = serde_yaml::from_slice(&value)
+            .map_err(|e| error!(Kv => "invalid value of key {}: {}", entry.key, e))?;
+        if fragment.get("include").is_some() {
+            return Err(error!(Kv => "include in key {}", entry.key));
+        }
+        fragments.push(fragment);
+    }
+    Ok(fragments)
+}
+
+/// Returns the version of the keys under the prefix, it changes once one of
+/// them is created, modified or deleted.
+pub fn version(config: &KvConfig) -> Result<String> {
+    Ok(list(config)?
+        .iter()
+        .map(|entry| format!("{}:{}", entry.key, entry.revision))
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+fn list(config: &KvConfig) -> Result<Vec<Entry>> {
+    let agent = ureq::AgentBuilder::new().timeout(config.timeout()).build();
+    let mut entries = match config.backend() {
+        KvBackend::Consul => {
+            let url = format!("{}/v1/kv/{}", config.url(), config.prefix());
+            let mut request = agent.get(&url).query("recurse", "true");
+            if let Some(token) = config.token() {
+                request = request.set("x-consul-token", token);
+            }
+            match request.call() {
+                Ok(response) => parse_consul(&response.into_string()?)?,
+                // No key under the prefix
+                Err(ureq::Error::Status(404, _)) => Vec::new(),
+                Err(e) => return Err(request_error(e)),
+            }
+        }
+        KvBackend::Etcd => {
+            let url = format!("{}/v3/kv/range", config.url());
+            let engine = base64::engine::general_purpose::STANDARD;
+            let body = format!(
+                r#"{{"key":"{}","range_end":"{}"}}"#,
+                engine.encode(config.prefix()),
+                engine.encode(prefix_end(config.prefix().as_bytes()))
+            );
+            let mut request = agent.post(&url).set("content-type", "application/json");
+            if let Some(token) = config.token() {
+                request = request.set("authorization", token);
+            }
+            let response = request.send_string(&body).map_err(request_error)?;
+            parse_etcd(&response.into_string()?)?
+        }
+    };
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(entries)
+}
+
+fn request_error(e: ureq::Error) -> error::Error {
+    match e {
+        ureq::Error::Status(code, _) => {
+            error!(Kv => "listing the keys failed with status {}", code)
+        }
+        e => error!(Kv => "listing the keys failed: {}", e),
+    }
+}
+
+fn parse_consul(body: &str) -> Result<Vec<Entry>> {
+    let entries: Vec<ConsulEntry> =
+        parse_json(body).map_err(|e| error!(Kv => "invalid consul response: {}", e))?;
+    entries
+        .into_iter()
+        .map(|entry| {
+            let value = entry.value.as_deref().map(decode).transpose()?;
+            Ok(Entry {
+                key: entry.key,
+                value,
+                revision: entry.modify_index.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn parse_etcd(body: &str) -> Result<Vec<Entry>> {
+    let range: EtcdRange =
+        parse_json(body).map_err(|e| error!(Kv => "invalid etcd response: {}", e))?;
+    range
+        .kvs
+        .into_iter()
+        .map(|entry| {
+            let key = String::from_utf8(decode(&entry.key)?)
+                .map_err(|_| error!(Kv => "invalid etcd key {}", entry.key))?;
+            Ok(Entry {
+                key,
+                value: Some(decode(&entry.value)?),
+                revision: entry.mod_revision,
+            })
+        })
+        .collect()
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map_err(|e| error!(Kv => "invalid base64 value: {}", e))
+}
+
+/// Returns the end of the etcd range of the keys starting with `prefix`, the
+/// prefix with its last byte incremented.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every key
+    vec![0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_range_ends_after_the_prefix() {
+        assert_eq!(prefix_end(b"dnsr/"), b"dnsr0");
+        assert_eq!(prefix_end(b"a\xff"), b"b");
+        assert_eq!(prefix_end(b""), b"\0");
+    }
+
+    #[test]
+    fn consul_and_etcd_responses_are_parsed() {
+        let consul = r#"[
+            {"LockIndex":0,"Key":"dnsr/","Flags":0,"Value":null,"CreateIndex":12,"ModifyIndex":12},
+            {"LockIndex":0,"Key":"dnsr/team-a.yml","Flags":0,"Value":"a2V5czoge30K","CreateIndex":13,"ModifyIndex":20}
+        ]"#;
+        assert_eq!(
+            parse_consul(consul).unwrap(),
+            [
+                Entry {
+                    key: "dnsr/".into(),
+                    value: None,
+                    revision: "12".into(),
+                },
+                Entry {
+                    key: "dnsr/team-a.yml".into(),
+                    value: Some(b"keys: {}\n".to_vec()),
+                    revision: "20".into(),
+                },
+            ]
+        );
+
+        let etcd = r#"{"header":{"cluster_id":"14841639068965178418","revision":"7"},"kvs":[
+            {"key":"ZG5zci90ZWFtLWEueW1s","create_revision":"5","mod_revision":"7","version":"2","value":"a2V5czoge30K"}
+        ],"count":"1"}"#;
+        assert_eq!(
+            parse_etcd(etcd).unwrap(),
+            [Entry {
+                key: "dnsr/team-a.yml".into(),
+                value: Some(b"keys: {}\n".to_vec()),
+                revision: "7".into(),
+            }]
+        );
+        assert!(parse_etcd(r#"{"header":{"revision":"7"}}"#)
+            .unwrap()
+            .is_empty());
+    }
+}
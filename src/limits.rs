@@ -0,0 +1,106 @@
+//! Process-wide resource limits.
+//!
+//! The limit of open file descriptors is requested once at startup and the
+//! resident memory is checked periodically against the warning threshold, so
+//! that the operators of shared hosts notice a runaway instance before the
+//! kernel does. The limit of zones is enforced by the zone tree itself.
+
+use std::io::{Error, Result};
+use std::time::Duration;
+
+use crate::config::LimitsConfig;
+
+/// The interval between two checks of the resident memory.
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Applies the limits of `config` to the process.
+pub fn apply(config: &LimitsConfig) {
+    if let Some(max) = config.max_open_files() {
+        match set_open_files(max) {
+            Ok(limit) if limit < max => {
+                log::warn!(target: "limits", "open files limited to {} by the hard limit", limit)
+            }
+            Ok(limit) => log::info!(target: "limits", "open files limited to {}", limit),
+            Err(e) => log::error!(target: "limits", "failed to set the open files limit: {}", e),
+        }
+    }
+
+    if let Some(threshold) = config.memory_warning() {
+        tokio::spawn(watch_memory(threshold));
+    }
+}
+
+/// Sets the soft limit of open file descriptors to `max`, capped to the hard
+/// limit, and returns the limit set.
+fn set_open_files(max: u64) -> Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit for the duration of the calls
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) != 0 {
+            return Err(Error::last_os_error());
+        }
+        limit.rlim_cur = max.min(limit.rlim_max);
+        if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(limit.rlim_cur)
+}
+
+/// Logs a warning whenever the resident memory goes above `threshold` bytes,
+/// and once it is back below.
+async fn watch_memory(threshold: u64) {
+    let mut interval = tokio::time::interval(MEMORY_CHECK_INTERVAL);
+    let mut above = false;
+    loop {
+        interval.tick().await;
+        let resident = match resident_memory() {
+            Ok(resident) => resident,
+            Err(e) => {
+                log::error!(target: "limits", "failed to read the resident memory: {}", e);
+                return;
+            }
+        };
+
+        if resident > threshold && !above {
+            log::warn!(
+                target: "limits",
+                "resident memory of {} MiB above the {} MiB threshold",
+                resident / 1024 / 1024,
+                threshold / 1024 / 1024
+            );
+        } else if resident <= threshold && above {
+            log::info!(target: "limits", "resident memory back below the threshold");
+        }
+        above = resident > threshold;
+    }
+}
+
+/// Returns the resident memory of the process in bytes.
+fn resident_memory() -> Result<u64> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    parse_resident_memory(&status).ok_or_else(|| Error::other("no VmRSS line"))
+}
+
+fn parse_resident_memory(status: &str) -> Option<u64> {
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kib = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resident_memory_is_read_from_the_status() {
+        let status = "Name:\tdnsr\nVmPeak:\t  20480 kB\nVmRSS:\t   2048 kB\nThreads:\t4\n";
+        assert_eq!(parse_resident_memory(status), Some(2048 * 1024));
+        assert_eq!(parse_resident_memory("Name:\tdnsr\n"), None);
+    }
+}
@@ -1,8 +1,16 @@
 //! This crate is strongly inspired by the [simple_logger](https://docs.rs/simple_logger/latest/simple_logger/) crate
 //! This just uses less/other dependancies to keep the dependencies as low as possible on the project.
 
+use std::time::SystemTime;
+
 use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
 
+pub use self::file::LogFile;
+pub use self::syslog::Syslog;
+
+mod file;
+mod syslog;
+
 pub struct Logger {
     /// The default logging level
     default_level: LevelFilter,
@@ -20,6 +28,12 @@ pub struct Logger {
 
     /// Whether to log metrics or not
     metrics: bool,
+
+    /// The syslog sink used instead of stdout/stderr, if any
+    syslog: Option<Syslog>,
+
+    /// The log file used instead of stdout/stderr, if any
+    file: Option<LogFile>,
 }
 
 impl Logger {
@@ -30,6 +44,8 @@ impl Logger {
             threads: false,
             stderr: false,
             metrics: true,
+            syslog: None,
+            file: None,
         }
     }
 
@@ -53,6 +69,16 @@ impl Logger {
         self
     }
 
+    pub fn with_syslog(mut self, syslog: Syslog) -> Logger {
+        self.syslog = Some(syslog);
+        self
+    }
+
+    pub fn with_file(mut self, file: LogFile) -> Logger {
+        self.file = Some(file);
+        self
+    }
+
     /// Configure the logger
     pub fn max_level(&self) -> LevelFilter {
         let max_level = self
@@ -117,6 +143,24 @@ impl Log for Logger {
 
             let message = format!("{} [{}{}] {}", level_string, target, thread, record.args());
 
+            if let Some(syslog) = &self.syslog {
+                let line = format!("[{}{}] {}", target, thread, record.args());
+                if let Err(e) = syslog.send(record.level(), target, &line) {
+                    eprintln!("{} (syslog error: {})", message, e);
+                }
+            }
+
+            if let Some(file) = &self.file {
+                let line = format!("{} {}", crate::time::rfc3339(SystemTime::now()), message);
+                if let Err(e) = file.write_line(&line) {
+                    eprintln!("{} (log file error: {})", message, e);
+                }
+            }
+
+            if self.syslog.is_some() || self.file.is_some() {
+                return;
+            }
+
             if self.stderr {
                 eprintln!("{}", message);
             } else {
@@ -0,0 +1,129 @@
+//! A log file rotated on its size or age.
+//!
+//! On rotation `dnsr.log` is renamed `dnsr.log.1`, the previous `dnsr.log.1` is
+//! renamed `dnsr.log.2` and so on up to the retention, the oldest file being
+//! removed.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::config::LogFileConfig;
+
+pub struct LogFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    retention: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+}
+
+impl LogFile {
+    pub fn open(config: &LogFileConfig) -> Result<Self> {
+        Ok(Self {
+            path: config.path().to_path_buf(),
+            max_size: config.max_size(),
+            max_age: config.max_age(),
+            retention: config.retention(),
+            state: Mutex::new(State::open(config.path())?),
+        })
+    }
+
+    /// Appends `line` to the file, the file is rotated first if it is full or
+    /// too old.
+    pub fn write_line(&self, line: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let now = SystemTime::now();
+
+        let full = self
+            .max_size
+            .is_some_and(|max| state.size > 0 && state.size + line.len() as u64 + 1 > max);
+        let expired = self.max_age.is_some_and(|max| {
+            now.duration_since(state.opened_at)
+                .is_ok_and(|age| age >= max)
+        });
+        if full || expired {
+            self.rotate()?;
+            *state = State::open(&self.path)?;
+        }
+
+        writeln!(state.file, "{}", line)?;
+        state.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&self) -> Result<()> {
+        if self.retention == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+
+        let oldest = rotated(self.retention);
+        if oldest.exists() {
+            std::fs::remove_file(oldest)?;
+        }
+        for n in (1..self.retention).rev() {
+            let path = rotated(n);
+            if path.exists() {
+                std::fs::rename(path, rotated(n + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated(1))
+    }
+}
+
+impl State {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            file,
+            size,
+            opened_at: SystemTime::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_rotated_on_size() {
+        let dir = std::env::temp_dir().join(format!("dnsr-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnsr.log");
+
+        let config: LogFileConfig = serde_yaml::from_str(&format!(
+            "{{ path: {}, max_size: 10, retention: 2 }}",
+            path.display()
+        ))
+        .unwrap();
+        let file = LogFile::open(&config).unwrap();
+        for line in ["line 1", "line 2", "line 3", "line 4"] {
+            file.write_line(line).unwrap();
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("dnsr.log"), "line 4\n");
+        assert_eq!(read("dnsr.log.1"), "line 3\n");
+        assert_eq!(read("dnsr.log.2"), "line 2\n");
+        assert!(!dir.join("dnsr.log.3").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+//! An [RFC 5424](https://www.rfc-editor.org/rfc/rfc5424) syslog sink.
+//!
+//! The messages are sent to the local syslog socket, or to a remote server over
+//! UDP or TCP. Over TCP the messages are framed with their length as described
+//! in [RFC 6587](https://www.rfc-editor.org/rfc/rfc6587#section-3.4.1) and sent
+//! by a background thread, so that an unreachable server never blocks the
+//! threads logging: the messages are dropped while it cannot be reached or
+//! while the queue of the thread is full.
+
+use std::io::{Error, ErrorKind, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::Level;
+
+use crate::config::SyslogConfig;
+
+/// The longest MSGID allowed by the RFC 5424.
+const MAX_MSGID_LEN: usize = 32;
+
+/// The number of messages queued for the TCP server.
+const TCP_QUEUE_LEN: usize = 1024;
+
+/// The timeout of the connection to the TCP server and of the writes.
+const TCP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The bounds of the delay before connecting again to the TCP server.
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+pub struct Syslog {
+    transport: Transport,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+}
+
+enum Transport {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    /// The queue of the thread sending the frames to the server
+    Tcp(SyncSender<String>),
+}
+
+impl Syslog {
+    pub fn connect(config: &SyslogConfig) -> Result<Self> {
+        let facility = FACILITIES
+            .iter()
+            .position(|f| *f == config.facility())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown syslog facility {}", config.facility()),
+                )
+            })? as u8;
+
+        let address = config.address();
+        let transport = if let Some(address) = address.strip_prefix("udp://") {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(address)?;
+            Transport::Udp(socket)
+        } else if let Some(address) = address.strip_prefix("tcp://") {
+            let stream = connect_tcp(address)?;
+            let (frames, queue) = sync_channel(TCP_QUEUE_LEN);
+            let address = address.to_string();
+            std::thread::spawn(move || send_frames(&address, Some(stream), queue));
+            Transport::Tcp(frames)
+        } else {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(address)?;
+            Transport::Unix(socket)
+        };
+
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|hostname| hostname.trim().to_string())
+            .unwrap_or_default();
+
+        Ok(Self {
+            transport,
+            facility,
+            hostname,
+            app_name: config.app_name().to_string(),
+        })
+    }
+
+    pub fn send(&self, level: Level, target: &str, message: &str) -> Result<()> {
+        let message = self.format(level, target, message, SystemTime::now());
+
+        match &self.transport {
+            Transport::Unix(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Transport::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Transport::Tcp(frames) => {
+                let frame = format!("{} {}", message.len(), message);
+                frames.try_send(frame).map_err(|e| match e {
+                    TrySendError::Full(_) => {
+                        Error::new(ErrorKind::WouldBlock, "syslog queue full, message dropped")
+                    }
+                    TrySendError::Disconnected(_) => {
+                        Error::new(ErrorKind::BrokenPipe, "syslog sender stopped")
+                    }
+                })
+            }
+        }
+    }
+
+    fn format(&self, level: Level, target: &str, message: &str, now: SystemTime) -> String {
+        let severity = match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+
+        format!(
+            "<{}>1 {} {} {} {} {} - {}",
+            self.facility as u32 * 8 + severity,
+            crate::time::rfc3339(now),
+            header_field(&self.hostname, 255),
+            header_field(&self.app_name, 48),
+            std::process::id(),
+            header_field(target, MAX_MSGID_LEN),
+            message
+        )
+    }
+}
+
+/// Connects to the TCP server at `address` with timeouts.
+fn connect_tcp(address: &str) -> Result<TcpStream> {
+    let addr = address.to_socket_addrs()?.next().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown syslog server {}", address),
+        )
+    })?;
+    let stream = TcpStream::connect_timeout(&addr, TCP_TIMEOUT)?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Sends the queued `frames` to the TCP server at `address` over `stream`.
+///
+/// A lost connection is opened again at once, then with an exponential
+/// backoff while the server is unreachable, the frames being dropped meanwhile.
+fn send_frames(address: &str, mut stream: Option<TcpStream>, frames: Receiver<String>) {
+    let mut backoff = MIN_RECONNECT_BACKOFF;
+    let mut next_attempt = Instant::now();
+
+    for frame in frames {
+        let sent = stream
+            .as_mut()
+            .is_some_and(|s| s.write_all(frame.as_bytes()).is_ok());
+        if sent {
+            continue;
+        }
+        stream = None;
+        if Instant::now() < next_attempt {
+            continue;
+        }
+
+        let reconnected = connect_tcp(address).and_then(|mut s| {
+            s.write_all(frame.as_bytes())?;
+            Ok(s)
+        });
+        match reconnected {
+            Ok(s) => {
+                stream = Some(s);
+                backoff = MIN_RECONNECT_BACKOFF;
+            }
+            Err(e) => {
+                eprintln!(
+                    "syslog server {} unreachable, messages dropped for {}ms: {}",
+                    address,
+                    backoff.as_millis(),
+                    e
+                );
+                next_attempt = Instant::now() + backoff;
+                backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Keeps the printable characters of a header field, an empty field is written
+/// as the nil value.
+fn header_field(value: &str, max_len: usize) -> String {
+    let value = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect::<String>();
+
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    #[test]
+    fn messages_follow_rfc5424() {
+        let syslog = Syslog {
+            transport: Transport::Udp(UdpSocket::bind("127.0.0.1:0").unwrap()),
+            facility: 3,
+            hostname: "ns-acme".to_string(),
+            app_name: "dnsr".to_string(),
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1722353587);
+
+        assert_eq!(
+            syslog.format(Level::Warn, "update", "zone updated", now),
+            format!(
+                "<28>1 2024-07-30T15:33:07.000000Z ns-acme dnsr {} update - zone updated",
+                std::process::id()
+            )
+        );
+        // An empty target is written as the nil MSGID
+        let message = syslog.format(Level::Info, "", "message", now);
+        assert!(message.starts_with("<30>1 "));
+        assert!(message.ends_with(&format!(" {} - - message", std::process::id())));
+    }
+
+    #[test]
+    fn tcp_messages_are_framed_and_never_block() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("address: tcp://{}", listener.local_addr().unwrap());
+        let syslog = Syslog::connect(&serde_yaml::from_str(&address).unwrap()).unwrap();
+
+        let (mut server, _) = listener.accept().unwrap();
+        syslog.send(Level::Info, "update", "zone updated").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut received = String::new();
+        let mut buf = [0; 512];
+        while !received.ends_with("zone updated") {
+            let n = server.read(&mut buf).unwrap();
+            received.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+        }
+        let (len, message) = received.split_once(' ').unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), message.len());
+
+        // The messages are queued, then dropped, once the server is gone
+        drop((server, listener));
+        for _ in 0..2 * TCP_QUEUE_LEN {
+            let _ = syslog.send(Level::Info, "update", "zone updated");
+        }
+    }
+}
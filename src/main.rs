@@ -18,55 +18,135 @@
 use core::future::pending;
 use core::time::Duration;
 
+use std::net::SocketAddr;
+use std::path::Path;
 use std::process::exit;
 use std::sync::Arc;
 
 use domain::net::server::buf::VecBufSource;
-use domain::net::server::dgram::DgramServer;
-use domain::net::server::middleware::edns::EdnsMiddlewareSvc;
-use domain::net::server::middleware::mandatory::MandatoryMiddlewareSvc;
 use domain::net::server::stream::StreamServer;
-use tokio::net::{TcpListener, UdpSocket};
+use tokio::net::TcpListener;
 
-use crate::service::middleware::{MetricsMiddlewareSvc, Rfc2136MiddlewareSvc, Stats};
+use crate::admin::AdminServer;
+use crate::config::DNS_PORT;
+use crate::report::MetricsReporter;
+use crate::service::middleware::Stats;
 use crate::service::Watcher;
+use crate::solver::SolverServer;
+use crate::workers::UdpWorkers;
 
+mod admin;
+mod blocklist;
+mod cidr;
 mod config;
+mod dname;
 mod error;
+mod geo;
 mod key;
+#[cfg(feature = "kubernetes")]
+mod kubernetes;
+mod kv;
+mod limits;
 mod logger;
+#[cfg(target_os = "linux")]
+mod mmsg;
+mod policy;
+mod report;
+mod serial;
 mod service;
+mod sig0;
+mod solver;
+mod store;
+mod systemd;
+mod telemetry;
+mod time;
 mod tsig;
+mod vault;
 // mod watcher;
+mod workers;
 mod zone;
 
-#[tokio::main()]
-async fn main() {
+fn main() {
+    // Describe an error code instead of serving, it needs no configuration
+    if std::env::args().nth(1).as_deref() == Some("explain") {
+        let code = std::env::args().nth(2);
+        match error::explain(code.as_deref()) {
+            Some(explanation) => print!("{}", explanation),
+            None => {
+                eprintln!("Unknown error code {}", code.unwrap_or_default());
+                exit(1);
+            }
+        }
+        return;
+    }
+
     // Fetch the configuration
-    let config_path = std::env::var("DNSR_CONFIG").unwrap_or(config::BASE_CONFIG_FILE.into());
-    let bytes = match std::fs::read(&config_path) {
-        Ok(b) => b,
+    let config_path = config::Config::config_file_path();
+    let config = match config::Config::from_file(Path::new(&config_path)) {
+        Ok(c) => c,
         Err(e) => {
-            eprintln!("Failed to read config file at path {}: {}", config_path, e);
+            eprintln!("Failed to load config file at path {}: {}", config_path, e);
             exit(1);
         }
     };
-    let config = match config::Config::try_from(&bytes) {
-        Ok(c) => c,
+
+    // The runtime is sized from the configuration
+    let workers = config.workers_config();
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = workers.tokio_threads() {
+        runtime.worker_threads(threads);
+    }
+    if let Some(threads) = workers.blocking_threads() {
+        runtime.max_blocking_threads(threads);
+    }
+    let runtime = match runtime.build() {
+        Ok(runtime) => runtime,
         Err(e) => {
-            eprintln!("Failed to parse config file at path {}: {}", config_path, e);
+            eprintln!("Failed to start the runtime: {}", e);
             exit(1);
         }
     };
 
+    runtime.block_on(serve(config));
+}
+
+/// Serves the configuration, or runs the subcommand given instead.
+async fn serve(config: config::Config) {
+    // The subcommand run instead of serving, if any
+    let command = std::env::args().nth(1);
+
     // Initialize the custom logger
-    logger::Logger::new()
-        .with_level(config.log_config().level())
-        .with_metrics(config.log_config().enable_metrics())
-        .with_stderr(config.log_config().stderr())
-        .with_thread(config.log_config().enable_thread_id())
-        .init()
-        .expect("Failed to initialize custom logger");
+    let log_config = config.log_config();
+    let mut logger = logger::Logger::new()
+        .with_level(log_config.level())
+        .with_metrics(log_config.enable_metrics())
+        // The exported zones are written to stdout
+        .with_stderr(log_config.stderr() || command.as_deref() == Some("export"))
+        .with_thread(log_config.enable_thread_id());
+    if let Some(syslog) = log_config.syslog() {
+        match logger::Syslog::connect(syslog) {
+            Ok(syslog) => logger = logger.with_syslog(syslog),
+            Err(e) => {
+                eprintln!("Failed to connect to syslog at {}: {}", syslog.address(), e);
+                exit(1);
+            }
+        }
+    }
+    if let Some(file) = log_config.file() {
+        match logger::LogFile::open(file) {
+            Ok(file) => logger = logger.with_file(file),
+            Err(e) => {
+                eprintln!(
+                    "Failed to open log file at {}: {}",
+                    file.path().display(),
+                    e
+                );
+                exit(1);
+            }
+        }
+    }
+    logger.init().expect("Failed to initialize custom logger");
 
     // Create the DNSR service
     let config = Arc::new(config);
@@ -74,29 +154,121 @@ async fn main() {
     let stats = Stats::new_shared();
 
     let dnsr = Arc::new(dnsr);
-    let dnsr_svc = EdnsMiddlewareSvc::new(dnsr.clone());
-    let dnsr_svc = MandatoryMiddlewareSvc::new(dnsr_svc);
-    let dnsr_svc = Rfc2136MiddlewareSvc::new(dnsr.clone(), dnsr_svc);
-    let dnsr_svc = MetricsMiddlewareSvc::new(dnsr_svc, stats.clone());
-
-    let addr = "0.0.0.0:53";
-
-    // Start the UDP and TCP servers
-    let sock = UdpSocket::bind(addr).await.unwrap();
-    let sock = Arc::new(sock);
-    let num_cores = std::thread::available_parallelism().unwrap().get();
-    for _i in 0..num_cores {
-        let udp_srv = DgramServer::new(sock.clone(), VecBufSource, dnsr_svc.clone());
-        tokio::spawn(async move { udp_srv.run().await });
+
+    // Run a single message read from stdin instead of serving, see `service::ingest`
+    if command.as_deref() == Some("ingest") {
+        let transport = std::env::args().nth(2).unwrap_or("udp".into());
+        let res = match transport.parse() {
+            Ok(transport) => service::ingest::ingest_stdin(dnsr, transport).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            eprintln!("Failed to ingest message: {}", e);
+            exit(1);
+        }
+        return;
     }
 
+    // Write the zones to stdout instead of serving, see `service::export`
+    if command.as_deref() == Some("export") {
+        let res = service::export::ExportOptions::from_args(std::env::args().skip(2))
+            .and_then(|options| service::export::export_stdout(dnsr, &options));
+        if let Err(e) = res {
+            eprintln!("Failed to export zones: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    limits::apply(&config.limits_config());
+
+    let dnsr_svc = service::middleware_chain(dnsr.clone(), stats.clone());
+    let reporter = Arc::new(MetricsReporter::new(stats.clone(), config.metrics_config()));
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], DNS_PORT));
+
+    // Start the UDP and TCP servers, the UDP workers are scaled on the load
+    let mut udp_workers = UdpWorkers::new(addr, dnsr_svc.clone(), &config);
+    if let Err(e) = udp_workers.start() {
+        eprintln!("Failed to bind UDP socket on {}: {}", addr, e);
+        exit(1);
+    }
+    tokio::spawn(udp_workers.run(stats.clone()));
+
     let sock = TcpListener::bind(addr).await.unwrap();
     let tcp_srv = StreamServer::new(sock, VecBufSource, dnsr_svc.clone());
 
     tokio::spawn(async move { tcp_srv.run().await });
 
-    tokio::spawn(async move {
-        match dnsr.watch_lock() {
+    if let Some(store) = dnsr.store.clone() {
+        let zones = dnsr.zones.clone();
+        std::thread::spawn(move || store.run(&zones));
+    }
+
+    if let Some(snapshots) = dnsr.snapshots.clone() {
+        let dnsr = dnsr.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(snapshots.interval());
+            // A standby mirrors the snapshots of the primary instead
+            let result = if dnsr.is_standby() {
+                snapshots.restore(&dnsr.zones, &dnsr.journal)
+            } else {
+                snapshots.upload(&dnsr.zones, &dnsr.journal)
+            };
+            if let Err(e) = result {
+                log::error!(target: "s3", "failed to synchronize zones snapshot: {}", e);
+            }
+        });
+    }
+
+    if let Some(tracer) = dnsr.tracer.clone() {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(tracer.interval());
+            if let Err(e) = tracer.export() {
+                log::error!(target: "telemetry", "failed to export traces: {}", e);
+            }
+        });
+    }
+
+    if let Some(expiry) = dnsr.expiry.clone() {
+        let dnsr = dnsr.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(expiry.interval());
+            expiry.sweep(&dnsr);
+        });
+    }
+
+    if let Some(admin) = config.admin_config() {
+        let server = AdminServer::new(dnsr.clone(), reporter.clone(), admin);
+        std::thread::spawn(move || {
+            if let Err(e) = server.run() {
+                log::error!(target: "admin", "admin api stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(cert_manager) = config.cert_manager_config() {
+        let server = SolverServer::new(dnsr.clone(), cert_manager);
+        std::thread::spawn(move || {
+            if let Err(e) = server.run() {
+                log::error!(target: "solver", "cert-manager solver stopped: {}", e);
+            }
+        });
+    }
+
+    {
+        let dnsr = dnsr.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(60));
+            dnsr.purge_removed_zones();
+        });
+    }
+
+    // The watcher blocks on the file system events and polls the backends, it
+    // runs on its own thread so that it never holds a runtime worker
+    std::thread::spawn(move || {
+        // The listeners are bound, dnsr is ready once the zones are loaded
+        match dnsr.watch_lock(systemd::notify_ready) {
             Ok(_) => (),
             Err(e) => {
                 log::error!(target: "watcher", "failed to watch lock: {}", e);
@@ -105,13 +277,7 @@ async fn main() {
         }
     });
 
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(5));
-        loop {
-            interval.tick().await;
-            log::info!(target: "metrics", "metrics report: {}", stats.read().unwrap());
-        }
-    });
+    tokio::spawn(reporter.run());
 
     pending::<()>().await;
 }
@@ -0,0 +1,338 @@
+//! The batched UDP workers of Linux.
+//!
+//! Once its socket is readable, a batched worker reads up to its batch size of
+//! datagrams with a single `recvmmsg` call and spawns a task per query. The
+//! responses are written as they complete, those ready at the same time with
+//! a single `sendmmsg` call, so that the syscalls are shared by the queries at
+//! high rates while a slow query never holds back the others.
+
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::pin;
+use std::time::Duration;
+
+use domain::base::Message;
+use domain::net::server::message::{Request, TransportSpecificContext, UdpTransportContext};
+use domain::net::server::service::Service;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::future::join;
+use futures::{SinkExt, StreamExt};
+use socket2::SockAddr;
+use tokio::io::unix::AsyncFd;
+use tokio::time::Instant;
+
+use crate::service::DnsrSvc;
+
+/// The size of the receive buffers, the larger datagrams are dropped.
+const RECV_BUF_SIZE: usize = 4096;
+
+/// The largest batch, the kernel does not handle more messages per call.
+const MAX_BATCH: usize = 1024;
+
+/// The first and the longest waits after a failed receive, doubled on every
+/// consecutive failure.
+const MIN_RECV_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_RECV_BACKOFF: Duration = Duration::from_secs(1);
+
+pub struct BatchedServer {
+    sock: AsyncFd<UdpSocket>,
+    svc: DnsrSvc,
+    batch: usize,
+    max_response_size: u16,
+}
+
+impl BatchedServer {
+    /// Serves the nonblocking `sock` with batches of `batch` datagrams.
+    pub fn new(
+        sock: UdpSocket,
+        svc: DnsrSvc,
+        batch: usize,
+        max_response_size: u16,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            sock: AsyncFd::new(sock)?,
+            svc,
+            batch: batch.clamp(1, MAX_BATCH),
+            max_response_size,
+        })
+    }
+
+    /// Serves the datagrams until the task is aborted.
+    pub async fn run(self) {
+        let (responses, ready) = channel(self.batch);
+        join(self.receive(responses), self.reply(ready)).await;
+    }
+
+    /// Receives the datagrams and spawns a task per query, which sends its
+    /// responses to `responses`.
+    async fn receive(&self, responses: Sender<(SocketAddr, Vec<u8>)>) {
+        let mut bufs = vec![vec![0; RECV_BUF_SIZE]; self.batch];
+        let mut backoff = Duration::ZERO;
+        loop {
+            let datagrams = match self.recv(&mut bufs).await {
+                Ok(datagrams) => {
+                    backoff = Duration::ZERO;
+                    datagrams
+                }
+                Err(e) => {
+                    // A persistent error would otherwise spin the worker
+                    backoff = (backoff * 2).clamp(MIN_RECV_BACKOFF, MAX_RECV_BACKOFF);
+                    log::error!(target: "udp_workers", "failed to receive datagrams, retrying in {:?}: {}", backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    continue;
+                }
+            };
+
+            let received_at = Instant::now();
+            for (bytes, addr) in datagrams {
+                // Messages too short to hold a header never reach the service
+                let Ok(message) = Message::from_octets(bytes) else {
+                    continue;
+                };
+                let context = UdpTransportContext::new(Some(self.max_response_size));
+                let request = Request::new(
+                    addr,
+                    received_at,
+                    message,
+                    TransportSpecificContext::Udp(context),
+                );
+                tokio::spawn(handle(self.svc.clone(), request, responses.clone()));
+            }
+        }
+    }
+
+    /// Writes the responses as they complete, all the ones ready at once, up to
+    /// the batch size, with a single call.
+    async fn reply(&self, mut ready: Receiver<(SocketAddr, Vec<u8>)>) {
+        let mut responses = Vec::with_capacity(self.batch);
+        while let Some(response) = ready.next().await {
+            responses.push(response);
+            while responses.len() < self.batch {
+                match ready.try_next() {
+                    Ok(Some(response)) => responses.push(response),
+                    _ => break,
+                }
+            }
+
+            if let Err(e) = send(&self.sock, &responses).await {
+                log::error!(target: "udp_workers", "failed to send {} responses: {}", responses.len(), e);
+            }
+            responses.clear();
+        }
+    }
+
+    async fn recv(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(Vec<u8>, SocketAddr)>> {
+        loop {
+            let mut guard = self.sock.readable().await?;
+            if let Ok(result) = guard.try_io(|sock| recv_batch(sock.as_raw_fd(), bufs)) {
+                return result;
+            }
+        }
+    }
+}
+
+/// Runs a request through the service and sends its responses, with the
+/// address of its client, to `responses`.
+async fn handle(
+    svc: DnsrSvc,
+    request: Request<Vec<u8>>,
+    mut responses: Sender<(SocketAddr, Vec<u8>)>,
+) {
+    let addr = request.client_addr();
+    let mut items = pin!(svc.call(request).await);
+    while let Some(item) = items.next().await {
+        let response = match item {
+            Ok(item) => item.response().map(|r| (addr, r.as_slice().to_vec())),
+            Err(e) => {
+                log::debug!(target: "udp_workers", "service error: {}", e);
+                None
+            }
+        };
+        // The worker is stopped once the receiver is dropped
+        if let Some(response) = response {
+            if responses.send(response).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Writes every datagram to its address on `sock`. A datagram which cannot be
+/// sent, e.g. to an unreachable address, is skipped and the following ones are
+/// still sent.
+async fn send(
+    sock: &AsyncFd<UdpSocket>,
+    mut datagrams: &[(SocketAddr, Vec<u8>)],
+) -> io::Result<()> {
+    while let Some((addr, _)) = datagrams.first() {
+        let mut guard = sock.writable().await?;
+        match guard.try_io(|sock| send_batch(sock.as_raw_fd(), datagrams)) {
+            Ok(Ok(sent)) => datagrams = &datagrams[sent..],
+            // `sendmmsg` only fails when the first datagram is not sent
+            Ok(Err(e)) => {
+                log::debug!(target: "udp_workers", "failed to send a response to {}: {}", addr, e);
+                datagrams = &datagrams[1..];
+            }
+            Err(_would_block) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Reads up to one datagram per buffer with a single `recvmmsg` call, the
+/// datagrams are returned with the address of their sender.
+fn recv_batch(fd: RawFd, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(Vec<u8>, SocketAddr)>> {
+    // SAFETY: an all zero address is a valid unspecified address
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+    let mut iovecs = bufs
+        .iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        })
+        .collect::<Vec<_>>();
+    let mut headers = iovecs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .map(|(iov, addr)| {
+            let len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header(iov, (addr as *mut libc::sockaddr_storage).cast(), len)
+        })
+        .collect::<Vec<_>>();
+
+    // SAFETY: every header points to a buffer and an address which outlive
+    // the call
+    let received = unsafe {
+        libc::recvmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            headers.len() as _,
+            libc::MSG_DONTWAIT as _,
+            std::ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut datagrams = Vec::with_capacity(received as usize);
+    for (i, header) in headers.iter().take(received as usize).enumerate() {
+        // The datagrams larger than the buffers are dropped
+        if header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+            continue;
+        }
+        // SAFETY: the kernel wrote an address of `msg_namelen` bytes
+        let addr = unsafe { SockAddr::new(addrs[i], header.msg_hdr.msg_namelen) };
+        if let Some(addr) = addr.as_socket() {
+            datagrams.push((bufs[i][..header.msg_len as usize].to_vec(), addr));
+        }
+    }
+    Ok(datagrams)
+}
+
+/// Writes the datagrams to their address with a single `sendmmsg` call and
+/// returns the number of datagrams written.
+fn send_batch(fd: RawFd, datagrams: &[(SocketAddr, Vec<u8>)]) -> io::Result<usize> {
+    let addrs = datagrams
+        .iter()
+        .map(|(addr, _)| SockAddr::from(*addr))
+        .collect::<Vec<_>>();
+    let mut iovecs = datagrams
+        .iter()
+        .map(|(_, bytes)| libc::iovec {
+            iov_base: bytes.as_ptr() as *mut libc::c_void,
+            iov_len: bytes.len(),
+        })
+        .collect::<Vec<_>>();
+    let mut headers = iovecs
+        .iter_mut()
+        .zip(&addrs)
+        .map(|(iov, addr)| header(iov, addr.as_ptr() as *mut libc::c_void, addr.len()))
+        .collect::<Vec<_>>();
+
+    // SAFETY: every header points to a datagram and an address which outlive
+    // the call, the kernel only reads them
+    let sent = unsafe {
+        libc::sendmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            headers.len() as _,
+            libc::MSG_DONTWAIT as _,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+fn header(
+    iov: *mut libc::iovec,
+    name: *mut libc::c_void,
+    namelen: libc::socklen_t,
+) -> libc::mmsghdr {
+    // SAFETY: an all zero header is valid, its padding fields differ between
+    // the C libraries
+    let mut msg_hdr: libc::msghdr = unsafe { mem::zeroed() };
+    msg_hdr.msg_name = name;
+    msg_hdr.msg_namelen = namelen;
+    msg_hdr.msg_iov = iov;
+    msg_hdr.msg_iovlen = 1;
+    libc::mmsghdr {
+        msg_hdr,
+        msg_len: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagrams_are_sent_and_received_in_batches() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = receiver.local_addr().unwrap();
+
+        let datagrams = [
+            (to, b"first".to_vec()),
+            (to, b"second".to_vec()),
+            (to, vec![0; RECV_BUF_SIZE + 1]),
+        ];
+        assert_eq!(send_batch(sender.as_raw_fd(), &datagrams).unwrap(), 3);
+
+        // The oversized datagram is dropped
+        let mut bufs = vec![vec![0; RECV_BUF_SIZE]; 4];
+        let received = recv_batch(receiver.as_raw_fd(), &mut bufs).unwrap();
+        let from = sender.local_addr().unwrap();
+        assert_eq!(
+            received,
+            [(b"first".to_vec(), from), (b"second".to_vec(), from)]
+        );
+
+        let e = recv_batch(receiver.as_raw_fd(), &mut bufs).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[tokio::test]
+    async fn a_datagram_failing_to_be_sent_is_skipped() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.set_nonblocking(true).unwrap();
+        let sender = AsyncFd::new(sender).unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = receiver.local_addr().unwrap();
+
+        // An IPv4 socket cannot send to an IPv6 address
+        let datagrams = [
+            ("[::1]:53".parse().unwrap(), b"first".to_vec()),
+            (to, b"second".to_vec()),
+        ];
+        send(&sender, &datagrams).await.unwrap();
+
+        let mut buf = [0; 16];
+        let (len, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"second");
+    }
+}
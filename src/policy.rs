@@ -0,0 +1,289 @@
+//! The update policies of the domains, in the grammar of the BIND
+//! `update-policy` statement.
+//!
+//! A rule is written `grant|deny <identity> <nametype> [<name>] [<types>]`,
+//! the rules of a domain are checked in order for every record of an update
+//! and the first rule matching the signer, the owner and the type of the
+//! record grants or denies it. A record no rule matches is denied.
+//!
+//! The identity is the name of the signing key, TSIG or SIG(0), and may start
+//! with a `*.` label to match the keys below a name. The nametypes are:
+//!
+//! - `name`: the owner is the name,
+//! - `subdomain`: the owner is the name or below it,
+//! - `wildcard`: the owner matches the wildcard name, e.g. `*.example.fr`,
+//! - `zonesub`: the owner is the zone or below it, the rule has no name,
+//! - `self`: the owner is the identity, the name is ignored,
+//! - `selfsub`: the owner is the identity or below it, the name is ignored,
+//! - `selfwild`: the owner is below the identity, the name is ignored.
+//!
+//! The types are record type mnemonics, every type if there are none or for
+//! `ANY`.
+
+use std::str::FromStr;
+
+use domain::base::Rtype;
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NameType {
+    Name,
+    Subdomain,
+    Wildcard,
+    Zonesub,
+    SelfName,
+    Selfsub,
+    Selfwild,
+}
+
+impl NameType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NameType::Name => "name",
+            NameType::Subdomain => "subdomain",
+            NameType::Wildcard => "wildcard",
+            NameType::Zonesub => "zonesub",
+            NameType::SelfName => "self",
+            NameType::Selfsub => "selfsub",
+            NameType::Selfwild => "selfwild",
+        }
+    }
+}
+
+impl FromStr for NameType {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "name" => Ok(NameType::Name),
+            "subdomain" => Ok(NameType::Subdomain),
+            "wildcard" => Ok(NameType::Wildcard),
+            "zonesub" => Ok(NameType::Zonesub),
+            "self" => Ok(NameType::SelfName),
+            "selfsub" => Ok(NameType::Selfsub),
+            "selfwild" => Ok(NameType::Selfwild),
+            _ => Err(error!(Policy => "unknown nametype {}", s)),
+        }
+    }
+}
+
+/// A rule of the update policy of a domain.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct UpdateRule {
+    grant: bool,
+    identity: String,
+    name_type: NameType,
+    /// The name of the rule, `None` for `zonesub`
+    name: Option<String>,
+    /// The types of the rule, every type if empty
+    types: Vec<Rtype>,
+}
+
+impl UpdateRule {
+    /// Returns whether the rule applies to the `rtype` record of `owner`
+    /// updated in `zone` by the key `signer`.
+    fn matches(&self, signer: &str, zone: &str, owner: &str, rtype: Rtype) -> bool {
+        let signer = normalize(signer);
+        let identity_matches = match self.identity.strip_prefix("*.") {
+            Some(parent) => is_below(&signer, parent),
+            None => signer == self.identity || self.identity == "*",
+        };
+        if !identity_matches || !(self.types.is_empty() || self.types.contains(&rtype)) {
+            return false;
+        }
+
+        let owner = normalize(owner);
+        let name = self.name.as_deref().unwrap_or_default();
+        match self.name_type {
+            NameType::Name => owner == name,
+            NameType::Subdomain => owner == name || is_below(&owner, name),
+            NameType::Wildcard => name
+                .strip_prefix("*.")
+                .is_some_and(|parent| is_below(&owner, parent)),
+            NameType::Zonesub => {
+                let zone = normalize(zone);
+                owner == zone || is_below(&owner, &zone)
+            }
+            NameType::SelfName => owner == signer,
+            NameType::Selfsub => owner == signer || is_below(&owner, &signer),
+            NameType::Selfwild => is_below(&owner, &signer),
+        }
+    }
+}
+
+/// Evaluates the `policy` for the `rtype` record of `owner` updated in `zone`
+/// by the key `signer`: the first matching rule decides and a record no rule
+/// matches is denied.
+pub fn allows(policy: &[UpdateRule], signer: &str, zone: &str, owner: &str, rtype: Rtype) -> bool {
+    policy
+        .iter()
+        .find(|rule| rule.matches(signer, zone, owner, rtype))
+        .is_some_and(|rule| rule.grant)
+}
+
+/// Lowercases `name` and strips its trailing dot.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Returns whether the normalized `name` is strictly below `parent`.
+fn is_below(name: &str, parent: &str) -> bool {
+    match parent {
+        "" => !name.is_empty(),
+        parent => name
+            .strip_suffix(parent)
+            .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.')),
+    }
+}
+
+impl std::fmt::Display for UpdateRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = if self.grant { "grant" } else { "deny" };
+        write!(
+            f,
+            "{} {} {}",
+            action,
+            self.identity,
+            self.name_type.as_str()
+        )?;
+        if let Some(name) = &self.name {
+            write!(f, " {}", name)?;
+        }
+        for rtype in &self.types {
+            write!(f, " {}", rtype)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<UpdateRule> for String {
+    fn from(value: UpdateRule) -> Self {
+        value.to_string()
+    }
+}
+
+impl FromStr for UpdateRule {
+    type Err = error::Error;
+
+    /// Parses `grant|deny <identity> <nametype> [<name>] [<types>]`, the name
+    /// is required by every nametype but `zonesub`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.trim().trim_end_matches(';').split_whitespace();
+        let mut next = |field: &str| {
+            tokens
+                .next()
+                .ok_or_else(|| error!(Policy => "missing {} in rule {}", field, s))
+        };
+
+        let grant = match next("action")?.to_ascii_lowercase().as_str() {
+            "grant" => true,
+            "deny" => false,
+            action => return Err(error!(Policy => "unknown action {} in rule {}", action, s)),
+        };
+        let identity = normalize(next("identity")?);
+        let name_type = NameType::from_str(next("nametype")?)?;
+        let name = match name_type {
+            NameType::Zonesub => None,
+            _ => Some(normalize(next("name")?)),
+        };
+        if name_type == NameType::Wildcard && !name.as_deref().is_some_and(|n| n.starts_with("*."))
+        {
+            return Err(error!(Policy => "the wildcard name of rule {} does not start with *.", s));
+        }
+
+        let mut types = Vec::new();
+        for token in tokens {
+            let rtype = Rtype::from_str(token)
+                .map_err(|_| error!(Policy => "unknown type {} in rule {}", token, s))?;
+            if rtype == Rtype::ANY {
+                types.clear();
+                break;
+            }
+            types.push(rtype);
+        }
+
+        Ok(Self {
+            grant,
+            identity,
+            name_type,
+            name,
+            types,
+        })
+    }
+}
+
+impl TryFrom<String> for UpdateRule {
+    type Error = error::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "_acme-challenge.example.fr.";
+
+    fn policy(rules: &[&str]) -> Vec<UpdateRule> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn rules_are_parsed_and_written() {
+        let rule =
+            UpdateRule::from_str("grant Key1. subdomain _ACME-challenge.example.fr. TXT;").unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "grant key1 subdomain _acme-challenge.example.fr TXT"
+        );
+        assert_eq!(
+            UpdateRule::from_str("deny * zonesub ANY")
+                .unwrap()
+                .to_string(),
+            "deny * zonesub"
+        );
+
+        assert!(UpdateRule::from_str("allow key1 zonesub").is_err());
+        assert!(UpdateRule::from_str("grant key1 subdomain").is_err());
+        assert!(UpdateRule::from_str("grant key1 nametype example.fr").is_err());
+        assert!(UpdateRule::from_str("grant key1 wildcard example.fr").is_err());
+        assert!(UpdateRule::from_str("grant key1 zonesub NOTATYPE").is_err());
+    }
+
+    #[test]
+    fn the_first_matching_rule_decides() {
+        let policy = policy(&[
+            "deny key1 name _acme-challenge.example.fr TXT",
+            "grant key1 zonesub TXT",
+            "grant *.clients.example.fr selfsub .",
+            "grant key2 wildcard *.sub._acme-challenge.example.fr",
+        ]);
+        let granted = |signer, owner| allows(&policy, signer, ZONE, owner, Rtype::TXT);
+
+        assert!(!granted("key1", "_acme-challenge.example.fr."));
+        assert!(granted("key1.", "www._acme-challenge.example.fr"));
+        assert!(!granted("key1", "_acme-challenge.example.com"));
+        assert!(!granted("key1", "x_acme-challenge.example.fr"));
+        assert!(!allows(
+            &policy,
+            "key1",
+            ZONE,
+            "www._acme-challenge.example.fr",
+            Rtype::A
+        ));
+
+        assert!(granted("a.clients.example.fr", "a.clients.example.fr"));
+        assert!(granted("a.clients.example.fr", "x.a.clients.example.fr"));
+        assert!(!granted("a.clients.example.fr", "b.clients.example.fr"));
+        assert!(!granted("clients.example.fr", "clients.example.fr"));
+
+        assert!(granted("key2", "a.sub._acme-challenge.example.fr"));
+        assert!(!granted("key2", "sub._acme-challenge.example.fr"));
+        assert!(!granted("key3", "www._acme-challenge.example.fr"));
+    }
+}
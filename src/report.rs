@@ -0,0 +1,69 @@
+//! The periodic reports of the metrics.
+//!
+//! The reports are sent to the sinks of the `metrics` configuration, which
+//! can be replaced at runtime through the admin API.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::config::{MetricsConfig, MetricsSink, MetricsVerbosity};
+use crate::service::middleware::{Stats, Summary};
+
+pub struct MetricsReporter {
+    stats: Arc<RwLock<Stats>>,
+    config: RwLock<MetricsConfig>,
+    /// The last report, kept for the admin sink
+    last_report: Mutex<Option<String>>,
+}
+
+impl MetricsReporter {
+    pub fn new(stats: Arc<RwLock<Stats>>, config: MetricsConfig) -> Self {
+        Self {
+            stats,
+            config: RwLock::new(config),
+            last_report: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> MetricsConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replaces the configuration, applied from the next report.
+    pub fn set_config(&self, config: MetricsConfig) {
+        log::info!(target: "admin", "metrics configuration updated: {:?}", config);
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Returns the last report, `None` if the admin sink is disabled.
+    pub fn last_report(&self) -> Option<String> {
+        if !self.config().has_sink(MetricsSink::Admin) {
+            return None;
+        }
+        Some(self.last_report.lock().unwrap().clone().unwrap_or_default())
+    }
+
+    /// Reports the metrics at the configured interval, forever.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(self.config().interval()).await;
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        let config = self.config();
+        let report = {
+            let stats = self.stats.read().unwrap();
+            match config.verbosity() {
+                MetricsVerbosity::Summary => Summary(&stats).to_string(),
+                MetricsVerbosity::Full => stats.to_string(),
+            }
+        };
+
+        if config.has_sink(MetricsSink::Log) {
+            log::info!(target: "metrics", "metrics report: {}", report);
+        }
+        let mut last_report = self.last_report.lock().unwrap();
+        *last_report = config.has_sink(MetricsSink::Admin).then_some(report);
+    }
+}
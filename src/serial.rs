@@ -0,0 +1,175 @@
+//! SOA serial handling.
+//!
+//! Serials are compared and incremented using the sequence space arithmetic
+//! of [RFC 1982](https://www.rfc-editor.org/rfc/rfc1982) so that they keep
+//! working once they wrap around 2^32.
+
+use std::cmp::Ordering;
+use std::time::SystemTime;
+
+use domain::base::{Rtype, Serial};
+use domain::rdata::{Soa, ZoneRecordData};
+use serde::Deserialize;
+
+use crate::store::ZoneRecords;
+
+/// The largest value which can be added to a serial (2^31 - 1).
+pub const MAX_INCREMENT: u32 = 0x7fff_ffff;
+
+/// Adds `n` to `serial`, returns `None` if `n` is larger than [`MAX_INCREMENT`].
+pub fn serial_add(serial: u32, n: u32) -> Option<u32> {
+    if n > MAX_INCREMENT {
+        return None;
+    }
+    Some(serial.wrapping_add(n))
+}
+
+/// Compares two serials, returns `None` when the comparison is undefined (the
+/// serials are exactly 2^31 apart).
+pub fn serial_cmp(s1: u32, s2: u32) -> Option<Ordering> {
+    match s2.wrapping_sub(s1) {
+        0 => Some(Ordering::Equal),
+        d if d < 0x8000_0000 => Some(Ordering::Less),
+        d if d > 0x8000_0000 => Some(Ordering::Greater),
+        _ => None,
+    }
+}
+
+/// Whether `candidate` is strictly more recent than `current`.
+pub fn is_newer(candidate: u32, current: u32) -> bool {
+    serial_cmp(candidate, current) == Some(Ordering::Greater)
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SerialPolicy {
+    /// Increments the serial by one on every change
+    #[default]
+    Increment,
+    /// Uses the YYYYMMDDnn format, nn being the number of the change in the day
+    Date,
+}
+
+impl SerialPolicy {
+    pub fn next(self, serial: u32, now: SystemTime) -> u32 {
+        let incremented = serial_add(serial, 1).expect("1 is a valid serial increment");
+
+        match self {
+            SerialPolicy::Increment => incremented,
+            SerialPolicy::Date => {
+                let (year, month, day) = crate::time::civil_date(crate::time::unix_secs(now));
+                let today = year * 1_000_000 + month * 10_000 + day * 100;
+
+                if is_newer(today, serial) {
+                    today
+                } else {
+                    incremented
+                }
+            }
+        }
+    }
+}
+
+/// Returns the serial of the SOA record found in `records`.
+pub fn soa_serial(records: &ZoneRecords) -> Option<u32> {
+    records
+        .iter()
+        .filter(|((rtype, _), _)| *rtype == Rtype::SOA)
+        .flat_map(|(_, data)| data)
+        .find_map(|data| match data {
+            ZoneRecordData::Soa(soa) => Some(soa.serial().into_int()),
+            _ => None,
+        })
+}
+
+/// Bumps the serial of the SOA records found in `records` according to `policy`.
+pub fn bump_soa_serial(records: &mut ZoneRecords, policy: SerialPolicy) {
+    let now = SystemTime::now();
+
+    records
+        .iter_mut()
+        .filter(|((rtype, _), _)| *rtype == Rtype::SOA)
+        .flat_map(|(_, data)| data.iter_mut())
+        .for_each(|data| {
+            if let ZoneRecordData::Soa(soa) = data {
+                let serial = Serial::from(policy.next(soa.serial().into_int(), now));
+                log::debug!(target: "serial", "bumping soa serial from {} to {}", soa.serial(), serial);
+
+                *soa = Soa::new(
+                    soa.mname().clone(),
+                    soa.rname().clone(),
+                    serial,
+                    soa.refresh(),
+                    soa.retry(),
+                    soa.expire(),
+                    soa.minimum(),
+                );
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    // 2024-08-30T12:36:00Z
+    const NOW: u64 = 1725021360;
+
+    #[test]
+    fn add_wraps_around() {
+        assert_eq!(serial_add(u32::MAX, 1), Some(0));
+        assert_eq!(serial_add(u32::MAX - 4, 10), Some(5));
+        assert_eq!(serial_add(1, MAX_INCREMENT), Some(0x8000_0000));
+        assert_eq!(serial_add(1, MAX_INCREMENT + 1), None);
+    }
+
+    #[test]
+    fn cmp_around_the_wrap() {
+        assert_eq!(serial_cmp(42, 42), Some(Ordering::Equal));
+        assert_eq!(serial_cmp(1, 2), Some(Ordering::Less));
+        assert_eq!(serial_cmp(u32::MAX, 0), Some(Ordering::Less));
+        assert_eq!(serial_cmp(0, u32::MAX), Some(Ordering::Greater));
+        assert_eq!(serial_cmp(0, 0x7fff_ffff), Some(Ordering::Less));
+        assert_eq!(serial_cmp(0, 0x8000_0001), Some(Ordering::Greater));
+        assert_eq!(serial_cmp(0, 0x8000_0000), None);
+        assert_eq!(serial_cmp(0x8000_0000, 0), None);
+    }
+
+    #[test]
+    fn newer_after_wrap() {
+        assert!(is_newer(0, u32::MAX));
+        assert!(is_newer(5, 0xffff_fff0));
+        assert!(!is_newer(0xffff_fff0, 5));
+        assert!(!is_newer(7, 7));
+        assert!(!is_newer(0x8000_0000, 0));
+    }
+
+    #[test]
+    fn increment_policy() {
+        let now = UNIX_EPOCH + Duration::from_secs(NOW);
+
+        assert_eq!(SerialPolicy::Increment.next(1, now), 2);
+        assert_eq!(SerialPolicy::Increment.next(u32::MAX, now), 0);
+        assert!(is_newer(
+            SerialPolicy::Increment.next(u32::MAX, now),
+            u32::MAX
+        ));
+    }
+
+    #[test]
+    fn date_policy() {
+        let now = UNIX_EPOCH + Duration::from_secs(NOW);
+
+        // A unix timestamp serial jumps to the date format
+        assert_eq!(SerialPolicy::Date.next(1722353587, now), 2024083000);
+        // Changes in the same day are numbered
+        assert_eq!(SerialPolicy::Date.next(2024083000, now), 2024083001);
+        // Changes of a previous day restart from the current day
+        assert_eq!(SerialPolicy::Date.next(2024082917, now), 2024083000);
+        // A serial ahead of the current date is never decreased
+        assert_eq!(SerialPolicy::Date.next(2024083099, now), 2024083100);
+        assert_eq!(SerialPolicy::Date.next(2025010100, now), 2025010101);
+    }
+}
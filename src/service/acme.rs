@@ -0,0 +1,149 @@
+//! End-to-end simulation of an ACME DNS-01 challenge.
+//!
+//! The servers are bound on loopback sockets and driven the way an ACME client
+//! and a CA would: the client publishes the token with a signed update, the CA
+//! resolves it over UDP and TCP, then the client removes it.
+//!
+//! The test binds sockets, it is only run with the `acme-e2e` feature:
+//!
+//!   cargo test --features acme-e2e
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use domain::base::iana::{Class, Opcode, Rcode};
+use domain::base::{Message, MessageBuilder, Name, Rtype, Ttl};
+use domain::net::server::buf::VecBufSource;
+use domain::net::server::dgram::DgramServer;
+use domain::net::server::stream::StreamServer;
+use domain::rdata::tsig::Time48;
+use domain::rdata::Txt;
+use domain::tsig::{ClientTransaction, Key};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+use super::middleware::Stats;
+use super::testing::{dnsr, query, register_key, texts, ZONE};
+use super::{middleware_chain, Dnsr};
+
+/// A key authorization digest as computed by the ACME client.
+const TOKEN: &str = "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0";
+
+/// The longest wait for a response.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The addresses of the running servers.
+struct Servers {
+    udp: SocketAddr,
+    tcp: SocketAddr,
+}
+
+/// Serves the zones of the configuration on loopback UDP and TCP sockets.
+async fn serve() -> (Arc<Dnsr>, Servers) {
+    let dnsr = dnsr();
+    let svc = middleware_chain(dnsr.clone(), Stats::new_shared());
+
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let servers = Servers {
+        udp: udp.local_addr().unwrap(),
+        tcp: tcp.local_addr().unwrap(),
+    };
+
+    let udp_srv = DgramServer::new(Arc::new(udp), VecBufSource, svc.clone());
+    tokio::spawn(async move { udp_srv.run().await });
+    let tcp_srv = StreamServer::new(tcp, VecBufSource, svc);
+    tokio::spawn(async move { tcp_srv.run().await });
+
+    (dnsr, servers)
+}
+
+async fn exchange_udp(addr: SocketAddr, message: &Message<Vec<u8>>) -> Message<Vec<u8>> {
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    sock.send_to(message.as_slice(), addr).await.unwrap();
+
+    let mut buf = vec![0; 65535];
+    let (len, _) = timeout(TIMEOUT, sock.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    buf.truncate(len);
+    Message::from_octets(buf).unwrap()
+}
+
+/// Sends `message` over a new connection, framed as in RFC 1035 section 4.2.2.
+async fn exchange_tcp(addr: SocketAddr, message: &Message<Vec<u8>>) -> Message<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let len = u16::try_from(message.as_slice().len()).unwrap();
+    stream.write_all(&len.to_be_bytes()).await.unwrap();
+    stream.write_all(message.as_slice()).await.unwrap();
+
+    let len = timeout(TIMEOUT, stream.read_u16()).await.unwrap().unwrap();
+    let mut buf = vec![0; usize::from(len)];
+    stream.read_exact(&mut buf).await.unwrap();
+    Message::from_octets(buf).unwrap()
+}
+
+/// Builds the signed update publishing the token, or removing it if `add` is
+/// unset, as the RFC 2136 plugins of the ACME clients do.
+fn update(key: &Key, add: bool) -> (Message<Vec<u8>>, ClientTransaction<&Key>) {
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_id(0xacde);
+    builder.header_mut().set_opcode(Opcode::UPDATE);
+
+    let name = Name::<Vec<u8>>::from_str(ZONE).unwrap();
+    let mut question = builder.question();
+    question.push((name.clone(), Rtype::SOA)).unwrap();
+
+    let mut authority = question.authority();
+    let (class, ttl) = if add {
+        (Class::IN, Ttl::from_secs(60))
+    } else {
+        (Class::NONE, Ttl::from_secs(0))
+    };
+    let txt = Txt::<Vec<u8>>::build_from_slice(TOKEN.as_bytes()).unwrap();
+    authority.push((name, class, ttl, txt)).unwrap();
+
+    let mut additional = authority.additional();
+    let transaction = ClientTransaction::request(key, &mut additional, Time48::now()).unwrap();
+    (additional.into_message(), transaction)
+}
+
+/// Returns the TXT records of the answer of `response`, checking that it is
+/// an authoritative answer as the CA expects.
+fn challenge_texts(response: &Message<Vec<u8>>) -> Vec<String> {
+    assert_eq!(response.header().rcode(), Rcode::NOERROR);
+    assert!(response.header().aa());
+    assert!(!response.header().tc());
+    texts(response)
+}
+
+#[tokio::test]
+async fn dns01_challenge_is_published_resolved_and_cleaned_up() {
+    let (dnsr, servers) = serve().await;
+    let key = register_key(&dnsr, "key1");
+
+    // The client publishes the token and checks the signature of the response
+    let (request, transaction) = update(&key, true);
+    let mut response = exchange_tcp(servers.tcp, &request).await;
+    assert_eq!(response.header().rcode(), Rcode::NOERROR);
+    transaction.answer(&mut response, Time48::now()).unwrap();
+
+    // The CA resolves the challenge over both transports
+    let response = exchange_udp(servers.udp, &query(ZONE, Rtype::TXT)).await;
+    assert_eq!(challenge_texts(&response), [TOKEN]);
+    let response = exchange_tcp(servers.tcp, &query(ZONE, Rtype::TXT)).await;
+    assert_eq!(challenge_texts(&response), [TOKEN]);
+
+    // The client cleans up once the order is validated
+    let (request, transaction) = update(&key, false);
+    let mut response = exchange_udp(servers.udp, &request).await;
+    assert_eq!(response.header().rcode(), Rcode::NOERROR);
+    transaction.answer(&mut response, Time48::now()).unwrap();
+
+    let response = exchange_udp(servers.udp, &query(ZONE, Rtype::TXT)).await;
+    assert!(challenge_texts(&response).is_empty());
+}
@@ -0,0 +1,255 @@
+//! In-memory capture of the last exchanges with the clients.
+//!
+//! The raw messages of the last exchanges are kept in a ring buffer which is
+//! dumped in the pcap format through the admin API. As only the DNS messages
+//! are captured, every message is written as a UDP datagram between the client
+//! and port 53 of the server, TCP messages included.
+
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The pcap link type of raw IPv4 or IPv6 packets.
+const LINKTYPE_RAW: u32 = 101;
+const SERVER_PORT: u16 = 53;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const UDP_PROTOCOL: u8 = 17;
+
+#[derive(Debug)]
+pub struct WireCapture {
+    size: usize,
+    ring: Mutex<Ring>,
+}
+
+#[derive(Debug, Default)]
+struct Ring {
+    next_id: u64,
+    exchanges: VecDeque<Exchange>,
+}
+
+#[derive(Debug)]
+struct Exchange {
+    id: u64,
+    client_addr: SocketAddr,
+    query: Packet,
+    responses: Vec<Packet>,
+}
+
+#[derive(Debug)]
+struct Packet {
+    time: SystemTime,
+    message: Vec<u8>,
+}
+
+impl Packet {
+    fn now(message: &[u8]) -> Self {
+        Self {
+            time: SystemTime::now(),
+            message: message.to_vec(),
+        }
+    }
+}
+
+impl WireCapture {
+    /// Creates a capture keeping the last `size` exchanges.
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            ring: Mutex::default(),
+        }
+    }
+
+    /// Records a query received from `client_addr` and returns the id of its
+    /// exchange, the oldest exchange is dropped when the capture is full.
+    pub fn record_query(&self, client_addr: SocketAddr, message: &[u8]) -> u64 {
+        let mut ring = self.ring.lock().unwrap();
+        let id = ring.next_id;
+        ring.next_id += 1;
+
+        if ring.exchanges.len() >= self.size {
+            ring.exchanges.pop_front();
+        }
+        ring.exchanges.push_back(Exchange {
+            id,
+            client_addr,
+            query: Packet::now(message),
+            responses: Vec::new(),
+        });
+        id
+    }
+
+    /// Records a response of the exchange `id`, unless it was already dropped.
+    pub fn record_response(&self, id: u64, message: &[u8]) {
+        let mut ring = self.ring.lock().unwrap();
+        if let Some(exchange) = ring.exchanges.iter_mut().rev().find(|e| e.id == id) {
+            exchange.responses.push(Packet::now(message));
+        }
+    }
+
+    /// Dumps the captured messages in the pcap format.
+    pub fn to_pcap(&self) -> Vec<u8> {
+        let mut pcap = Vec::new();
+        pcap.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+        pcap.extend_from_slice(&2u16.to_le_bytes());
+        pcap.extend_from_slice(&4u16.to_le_bytes());
+        pcap.extend_from_slice(&0i32.to_le_bytes());
+        pcap.extend_from_slice(&0u32.to_le_bytes());
+        pcap.extend_from_slice(&(u16::MAX as u32).to_le_bytes());
+        pcap.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+
+        let ring = self.ring.lock().unwrap();
+        for exchange in ring.exchanges.iter() {
+            let client = exchange.client_addr;
+            let server = match client.ip() {
+                IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), SERVER_PORT),
+                IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), SERVER_PORT),
+            };
+
+            write_record(&mut pcap, &exchange.query, client, server);
+            for response in exchange.responses.iter() {
+                write_record(&mut pcap, response, server, client);
+            }
+        }
+
+        pcap
+    }
+}
+
+fn write_record(pcap: &mut Vec<u8>, packet: &Packet, src: SocketAddr, dst: SocketAddr) {
+    let datagram = ip_datagram(&packet.message, src, dst);
+    let time = packet.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    pcap.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+    pcap.extend_from_slice(&time.subsec_micros().to_le_bytes());
+    pcap.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+    pcap.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+    pcap.extend_from_slice(&datagram);
+}
+
+/// Wraps `message` in the UDP and IP headers of a datagram from `src` to `dst`,
+/// a message too large for a single datagram is truncated.
+fn ip_datagram(message: &[u8], src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let header_len = match src.ip() {
+        IpAddr::V4(_) => IPV4_HEADER_LEN,
+        IpAddr::V6(_) => IPV6_HEADER_LEN,
+    };
+    let message = &message[..message
+        .len()
+        .min(u16::MAX as usize - header_len - UDP_HEADER_LEN)];
+    let udp_len = (UDP_HEADER_LEN + message.len()) as u16;
+
+    let mut datagram = Vec::with_capacity(header_len + udp_len as usize);
+    let pseudo_header = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = [0u8; IPV4_HEADER_LEN];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&(IPV4_HEADER_LEN as u16 + udp_len).to_be_bytes());
+            // Don't fragment
+            header[6] = 0x40;
+            header[8] = 64;
+            header[9] = UDP_PROTOCOL;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let checksum = checksum(&[&header]);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            datagram.extend_from_slice(&header);
+
+            [
+                &src.octets()[..],
+                &dst.octets(),
+                &[0, UDP_PROTOCOL],
+                &udp_len.to_be_bytes(),
+            ]
+            .concat()
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut header = [0u8; IPV6_HEADER_LEN];
+            header[0] = 0x60;
+            header[4..6].copy_from_slice(&udp_len.to_be_bytes());
+            header[6] = UDP_PROTOCOL;
+            header[7] = 64;
+            header[8..24].copy_from_slice(&src.octets());
+            header[24..40].copy_from_slice(&dst.octets());
+            datagram.extend_from_slice(&header);
+
+            [
+                &src.octets()[..],
+                &dst.octets(),
+                &(udp_len as u32).to_be_bytes(),
+                &[0, 0, 0, UDP_PROTOCOL],
+            ]
+            .concat()
+        }
+        _ => unreachable!("the client and server addresses are of the same family"),
+    };
+
+    let mut udp_header = [0u8; UDP_HEADER_LEN];
+    udp_header[0..2].copy_from_slice(&src.port().to_be_bytes());
+    udp_header[2..4].copy_from_slice(&dst.port().to_be_bytes());
+    udp_header[4..6].copy_from_slice(&udp_len.to_be_bytes());
+    let checksum = match checksum(&[&pseudo_header, &udp_header, message]) {
+        // A zero checksum is transmitted as all ones
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp_header[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    datagram.extend_from_slice(&udp_header);
+    datagram.extend_from_slice(message);
+    datagram
+}
+
+/// Computes the internet checksum of the concatenation of `chunks`.
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut bytes = chunks.iter().flat_map(|chunk| chunk.iter());
+    while let Some(high) = bytes.next() {
+        let low = bytes.next().copied().unwrap_or(0);
+        sum += u16::from_be_bytes([*high, low]) as u32;
+    }
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_exchanges_are_dropped() {
+        let capture = WireCapture::new(2);
+        let client = "192.0.2.1:5353".parse().unwrap();
+
+        let first = capture.record_query(client, b"first");
+        let second = capture.record_query(client, b"second");
+        capture.record_response(second, b"second response");
+        capture.record_query(client, b"third");
+        capture.record_response(first, b"first response");
+
+        let ring = capture.ring.lock().unwrap();
+        let queries = ring
+            .exchanges
+            .iter()
+            .map(|e| (e.query.message.as_slice(), e.responses.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(queries, [(&b"second"[..], 1), (&b"third"[..], 0)]);
+    }
+
+    #[test]
+    fn datagrams_have_valid_checksums() {
+        let src = "192.0.2.1:5353".parse().unwrap();
+        let dst = "192.0.2.2:53".parse().unwrap();
+        let datagram = ip_datagram(b"odd message", src, dst);
+
+        assert_eq!(datagram.len(), IPV4_HEADER_LEN + UDP_HEADER_LEN + 11);
+        assert_eq!(checksum(&[&datagram[..IPV4_HEADER_LEN]]), 0);
+        let pseudo_header = [192, 0, 2, 1, 192, 0, 2, 2, 0, UDP_PROTOCOL, 0, 19];
+        assert_eq!(checksum(&[&pseudo_header, &datagram[IPV4_HEADER_LEN..]]), 0);
+    }
+}
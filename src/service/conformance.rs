@@ -0,0 +1,731 @@
+//! Protocol conformance checks of the behaviors claimed by dnsr.
+//!
+//! Every test crafts a wire message, runs it through the same middleware chain
+//! as the server and checks the responses against the relevant RFC:
+//!
+//! - [RFC 1034](https://www.rfc-editor.org/rfc/rfc1034) and
+//!   [RFC 1035](https://www.rfc-editor.org/rfc/rfc1035) for the header flags
+//!   and the rcode selection,
+//! - [RFC 2308](https://www.rfc-editor.org/rfc/rfc2308) for the SOA of the
+//!   negative answers,
+//! - [RFC 5936](https://www.rfc-editor.org/rfc/rfc5936) for the AXFR framing,
+//! - [RFC 2136](https://www.rfc-editor.org/rfc/rfc2136) for the update semantics,
+//! - [RFC 6891](https://www.rfc-editor.org/rfc/rfc6891) for the EDNS payload
+//!   size and the truncation of the UDP responses,
+//! - [RFC 8020](https://www.rfc-editor.org/rfc/rfc8020) for the NODATA
+//!   answers of the names above a zone,
+//! - [RFC 8482](https://www.rfc-editor.org/rfc/rfc8482) for the minimal
+//!   answers to ANY queries,
+//! - [RFC 8945](https://www.rfc-editor.org/rfc/rfc8945) for the TSIG errors
+//!   of the signed requests.
+
+use std::str::FromStr;
+
+use bytes::Bytes;
+use domain::base::iana::{Class, Opcode, Rcode, TsigRcode};
+use domain::base::{Message, MessageBuilder, Name, ParsedName, Rtype, Ttl};
+use domain::rdata::tsig::{Time48, Tsig};
+use domain::rdata::{Soa, Txt};
+use domain::tsig::{Algorithm, ClientTransaction, Key, KeyName};
+use domain::zonefile::inplace;
+use domain::zonetree::Zone;
+use futures::executor::block_on;
+
+use super::ingest::Transport;
+use super::testing::{
+    answer_types, call, dnsr, dnsr_from, query, register_key, texts, CONFIG, ZONE,
+};
+use crate::store::ZoneRecords;
+
+/// Builds a query with an OPT record advertising `udp_payload_size`.
+fn edns_query(qname: &str, qtype: Rtype, udp_payload_size: u16) -> Message<Vec<u8>> {
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_id(0xbeef);
+
+    let mut question = builder.question();
+    question
+        .push((Name::<Vec<u8>>::from_str(qname).unwrap(), qtype))
+        .unwrap();
+
+    let mut additional = question.additional();
+    additional
+        .opt(|opt| {
+            opt.set_udp_payload_size(udp_payload_size);
+            Ok(())
+        })
+        .unwrap();
+    additional.into_message()
+}
+
+/// Builds a query like `query`, signed by the client with `key`.
+fn signed_query(qname: &str, qtype: Rtype, key: &Key) -> Message<Vec<u8>> {
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_id(0xbeef);
+
+    let mut question = builder.question();
+    question
+        .push((Name::<Vec<u8>>::from_str(qname).unwrap(), qtype))
+        .unwrap();
+
+    let mut additional = question.additional();
+    ClientTransaction::request(key, &mut additional, Time48::now()).unwrap();
+    additional.into_message()
+}
+
+/// Builds an update of the TXT records of `zone`, records of class NONE are deletions.
+fn update(zone: &str, records: &[(Class, &str)], key: Option<&Key>) -> Message<Vec<u8>> {
+    update_signed_at(zone, records, key, Time48::now())
+}
+
+/// Builds an update like `update`, signed at `time` by the client.
+fn update_signed_at(
+    zone: &str,
+    records: &[(Class, &str)],
+    key: Option<&Key>,
+    time: Time48,
+) -> Message<Vec<u8>> {
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_id(0xcafe);
+    builder.header_mut().set_opcode(Opcode::UPDATE);
+
+    let name = Name::<Vec<u8>>::from_str(zone).unwrap();
+    let mut question = builder.question();
+    question.push((name.clone(), Rtype::SOA)).unwrap();
+
+    let mut authority = question.authority();
+    for (class, text) in records {
+        let ttl = match *class {
+            Class::NONE => Ttl::from_secs(0),
+            _ => Ttl::from_secs(60),
+        };
+        let txt = Txt::<Vec<u8>>::build_from_slice(text.as_bytes()).unwrap();
+        authority.push((name.clone(), *class, ttl, txt)).unwrap();
+    }
+
+    let mut additional = authority.additional();
+    if let Some(key) = key {
+        ClientTransaction::request(key, &mut additional, time).unwrap();
+    }
+    additional.into_message()
+}
+
+#[test]
+fn response_header_mirrors_the_query() {
+    let dnsr = dnsr();
+    let request = query(ZONE, Rtype::SOA);
+
+    let responses = call(&dnsr, request.clone(), Transport::Udp);
+    assert_eq!(responses.len(), 1);
+
+    let header = responses[0].header();
+    assert_eq!(header.id(), request.header().id());
+    assert!(header.qr());
+    assert_eq!(header.opcode(), Opcode::QUERY);
+    assert!(header.rd());
+    assert!(!header.tc());
+    assert_eq!(
+        responses[0].sole_question().unwrap(),
+        request.sole_question().unwrap()
+    );
+}
+
+#[test]
+fn apex_soa_is_answered() {
+    let dnsr = dnsr();
+
+    let responses = call(&dnsr, query(ZONE, Rtype::SOA), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::SOA]);
+}
+
+#[test]
+fn missing_type_is_an_empty_answer() {
+    let dnsr = dnsr();
+
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    assert!(answer_types(&responses[0]).is_empty());
+}
+
+#[test]
+fn negative_answers_carry_the_zone_soa() {
+    let dnsr = dnsr();
+
+    let negatives = [
+        (ZONE, Rcode::NOERROR),
+        ("token._acme-challenge.example.fr.", Rcode::NXDOMAIN),
+    ];
+    for (qname, rcode) in negatives {
+        let responses = call(&dnsr, query(qname, Rtype::TXT), Transport::Udp);
+        assert_eq!(responses[0].header().rcode(), rcode);
+        assert!(answer_types(&responses[0]).is_empty());
+
+        let soas = responses[0]
+            .authority()
+            .unwrap()
+            .limit_to::<Soa<ParsedName<_>>>()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(soas.len(), 1);
+        assert_eq!(soas[0].owner().to_string(), "_acme-challenge.example.fr");
+        assert!(soas[0].ttl() <= soas[0].data().minimum());
+    }
+}
+
+#[test]
+fn any_query_is_answered_with_a_synthesized_hinfo() {
+    let dnsr = dnsr();
+
+    let responses = call(&dnsr, query(ZONE, Rtype::ANY), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    assert!(responses[0].header().aa());
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::HINFO]);
+
+    let responses = call(
+        &dnsr,
+        query("token._acme-challenge.example.fr.", Rtype::ANY),
+        Transport::Udp,
+    );
+    assert_eq!(responses[0].header().rcode(), Rcode::NXDOMAIN);
+}
+
+#[test]
+fn names_below_the_apex_are_answered() {
+    let dnsr = dnsr();
+    let zonefile = "$ORIGIN example.net.
+@ 3600 IN SOA ns.example.net. postmaster.example.net. 1 7200 3600 1209600 300
+www.sub 300 IN TXT \"hello\"
+";
+    let reader = inplace::Zonefile::load(&mut zonefile.as_bytes()).unwrap();
+    dnsr.zones
+        .insert_zone(Zone::try_from(reader).unwrap())
+        .unwrap();
+
+    let responses = call(
+        &dnsr,
+        query("www.sub.example.net.", Rtype::TXT),
+        Transport::Udp,
+    );
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::TXT]);
+
+    let responses = call(
+        &dnsr,
+        query("www.sub.example.net.", Rtype::ANY),
+        Transport::Udp,
+    );
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::HINFO]);
+
+    // The empty non-terminal exists, the names below it do not
+    let responses = call(&dnsr, query("sub.example.net.", Rtype::TXT), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    let responses = call(
+        &dnsr,
+        query("ftp.sub.example.net.", Rtype::TXT),
+        Transport::Udp,
+    );
+    assert_eq!(responses[0].header().rcode(), Rcode::NXDOMAIN);
+    let soas = responses[0]
+        .authority()
+        .unwrap()
+        .limit_to::<Soa<ParsedName<_>>>()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>();
+    assert_eq!(soas[0].owner().to_string(), "example.net");
+}
+
+#[test]
+fn cnames_are_followed_within_the_zone() {
+    let dnsr = dnsr();
+    let zonefile = "$ORIGIN example.net.
+@ 3600 IN SOA ns.example.net. postmaster.example.net. 1 7200 3600 1209600 300
+www 300 IN CNAME web
+web 300 IN CNAME host
+host 300 IN TXT \"hello\"
+ext 300 IN CNAME www.example.com.
+loop 300 IN CNAME loop
+";
+    let reader = inplace::Zonefile::load(&mut zonefile.as_bytes()).unwrap();
+    dnsr.zones
+        .insert_zone(Zone::try_from(reader).unwrap())
+        .unwrap();
+
+    let responses = call(&dnsr, query("www.example.net.", Rtype::TXT), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    assert!(responses[0].header().aa());
+    assert_eq!(
+        answer_types(&responses[0]),
+        vec![Rtype::CNAME, Rtype::CNAME, Rtype::TXT]
+    );
+
+    // The chain ends at the target, with its negative answer
+    let responses = call(&dnsr, query("www.example.net.", Rtype::A), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    assert_eq!(
+        answer_types(&responses[0]),
+        vec![Rtype::CNAME, Rtype::CNAME]
+    );
+    assert_eq!(responses[0].header_counts().nscount(), 1);
+
+    // The targets out of the zone and the loops are not followed
+    let responses = call(&dnsr, query("ext.example.net.", Rtype::TXT), Transport::Udp);
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::CNAME]);
+    let responses = call(
+        &dnsr,
+        query("loop.example.net.", Rtype::TXT),
+        Transport::Udp,
+    );
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::CNAME]);
+
+    let responses = call(
+        &dnsr,
+        query("www.example.net.", Rtype::CNAME),
+        Transport::Udp,
+    );
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::CNAME]);
+}
+
+#[test]
+fn existing_names_without_the_type_are_nodata() {
+    let dnsr = dnsr();
+    let zonefile = "$ORIGIN example.net.
+@ 3600 IN SOA ns.example.net. postmaster.example.net. 1 7200 3600 1209600 300
+www.sub 300 IN TXT \"hello\"
+";
+    let reader = inplace::Zonefile::load(&mut zonefile.as_bytes()).unwrap();
+    dnsr.zones
+        .insert_zone(Zone::try_from(reader).unwrap())
+        .unwrap();
+
+    // An empty non-terminal and a name with records of another type
+    for (qname, qtype) in [
+        ("sub.example.net.", Rtype::TXT),
+        ("www.sub.example.net.", Rtype::A),
+    ] {
+        let responses = call(&dnsr, query(qname, qtype), Transport::Udp);
+        assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+        assert!(answer_types(&responses[0]).is_empty());
+        assert_eq!(responses[0].header_counts().nscount(), 1);
+    }
+
+    let responses = call(&dnsr, query("www.example.net.", Rtype::A), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NXDOMAIN);
+}
+
+#[test]
+fn name_above_a_zone_is_nodata() {
+    let dnsr = dnsr_from(&format!("refuse_out_of_zone: false\n{}", CONFIG));
+
+    let responses = call(&dnsr, query("example.fr.", Rtype::TXT), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    assert!(answer_types(&responses[0]).is_empty());
+
+    let responses = call(&dnsr, query("www.example.fr.", Rtype::TXT), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NXDOMAIN);
+}
+
+#[test]
+fn unknown_zone_is_refused() {
+    let dnsr = dnsr();
+
+    let request = query("_acme-challenge.unknown.fr.", Rtype::TXT);
+    let responses = call(&dnsr, request.clone(), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::REFUSED);
+    assert!(answer_types(&responses[0]).is_empty());
+
+    let responses = call(&dnsr, query("example.fr.", Rtype::TXT), Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::REFUSED);
+
+    let dnsr = dnsr_from(&format!("refuse_out_of_zone: false\n{}", CONFIG));
+    let responses = call(&dnsr, request, Transport::Udp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NXDOMAIN);
+    assert!(answer_types(&responses[0]).is_empty());
+}
+
+#[test]
+fn zone_acl_applies_below_the_apex() {
+    let dnsr = dnsr_from(&format!(
+        "acl:\n  zones:\n    example.fr:\n      deny: [127.0.0.1/32]\n{}",
+        CONFIG
+    ));
+
+    for qname in [ZONE, "token._acme-challenge.example.fr."] {
+        let responses = call(&dnsr, query(qname, Rtype::TXT), Transport::Udp);
+        assert_eq!(responses[0].header().rcode(), Rcode::REFUSED);
+    }
+    let responses = call(
+        &dnsr,
+        query("token._acme-challenge.another-example.fr.", Rtype::TXT),
+        Transport::Udp,
+    );
+    assert_ne!(responses[0].header().rcode(), Rcode::REFUSED);
+}
+
+#[test]
+fn malformed_query_is_formerr() {
+    let dnsr = dnsr();
+    let name = Name::<Vec<u8>>::from_str(ZONE).unwrap();
+
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_id(0xbeef);
+    let mut question = builder.question();
+    question.push((name.clone(), Rtype::TXT)).unwrap();
+    question.push((name, Rtype::SOA)).unwrap();
+    let two_questions = question.into_message();
+
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_id(0xbeef);
+    let no_question = builder.question().into_message();
+
+    let mut truncated = query(ZONE, Rtype::TXT).into_octets();
+    truncated.truncate(truncated.len() - 3);
+    let truncated = Message::from_octets(truncated).unwrap();
+
+    for request in [two_questions, no_question, truncated] {
+        let responses = call(&dnsr, request, Transport::Udp);
+        assert_eq!(responses.len(), 1);
+        let header = responses[0].header();
+        assert_eq!(header.id(), 0xbeef);
+        assert!(header.qr());
+        assert_eq!(header.rcode(), Rcode::FORMERR);
+    }
+}
+
+#[test]
+fn axfr_is_framed_by_the_soa() {
+    let dnsr = dnsr();
+    let request = query(ZONE, Rtype::AXFR);
+
+    let responses = call(&dnsr, request.clone(), Transport::Tcp);
+    assert!(responses.len() >= 2);
+
+    let first = responses.first().unwrap();
+    let last = responses.last().unwrap();
+    assert_eq!(answer_types(first).first(), Some(&Rtype::SOA));
+    assert_eq!(answer_types(last).last(), Some(&Rtype::SOA));
+    assert_eq!(
+        first.sole_question().unwrap(),
+        request.sole_question().unwrap()
+    );
+
+    for response in &responses[1..responses.len() - 1] {
+        assert!(!answer_types(response).contains(&Rtype::SOA));
+    }
+
+    for response in &responses {
+        let header = response.header();
+        assert_eq!(header.id(), request.header().id());
+        assert!(header.qr());
+        assert!(header.aa());
+        assert!(!header.tc());
+        assert_eq!(header.opcode(), Opcode::QUERY);
+        assert_eq!(header.rcode(), Rcode::NOERROR);
+    }
+}
+
+#[test]
+fn axfr_ttls_are_clamped_for_the_client() {
+    let config = format!(
+        "acl:\n  transfer_ttl:\n    - {{ clients: [127.0.0.1], min: 7200 }}\n{}",
+        CONFIG
+    );
+    let dnsr = dnsr_from(&config);
+
+    let responses = call(&dnsr, query(ZONE, Rtype::AXFR), Transport::Tcp);
+    let ttls = responses
+        .iter()
+        .flat_map(|response| response.answer().unwrap())
+        .map(|record| record.unwrap().ttl().as_secs())
+        .collect::<Vec<_>>();
+    assert!(ttls.len() >= 2);
+    assert!(ttls.iter().all(|ttl| *ttl >= 7200));
+}
+
+#[test]
+fn signed_axfr_requires_the_key_of_the_zone() {
+    let dnsr = dnsr_from(&format!("acl:\n  transfer:\n    tsig: true\n{}", CONFIG));
+    let key1 = register_key(&dnsr, "key1");
+    let key2 = register_key(&dnsr, "key2");
+
+    let responses = call(&dnsr, query(ZONE, Rtype::AXFR), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::REFUSED);
+
+    // The key of another zone is known but does not sign this transfer
+    let request = signed_query(ZONE, Rtype::AXFR, &key2);
+    let responses = call(&dnsr, request, Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::REFUSED);
+
+    let request = signed_query(ZONE, Rtype::AXFR, &key1);
+    let responses = call(&dnsr, request, Transport::Tcp);
+    assert!(responses.len() >= 2);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+}
+
+#[test]
+fn axfr_over_udp_is_not_implemented() {
+    let dnsr = dnsr();
+
+    let responses = call(&dnsr, query(ZONE, Rtype::AXFR), Transport::Udp);
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOTIMP);
+    assert!(answer_types(&responses[0]).is_empty());
+}
+
+#[test]
+fn signed_update_adds_and_deletes_records() {
+    let dnsr = dnsr();
+    let key = register_key(&dnsr, "key1");
+
+    let records = [(Class::IN, "token-1"), (Class::IN, "token-2")];
+    let responses = call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    assert_eq!(responses[0].header().opcode(), Opcode::UPDATE);
+
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::TXT, Rtype::TXT]);
+
+    let records = [(Class::NONE, "token-1")];
+    let responses = call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::TXT]);
+}
+
+#[test]
+fn simultaneous_challenges_are_held_and_cleaned_up_apart() {
+    let dnsr = dnsr();
+    let key = register_key(&dnsr, "key1");
+
+    // The challenges of `example.fr` and `*.example.fr` are published by two
+    // updates of the same name, a retried update is not duplicated
+    for token in ["token-1", "token-2", "token-2"] {
+        let records = [(Class::IN, token)];
+        let responses = call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+        assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    }
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::TXT, Rtype::TXT]);
+
+    let records = [(Class::NONE, "token-2")];
+    call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert_eq!(texts(&responses[0]), ["token-1"]);
+}
+
+#[test]
+fn update_with_key_out_of_scope_is_refused() {
+    let dnsr = dnsr();
+    let key = register_key(&dnsr, "key2");
+
+    let records = [(Class::IN, "token")];
+    let responses = call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::REFUSED);
+
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert!(answer_types(&responses[0]).is_empty());
+}
+
+/// Returns the TSIG error of the signed `response`.
+fn tsig_error(response: &Message<Vec<u8>>) -> TsigRcode {
+    let tsig = response
+        .additional()
+        .unwrap()
+        .limit_to::<Tsig<_, ParsedName<_>>>()
+        .next()
+        .unwrap()
+        .unwrap();
+    tsig.data().error()
+}
+
+#[test]
+fn update_with_unknown_key_or_wrong_secret_is_notauth() {
+    let dnsr = dnsr();
+    let records = [(Class::IN, "token")];
+
+    let rng = ring::rand::SystemRandom::new();
+    let name = KeyName::from_str("key1").unwrap();
+    let (unknown, _) = Key::generate(Algorithm::Sha512, &rng, name, None, None).unwrap();
+    let responses = call(
+        &dnsr,
+        update(ZONE, &records, Some(&unknown)),
+        Transport::Tcp,
+    );
+    assert_eq!(responses[0].header().rcode(), Rcode::NOTAUTH);
+    assert_eq!(tsig_error(&responses[0]), TsigRcode::BADKEY);
+
+    // Same name and algorithm as the registered key, another secret
+    register_key(&dnsr, "key1");
+    let responses = call(
+        &dnsr,
+        update(ZONE, &records, Some(&unknown)),
+        Transport::Tcp,
+    );
+    assert_eq!(responses[0].header().rcode(), Rcode::NOTAUTH);
+    assert_eq!(tsig_error(&responses[0]), TsigRcode::BADSIG);
+
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert!(answer_types(&responses[0]).is_empty());
+}
+
+#[test]
+fn update_signed_out_of_the_fudge_is_badtime() {
+    let records = [(Class::IN, "token")];
+    let skewed = Time48::from_u64(Time48::now().into_int() - 3600);
+
+    let dnsr = dnsr();
+    let key = register_key(&dnsr, "key1");
+    let request = update_signed_at(ZONE, &records, Some(&key), skewed);
+    let responses = call(&dnsr, request, Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOTAUTH);
+
+    // The server time is sent along the error so that the client resyncs
+    let tsig = responses[0]
+        .additional()
+        .unwrap()
+        .limit_to::<Tsig<_, ParsedName<_>>>()
+        .next()
+        .unwrap()
+        .unwrap();
+    assert_eq!(tsig.data().error(), TsigRcode::BADTIME);
+    assert_eq!(tsig.data().other().len(), 6);
+
+    let dnsr = dnsr_from(&format!("tsig:\n  fudge: 7200\n{}", CONFIG));
+    let key = register_key(&dnsr, "key1");
+    let request = update_signed_at(ZONE, &records, Some(&key), skewed);
+    let responses = call(&dnsr, request, Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+}
+
+#[test]
+fn client_fudge_does_not_widen_the_server_fudge() {
+    let records = [(Class::IN, "token")];
+    // Within the fudge of 300 seconds announced by the client
+    let skewed = Time48::from_u64(Time48::now().into_int() - 120);
+
+    let dnsr = dnsr_from(&format!("tsig:\n  fudge: 60\n{}", CONFIG));
+    let key = register_key(&dnsr, "key1");
+    let request = update_signed_at(ZONE, &records, Some(&key), skewed);
+    let responses = call(&dnsr, request, Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOTAUTH);
+    assert_eq!(tsig_error(&responses[0]), TsigRcode::BADTIME);
+}
+
+#[test]
+fn update_from_outside_the_key_networks_is_refused() {
+    let config = CONFIG.replace(
+        "      rname: postmaster.example.fr.\n",
+        "      rname: postmaster.example.fr.\n      allowed_clients: [192.0.2.0/24]\n",
+    );
+    let dnsr = dnsr_from(&config);
+    let key = register_key(&dnsr, "key1");
+
+    let records = [(Class::IN, "token")];
+    let responses = call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::REFUSED);
+
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert!(answer_types(&responses[0]).is_empty());
+}
+
+#[test]
+fn standby_refuses_updates_until_promoted() {
+    let dnsr = dnsr_from(&format!("standby: true{}", CONFIG));
+    let key = register_key(&dnsr, "key1");
+
+    let records = [(Class::IN, "token")];
+    let responses = call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::REFUSED);
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert!(answer_types(&responses[0]).is_empty());
+
+    assert!(dnsr.promote());
+    assert!(!dnsr.promote());
+    let responses = call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+}
+
+#[test]
+fn unsigned_update_does_not_modify_the_zone() {
+    let dnsr = dnsr();
+
+    let records = [(Class::IN, "token")];
+    let responses = call(&dnsr, update(ZONE, &records, None), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::REFUSED);
+
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert!(answer_types(&responses[0]).is_empty());
+}
+
+#[test]
+fn signed_queries_and_transfers_take_no_update_tokens() {
+    let config = format!(
+        "update_rate_limit:\n  per_key: 1\n  per_zone: 1\n{}",
+        CONFIG
+    );
+    let dnsr = dnsr_from(&config);
+    let key = register_key(&dnsr, "key1");
+
+    for qtype in [Rtype::TXT, Rtype::AXFR] {
+        let responses = call(&dnsr, signed_query(ZONE, qtype, &key), Transport::Tcp);
+        assert!(responses
+            .iter()
+            .all(|response| response.header().rcode() == Rcode::NOERROR));
+    }
+
+    // The only token of the key and of the zone is left for the update
+    let records = [(Class::IN, "token")];
+    let responses = call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOERROR);
+    let responses = call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::REFUSED);
+}
+
+#[test]
+fn update_with_unsupported_class_is_not_applied() {
+    let dnsr = dnsr();
+    let key = register_key(&dnsr, "key1");
+
+    let records = [(Class::IN, "token"), (Class::ANY, "token")];
+    let responses = call(&dnsr, update(ZONE, &records, Some(&key)), Transport::Tcp);
+    assert_eq!(responses[0].header().rcode(), Rcode::NOTIMP);
+
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    assert!(answer_types(&responses[0]).is_empty());
+}
+
+#[test]
+fn large_udp_answer_is_truncated() {
+    let dnsr = dnsr();
+    let text = "t".repeat(100);
+    let txt = Txt::<Bytes>::build_from_slice(text.as_bytes()).unwrap();
+    let mut records = ZoneRecords::new();
+    records.insert((Rtype::TXT, Ttl::from_secs(60)), vec![txt.into(); 20]);
+    let zone = Name::<Vec<u8>>::from_str(ZONE).unwrap();
+    block_on(dnsr.zones.write_records(&zone, records)).unwrap();
+
+    // Without EDNS a UDP response is limited to 512 bytes
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Udp);
+    let header = responses[0].header();
+    assert!(header.tc());
+    assert_eq!(header.id(), 0xbeef);
+    assert!(answer_types(&responses[0]).is_empty());
+    assert!(responses[0].opt().is_none());
+    assert!(responses[0].sole_question().is_ok());
+
+    // The client size is capped by the advertised size
+    let responses = call(&dnsr, edns_query(ZONE, Rtype::TXT, 4096), Transport::Udp);
+    assert!(responses[0].header().tc());
+    assert!(responses[0].as_slice().len() <= 1232);
+    assert_eq!(responses[0].opt().unwrap().udp_payload_size(), 1232);
+
+    let responses = call(&dnsr, edns_query(ZONE, Rtype::SOA, 512), Transport::Udp);
+    assert!(!responses[0].header().tc());
+    assert_eq!(answer_types(&responses[0]), vec![Rtype::SOA]);
+    assert_eq!(responses[0].opt().unwrap().udp_payload_size(), 1232);
+
+    // TCP responses are never truncated
+    let responses = call(&dnsr, query(ZONE, Rtype::TXT), Transport::Tcp);
+    assert!(!responses[0].header().tc());
+    assert_eq!(answer_types(&responses[0]).len(), 20);
+}
@@ -0,0 +1,179 @@
+//! The expiry of the stale challenge records.
+//!
+//! The ACME clients remove their TXT records once the challenge is validated,
+//! but a client crashing or losing its key leaves them in the zone forever. The
+//! challenge zones are swept periodically, a TXT record is stamped the first
+//! time it is seen and removed once it was served for the configured time, the
+//! static records of the domain excepted.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use domain::base::Rtype;
+use domain::zonetree::types::{StoredName, StoredRecordData};
+use futures::executor::block_on;
+
+use super::Dnsr;
+use crate::config::ChallengeExpiryConfig;
+use crate::dname::{DomainName, CHALLENGE_PREFIX};
+
+#[derive(Debug)]
+pub struct ChallengeExpiry {
+    config: ChallengeExpiryConfig,
+    /// The time every challenge record of a zone was first seen at
+    seen: Mutex<HashMap<StoredName, Vec<(StoredRecordData, Instant)>>>,
+}
+
+impl ChallengeExpiry {
+    pub fn new(config: ChallengeExpiryConfig) -> Self {
+        Self {
+            config,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval()
+    }
+
+    /// Removes the expired challenge records of every challenge zone of
+    /// `dnsr`, a standby leaves them to its primary.
+    pub fn sweep(&self, dnsr: &Dnsr) {
+        if dnsr.is_standby() {
+            return;
+        }
+
+        let apexes = dnsr.zones.apex_names();
+        self.seen
+            .lock()
+            .unwrap()
+            .retain(|apex, _| apexes.contains(apex));
+        for apex in apexes {
+            if apex.to_string().starts_with(CHALLENGE_PREFIX) {
+                self.sweep_zone(dnsr, &apex, Instant::now());
+            }
+        }
+    }
+
+    fn sweep_zone(&self, dnsr: &Dnsr, apex: &StoredName, now: Instant) {
+        // An update racing the sweep is not overwritten
+        let _changes = block_on(dnsr.zones.lock_changes(apex));
+        let mut records = dnsr.zones.records(apex);
+        let before = records.clone();
+
+        let static_records = static_records(dnsr, apex);
+        let values = records
+            .iter()
+            .filter(|((rtype, _), _)| *rtype == Rtype::TXT)
+            .flat_map(|(_, data)| data.iter())
+            .filter(|data| !static_records.contains(data))
+            .cloned()
+            .collect::<Vec<_>>();
+        let expired = self.expired(apex, &values, now);
+        if expired.is_empty() {
+            return;
+        }
+
+        for ((rtype, _), data) in records.iter_mut() {
+            if *rtype == Rtype::TXT {
+                data.retain(|data| !expired.contains(data));
+            }
+        }
+        match block_on(dnsr.commit_records(apex, &before, &mut records)) {
+            Ok(()) => {
+                log::info!(target: "expiry", "removed {} expired challenge records of {}", expired.len(), apex)
+            }
+            Err(e) => {
+                log::error!(target: "expiry", "failed to remove the expired challenge records of {}: {}", apex, e)
+            }
+        }
+    }
+
+    /// Stamps the challenge `values` of the zone `apex` seen for the first
+    /// time at `now` and returns the ones served for longer than the expiry.
+    fn expired(
+        &self,
+        apex: &StoredName,
+        values: &[StoredRecordData],
+        now: Instant,
+    ) -> Vec<StoredRecordData> {
+        let mut seen = self.seen.lock().unwrap();
+        let seen = seen.entry(apex.clone()).or_default();
+
+        // The values removed since the last sweep are forgotten so that a new
+        // challenge reusing one is stamped again
+        seen.retain(|(data, _)| values.contains(data));
+        for data in values {
+            if !seen.iter().any(|(seen, _)| seen == data) {
+                seen.push((data.clone(), now));
+            }
+        }
+
+        let after = self.config.after();
+        seen.iter()
+            .filter(|(_, first_seen)| now.saturating_duration_since(*first_seen) >= after)
+            .map(|(data, _)| data.clone())
+            .collect()
+    }
+}
+
+/// Returns the static records of the domain of the challenge zone `apex`,
+/// they never expire.
+fn static_records(dnsr: &Dnsr, apex: &StoredName) -> Vec<StoredRecordData> {
+    let domain = DomainName::from_name(apex);
+    let provisioned = dnsr.provisioned.read().unwrap();
+    let Some((_, info)) = dnsr
+        .config
+        .keys
+        .find_domain(&domain)
+        .or_else(|| provisioned.find_domain(&domain))
+    else {
+        return Vec::new();
+    };
+
+    info.static_rrsets()
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|rrset| rrset.data().to_vec())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bytes::Bytes;
+    use domain::rdata::Txt;
+
+    use super::*;
+
+    fn txt(text: &str) -> StoredRecordData {
+        Txt::<Bytes>::build_from_slice(text.as_bytes())
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn challenges_expire_after_they_were_first_seen() {
+        let config = serde_yaml::from_str("{ after: 3600 }").unwrap();
+        let expiry = ChallengeExpiry::new(config);
+        let apex = StoredName::from_str("_acme-challenge.example.fr.").unwrap();
+        let start = Instant::now();
+        let later = |secs| start + Duration::from_secs(secs);
+
+        assert!(expiry.expired(&apex, &[txt("token-1")], start).is_empty());
+        let values = [txt("token-1"), txt("token-2")];
+        assert!(expiry.expired(&apex, &values, later(1800)).is_empty());
+        assert_eq!(
+            expiry.expired(&apex, &values, later(3600)),
+            [txt("token-1")]
+        );
+
+        // A removed value seen again is a new challenge
+        assert!(expiry.expired(&apex, &[], later(3660)).is_empty());
+        assert!(expiry
+            .expired(&apex, &[txt("token-1")], later(3720))
+            .is_empty());
+    }
+}
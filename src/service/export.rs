@@ -0,0 +1,202 @@
+//! Export of the zones in presentation format without any DNS request.
+//!
+//! The zones of the configuration are loaded and their records restored from
+//! the shared backends, if any, before being written to stdout. This allows
+//! backups and diffs in shell pipelines, e.g.:
+//!
+//!   dnsr export --all --gzip > zones.gz
+//!   dnsr export _acme-challenge.example.fr | diff - backup.zone
+
+use std::io::Write;
+use std::sync::Arc;
+
+use domain::base::Rtype;
+use domain::zonetree::types::StoredName;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use super::{Dnsr, Zones};
+use crate::error;
+use crate::error::Result;
+use crate::key::{build_zones, SystemClock};
+
+/// The zones to export and how.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ExportOptions {
+    /// The apexes of the zones to export, every zone if empty
+    zones: Vec<StoredName>,
+    gzip: bool,
+}
+
+impl ExportOptions {
+    /// Parses the arguments following `export`, either `--all` or the apexes
+    /// of the zones, and `--gzip`.
+    pub fn from_args<I>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut options = Self::default();
+        let mut all = false;
+
+        for arg in args {
+            match arg.as_str() {
+                "--all" => all = true,
+                "--gzip" => options.gzip = true,
+                _ if arg.starts_with('-') => {
+                    return Err(error!(Export => "unknown option {}", arg));
+                }
+                _ => options.zones.push(StoredName::bytes_from_str(&arg)?),
+            }
+        }
+
+        if all == !options.zones.is_empty() {
+            return Err(error!(Export => "either --all or the zones to export are expected"));
+        }
+        Ok(options)
+    }
+}
+
+/// Writes the zones selected by `options` to stdout.
+pub fn export_stdout(dnsr: Arc<Dnsr>, options: &ExportOptions) -> Result<()> {
+    for (name, info) in dnsr.config.keys.domains() {
+        for zone in build_zones(name, info, &SystemClock)? {
+            dnsr.zones.insert_zone(zone)?;
+        }
+    }
+    // The snapshot may be older than the records of the store
+    if let Some(snapshots) = &dnsr.snapshots {
+        snapshots.restore(&dnsr.zones, &dnsr.journal)?;
+    }
+    if let Some(store) = &dnsr.store {
+        store.sync(&dnsr.zones)?;
+    }
+
+    let mut apexes = if options.zones.is_empty() {
+        dnsr.zones.apex_names()
+    } else {
+        options.zones.clone()
+    };
+    apexes.sort();
+
+    let stdout = std::io::stdout().lock();
+    if options.gzip {
+        let mut out = GzEncoder::new(stdout, Compression::default());
+        write_zones(&dnsr.zones, &apexes, &mut out)?;
+        out.finish()?.flush()?;
+    } else {
+        let mut out = std::io::BufWriter::new(stdout);
+        write_zones(&dnsr.zones, &apexes, &mut out)?;
+        out.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Writes the records of the zones `apexes`, the SOA first, one per line.
+fn write_zones<W>(zones: &Zones, apexes: &[StoredName], out: &mut W) -> Result<()>
+where
+    W: Write,
+{
+    let apex_names = zones.apex_names();
+
+    for apex in apexes {
+        if !apex_names.contains(apex) {
+            return Err(error!(Export => "unknown zone {}", apex));
+        }
+
+        let mut records = zones.records(apex).into_iter().collect::<Vec<_>>();
+        records
+            .sort_by_key(|((rtype, ttl), _)| (*rtype != Rtype::SOA, rtype.to_int(), ttl.as_secs()));
+
+        for ((rtype, ttl), data) in records {
+            let mut data = data.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+            data.sort();
+            for data in data {
+                writeln!(out, "{}.\t{}\tIN\t{}\t{}", apex, ttl.as_secs(), rtype, data)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use domain::base::Ttl;
+    use domain::rdata::Txt;
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::store::ZoneRecords;
+
+    const CONFIG: &str = "
+keys:
+  key1:
+    example.fr:
+      mname: ns-acme.example.fr.
+      rname: postmaster.example.fr.
+      domain_zone:
+        records: [A 192.0.2.1]
+";
+
+    fn args(args: &[&str]) -> Result<ExportOptions> {
+        ExportOptions::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn arguments_select_the_zones() {
+        let all = args(&["--all", "--gzip"]).unwrap();
+        assert!(all.zones.is_empty());
+        assert!(all.gzip);
+
+        let some = args(&["_acme-challenge.example.fr"]).unwrap();
+        assert_eq!(some.zones.len(), 1);
+        assert!(!some.gzip);
+
+        assert!(args(&[]).is_err());
+        assert!(args(&["--all", "example.fr"]).is_err());
+        assert!(args(&["--all", "--zip"]).is_err());
+    }
+
+    #[test]
+    fn zones_are_written_in_presentation_format() {
+        let config = Config::try_from(&CONFIG.as_bytes().to_vec()).unwrap();
+        let dnsr = Dnsr::from(Arc::new(config));
+        let clock = UNIX_EPOCH + Duration::from_secs(1722353587);
+        for (name, info) in dnsr.config.keys.domains() {
+            for zone in build_zones(name, info, &clock).unwrap() {
+                dnsr.zones.insert_zone(zone).unwrap();
+            }
+        }
+
+        let challenge = StoredName::bytes_from_str("_acme-challenge.example.fr").unwrap();
+        let mut records = ZoneRecords::new();
+        records.insert(
+            (Rtype::TXT, Ttl::from_secs(60)),
+            vec![Txt::build_from_slice(b"token").unwrap().into()],
+        );
+        block_on(dnsr.zones.replace_records(&challenge, records)).unwrap();
+
+        let apexes = [challenge, StoredName::bytes_from_str("example.fr").unwrap()];
+        let mut out = Vec::new();
+        write_zones(&dnsr.zones, &apexes, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[0],
+            "_acme-challenge.example.fr.\t3600\tIN\tSOA\tns-acme.example.fr. postmaster.example.fr. 1722353587 10800 3600 605800 3600"
+        );
+        assert!(lines[1].starts_with("_acme-challenge.example.fr.\t60\tIN\tTXT\t"));
+        assert!(lines[2].starts_with("example.fr.\t3600\tIN\tSOA\t"));
+        assert_eq!(lines[3], "example.fr.\t3600\tIN\tA\t192.0.2.1");
+        assert_eq!(lines[4], "example.fr.\t3600\tIN\tNS\tns-acme.example.fr.");
+
+        let unknown = StoredName::bytes_from_str("www.example.fr").unwrap();
+        assert!(write_zones(&dnsr.zones, &[unknown], &mut Vec::new()).is_err());
+    }
+}
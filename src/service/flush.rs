@@ -0,0 +1,325 @@
+//! Flush of the updated names from the caches of downstream resolvers.
+//!
+//! Once a zone is updated, its apex is flushed from every configured resolver
+//! so that the ACME servers behind them see the new challenge records without
+//! waiting for a cached negative answer to expire. The flushes run on their own
+//! thread and are best effort: a flush failing after its retries is logged and
+//! the next target tried.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+
+use domain::base::iana::{Opcode, Rcode};
+use domain::base::{Message, MessageBuilder, Name, Rtype, ToName};
+use domain::rdata::tsig::Time48;
+use domain::tsig::{ClientTransaction, Key};
+use domain::zonetree::types::StoredName;
+use ring::rand::SecureRandom;
+
+use crate::config::{CacheFlushConfig, FlushTarget};
+use crate::error;
+use crate::error::Result;
+
+/// The random ports tried before falling back to a port chosen by the system.
+const BIND_ATTEMPTS: usize = 8;
+
+#[derive(Debug)]
+pub struct CacheFlusher {
+    sender: Sender<StoredName>,
+}
+
+impl CacheFlusher {
+    /// Starts the thread flushing the names from the targets of `config`.
+    pub fn new(config: CacheFlushConfig) -> Self {
+        let (sender, receiver) = channel::<StoredName>();
+        std::thread::spawn(move || {
+            for name in receiver {
+                for target in config.targets() {
+                    match flush_with_retries(&config, target, &name) {
+                        Ok(()) => log::debug!(target: "flush", "flushed {} from {}", name, target),
+                        Err(e) => {
+                            log::warn!(target: "flush", "failed to flush {} from {}: {}", name, target, e)
+                        }
+                    }
+                }
+            }
+        });
+
+        Self { sender }
+    }
+
+    /// Queues the flush of `name` from every target.
+    pub fn flush<N>(&self, name: &N)
+    where
+        N: ToName,
+    {
+        let _ = self.sender.send(name.to_bytes());
+    }
+}
+
+/// Flushes `name` from `target` until it succeeds or the retries of `config`
+/// are exhausted.
+fn flush_with_retries(
+    config: &CacheFlushConfig,
+    target: &FlushTarget,
+    name: &StoredName,
+) -> Result<()> {
+    let mut backoff = config.backoff();
+    let mut retries = config.retries();
+    loop {
+        match flush(target, name, config.timeout()) {
+            Err(e) if retries > 0 => {
+                log::debug!(target: "flush", "flush of {} from {} failed, retrying in {:?}: {}", name, target, backoff, e);
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                retries -= 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn flush(target: &FlushTarget, name: &StoredName, timeout: Duration) -> Result<()> {
+    match target {
+        FlushTarget::Notify(addr) => notify(*addr, name, timeout, None),
+        FlushTarget::Kresd(path) => {
+            let mut stream = UnixStream::connect(path)?;
+            stream.set_write_timeout(Some(timeout))?;
+            stream.set_read_timeout(Some(timeout))?;
+            writeln!(stream, "cache.clear('{}', true)", name)?;
+            stream.shutdown(std::net::Shutdown::Write)?;
+            // Wait for the result so that the flush is done once returned
+            let _ = stream.read(&mut [0; 512])?;
+            Ok(())
+        }
+        FlushTarget::Command(argv) => {
+            let Some((program, args)) = argv.split_first() else {
+                return Err(error!(Flush => "empty command"));
+            };
+            let status = Command::new(program)
+                .args(args)
+                .arg(name.to_string())
+                .status()?;
+            if !status.success() {
+                return Err(error!(Flush => "{} exited with {}", program, status));
+            }
+            Ok(())
+        }
+        FlushTarget::Http(url) => {
+            let body = format!("{{\"name\":\"{}\"}}", name);
+            match ureq::post(url)
+                .timeout(timeout)
+                .set("content-type", "application/json")
+                .send_string(&body)
+            {
+                Ok(_) => Ok(()),
+                Err(ureq::Error::Status(code, _)) => {
+                    Err(error!(Flush => "flush failed with status {}", code))
+                }
+                Err(e) => Err(error!(Flush => "flush failed: {}", e)),
+            }
+        }
+    }
+}
+
+/// Sends a NOTIFY of the zone `apex` to `addr`, signed with `key` if any, and
+/// waits for its acknowledgement, see RFC 1996.
+///
+/// Against off-path spoofing, the NOTIFY is sent from a random port with a
+/// random ID and the letters of its question in a random case, which the
+/// acknowledgement must echo, see draft-vixie-dnsext-dns0x20.
+pub(super) fn notify(
+    addr: SocketAddr,
+    apex: &StoredName,
+    timeout: Duration,
+    key: Option<&Key>,
+) -> Result<()> {
+    let rng = ring::rand::SystemRandom::new();
+    let mut id = [0; 2];
+    rng.fill(&mut id).map_err(|_| error!(RingUnspecified))?;
+    let id = u16::from_be_bytes(id);
+    let qname = randomize_case(apex, &rng)?;
+
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_id(id);
+    builder.header_mut().set_opcode(Opcode::NOTIFY);
+    builder.header_mut().set_aa(true);
+    let mut question = builder.question();
+    question.push((&qname, Rtype::SOA))?;
+    let mut additional = question.additional();
+    let transaction = key
+        .map(|key| ClientTransaction::request(key, &mut additional, Time48::now()))
+        .transpose()?;
+    let request = additional.finish();
+
+    let socket = bind_random_port(addr, &rng)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(addr)?;
+    socket.send(&request)?;
+
+    let mut buf = [0; 512];
+    loop {
+        let len = socket.recv(&mut buf)?;
+        let Ok(mut response) = Message::from_octets(buf[..len].to_vec()) else {
+            continue;
+        };
+        let header = response.header();
+        if header.id() != id || !header.qr() || !echoes_question(&response, &qname) {
+            continue;
+        }
+        if let Some(transaction) = &transaction {
+            transaction
+                .answer(&mut response, Time48::now())
+                .map_err(|e| error!(Flush => "invalid notify response signature: {}", e))?;
+        }
+        return match header.rcode() {
+            Rcode::NOERROR => Ok(()),
+            rcode => Err(error!(Flush => "notify answered {}", rcode)),
+        };
+    }
+}
+
+/// Returns `name` with each of its letters in a random case.
+fn randomize_case(name: &StoredName, rng: &dyn SecureRandom) -> Result<Name<Vec<u8>>> {
+    let mut octets = name.as_octets().to_vec();
+    let mut bits = vec![0; octets.len()];
+    rng.fill(&mut bits).map_err(|_| error!(RingUnspecified))?;
+
+    // The label lengths are below 64 and never letters
+    for (octet, bit) in octets.iter_mut().zip(bits) {
+        if octet.is_ascii_alphabetic() && bit & 1 == 1 {
+            *octet ^= 0x20;
+        }
+    }
+    Name::from_octets(octets).map_err(|_| error!(Flush => "invalid name {}", name))
+}
+
+/// Returns whether the question of `response` is `qname` with the same case.
+fn echoes_question(response: &Message<Vec<u8>>, qname: &Name<Vec<u8>>) -> bool {
+    response.sole_question().is_ok_and(|question| {
+        question
+            .qname()
+            .iter()
+            .map(|label| label.as_slice())
+            .eq(qname.iter().map(|label| label.as_slice()))
+    })
+}
+
+/// Binds a UDP socket of the family of `addr` on a random unprivileged port,
+/// on a port chosen by the system if the random ones are taken.
+fn bind_random_port(addr: SocketAddr, rng: &dyn SecureRandom) -> Result<UdpSocket> {
+    let ip: IpAddr = if addr.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+
+    for _ in 0..BIND_ATTEMPTS {
+        let mut port = [0; 2];
+        rng.fill(&mut port).map_err(|_| error!(RingUnspecified))?;
+        let port = 1024 + u16::from_be_bytes(port) % (u16::MAX - 1024);
+        match UdpSocket::bind((ip, port)) {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(UdpSocket::bind((ip, 0))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use domain::net::server::util::mk_builder_for_target;
+    use domain::tsig::{Algorithm, KeyName, ServerTransaction};
+
+    use super::*;
+
+    #[test]
+    fn notify_waits_for_the_acknowledgement() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = resolver.local_addr().unwrap();
+        let acknowledged = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, client) = resolver.recv_from(&mut buf).unwrap();
+            let request = Message::from_octets(buf[..len].to_vec()).unwrap();
+            assert_eq!(request.header().opcode(), Opcode::NOTIFY);
+            let question = request.sole_question().unwrap();
+            assert_eq!(question.qtype(), Rtype::SOA);
+            let qname = question.qname().to_string();
+
+            // The acknowledgement is the request with the QR bit set
+            let mut response = request.into_octets();
+            response[2] |= 0x80;
+            resolver.send_to(&response, client).unwrap();
+            qname
+        });
+
+        let apex = StoredName::bytes_from_str("_acme-challenge.example.fr").unwrap();
+        notify(addr, &apex, Duration::from_secs(2), None).unwrap();
+        assert!(acknowledged
+            .join()
+            .unwrap()
+            .eq_ignore_ascii_case("_acme-challenge.example.fr"));
+    }
+
+    #[test]
+    fn acknowledgement_not_echoing_the_case_is_ignored() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = resolver.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, client) = resolver.recv_from(&mut buf).unwrap();
+
+            // The case of every letter of the question is swapped
+            let mut response = buf[..len].to_vec();
+            response[2] |= 0x80;
+            for octet in &mut response[12..] {
+                if octet.is_ascii_alphabetic() {
+                    *octet ^= 0x20;
+                }
+            }
+            resolver.send_to(&response, client).unwrap();
+        });
+
+        let apex = StoredName::bytes_from_str("_acme-challenge.example.fr").unwrap();
+        assert!(notify(addr, &apex, Duration::from_secs(1), None).is_err());
+    }
+
+    #[test]
+    fn signed_notify_expects_a_signed_acknowledgement() {
+        let rng = ring::rand::SystemRandom::new();
+        let name = KeyName::from_str("secondary").unwrap();
+        let (key, _) = Key::generate(Algorithm::Sha512, &rng, name, None, None).unwrap();
+        let key = Arc::new(key);
+
+        let secondary = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = secondary.local_addr().unwrap();
+        let keystore = key.clone();
+        let acknowledged = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, client) = secondary.recv_from(&mut buf).unwrap();
+            let mut request = Message::from_octets(buf[..len].to_vec()).unwrap();
+            let transaction = ServerTransaction::request(&keystore, &mut request, Time48::now())
+                .unwrap()
+                .unwrap();
+
+            let mut response = mk_builder_for_target::<Vec<u8>>()
+                .start_answer(&request, Rcode::NOERROR)
+                .unwrap()
+                .additional();
+            transaction.answer(&mut response, Time48::now()).unwrap();
+            secondary.send_to(response.as_slice(), client).unwrap();
+        });
+
+        let apex = StoredName::bytes_from_str("_acme-challenge.example.fr").unwrap();
+        notify(addr, &apex, Duration::from_secs(2), Some(&key)).unwrap();
+        acknowledged.join().unwrap();
+    }
+}
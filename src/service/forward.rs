@@ -0,0 +1,114 @@
+//! The forwarding of the updates received by a standby to the primary.
+//!
+//! A standby does not write its zones, they are mirrored from the primary. The
+//! updates it receives are forwarded as is over TCP, so that the primary checks
+//! their signature, and the response code of the primary is relayed to the
+//! client, as BIND's `allow-update-forwarding`. An update is only forwarded
+//! again when the primary could not be reached, up to the configured retries.
+
+use bytes::Bytes;
+use domain::base::iana::Rcode;
+use domain::base::Message;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::config::UpdateForwardingConfig;
+use crate::error;
+use crate::error::Result;
+
+/// Forwards the update `request` to the primary of `config` and returns the
+/// rcode of its response, every exchange is bounded by the timeout of
+/// `config`.
+pub async fn forward_update(
+    config: &UpdateForwardingConfig,
+    request: &Message<Bytes>,
+) -> Result<Rcode> {
+    let primary = config.primary();
+    let mut backoff = config.backoff();
+    let mut retries = config.retries();
+    let response = loop {
+        let response = timeout(config.timeout(), exchange(config, request.as_slice()))
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+        match response {
+            Err(e) if retries > 0 => {
+                log::debug!(target: "forward", "failed to reach the primary {}, retrying in {:?}: {}", primary, backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                retries -= 1;
+            }
+            response => {
+                break response.map_err(
+                    |e| error!(Forward => "failed to reach the primary {}: {}", primary, e),
+                )?
+            }
+        }
+    };
+
+    let response = Message::from_octets(response)
+        .map_err(|_| error!(Forward => "short response from the primary {}", primary))?;
+    let header = response.header();
+    if header.id() != request.header().id() || !header.qr() {
+        return Err(error!(Forward => "unexpected response from the primary {}", primary));
+    }
+    Ok(header.rcode())
+}
+
+/// Sends `request` to the primary over TCP and reads its response.
+async fn exchange(config: &UpdateForwardingConfig, request: &[u8]) -> std::io::Result<Vec<u8>> {
+    let len =
+        u16::try_from(request.len()).map_err(|_| std::io::Error::other("update too large"))?;
+    let mut stream = TcpStream::connect(config.primary()).await?;
+
+    // Every message is prefixed by its length over TCP, see RFC 1035 section 4.2.2
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(request).await?;
+    let len = stream.read_u16().await?;
+    let mut response = vec![0; usize::from(len)];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    use domain::base::iana::Opcode;
+    use domain::base::MessageBuilder;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn the_rcode_of_the_primary_is_relayed() {
+        let primary = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config: UpdateForwardingConfig =
+            serde_yaml::from_str(&format!("primary: {}", primary.local_addr().unwrap())).unwrap();
+        let answered = std::thread::spawn(move || {
+            let (mut stream, _) = primary.accept().unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut request = vec![0; usize::from(u16::from_be_bytes(len))];
+            stream.read_exact(&mut request).unwrap();
+
+            // The response is the request with the QR bit and the rcode set
+            let mut response = request;
+            response[2] |= 0x80;
+            response[3] = (response[3] & 0xf0) | Rcode::NOTAUTH.to_int();
+            stream.write_all(&len).unwrap();
+            stream.write_all(&response).unwrap();
+        });
+
+        let mut builder = MessageBuilder::new_bytes();
+        builder.header_mut().set_id(0xcafe);
+        builder.header_mut().set_opcode(Opcode::UPDATE);
+        let request = builder.into_message();
+
+        assert_eq!(
+            forward_update(&config, &request).await.unwrap(),
+            Rcode::NOTAUTH
+        );
+        answered.join().unwrap();
+    }
+}
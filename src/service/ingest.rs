@@ -0,0 +1,114 @@
+//! Entry point running raw wire messages through the middleware chain.
+//!
+//! This does not need any socket which makes it suitable for fuzzers and
+//! property tests, e.g. with AFL reading the message from stdin:
+//!
+//!   afl-fuzz -i corpus -o findings -- dnsr ingest udp
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use domain::base::Message;
+use domain::net::server::message::{
+    NonUdpTransportContext, Request, TransportSpecificContext, UdpTransportContext,
+};
+use domain::net::server::service::Service;
+use futures::StreamExt;
+use tokio::time::Instant;
+
+use super::middleware::Stats;
+use super::{middleware_chain, Dnsr, DnsrSvc};
+use crate::error;
+use crate::error::Result;
+use crate::key::{build_zones, SystemClock};
+
+/// The transport a message is pretended to be received on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    fn context(self) -> TransportSpecificContext {
+        match self {
+            Transport::Udp => TransportSpecificContext::Udp(UdpTransportContext::new(None)),
+            Transport::Tcp => TransportSpecificContext::NonUdp(NonUdpTransportContext::new(None)),
+        }
+    }
+}
+
+impl std::str::FromStr for Transport {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            _ => Err(error!(Ingest => "unknown transport {}", s)),
+        }
+    }
+}
+
+/// Runs the wire message `bytes` through `svc` and returns every wire response.
+///
+/// Messages too short to hold a header are rejected before reaching the
+/// service, just like the servers do.
+pub async fn ingest<Svc>(
+    svc: &Svc,
+    bytes: &[u8],
+    client_addr: SocketAddr,
+    transport: Transport,
+) -> Result<Vec<Vec<u8>>>
+where
+    Svc: Service<Vec<u8>>,
+    Svc::Target: AsRef<[u8]>,
+{
+    let message = Message::from_octets(bytes.to_vec())
+        .map_err(|e| error!(Ingest => "invalid message: {}", e))?;
+    let request = Request::new(client_addr, Instant::now(), message, transport.context());
+
+    let responses = svc
+        .call(request)
+        .await
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .filter_map(|item| match item {
+            Ok(item) => item.response().map(|r| r.as_slice().to_vec()),
+            Err(e) => {
+                log::debug!(target: "ingest", "service error: {}", e);
+                None
+            }
+        })
+        .collect();
+
+    Ok(responses)
+}
+
+/// Runs the message read from stdin through the middleware chain of `dnsr`.
+///
+/// The zones are loaded from the configuration without any key so that no
+/// file is written, each response is written to stdout with a two bytes
+/// length prefix like on TCP.
+pub async fn ingest_stdin(dnsr: Arc<Dnsr>, transport: Transport) -> Result<()> {
+    for (name, info) in dnsr.config.keys.domains() {
+        for zone in build_zones(name, info, &SystemClock)? {
+            dnsr.zones.insert_zone(zone)?;
+        }
+    }
+    let svc: DnsrSvc = middleware_chain(dnsr, Stats::new_shared());
+
+    let mut bytes = Vec::new();
+    std::io::stdin().read_to_end(&mut bytes)?;
+
+    let client_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut stdout = std::io::stdout().lock();
+    for response in ingest(&svc, &bytes, client_addr, transport).await? {
+        stdout.write_all(&(response.len() as u16).to_be_bytes())?;
+        stdout.write_all(&response)?;
+    }
+
+    Ok(())
+}
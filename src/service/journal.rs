@@ -0,0 +1,333 @@
+//! The journal of the changes of the zones.
+//!
+//! Every committed update of a zone is recorded with the RRsets it changed
+//! and the SOA serial it produced. The journal of a zone only keeps its last
+//! entries, up to a number of entries and a size of record data, so that it
+//! can back incremental transfers, audits and rollbacks without growing
+//! unbounded.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use domain::base::rdata::ComposeRecordData;
+use domain::base::{Rtype, ToName, Ttl};
+use domain::zonetree::types::{StoredName, StoredRecordData};
+
+use crate::config::JournalConfig;
+use crate::serial::{serial_cmp, soa_serial};
+use crate::store::ZoneRecords;
+
+/// The change of a single RRset, identified by its type and ttl.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RrsetChange {
+    pub rtype: Rtype,
+    pub ttl: Ttl,
+    pub removed: Vec<StoredRecordData>,
+    pub added: Vec<StoredRecordData>,
+}
+
+/// A committed update of a zone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    /// The serial of the zone before the update
+    pub from_serial: u32,
+    /// The serial produced by the update
+    pub serial: u32,
+    pub committed_at: SystemTime,
+    pub changes: Vec<RrsetChange>,
+}
+
+impl JournalEntry {
+    /// The size of the record data of the entry in wire format.
+    fn len(&self) -> usize {
+        self.changes
+            .iter()
+            .flat_map(|change| change.removed.iter().chain(&change.added))
+            .map(|data| {
+                let mut rdata = Vec::new();
+                let Ok(()) = data.compose_rdata(&mut rdata);
+                rdata.len()
+            })
+            .sum()
+    }
+}
+
+impl std::fmt::Display for JournalEntry {
+    /// One `serial <from> <to> <unix time>` line followed by one line per
+    /// removed (`-`) or added (`+`) record.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "serial {} {} {}",
+            self.from_serial,
+            self.serial,
+            crate::time::unix_secs(self.committed_at)
+        )?;
+        for change in &self.changes {
+            for data in &change.removed {
+                writeln!(f, "- {} {} {}", change.rtype, change.ttl.as_secs(), data)?;
+            }
+            for data in &change.added {
+                writeln!(f, "+ {} {} {}", change.rtype, change.ttl.as_secs(), data)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    config: JournalConfig,
+    zones: Mutex<HashMap<StoredName, ZoneJournal>>,
+}
+
+#[derive(Debug, Default)]
+struct ZoneJournal {
+    entries: VecDeque<JournalEntry>,
+    /// The size of the record data of the entries
+    len: usize,
+}
+
+impl ZoneJournal {
+    /// Appends `entry`, the oldest entries are dropped once the retention
+    /// limits are exceeded.
+    fn push(&mut self, entry: JournalEntry, config: &JournalConfig) {
+        self.len += entry.len();
+        self.entries.push_back(entry);
+
+        // The last entry is always kept, even if larger than the limit
+        while self.entries.len() > 1
+            && (self.entries.len() > config.max_entries() || self.len > config.max_bytes())
+        {
+            if let Some(oldest) = self.entries.pop_front() {
+                self.len -= oldest.len();
+            }
+        }
+    }
+}
+
+impl Journal {
+    pub fn new(config: JournalConfig) -> Self {
+        Self {
+            config,
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the update of the zone `apex` from `before` to `after`, the
+    /// oldest entries are dropped once the retention limits are exceeded.
+    pub fn record<N>(&self, apex: &N, before: &ZoneRecords, after: &ZoneRecords)
+    where
+        N: ToName,
+    {
+        let (Some(from_serial), Some(serial)) = (soa_serial(before), soa_serial(after)) else {
+            return;
+        };
+        let entry = JournalEntry {
+            from_serial,
+            serial,
+            committed_at: SystemTime::now(),
+            changes: diff(before, after),
+        };
+        if entry.changes.is_empty() || self.config.max_entries() == 0 {
+            return;
+        }
+
+        let mut zones = self.zones.lock().unwrap();
+        let journal = zones.entry(apex.to_bytes()).or_default();
+        journal.push(entry, &self.config);
+    }
+
+    /// Replaces the entries of the zone `apex` by `entries`, the oldest first,
+    /// e.g. with the entries of a snapshot.
+    pub fn restore<N>(&self, apex: &N, entries: Vec<JournalEntry>)
+    where
+        N: ToName,
+    {
+        if self.config.max_entries() == 0 {
+            return;
+        }
+
+        let mut journal = ZoneJournal::default();
+        for entry in entries {
+            journal.push(entry, &self.config);
+        }
+        self.zones.lock().unwrap().insert(apex.to_bytes(), journal);
+    }
+
+    /// Returns the entries of the zone `apex` leading from `serial` to its
+    /// last serial, as an incremental transfer from `serial` sends them, or
+    /// `None` if the journal does not reach back to `serial`.
+    pub fn entries_since<N>(&self, apex: &N, serial: u32) -> Option<Vec<JournalEntry>>
+    where
+        N: ToName,
+    {
+        let entries = self.entries(apex);
+        match serial_cmp(serial, entries.last()?.serial)? {
+            Ordering::Equal => return Some(Vec::new()),
+            // A serial ahead of the zone was never served
+            Ordering::Greater => return None,
+            Ordering::Less => (),
+        }
+
+        let since = entries
+            .into_iter()
+            .skip_while(|entry| serial_cmp(entry.from_serial, serial) == Some(Ordering::Less))
+            .collect::<Vec<_>>();
+        (since.first()?.from_serial == serial).then_some(since)
+    }
+
+    /// Returns the zones with retained entries.
+    pub fn apex_names(&self) -> Vec<StoredName> {
+        let zones = self.zones.lock().unwrap();
+        zones
+            .iter()
+            .filter(|(_, journal)| !journal.entries.is_empty())
+            .map(|(apex, _)| apex.clone())
+            .collect()
+    }
+
+    /// Returns the retained entries of the zone `apex`, the oldest first.
+    pub fn entries<N>(&self, apex: &N) -> Vec<JournalEntry>
+    where
+        N: ToName,
+    {
+        let zones = self.zones.lock().unwrap();
+        zones
+            .get(&apex.to_bytes())
+            .map(|journal| journal.entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Returns the changes of the non SOA RRsets between `before` and `after`.
+fn diff(before: &ZoneRecords, after: &ZoneRecords) -> Vec<RrsetChange> {
+    let mut keys = before.keys().chain(after.keys()).collect::<Vec<_>>();
+    keys.sort_by_key(|(rtype, ttl)| (rtype.to_int(), ttl.as_secs()));
+    keys.dedup();
+
+    keys.into_iter()
+        .filter(|(rtype, _)| *rtype != Rtype::SOA)
+        .filter_map(|key| {
+            let old = before.get(key).map(Vec::as_slice).unwrap_or_default();
+            let new = after.get(key).map(Vec::as_slice).unwrap_or_default();
+            let change = RrsetChange {
+                rtype: key.0,
+                ttl: key.1,
+                removed: old.iter().filter(|d| !new.contains(d)).cloned().collect(),
+                added: new.iter().filter(|d| !old.contains(d)).cloned().collect(),
+            };
+            (!change.removed.is_empty() || !change.added.is_empty()).then_some(change)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use domain::base::Serial;
+    use domain::rdata::{Soa, Txt};
+
+    use super::*;
+
+    fn records(serial: u32, texts: &[&str]) -> ZoneRecords {
+        let name = StoredName::bytes_from_str("ns-acme.example.fr").unwrap();
+        let soa = Soa::new(
+            name.clone(),
+            name,
+            Serial::from(serial),
+            Ttl::HOUR,
+            Ttl::HOUR,
+            Ttl::HOUR,
+            Ttl::HOUR,
+        );
+
+        let mut records = ZoneRecords::new();
+        records.insert((Rtype::SOA, Ttl::HOUR), vec![soa.into()]);
+        records.insert(
+            (Rtype::TXT, Ttl::from_secs(60)),
+            texts
+                .iter()
+                .map(|text| Txt::build_from_slice(text.as_bytes()).unwrap().into())
+                .collect(),
+        );
+        records
+    }
+
+    #[test]
+    fn changes_are_recorded_with_their_serial() {
+        let config = serde_yaml::from_str("{ max_entries: 2 }").unwrap();
+        let journal = Journal::new(config);
+        let apex = StoredName::bytes_from_str("_acme-challenge.example.fr").unwrap();
+
+        journal.record(&apex, &records(1, &[]), &records(2, &["a"]));
+        journal.record(&apex, &records(2, &["a"]), &records(3, &["a", "b"]));
+        journal.record(&apex, &records(3, &["a", "b"]), &records(4, &["b"]));
+
+        let entries = journal.entries(&apex);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].from_serial, entries[0].serial), (2, 3));
+        assert_eq!(entries[1].serial, 4);
+        assert_eq!(entries[1].changes.len(), 1);
+        assert_eq!(entries[1].changes[0].removed.len(), 1);
+        assert!(entries[1].changes[0].added.is_empty());
+        assert!(entries[1].to_string().starts_with("serial 3 4 "));
+        assert!(entries[1].to_string().contains("\n- TXT 60 "));
+    }
+
+    #[test]
+    fn entries_since_a_serial_are_looked_up() {
+        let config = serde_yaml::from_str("{ max_entries: 2 }").unwrap();
+        let journal = Journal::new(config);
+        let apex = StoredName::bytes_from_str("_acme-challenge.example.fr").unwrap();
+        assert_eq!(journal.entries_since(&apex, 1), None);
+
+        // The serials wrap around
+        journal.record(
+            &apex,
+            &records(u32::MAX - 1, &[]),
+            &records(u32::MAX, &["a"]),
+        );
+        journal.record(&apex, &records(u32::MAX, &["a"]), &records(0, &["a", "b"]));
+        journal.record(&apex, &records(0, &["a", "b"]), &records(1, &["b"]));
+
+        let serials = |entries: Vec<JournalEntry>| {
+            entries
+                .iter()
+                .map(|entry| (entry.from_serial, entry.serial))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            journal.entries_since(&apex, u32::MAX).map(serials),
+            Some(vec![(u32::MAX, 0), (0, 1)])
+        );
+        assert_eq!(
+            journal.entries_since(&apex, 0).map(serials),
+            Some(vec![(0, 1)])
+        );
+        assert_eq!(journal.entries_since(&apex, 1), Some(Vec::new()));
+
+        // The dropped entries and the serials never served
+        assert_eq!(journal.entries_since(&apex, u32::MAX - 1), None);
+        assert_eq!(journal.entries_since(&apex, 2), None);
+    }
+
+    #[test]
+    fn oldest_entries_are_dropped_over_the_size_limit() {
+        let config = serde_yaml::from_str("{ max_bytes: 11 }").unwrap();
+        let journal = Journal::new(config);
+        let apex = StoredName::bytes_from_str("_acme-challenge.example.fr").unwrap();
+
+        journal.record(&apex, &records(1, &[]), &records(2, &["12345"]));
+        journal.record(
+            &apex,
+            &records(2, &["12345"]),
+            &records(3, &["12345", "6789"]),
+        );
+        assert_eq!(journal.entries(&apex).len(), 2);
+
+        journal.record(&apex, &records(3, &["12345", "6789"]), &records(4, &[]));
+        assert_eq!(journal.entries(&apex).len(), 1);
+    }
+}
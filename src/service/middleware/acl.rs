@@ -0,0 +1,98 @@
+use core::future::{ready, Ready};
+
+use std::sync::Arc;
+
+use domain::base::iana::Rcode;
+use domain::base::wire::Composer;
+use domain::base::ToName;
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use domain::net::server::service::{CallResult, Service, ServiceResult};
+use domain::net::server::util::mk_builder_for_target;
+use domain::zonetree::Answer;
+use futures::stream::{once, Once};
+
+use crate::config::AclConfig;
+use crate::dname::DomainName;
+use crate::geo::GeoDb;
+use crate::service::Zones;
+
+/// Refuses the requests of the clients denied by the access lists of the
+/// configuration before they reach the inner service.
+#[derive(Clone)]
+pub struct AclMiddlewareSvc<Svc> {
+    acl: Arc<AclConfig>,
+    geo: Option<Arc<GeoDb>>,
+    zones: Arc<Zones>,
+    svc: Svc,
+}
+
+impl<Svc> AclMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc, acl: AclConfig, geo: Option<Arc<GeoDb>>, zones: Arc<Zones>) -> Self {
+        Self {
+            svc,
+            acl: Arc::new(acl),
+            geo,
+            zones,
+        }
+    }
+
+    fn preprocess<RequestOctets>(&self, request: &Request<RequestOctets>) -> bool
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+    {
+        let addr = request.client_addr().ip();
+        // The zone lists only apply to the requests with a single question,
+        // the list of the zone serving the question applies to every name
+        // below its apex
+        let domain = request.message().sole_question().ok().map(|q| {
+            match self.zones.enclosing_apex_name(q.qname()) {
+                Some(apex) => DomainName::from_name(&apex),
+                None => DomainName::from_name(q.qname()),
+            }
+        });
+
+        let geo = self.geo.as_ref().and_then(|geo| geo.lookup(addr));
+        let allowed = self.acl.allows(addr, geo, domain.as_ref());
+        if !allowed {
+            log::info!(target: "acl", "refused request from {}", addr);
+        }
+        allowed
+    }
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for AclMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Target: Composer + Default,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, ()>,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        if !self.preprocess(&request) {
+            let answer = Answer::new(Rcode::REFUSED);
+            let builder = mk_builder_for_target();
+            let response = answer.to_message(request.message(), builder);
+            return ready(MiddlewareStream::Result(once(ready(Ok(CallResult::new(
+                response,
+            ))))));
+        }
+
+        let svc_call_fut = self.svc.call(request.clone());
+        let map = PostprocessingStream::new(svc_call_fut, request, (), |_, item, _| item);
+        ready(MiddlewareStream::Map(map))
+    }
+}
@@ -0,0 +1,70 @@
+use core::future::{ready, Ready};
+
+use std::sync::Arc;
+
+use domain::base::iana::Rcode;
+use domain::base::wire::Composer;
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use domain::net::server::service::{CallResult, Service, ServiceResult};
+use domain::net::server::util::mk_builder_for_target;
+use domain::zonetree::Answer;
+use futures::stream::{iter, Iter};
+
+use crate::blocklist::Blocklist;
+use crate::config::BlocklistAction;
+
+/// Drops or refuses the requests of the blocked clients before they reach the
+/// inner service, the blocklist is shared with the watcher reloading it.
+#[derive(Clone)]
+pub struct BlocklistMiddlewareSvc<Svc> {
+    blocklist: Arc<Blocklist>,
+    svc: Svc,
+}
+
+impl<Svc> BlocklistMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc, blocklist: Arc<Blocklist>) -> Self {
+        Self { svc, blocklist }
+    }
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for BlocklistMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Target: Composer + Default,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, ()>,
+        Iter<std::option::IntoIter<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        if let Some(action) = self.blocklist.check(request.client_addr().ip()) {
+            log::debug!(target: "blocklist", "blocked request from {}", request.client_addr());
+            let response = match action {
+                BlocklistAction::Drop => None,
+                BlocklistAction::Refuse => {
+                    let answer = Answer::new(Rcode::REFUSED);
+                    Some(answer.to_message(request.message(), mk_builder_for_target()))
+                }
+            };
+            return ready(MiddlewareStream::Result(iter(
+                response.map(|response| Ok(CallResult::new(response))),
+            )));
+        }
+
+        let svc_call_fut = self.svc.call(request.clone());
+        let map = PostprocessingStream::new(svc_call_fut, request, (), |_, item, _| item);
+        ready(MiddlewareStream::Map(map))
+    }
+}
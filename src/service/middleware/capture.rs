@@ -0,0 +1,78 @@
+use core::future::{ready, Ready};
+
+use std::sync::Arc;
+
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use domain::net::server::service::{Service, ServiceResult};
+use futures::stream::Empty;
+
+use crate::service::capture::WireCapture;
+
+/// Records the raw queries and responses in the wire capture, if enabled.
+#[derive(Clone)]
+pub struct CaptureMiddlewareSvc<Svc> {
+    capture: Option<Arc<WireCapture>>,
+    svc: Svc,
+}
+
+impl<Svc> CaptureMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc, capture: Option<Arc<WireCapture>>) -> Self {
+        Self { svc, capture }
+    }
+
+    fn map_stream_item<RequestOctets>(
+        _request: Request<RequestOctets>,
+        stream_item: ServiceResult<Svc::Target>,
+        exchange: Option<(Arc<WireCapture>, u64)>,
+    ) -> ServiceResult<Svc::Target>
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+        Svc: Service<RequestOctets>,
+        Svc::Target: AsRef<[u8]>,
+    {
+        if let (Ok(cr), Some((capture, id))) = (&stream_item, exchange) {
+            if let Some(response) = cr.response() {
+                capture.record_response(id, response.as_slice());
+            }
+        }
+        stream_item
+    }
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for CaptureMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Target: AsRef<[u8]>,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<
+            RequestOctets,
+            Svc::Future,
+            Svc::Stream,
+            Option<(Arc<WireCapture>, u64)>,
+        >,
+        Empty<ServiceResult<Self::Target>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        let exchange = self.capture.as_ref().map(|capture| {
+            let id = capture.record_query(request.client_addr(), request.message().as_slice());
+            (capture.clone(), id)
+        });
+
+        let svc_call_fut = self.svc.call(request.clone());
+        let map = PostprocessingStream::new(svc_call_fut, request, exchange, Self::map_stream_item);
+        ready(MiddlewareStream::Map(map))
+    }
+}
@@ -0,0 +1,302 @@
+use core::future::{ready, Ready};
+
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use domain::base::iana::{Class, Rcode};
+use domain::base::message_builder::AdditionalBuilder;
+use domain::base::rdata::RecordData;
+use domain::base::wire::Composer;
+use domain::base::{Rtype, Serial, StreamTarget, ToName, Ttl};
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use domain::net::server::service::{CallResult, Service, ServiceResult};
+use domain::net::server::util::mk_builder_for_target;
+use domain::rdata::{Ns, Ptr, Soa, Txt, ZoneRecordData};
+use domain::zonetree::types::StoredName;
+use futures::stream::{once, Once};
+
+use crate::config::CatalogConfig;
+use crate::dname::DomainName;
+use crate::key::TryInto;
+use crate::serial::{is_newer, serial_add};
+use crate::service::Dnsr;
+
+/// The version of the catalog zones schema, see RFC 9432 section 4.2.
+const SCHEMA_VERSION: &str = "2";
+
+type CatalogRecord = (StoredName, ZoneRecordData<Bytes, StoredName>);
+
+/// Serves the catalog zone listing every zone of dnsr as described in RFC
+/// 9432, if enabled, so that the secondaries provision the zones on their own.
+///
+/// The catalog is built from the served zones on every request, its serial is
+/// bumped whenever the list of zones changes. It is transferred in a single
+/// message and only without TSIG, the transfer policy of its domain applies.
+#[derive(Clone)]
+pub struct CatalogMiddlewareSvc<Svc> {
+    apex: Option<StoredName>,
+    dnsr: Arc<Dnsr>,
+    /// The members of the last catalog served and its serial
+    state: Arc<Mutex<(Vec<StoredName>, u32)>>,
+    svc: Svc,
+}
+
+impl<Svc> CatalogMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc, dnsr: Arc<Dnsr>, config: Option<&CatalogConfig>) -> Self {
+        let apex = config.and_then(|config| match config.apex() {
+            Ok(apex) => Some(apex),
+            Err(e) => {
+                log::error!(target: "catalog", "the catalog zone is disabled: {}", e);
+                None
+            }
+        });
+        let serial = crate::time::unix_secs(SystemTime::now()) as u32;
+
+        Self {
+            svc,
+            dnsr,
+            apex,
+            state: Arc::new(Mutex::new((Vec::new(), serial))),
+        }
+    }
+
+    /// Returns the records of the catalog, the SOA first.
+    fn records(&self, apex: &StoredName) -> Option<Vec<CatalogRecord>> {
+        let mut members = self.dnsr.zones.apex_names();
+        members.retain(|member| member != apex);
+        members.sort();
+
+        let serial = {
+            let mut state = self.state.lock().unwrap();
+            if state.0 != members {
+                let now = crate::time::unix_secs(SystemTime::now()) as u32;
+                state.1 = if is_newer(now, state.1) {
+                    now
+                } else {
+                    serial_add(state.1, 1)?
+                };
+                state.0.clone_from(&members);
+            }
+            state.1
+        };
+
+        let invalid: StoredName = "invalid.".try_into_t().ok()?;
+        let soa = Soa::new(
+            invalid.clone(),
+            invalid.clone(),
+            Serial::from(serial),
+            Ttl::from_secs(10800),
+            Ttl::HOUR,
+            Ttl::from_secs(605800),
+            Ttl::HOUR,
+        );
+        let version: StoredName = format!("version.{}", apex).try_into_t().ok()?;
+        let txt = Txt::<Bytes>::build_from_slice(SCHEMA_VERSION.as_bytes()).ok()?;
+
+        let mut records = vec![
+            (apex.clone(), soa.into()),
+            (apex.clone(), Ns::new(invalid).into()),
+            (version, txt.into()),
+        ];
+        for member in members {
+            let owner = format!("{}.zones.{}", member_id(&member), apex)
+                .try_into_t()
+                .ok()?;
+            records.push((owner, Ptr::new(member).into()));
+        }
+        Some(records)
+    }
+
+    fn answer<RequestOctets>(
+        &self,
+        request: &Request<RequestOctets>,
+    ) -> Option<AdditionalBuilder<StreamTarget<Svc::Target>>>
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+        Svc: Service<RequestOctets>,
+        Svc::Target: Composer + Default,
+    {
+        let apex = self.apex.as_ref()?;
+        let question = request.message().sole_question().ok()?;
+        let qname = question.qname();
+        if !qname.ends_with(apex) {
+            return None;
+        }
+
+        let qtype = question.qtype();
+        let axfr = qtype == Rtype::AXFR && qname.name_eq(apex);
+        let builder = mk_builder_for_target();
+        if axfr && !self.allows_transfer(request, apex) {
+            let rcode = if request.transport_ctx().is_udp() {
+                Rcode::NOTIMP
+            } else {
+                Rcode::REFUSED
+            };
+            let answer = builder.start_answer(request.message(), rcode).ok()?;
+            return Some(answer.additional());
+        }
+
+        // The names holding no record but some below are empty non-terminals
+        let records = self.records(apex)?;
+        let rcode = if records.iter().any(|(owner, _)| owner.ends_with(&qname)) {
+            Rcode::NOERROR
+        } else {
+            Rcode::NXDOMAIN
+        };
+        let mut answer = builder.start_answer(request.message(), rcode).ok()?;
+        answer.header_mut().set_aa(true);
+
+        if axfr {
+            // The whole catalog between two SOA records, see RFC 5936 section 2.2
+            for (owner, data) in records.iter().chain(records.first()) {
+                answer
+                    .push((owner, Class::IN, Ttl::from_secs(0), data))
+                    .ok()?;
+            }
+            return Some(answer.additional());
+        }
+
+        let mut found = false;
+        for (owner, data) in records.iter().filter(|(owner, data)| {
+            owner.name_eq(&qname) && (qtype == Rtype::ANY || data.rtype() == qtype)
+        }) {
+            found = true;
+            answer
+                .push((owner, Class::IN, Ttl::from_secs(0), data))
+                .ok()?;
+        }
+
+        // Negative answers carry the SOA, see RFC 2308 section 3
+        let mut authority = answer.authority();
+        if !found {
+            if let Some((owner, soa)) = records.first() {
+                authority
+                    .push((owner, Class::IN, Ttl::from_secs(0), soa))
+                    .ok()?;
+            }
+        }
+        Some(authority.additional())
+    }
+
+    /// Returns whether the catalog may be transferred to the client of
+    /// `request`, only over TCP and without TSIG.
+    fn allows_transfer<RequestOctets>(
+        &self,
+        request: &Request<RequestOctets>,
+        apex: &StoredName,
+    ) -> bool
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+    {
+        !request.transport_ctx().is_udp()
+            && self.dnsr.config.acl_config().allows_transfer(
+                request.client_addr().ip(),
+                &DomainName::from_name(apex),
+                || false,
+            )
+    }
+}
+
+/// Returns the unique label of the member zone `apex`, derived from its name
+/// so that it is stable across restarts and instances.
+fn member_id(apex: &StoredName) -> String {
+    let name = apex.to_string().to_ascii_lowercase();
+    let digest = ring::digest::digest(&ring::digest::SHA256, name.as_bytes());
+    digest.as_ref()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for CatalogMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Target: Composer + Default,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, ()>,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        if let Some(response) = self.answer(&request) {
+            return ready(MiddlewareStream::Result(once(ready(Ok(CallResult::new(
+                response,
+            ))))));
+        }
+
+        let svc_call_fut = self.svc.call(request.clone());
+        let map = PostprocessingStream::new(svc_call_fut, request, (), |_, item, _| item);
+        ready(MiddlewareStream::Map(map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use domain::base::ParsedName;
+
+    use super::*;
+    use crate::service::ingest::Transport;
+    use crate::service::middleware::Stats;
+    use crate::service::middleware_chain;
+    use crate::service::testing::{
+        answer_types, call_svc, dnsr_from, query, texts, CLIENT, CONFIG,
+    };
+
+    #[test]
+    fn catalog_lists_the_served_zones() {
+        let dnsr = dnsr_from(&format!(
+            "catalog:\n  name: catalog.ns1.example.fr{}",
+            CONFIG
+        ));
+        let svc = middleware_chain(dnsr, Stats::new_shared());
+        let call = |qname: &str, qtype: Rtype, transport: Transport| {
+            let mut responses = call_svc(&svc, &query(qname, qtype), CLIENT, transport);
+            responses.remove(0)
+        };
+
+        let response = call("catalog.ns1.example.fr.", Rtype::AXFR, Transport::Udp);
+        assert_eq!(response.header().rcode(), Rcode::NOTIMP);
+
+        let response = call("catalog.ns1.example.fr.", Rtype::AXFR, Transport::Tcp);
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        let rtypes = answer_types(&response);
+        assert_eq!(rtypes.first(), Some(&Rtype::SOA));
+        assert_eq!(rtypes.last(), Some(&Rtype::SOA));
+        let members = response
+            .answer()
+            .unwrap()
+            .limit_to::<Ptr<ParsedName<_>>>()
+            .map(|record| record.unwrap().data().ptrdname().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(members.len(), 2);
+        assert!(members.contains(&"_acme-challenge.example.fr".to_owned()));
+
+        let response = call(
+            "version.catalog.ns1.example.fr.",
+            Rtype::TXT,
+            Transport::Udp,
+        );
+        assert!(response.header().aa());
+        assert_eq!(texts(&response), [SCHEMA_VERSION]);
+
+        let response = call(
+            "unknown.catalog.ns1.example.fr.",
+            Rtype::TXT,
+            Transport::Udp,
+        );
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+    }
+}
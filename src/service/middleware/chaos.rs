@@ -0,0 +1,220 @@
+use core::future::{ready, Ready};
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use domain::base::iana::{Class, Rcode};
+use domain::base::message_builder::AdditionalBuilder;
+use domain::base::wire::Composer;
+use domain::base::{Rtype, StreamTarget, ToName, Ttl};
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use domain::net::server::service::{CallResult, Service, ServiceResult};
+use domain::net::server::util::mk_builder_for_target;
+use domain::rdata::{Txt, ZoneRecordData};
+use domain::zonetree::types::StoredName;
+use futures::stream::{once, Once};
+
+use crate::config::ChaosConfig;
+use crate::key::TryInto;
+use crate::service::Dnsr;
+
+/// The apex of the debug tree.
+const APEX: &str = "dnsr.";
+
+/// The names of the debug tree, below the apex, and their TXT records.
+const ENTRIES: &[(&str, fn(&Dnsr) -> Vec<String>)] = &[
+    ("version", version),
+    ("role", role),
+    ("zones", zones),
+    ("watcher", watcher),
+];
+
+/// Answers the CHAOS class TXT queries of the `dnsr.` tree with the internal
+/// state of the instance, if enabled, e.g. `dig CH TXT zones.dnsr`.
+///
+/// The tree is only answered to the clients of the allow list of its
+/// configuration, the others are refused.
+#[derive(Clone)]
+pub struct ChaosMiddlewareSvc<Svc> {
+    config: Option<ChaosConfig>,
+    dnsr: Arc<Dnsr>,
+    svc: Svc,
+}
+
+impl<Svc> ChaosMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc, dnsr: Arc<Dnsr>, config: Option<&ChaosConfig>) -> Self {
+        Self {
+            svc,
+            dnsr,
+            config: config.cloned(),
+        }
+    }
+
+    fn answer<RequestOctets>(
+        &self,
+        request: &Request<RequestOctets>,
+    ) -> Option<AdditionalBuilder<StreamTarget<Svc::Target>>>
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+        Svc: Service<RequestOctets>,
+        Svc::Target: Composer + Default,
+    {
+        let config = self.config.as_ref()?;
+        let question = request.message().sole_question().ok()?;
+        let qname = question.qname();
+        let apex: StoredName = APEX.try_into_t().ok()?;
+        if question.qclass() != Class::CH || !qname.ends_with(&apex) {
+            return None;
+        }
+
+        let builder = mk_builder_for_target();
+        if !config.allows(request.client_addr().ip()) {
+            log::info!(target: "chaos", "debug query of {} from {} refused", qname, request.client_addr());
+            let answer = builder
+                .start_answer(request.message(), Rcode::REFUSED)
+                .ok()?;
+            return Some(answer.additional());
+        }
+
+        let entry = ENTRIES.iter().find_map(|(label, texts)| {
+            let name: StoredName = format!("{}.{}", label, APEX).try_into_t().ok()?;
+            qname.name_eq(&name).then_some(texts)
+        });
+        let rcode = if entry.is_some() || qname.name_eq(&apex) {
+            Rcode::NOERROR
+        } else {
+            Rcode::NXDOMAIN
+        };
+
+        let mut answer = builder.start_answer(request.message(), rcode).ok()?;
+        answer.header_mut().set_aa(true);
+        if let (Some(texts), Rtype::TXT | Rtype::ANY) = (entry, question.qtype()) {
+            for text in texts(self.dnsr.as_ref()) {
+                let txt = Txt::<Bytes>::build_from_slice(text.as_bytes()).ok()?;
+                answer
+                    .push((qname.clone(), Class::CH, Ttl::from_secs(0), txt))
+                    .ok()?;
+            }
+        }
+        Some(answer.additional())
+    }
+}
+
+fn version(_: &Dnsr) -> Vec<String> {
+    vec![format!("dnsr {}", env!("CARGO_PKG_VERSION"))]
+}
+
+fn role(dnsr: &Dnsr) -> Vec<String> {
+    let role = if dnsr.is_standby() {
+        "standby"
+    } else {
+        "primary"
+    };
+    vec![role.into()]
+}
+
+/// One `<apex> serial=<serial>` record per served zone.
+fn zones(dnsr: &Dnsr) -> Vec<String> {
+    let mut apexes = dnsr.zones.apex_names();
+    apexes.sort();
+    apexes
+        .into_iter()
+        .map(|apex| {
+            let serial = dnsr
+                .zones
+                .records(&apex)
+                .into_iter()
+                .filter(|((rtype, _), _)| *rtype == Rtype::SOA)
+                .flat_map(|(_, data)| data)
+                .find_map(|data| match data {
+                    ZoneRecordData::Soa(soa) => Some(soa.serial().into_int()),
+                    _ => None,
+                });
+            match serial {
+                Some(serial) => format!("{} serial={}", apex, serial),
+                None => format!("{} serial=-", apex),
+            }
+        })
+        .collect()
+}
+
+/// The time elapsed since the last iteration of the configuration watcher.
+fn watcher(dnsr: &Dnsr) -> Vec<String> {
+    match dnsr.watcher_heartbeat() {
+        Some(elapsed) => vec![format!("heartbeat={}s", elapsed.as_secs())],
+        None => vec!["heartbeat=never".into()],
+    }
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for ChaosMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Target: Composer + Default,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, ()>,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        if let Some(response) = self.answer(&request) {
+            return ready(MiddlewareStream::Result(once(ready(Ok(CallResult::new(
+                response,
+            ))))));
+        }
+
+        let svc_call_fut = self.svc.call(request.clone());
+        let map = PostprocessingStream::new(svc_call_fut, request, (), |_, item, _| item);
+        ready(MiddlewareStream::Map(map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ingest::Transport;
+    use crate::service::middleware::Stats;
+    use crate::service::middleware_chain;
+    use crate::service::testing::{call_svc, class_query, dnsr_from, texts, CONFIG};
+
+    #[test]
+    fn internal_state_is_served_to_allowed_clients() {
+        let dnsr = dnsr_from(&format!("chaos:\n  allow: [127.0.0.1]{}", CONFIG));
+        let svc = middleware_chain(dnsr, Stats::new_shared());
+        let call = |qname: &str, client_addr: &str| {
+            let query = class_query(qname, Rtype::TXT, Class::CH);
+            let mut responses = call_svc(&svc, &query, client_addr, Transport::Udp);
+            responses.remove(0)
+        };
+
+        let response = call("role.dnsr.", "127.0.0.1:53000");
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(response.header().aa());
+        assert_eq!(texts(&response), ["primary"]);
+
+        let response = call("zones.dnsr.", "127.0.0.1:53000");
+        let zones = texts(&response);
+        assert_eq!(zones.len(), 2);
+        assert!(zones
+            .iter()
+            .any(|zone| zone.starts_with("_acme-challenge.example.fr serial=")));
+
+        let response = call("unknown.dnsr.", "127.0.0.1:53000");
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+
+        let response = call("zones.dnsr.", "192.0.2.1:53000");
+        assert_eq!(response.header().rcode(), Rcode::REFUSED);
+        assert!(texts(&response).is_empty());
+    }
+}
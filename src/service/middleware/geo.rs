@@ -0,0 +1,75 @@
+use core::future::{ready, Ready};
+
+use std::sync::{Arc, RwLock};
+
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use domain::net::server::service::{Service, ServiceResult};
+use futures::stream::Empty;
+
+use crate::geo::GeoDb;
+use crate::service::middleware::Stats;
+
+/// Tags the requests with the country and autonomous system of their client,
+/// if the geoip database is configured.
+///
+/// The tags are logged with the requests and counted per country in the
+/// metrics, the access lists look them up on their own.
+#[derive(Clone)]
+pub struct GeoMiddlewareSvc<Svc> {
+    geo: Option<Arc<GeoDb>>,
+    stats: Arc<RwLock<Stats>>,
+    svc: Svc,
+}
+
+impl<Svc> GeoMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc, geo: Option<Arc<GeoDb>>, stats: Arc<RwLock<Stats>>) -> Self {
+        Self { svc, geo, stats }
+    }
+
+    fn preprocess<RequestOctets>(&self, request: &Request<RequestOctets>)
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+    {
+        let Some(geo) = &self.geo else {
+            return;
+        };
+
+        let addr = request.client_addr();
+        match geo.lookup(addr.ip()) {
+            Some(info) => {
+                log::debug!(target: "geo", "request from {} ({})", addr, info);
+                self.stats.write().unwrap().record_country(&info.country);
+            }
+            None => log::debug!(target: "geo", "request from {} (untagged)", addr),
+        }
+    }
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for GeoMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, ()>,
+        Empty<ServiceResult<Self::Target>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        self.preprocess(&request);
+
+        let svc_call_fut = self.svc.call(request.clone());
+        let map = PostprocessingStream::new(svc_call_fut, request, (), |_, item, _| item);
+        ready(MiddlewareStream::Map(map))
+    }
+}
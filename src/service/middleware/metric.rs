@@ -2,17 +2,95 @@ use core::fmt;
 use core::future::{ready, Ready};
 use core::time::Duration;
 
+use std::collections::BTreeMap;
 use std::sync::{Arc, RwLock};
 
+use domain::base::iana::{Opcode, Rcode};
 use domain::base::message_builder::AdditionalBuilder;
-use domain::base::StreamTarget;
+use domain::base::{Header, Rtype, StreamTarget};
 use domain::dep::octseq::Octets;
 use domain::net::server::message::Request;
 use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
 use domain::net::server::service::{Service, ServiceResult};
+use domain::zonetree::types::StoredName;
 use futures::stream::Empty;
 use tokio::time::Instant;
 
+use crate::service::Zones;
+
+/// The number of sub-buckets of every power of two in the latency histogram.
+const SUB_BUCKETS: usize = 8;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+/// The durations are bucketed up to 2^32μs, above that they are all counted in
+/// the last bucket.
+const MAX_EXPONENT: u32 = 32;
+const NUM_BUCKETS: usize = SUB_BUCKETS * (MAX_EXPONENT - SUB_BUCKET_BITS + 1) as usize + 1;
+
+/// A latency histogram with log-linear buckets of the durations in
+/// microseconds.
+///
+/// Every power of two is split into linear sub-buckets, which bounds the error
+/// of the quantiles to 1/8 of the value.
+pub struct Histogram {
+    buckets: Box<[u32; NUM_BUCKETS]>,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: Box::new([0; NUM_BUCKETS]),
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        self.buckets[Self::bucket(micros)] += 1;
+        self.count += 1;
+    }
+
+    /// Returns the upper bound of the bucket of the `q` quantile, `None` if no
+    /// duration was recorded.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let rank = ((q * self.count as f64).ceil() as u64).clamp(1, self.count.max(1));
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += *count as u64;
+            if seen >= rank {
+                return Some(Duration::from_micros(Self::upper_bound(i)));
+            }
+        }
+        None
+    }
+
+    fn bucket(micros: u64) -> usize {
+        if micros < SUB_BUCKETS as u64 {
+            return micros as usize;
+        }
+
+        let exponent = (63 - micros.leading_zeros()).min(MAX_EXPONENT);
+        if exponent == MAX_EXPONENT {
+            return NUM_BUCKETS - 1;
+        }
+        let shift = exponent - SUB_BUCKET_BITS;
+        let sub = (micros >> shift) as usize - SUB_BUCKETS;
+        SUB_BUCKETS * (shift as usize + 1) + sub
+    }
+
+    fn upper_bound(bucket: usize) -> u64 {
+        if bucket < SUB_BUCKETS {
+            return bucket as u64;
+        }
+
+        let shift = (bucket / SUB_BUCKETS - 1) as u32;
+        let sub = (bucket % SUB_BUCKETS) as u64;
+        ((SUB_BUCKETS as u64 + sub + 1) << shift) - 1
+    }
+}
+
 #[derive(Default)]
 pub struct Stats {
     slowest_req: Option<Duration>,
@@ -23,53 +101,261 @@ pub struct Stats {
     num_ipv4: u32,
     num_ipv6: u32,
     num_udp: u32,
+    latency: Histogram,
+    rcodes: RcodeStats,
+    /// The queries per type of their question
+    qtypes: BTreeMap<u16, u32>,
+    update_failures: BTreeMap<&'static str, u32>,
+    /// The anomalous zone changes per kind of alert
+    alerts: BTreeMap<&'static str, u32>,
+    zones: BTreeMap<StoredName, ZoneStats>,
+    /// The requests per client country, when the clients are tagged
+    countries: BTreeMap<String, u32>,
+    /// The total delay between the reception and the handling of the UDP
+    /// requests since the last scaling of the workers, and their number
+    udp_queue_delay: Duration,
+    udp_queue_count: u32,
+}
+
+/// The responses per rcode, the rcodes without a counter of their own are
+/// counted together.
+#[derive(Default)]
+struct RcodeStats {
+    noerror: u32,
+    nxdomain: u32,
+    servfail: u32,
+    refused: u32,
+    notauth: u32,
+    other: u32,
+}
+
+impl RcodeStats {
+    fn record(&mut self, rcode: Rcode) {
+        let count = match rcode {
+            Rcode::NOERROR => &mut self.noerror,
+            Rcode::NXDOMAIN => &mut self.nxdomain,
+            Rcode::SERVFAIL => &mut self.servfail,
+            Rcode::REFUSED => &mut self.refused,
+            Rcode::NOTAUTH => &mut self.notauth,
+            _ => &mut self.other,
+        };
+        *count += 1;
+    }
+}
+
+impl std::fmt::Display for RcodeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "NOERROR={}, NXDOMAIN={}, SERVFAIL={}, REFUSED={}, NOTAUTH={}, other={}",
+            self.noerror, self.nxdomain, self.servfail, self.refused, self.notauth, self.other
+        )
+    }
+}
+
+/// The requests attributed to a zone.
+#[derive(Default)]
+struct ZoneStats {
+    queries: u32,
+    nxdomains: u32,
+    updates: u32,
 }
 
 impl Stats {
     pub fn new_shared() -> Arc<RwLock<Self>> {
         Arc::new(RwLock::new(Self::default()))
     }
+
+    /// Counts an update rejected because of the failed check `reason`.
+    pub fn record_update_failure(&mut self, reason: &'static str) {
+        *self.update_failures.entry(reason).or_default() += 1;
+    }
+
+    /// Counts an anomalous zone change of the alert `kind`.
+    pub fn record_alert(&mut self, kind: &'static str) {
+        *self.alerts.entry(kind).or_default() += 1;
+    }
+
+    /// Counts a request of a client of `country`.
+    pub fn record_country(&mut self, country: &str) {
+        match self.countries.get_mut(country) {
+            Some(count) => *count += 1,
+            None => {
+                self.countries.insert(country.to_owned(), 1);
+            }
+        }
+    }
+
+    /// Returns the mean queue delay of the UDP requests received since the last
+    /// call, `None` if there was none.
+    pub fn take_udp_queue_delay(&mut self) -> Option<Duration> {
+        let delay = std::mem::take(&mut self.udp_queue_delay);
+        let count = std::mem::take(&mut self.udp_queue_count);
+        (count > 0).then(|| delay / count)
+    }
+
+    /// Returns the counters of every zone which received a request.
+    pub fn zone_counters(&self) -> impl Iterator<Item = (&StoredName, String)> {
+        self.zones.iter().map(|(apex, zone)| {
+            let counters = format!(
+                "queries={} nxdomain={} updates={}",
+                zone.queries, zone.nxdomains, zone.updates
+            );
+            (apex, counters)
+        })
+    }
 }
 
-impl std::fmt::Display for Stats {
+/// The request and rcode counters and latencies of [`Stats`], without the
+/// per reason and per zone counters.
+pub struct Summary<'a>(pub &'a Stats);
+
+impl std::fmt::Display for Summary<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.0;
         write!(f, "# Reqs={} [UDP={}, IPv4={}, IPv6={}] Bytes [rx={}, tx={}] Speed [fastest={}, slowest={}]",
-            self.num_reqs,
-            self.num_udp,
-            self.num_ipv4,
-            self.num_ipv6,
-            self.num_req_bytes,
-            self.num_resp_bytes,
-            self.fastest_req.map(|v| format!("{}μs", v.as_micros())).unwrap_or_else(|| "-".to_string()),
-            self.slowest_req.map(|v| format!("{}ms", v.as_millis())).unwrap_or_else(|| "-".to_string()),
-    )
+            stats.num_reqs,
+            stats.num_udp,
+            stats.num_ipv4,
+            stats.num_ipv6,
+            stats.num_req_bytes,
+            stats.num_resp_bytes,
+            stats.fastest_req.map(|v| format!("{}μs", v.as_micros())).unwrap_or_else(|| "-".to_string()),
+            stats.slowest_req.map(|v| format!("{}ms", v.as_millis())).unwrap_or_else(|| "-".to_string()),
+        )?;
+
+        let quantile = |q| {
+            stats
+                .latency
+                .quantile(q)
+                .map(|v| format!("{}μs", v.as_micros()))
+                .unwrap_or_else(|| "-".to_string())
+        };
+        write!(
+            f,
+            " Latency [p50={}, p95={}, p99={}]",
+            quantile(0.5),
+            quantile(0.95),
+            quantile(0.99)
+        )?;
+
+        write!(f, " Rcodes [{}]", stats.rcodes)
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Summary(self))?;
+
+        write!(f, " Update failures [")?;
+        if self.update_failures.is_empty() {
+            write!(f, "-")?;
+        }
+        for (i, (reason, count)) in self.update_failures.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={}", reason, count)?;
+        }
+        write!(f, "]")?;
+
+        write!(f, " Alerts [")?;
+        if self.alerts.is_empty() {
+            write!(f, "-")?;
+        }
+        for (i, (kind, count)) in self.alerts.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={}", kind, count)?;
+        }
+        write!(f, "]")?;
+
+        write!(f, " Qtypes [")?;
+        if self.qtypes.is_empty() {
+            write!(f, "-")?;
+        }
+        for (i, (qtype, count)) in self.qtypes.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={}", Rtype::from_int(*qtype), count)?;
+        }
+        write!(f, "]")?;
+
+        write!(f, " Zones [")?;
+        if self.zones.is_empty() {
+            write!(f, "-")?;
+        }
+        for (i, (apex, zone)) in self.zones.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(
+                f,
+                "{} (queries={}, nxdomain={}, updates={})",
+                apex, zone.queries, zone.nxdomains, zone.updates
+            )?;
+        }
+        write!(f, "]")?;
+
+        if !self.countries.is_empty() {
+            write!(f, " Countries [")?;
+            for (i, (country, count)) in self.countries.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}={}", country, count)?;
+            }
+            write!(f, "]")?;
+        }
+
+        #[cfg(feature = "profiling")]
+        write!(f, " {}", crate::service::profiling::Report)?;
+
+        Ok(())
     }
 }
 
 #[derive(Clone)]
 pub struct MetricsMiddlewareSvc<Svc> {
     stats: Arc<RwLock<Stats>>,
+    zones: Arc<Zones>,
     svc: Svc,
 }
 
 impl<Svc> MetricsMiddlewareSvc<Svc> {
     /// Creates an instance of this processor.
     #[must_use]
-    pub fn new(svc: Svc, stats: Arc<RwLock<Stats>>) -> Self {
-        Self { svc, stats }
+    pub fn new(svc: Svc, stats: Arc<RwLock<Stats>>, zones: Arc<Zones>) -> Self {
+        Self { svc, stats, zones }
     }
 
-    fn preprocess<RequestOctets>(&self, request: &Request<RequestOctets>)
+    /// Counts the request and returns the apex of the zone it is attributed to.
+    fn preprocess<RequestOctets>(&self, request: &Request<RequestOctets>) -> Option<StoredName>
     where
         RequestOctets: Octets + Send + Sync + Unpin,
     {
+        // The zone section of an update is its question
+        let question = request.message().sole_question().ok();
+        let apex = question
+            .as_ref()
+            .and_then(|q| self.zones.enclosing_apex_name(q.qname()));
         let mut stats = self.stats.write().unwrap();
 
+        if let Some(question) = &question {
+            if request.message().header().opcode() == Opcode::QUERY {
+                *stats.qtypes.entry(question.qtype().to_int()).or_default() += 1;
+            }
+        }
+
         stats.num_reqs += 1;
         stats.num_req_bytes += request.message().as_slice().len() as u32;
 
         if request.transport_ctx().is_udp() {
             stats.num_udp += 1;
+            stats.udp_queue_delay += Instant::now().duration_since(request.received_at());
+            stats.udp_queue_count += 1;
         }
 
         if request.client_addr().is_ipv4() {
@@ -77,12 +363,23 @@ impl<Svc> MetricsMiddlewareSvc<Svc> {
         } else {
             stats.num_ipv6 += 1;
         }
+
+        if let Some(apex) = apex.as_ref() {
+            let zone = stats.zones.entry(apex.clone()).or_default();
+            if request.message().header().opcode() == Opcode::UPDATE {
+                zone.updates += 1;
+            } else {
+                zone.queries += 1;
+            }
+        }
+        apex
     }
 
     fn postprocess<RequestOctets>(
         request: &Request<RequestOctets>,
         response: &AdditionalBuilder<StreamTarget<Svc::Target>>,
         stats: Arc<RwLock<Stats>>,
+        apex: Option<&StoredName>,
     ) where
         RequestOctets: Octets + Send + Sync + Unpin,
         Svc: Service<RequestOctets>,
@@ -92,6 +389,15 @@ impl<Svc> MetricsMiddlewareSvc<Svc> {
         let mut stats = stats.write().unwrap();
 
         stats.num_resp_bytes += response.as_slice().len() as u32;
+        stats.latency.record(duration);
+
+        let rcode = Header::for_message_slice(response.as_slice()).rcode();
+        stats.rcodes.record(rcode);
+        if let Some(zone) = apex.and_then(|apex| stats.zones.get_mut(apex)) {
+            if rcode == Rcode::NXDOMAIN {
+                zone.nxdomains += 1;
+            }
+        }
 
         if duration < stats.fastest_req.unwrap_or(Duration::MAX) {
             stats.fastest_req = Some(duration);
@@ -104,7 +410,7 @@ impl<Svc> MetricsMiddlewareSvc<Svc> {
     fn map_stream_item<RequestOctets>(
         request: Request<RequestOctets>,
         stream_item: ServiceResult<Svc::Target>,
-        stats: Arc<RwLock<Stats>>,
+        (stats, apex): (Arc<RwLock<Stats>>, Option<StoredName>),
     ) -> ServiceResult<Svc::Target>
     where
         RequestOctets: Octets + Send + Sync + Unpin,
@@ -113,7 +419,7 @@ impl<Svc> MetricsMiddlewareSvc<Svc> {
     {
         if let Ok(cr) = &stream_item {
             if let Some(response) = cr.response() {
-                Self::postprocess(&request, response, stats);
+                Self::postprocess(&request, response, stats, apex.as_ref());
             }
         }
         stream_item
@@ -131,21 +437,79 @@ where
     type Stream = MiddlewareStream<
         Svc::Future,
         Svc::Stream,
-        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, Arc<RwLock<Stats>>>,
+        PostprocessingStream<
+            RequestOctets,
+            Svc::Future,
+            Svc::Stream,
+            (Arc<RwLock<Stats>>, Option<StoredName>),
+        >,
         Empty<ServiceResult<Self::Target>>,
         ServiceResult<Self::Target>,
     >;
     type Future = Ready<Self::Stream>;
 
     fn call(&self, request: Request<RequestOctets>) -> Self::Future {
-        self.preprocess(&request);
+        let apex = self.preprocess(&request);
         let svc_call_fut = self.svc.call(request.clone());
         let map = PostprocessingStream::new(
             svc_call_fut,
             request,
-            self.stats.clone(),
+            (self.stats.clone(), apex),
             Self::map_stream_item,
         );
         ready(MiddlewareStream::Map(map))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_are_counted_per_rcode() {
+        let mut rcodes = RcodeStats::default();
+        for rcode in [
+            Rcode::NOERROR,
+            Rcode::NOERROR,
+            Rcode::SERVFAIL,
+            Rcode::FORMERR,
+        ] {
+            rcodes.record(rcode);
+        }
+        assert_eq!(
+            rcodes.to_string(),
+            "NOERROR=2, NXDOMAIN=0, SERVFAIL=1, REFUSED=0, NOTAUTH=0, other=1"
+        );
+    }
+
+    #[test]
+    fn alerts_are_counted_per_kind() {
+        let mut stats = Stats::default();
+        assert!(stats.to_string().contains(" Alerts [-]"));
+
+        for kind in ["update_rate", "record_count", "update_rate"] {
+            stats.record_alert(kind);
+        }
+        assert!(stats
+            .to_string()
+            .contains(" Alerts [record_count=1, update_rate=2]"));
+    }
+
+    #[test]
+    fn latency_quantiles_are_bucketed() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_secs(u64::MAX));
+
+        // The quantiles are rounded up to the end of their bucket
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(51)));
+        assert_eq!(histogram.quantile(0.9), Some(Duration::from_micros(95)));
+        assert_eq!(histogram.quantile(0.99), Some(Duration::from_micros(103)));
+        let last = Histogram::upper_bound(NUM_BUCKETS - 1);
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_micros(last)));
+    }
+}
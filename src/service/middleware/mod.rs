@@ -1,5 +1,27 @@
+mod acl;
+mod blocklist;
+mod capture;
+mod catalog;
+mod chaos;
+mod geo;
 mod metric;
+mod rate_limit;
 mod rfc2136;
+mod stats_zone;
+mod tracing;
+mod truncation;
+mod validation;
 
-pub use metric::{MetricsMiddlewareSvc, Stats};
+pub use acl::AclMiddlewareSvc;
+pub use blocklist::BlocklistMiddlewareSvc;
+pub use capture::CaptureMiddlewareSvc;
+pub use catalog::CatalogMiddlewareSvc;
+pub use chaos::ChaosMiddlewareSvc;
+pub use geo::GeoMiddlewareSvc;
+pub use metric::{MetricsMiddlewareSvc, Stats, Summary};
+pub use rate_limit::{RateLimitMiddlewareSvc, UpdateLimiter};
 pub use rfc2136::Rfc2136MiddlewareSvc;
+pub use stats_zone::StatsZoneMiddlewareSvc;
+pub use tracing::TracingMiddlewareSvc;
+pub use truncation::TruncationMiddlewareSvc;
+pub use validation::ValidationMiddlewareSvc;
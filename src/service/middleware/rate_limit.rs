@@ -0,0 +1,311 @@
+use core::future::{ready, Ready};
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use domain::base::iana::Rcode;
+use domain::base::message_builder::AdditionalBuilder;
+use domain::base::wire::Composer;
+use domain::base::{Message, StreamTarget};
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use domain::net::server::service::{CallResult, Service, ServiceResult};
+use domain::net::server::util::mk_builder_for_target;
+use futures::stream::{iter, Iter};
+
+use crate::config::{RateLimitAction, RateLimitConfig, UpdateRateLimitConfig};
+
+/// The length of the IPv4 prefixes sharing a bucket.
+const IPV4_PREFIX_LEN: u32 = 24;
+
+/// The length of the IPv6 prefixes sharing a bucket, the prefix usually
+/// delegated to a single site.
+const IPV6_PREFIX_LEN: u32 = 56;
+
+/// The number of prefixes tracked above which the full buckets are evicted.
+const MAX_BUCKETS: usize = 65536;
+
+/// Limits the rate of the UDP requests of every client prefix with a token
+/// bucket, the requests above the rate are answered with an empty truncated
+/// response or dropped.
+///
+/// The requests over TCP are never limited, their source address cannot be
+/// spoofed and a truncated response sends the legitimate clients there.
+#[derive(Clone)]
+pub struct RateLimitMiddlewareSvc<Svc> {
+    limiter: Option<Arc<RateLimiter>>,
+    action: RateLimitAction,
+    svc: Svc,
+}
+
+impl<Svc> RateLimitMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc, config: Option<RateLimitConfig>) -> Self {
+        Self {
+            limiter: config.map(|config| Arc::new(RateLimiter::new(config))),
+            action: config.unwrap_or_default().action(),
+            svc,
+        }
+    }
+
+    fn is_limited<RequestOctets>(&self, request: &Request<RequestOctets>) -> bool
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+    {
+        request.transport_ctx().is_udp()
+            && self
+                .limiter
+                .as_ref()
+                .is_some_and(|limiter| !limiter.allows(request.client_addr().ip(), Instant::now()))
+    }
+}
+
+#[derive(Debug)]
+struct RateLimiter {
+    /// The tokens added to a bucket per second
+    rate: f64,
+    /// The capacity of a bucket
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether the last request of the prefix was limited
+    limited: bool,
+}
+
+impl RateLimiter {
+    fn new(config: RateLimitConfig) -> Self {
+        Self {
+            rate: f64::from(config.qps()),
+            burst: f64::from(config.burst()),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether the request of `addr` received at `now` is within the
+    /// rate of its prefix, and takes a token from the bucket of the prefix.
+    fn allows(&self, addr: IpAddr, now: Instant) -> bool {
+        let prefix = prefix(addr);
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return true;
+        };
+
+        if !buckets.contains_key(&prefix) && buckets.len() >= MAX_BUCKETS {
+            // A full bucket is the same as a missing one
+            buckets.retain(|_, bucket| bucket.refill(now, self.rate, self.burst) < self.burst);
+            if buckets.len() >= MAX_BUCKETS {
+                return false;
+            }
+        }
+
+        let bucket = buckets.entry(prefix).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+            limited: false,
+        });
+        let allowed = bucket.refill(now, self.rate, self.burst) >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        if allowed == bucket.limited {
+            if allowed {
+                log::info!(target: "rate_limit", "requests from the prefix of {} no longer limited", addr);
+            } else {
+                log::warn!(target: "rate_limit", "requests from the prefix of {} limited", addr);
+            }
+        }
+        bucket.limited = !allowed;
+        allowed
+    }
+}
+
+/// Limits the rate of the dynamic updates signed by every key and of every
+/// zone with a token bucket each, so that a runaway ACME client cannot flood
+/// the zones and their secondaries. The updates above either rate are refused.
+///
+/// The keys and zones are those of the configuration, the buckets are never
+/// evicted.
+#[derive(Debug)]
+pub struct UpdateLimiter {
+    /// The capacity of a bucket of a key, its updates per minute
+    per_key: f64,
+    /// The capacity of a bucket of a zone, its updates per minute
+    per_zone: f64,
+    /// The buckets of the keys and of the zones
+    buckets: Mutex<(HashMap<String, Bucket>, HashMap<String, Bucket>)>,
+}
+
+impl UpdateLimiter {
+    pub fn new(config: UpdateRateLimitConfig) -> Self {
+        Self {
+            per_key: f64::from(config.per_key()),
+            per_zone: f64::from(config.per_zone()),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Returns whether the update of `zone` signed by `key` at `now` is within
+    /// the rates of both, and takes a token from both buckets if so.
+    pub fn allows(&self, key: &str, zone: &str, now: Instant) -> bool {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return true;
+        };
+        let (keys, zones) = &mut *buckets;
+
+        let mut limited = [(keys, key, self.per_key), (zones, zone, self.per_zone)].map(
+            |(buckets, name, burst)| {
+                let bucket = buckets
+                    .entry(name.trim_end_matches('.').to_ascii_lowercase())
+                    .or_insert(Bucket {
+                        tokens: burst,
+                        updated: now,
+                        limited: false,
+                    });
+                bucket.refill(now, burst / 60.0, burst);
+                bucket
+            },
+        );
+        let allowed = limited.iter().all(|bucket| bucket.tokens >= 1.0);
+        if allowed {
+            for bucket in &mut limited {
+                bucket.tokens -= 1.0;
+            }
+        }
+        allowed
+    }
+}
+
+impl Bucket {
+    /// Adds the tokens earned since the last request and returns the tokens
+    /// of the bucket.
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+        self.tokens
+    }
+}
+
+/// Returns the prefix of `addr` sharing a bucket.
+fn prefix(addr: IpAddr) -> IpAddr {
+    match addr.to_canonical() {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX << (32 - IPV4_PREFIX_LEN);
+            IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX << (128 - IPV6_PREFIX_LEN);
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+        }
+    }
+}
+
+/// Builds an empty response with the TC bit set, `None` if the question of
+/// the request cannot be parsed.
+fn truncated<Octs, Target>(
+    message: &Message<Octs>,
+) -> Option<AdditionalBuilder<StreamTarget<Target>>>
+where
+    Octs: Octets,
+    Target: Composer + Default,
+{
+    let mut answer = mk_builder_for_target()
+        .start_answer(message, Rcode::NOERROR)
+        .ok()?;
+    answer.header_mut().set_tc(true);
+    Some(answer.additional())
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for RateLimitMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Target: Composer + Default,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, ()>,
+        Iter<std::option::IntoIter<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        if self.is_limited(&request) {
+            let response = match self.action {
+                RateLimitAction::Truncate => truncated(request.message()),
+                RateLimitAction::Drop => None,
+            };
+            return ready(MiddlewareStream::Result(iter(
+                response.map(|response| Ok(CallResult::new(response))),
+            )));
+        }
+
+        let svc_call_fut = self.svc.call(request.clone());
+        let map = PostprocessingStream::new(svc_call_fut, request, (), |_, item, _| item);
+        ready(MiddlewareStream::Map(map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn the_prefixes_share_a_bucket() {
+        let config = serde_yaml::from_str("{ qps: 2, burst: 3 }").unwrap();
+        let limiter = RateLimiter::new(config);
+        let now = Instant::now();
+        let allows = |addr: &str, now| limiter.allows(addr.parse().unwrap(), now);
+
+        assert!(allows("192.0.2.1", now));
+        assert!(allows("192.0.2.2", now));
+        assert!(allows("192.0.2.3", now));
+        assert!(!allows("192.0.2.4", now));
+        // Another prefix has its own bucket
+        assert!(allows("198.51.100.1", now));
+        assert!(allows("2001:db8:0:ff::1", now));
+        assert!(allows("2001:db8:0:1::1", now));
+        assert!(allows("2001:db8:0:2::1", now));
+        assert!(!allows("2001:db8:0:3::1", now));
+
+        // The tokens are earned back at the configured rate
+        let later = now + Duration::from_millis(500);
+        assert!(allows("192.0.2.1", later));
+        assert!(!allows("192.0.2.1", later));
+    }
+
+    #[test]
+    fn the_updates_are_limited_per_key_and_per_zone() {
+        let config = serde_yaml::from_str("{ per_key: 3, per_zone: 2 }").unwrap();
+        let limiter = UpdateLimiter::new(config);
+        let now = Instant::now();
+
+        assert!(limiter.allows("key1", "_acme-challenge.a.fr.", now));
+        assert!(limiter.allows("key1", "_acme-challenge.A.fr", now));
+        assert!(!limiter.allows("key1", "_acme-challenge.a.fr.", now));
+        // The refused update took no token of the key
+        assert!(limiter.allows("key1", "_acme-challenge.b.fr.", now));
+        assert!(!limiter.allows("key1", "_acme-challenge.c.fr.", now));
+        assert!(limiter.allows("key2", "_acme-challenge.c.fr.", now));
+
+        // A token of a zone is earned back every 30 seconds
+        let later = now + Duration::from_secs(30);
+        assert!(limiter.allows("key2", "_acme-challenge.a.fr.", later));
+        assert!(!limiter.allows("key2", "_acme-challenge.a.fr.", later));
+    }
+}
@@ -1,33 +1,44 @@
 use core::future::{ready, Ready};
 
-use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::sync::{Arc, Mutex};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
 use bytes::Bytes;
-use domain::base::iana::{Class, Rcode};
+use domain::base::iana::{Class, ExtendedErrorCode, Opcode, Rcode};
 use domain::base::message_builder::AdditionalBuilder;
-use domain::base::wire::Composer;
-use domain::base::{Message, Name, ParsedName, Rtype, StreamTarget, ToName, Ttl};
+use domain::base::opt::UnknownOptData;
+use domain::base::wire::{Composer, ParseError};
+use domain::base::{Message, Name, ParsedName, Rtype, StreamTarget, ToName};
+use domain::dep::octseq::str::Str;
 use domain::dep::octseq::Octets;
 use domain::net::server::message::Request;
-use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
 use domain::net::server::service::{Service, ServiceResult};
 use domain::net::server::util::mk_builder_for_target;
 use domain::rdata::tsig::Time48;
 use domain::rdata::{AllRecordData, ZoneRecordData};
-use domain::tsig::{Key, ServerSequence, ServerTransaction};
-use domain::zonetree::types::StoredRecordData;
-use domain::zonetree::{Answer, Rrset};
-use futures::stream::Once;
-use futures::FutureExt;
+use domain::tsig::{Key, ServerError, ServerSequence, ServerTransaction};
+use domain::zonetree::Answer;
+use futures::{FutureExt, Stream, StreamExt};
 
-use crate::key::{DomainName, KeyStore, Keys};
-use crate::service::handler::HandlerResult;
+use crate::config::UpdateForwardingConfig;
+use crate::dname::DomainName;
+use crate::error::ErrorKind;
+use crate::key::{KeyStore, Keys};
+use crate::service::middleware::Stats;
+use crate::service::profiling::{self, Stage};
+use crate::service::{add_record, forward};
+use crate::tsig::verification_time;
+
+/// The maximum number of characters of a client correlation id.
+const MAX_CLIENT_ID_LEN: usize = 64;
 
 #[derive(Clone, Debug)]
 pub struct Rfc2136MiddlewareSvc<Octets, Svc> {
     dnsr: Arc<crate::service::Dnsr>,
+    stats: Arc<RwLock<Stats>>,
     svc: Svc,
     _octets: PhantomData<Octets>,
 }
@@ -38,140 +49,193 @@ where
     Svc: Service<RequestOctets>,
     Svc::Target: Composer + Default,
 {
-    pub fn new(dnsr: Arc<crate::service::Dnsr>, svc: Svc) -> Self {
+    pub fn new(dnsr: Arc<crate::service::Dnsr>, svc: Svc, stats: Arc<RwLock<Stats>>) -> Self {
         Self {
             dnsr,
+            stats,
             svc,
             _octets: PhantomData,
         }
     }
 
-    fn postprocess_non_axfr(
+    async fn postprocess_non_axfr(
         dnsr: Arc<crate::service::Dnsr>,
+        stats: Arc<RwLock<Stats>>,
         qname: &Name<Bytes>,
+        client: IpAddr,
         message: &mut Message<Vec<u8>>,
         response: &mut AdditionalBuilder<StreamTarget<Svc::Target>>,
     ) -> Result<(), AdditionalBuilder<StreamTarget<<Svc as Service<RequestOctets>>::Target>>> {
-        let keystore = dnsr.keystore.read().unwrap();
-        let keys = &dnsr.config.keys;
         let cloned_message = message.clone();
         let bytes = cloned_message.as_slice();
-        let message_bytes = Message::from_octets(Bytes::copy_from_slice(bytes)).unwrap();
-
-        match ServerTransaction::request::<KeyStore, Vec<u8>>(&keystore, message, Time48::now()) {
-            Ok(None) => Ok(()),
-            Ok(Some(transaction)) if validate_key_scope(keys, transaction.key(), qname) => {
-                log::info!(target: "svc", "found tsig key for transaction");
-
-                match handle_update_query(dnsr.clone(), message_bytes) {
-                    Ok(_) => {
-                        log::info!(target: "update", "successfully updated the zone");
-                        transaction.answer(response, Time48::now()).unwrap();
-                        Ok(())
-                    }
-                    Err(e) => {
-                        log::error!(target: "update", "error while updating the dnsr zones: {}", e);
-                        let answer = Answer::new(Rcode::SERVFAIL);
-                        let builder = mk_builder_for_target();
-                        Err(answer.to_message(message, builder))
-                    }
-                }
-            }
-            Ok(_) => {
-                log::error!(target: "tsig", "tsig used is not in the valid scope");
-                let answer = Answer::new(Rcode::REFUSED);
-                let builder = mk_builder_for_target();
-                Err(answer.to_message(message, builder))
+        let Ok(message_bytes) = Message::from_octets(Bytes::copy_from_slice(bytes)) else {
+            return Err(failure_response(message, Rcode::FORMERR, None));
+        };
+
+        // An update signed with SIG(0) carries no TSIG record, it is verified
+        // with the public keys trusted by the domain of its zone
+        let sig0 = profiling::time(Stage::TsigVerify, || verify_sig0(&dnsr, qname, bytes));
+        match sig0 {
+            Ok(Some(signer)) => {
+                log::info!(target: "svc", "found sig(0) key {} for transaction", signer);
+                let signer = Signer::Sig0(&signer);
+                return apply_update(&dnsr, &stats, &signer, qname, client, message_bytes)
+                    .await
+                    .map_err(|failure| {
+                        failure_response(message, failure.rcode(), failure.error())
+                    });
             }
+            Ok(None) => (),
             Err(e) => {
-                log::error!(target: "tsig", "tsig transaction error: {}", e);
-                let answer = Answer::new(Rcode::REFUSED);
-                let builder = mk_builder_for_target();
-                Err(answer.to_message(message, builder))
+                log::error!(target: "sig0", "sig(0) verification error: {}", e);
+                return Err(failure_response(message, Rcode::NOTAUTH, Some(e.kind)));
+            }
+        }
+
+        // The keystore is released before the update is applied
+        let transaction = {
+            let keystore = dnsr.keystore.read().unwrap();
+            let transaction = profiling::time(Stage::TsigVerify, || {
+                let now = verification_time(message, dnsr.config.tsig_config().fudge());
+                ServerTransaction::request::<KeyStore, Vec<u8>>(&keystore, message, now)
+            });
+            match transaction {
+                Ok(transaction) => transaction,
+                Err(e) => {
+                    let error = log_tsig_error(&keystore, &cloned_message, &e);
+                    return Err(tsig_failure_response(message, e, error));
+                }
+            }
+        };
+        let Some(transaction) = transaction else {
+            // Only the signed updates are applied, the others are refused
+            // rather than answered by the zones
+            if message.header().opcode() == Opcode::UPDATE {
+                let failure = UpdateFailure::Unsigned;
+                log::warn!(target: "update", "unsigned update of {} refused", qname);
+                stats
+                    .write()
+                    .unwrap()
+                    .record_update_failure(failure.reason());
+                return Err(failure_response(message, failure.rcode(), None));
             }
+            return Ok(());
+        };
+        log::info!(target: "svc", "found tsig key for transaction");
+
+        match apply_update(
+            &dnsr,
+            &stats,
+            &Signer::Tsig(transaction.key()),
+            qname,
+            client,
+            message_bytes,
+        )
+        .await
+        {
+            Ok(()) => profiling::time(Stage::Sign, || transaction.answer(response, Time48::now()))
+                .map_err(|e| {
+                    log::error!(target: "tsig", "failed to sign the response: {}", e);
+                    failure_response(message, Rcode::SERVFAIL, Some(ErrorKind::PushError))
+                }),
+            Err(failure) => Err(failure_response(message, failure.rcode(), failure.error())),
         }
     }
 
-    fn postprocess_axfr(
+    async fn postprocess_axfr(
         dnsr: Arc<crate::service::Dnsr>,
+        stats: Arc<RwLock<Stats>>,
         qname: &Name<Bytes>,
+        client: IpAddr,
         message: &mut Message<Vec<u8>>,
         response: &mut AdditionalBuilder<StreamTarget<Svc::Target>>,
     ) -> Result<(), AdditionalBuilder<StreamTarget<<Svc as Service<RequestOctets>>::Target>>> {
-        let keystore = dnsr.keystore.read().unwrap();
-        let keys = &dnsr.config.keys;
         let cloned_message = message.clone();
         let bytes = cloned_message.as_slice();
-        let message_bytes = Message::from_octets(Bytes::copy_from_slice(bytes)).unwrap();
+        let Ok(message_bytes) = Message::from_octets(Bytes::copy_from_slice(bytes)) else {
+            return Err(failure_response(message, Rcode::FORMERR, None));
+        };
 
-        match ServerSequence::request::<KeyStore, Vec<u8>>(&keystore, message, Time48::now()) {
-            Ok(None) => Ok(()),
-            Ok(Some(mut sequence)) if validate_key_scope(keys, sequence.key(), qname) => {
-                log::info!(target: "svc", "found tsig key for transaction");
-
-                match handle_update_query(dnsr.clone(), message_bytes) {
-                    Ok(_) => {
-                        sequence.answer(response, Time48::now()).unwrap();
-                        Ok(())
-                    }
-                    Err(e) => {
-                        log::error!(target: "update", "error while updating the dnsr zones: {}", e);
-                        let answer = Answer::new(Rcode::SERVFAIL);
-                        let builder = mk_builder_for_target();
-                        Err(answer.to_message(message, builder))
-                    }
+        // The keystore is released before the update is applied
+        let sequence = {
+            let keystore = dnsr.keystore.read().unwrap();
+            let sequence = profiling::time(Stage::TsigVerify, || {
+                let now = verification_time(message, dnsr.config.tsig_config().fudge());
+                ServerSequence::request::<KeyStore, Vec<u8>>(&keystore, message, now)
+            });
+            match sequence {
+                Ok(sequence) => sequence,
+                Err(e) => {
+                    let error = log_tsig_error(&keystore, &cloned_message, &e);
+                    return Err(tsig_failure_response(message, e, error));
                 }
             }
-            Ok(_) => {
-                log::error!(target: "tsig", "tsig used is not in the valid scope");
-                let answer = Answer::new(Rcode::REFUSED);
-                let builder = mk_builder_for_target();
-                Err(answer.to_message(message, builder))
-            }
-            Err(e) => {
-                log::error!(target: "tsig", "tsig transaction error: {}", e);
-                let answer = Answer::new(Rcode::REFUSED);
-                let builder = mk_builder_for_target();
-                Err(answer.to_message(message, builder))
-            }
+        };
+        let Some(mut sequence) = sequence else {
+            return Ok(());
+        };
+        log::info!(target: "svc", "found tsig key for transaction");
+
+        // The transfers of a secondary are signed with its own key, which
+        // handles none of the zones
+        let key_file = sequence.key().name().into();
+        let result = if dnsr.config.is_secondary_key(client, &key_file) {
+            Ok(())
+        } else {
+            apply_update(
+                &dnsr,
+                &stats,
+                &Signer::Tsig(sequence.key()),
+                qname,
+                client,
+                message_bytes,
+            )
+            .await
+        };
+        match result {
+            Ok(()) => profiling::time(Stage::Sign, || sequence.answer(response, Time48::now()))
+                .map_err(|e| {
+                    log::error!(target: "tsig", "failed to sign the response: {}", e);
+                    failure_response(message, Rcode::SERVFAIL, Some(ErrorKind::PushError))
+                }),
+            Err(failure) => Err(failure_response(message, failure.rcode(), failure.error())),
         }
     }
 
-    fn postprocess(
+    async fn postprocess(
         dnsr: Arc<crate::service::Dnsr>,
+        stats: Arc<RwLock<Stats>>,
         request: &Request<RequestOctets>,
         response: &mut AdditionalBuilder<StreamTarget<Svc::Target>>,
     ) -> Result<(), AdditionalBuilder<StreamTarget<<Svc as Service<RequestOctets>>::Target>>> {
         let bytes = request.message().as_slice();
-        let mut message = Message::from_octets(bytes.to_vec()).unwrap();
-        let qname = request
-            .message()
-            .sole_question()
-            .unwrap()
-            .qname()
-            .to_bytes();
-
-        if !matches!(
-            request
-                .message()
-                .sole_question()
-                .map(|q| q.qtype() == Rtype::AXFR),
-            Ok(true)
-        ) {
-            Self::postprocess_non_axfr(dnsr, &qname, &mut message, response)
+        let Ok(mut message) =
+            profiling::time(Stage::Parse, || Message::from_octets(bytes.to_vec()))
+        else {
+            return Ok(());
+        };
+        let (qname, qtype) = match request.message().sole_question() {
+            Ok(question) => (question.qname().to_bytes(), question.qtype()),
+            Err(_) => return Ok(()),
+        };
+        let client = request.client_addr().ip();
+
+        if qtype != Rtype::AXFR {
+            Self::postprocess_non_axfr(dnsr, stats, &qname, client, &mut message, response).await
         } else {
-            Self::postprocess_axfr(dnsr, &qname, &mut message, response)
+            Self::postprocess_axfr(dnsr, stats, &qname, client, &mut message, response).await
         }
     }
 
-    fn map_stream_item(
+    async fn postprocess_item(
         request: Request<RequestOctets>,
         mut stream_item: ServiceResult<Svc::Target>,
-        metadata: Arc<crate::service::Dnsr>,
+        dnsr: Arc<crate::service::Dnsr>,
+        stats: Arc<RwLock<Stats>>,
     ) -> ServiceResult<Svc::Target> {
         if let Ok(cr) = &mut stream_item {
             if let Some(response) = cr.response_mut() {
-                if let Err(additional) = Self::postprocess(metadata, &request, response) {
+                if let Err(additional) = Self::postprocess(dnsr, stats, &request, response).await {
                     *response = additional;
                 }
             }
@@ -183,75 +247,461 @@ where
 impl<RequestOctets, Svc> Service<RequestOctets> for Rfc2136MiddlewareSvc<RequestOctets, Svc>
 where
     RequestOctets: Octets + Send + Sync + 'static + Unpin + Clone,
-    Svc: Service<RequestOctets>,
-    Svc::Future: core::future::Future + Unpin,
-    <Svc::Future as core::future::Future>::Output: Unpin,
-    Svc::Target: Composer + Default,
+    Svc: Service<RequestOctets> + 'static,
+    Svc::Future: Send + 'static,
+    Svc::Stream: Send + 'static,
+    Svc::Target: Composer + Default + Send + 'static,
 {
     type Target = Svc::Target;
-    type Stream = MiddlewareStream<
-        Svc::Future,
-        Svc::Stream,
-        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, Arc<crate::service::Dnsr>>,
-        Once<Ready<<Svc::Stream as futures::stream::Stream>::Item>>,
-        <Svc::Stream as futures::stream::Stream>::Item,
-    >;
-    type Future = core::future::Ready<Self::Stream>;
+    type Stream = Pin<Box<dyn Stream<Item = ServiceResult<Svc::Target>> + Send>>;
+    type Future = Ready<Self::Stream>;
 
     fn call(&self, request: Request<RequestOctets>) -> Self::Future {
-        let svc_call_fut = self.svc.call(request.clone());
-        let map = PostprocessingStream::new(
-            svc_call_fut,
-            request,
-            self.dnsr.clone(),
-            Self::map_stream_item,
-        );
-        ready(MiddlewareStream::Map(map))
+        let dnsr = self.dnsr.clone();
+        let stats = self.stats.clone();
+        let responses = self.svc.call(request.clone()).flatten_stream();
+        // The updates are applied while postprocessing the responses, the
+        // writes of the zones are awaited instead of blocking the server
+        let stream = responses.then(move |item| {
+            Self::postprocess_item(request.clone(), item, dnsr.clone(), stats.clone())
+        });
+        ready(Box::pin(stream))
     }
 }
 
-fn validate_key_scope(keys: &Keys, key: &Key, dname: &Name<Bytes>) -> bool {
-    let key_file = key.name().into();
-    let dname = Into::<DomainName>::into(dname).strip_prefix();
+/// The check an update failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UpdateFailure {
+    /// The update is signed with neither TSIG nor SIG(0).
+    Unsigned,
+    /// The key does not handle the zone.
+    Scope,
+    /// The owner of a record is outside of the zone.
+    NotZone,
+    /// The update policy of the key does not allow the type of a record.
+    Type,
+    /// The update policy of the key does not allow the owner of a record.
+    Name,
+    /// The type of a record is not supported.
+    UnsupportedType,
+    /// The class of a record is not a supported update class.
+    UnsupportedClass,
+    /// The update could not be parsed.
+    Malformed,
+    /// The updated records could not be written.
+    Write(ErrorKind),
+    /// The instance is a standby, not yet promoted.
+    Standby,
+    /// The update could not be forwarded to the primary.
+    Forward(ErrorKind),
+    /// The primary the update was forwarded to failed it with the rcode.
+    Primary(Rcode),
+    /// The update policy of the key does not allow the client address.
+    Client,
+    /// The `update_policy` rules of the domain deny a record.
+    Policy,
+    /// The key or the zone is above its rate of updates.
+    RateLimit,
+}
 
-    keys.get(&key_file)
-        .map(|d| d.contains_key(&dname))
-        .unwrap_or(false)
+impl UpdateFailure {
+    fn rcode(&self) -> Rcode {
+        match self {
+            UpdateFailure::Unsigned
+            | UpdateFailure::Scope
+            | UpdateFailure::Type
+            | UpdateFailure::Name
+            | UpdateFailure::Standby
+            | UpdateFailure::Client
+            | UpdateFailure::Policy
+            | UpdateFailure::RateLimit => Rcode::REFUSED,
+            UpdateFailure::NotZone => Rcode::NOTZONE,
+            UpdateFailure::UnsupportedType | UpdateFailure::UnsupportedClass => Rcode::NOTIMP,
+            UpdateFailure::Malformed => Rcode::FORMERR,
+            UpdateFailure::Write(_) | UpdateFailure::Forward(_) => Rcode::SERVFAIL,
+            UpdateFailure::Primary(rcode) => *rcode,
+        }
+    }
+
+    /// The kind of the error behind the failure, if any.
+    fn error(&self) -> Option<ErrorKind> {
+        match self {
+            UpdateFailure::Write(kind) | UpdateFailure::Forward(kind) => Some(*kind),
+            _ => None,
+        }
+    }
+
+    /// The name of the failure in the logs and metrics.
+    fn reason(&self) -> &'static str {
+        match self {
+            UpdateFailure::Unsigned => "unsigned",
+            UpdateFailure::Scope => "scope",
+            UpdateFailure::NotZone => "notzone",
+            UpdateFailure::Type => "type",
+            UpdateFailure::Name => "name",
+            UpdateFailure::UnsupportedType => "unsupported_type",
+            UpdateFailure::UnsupportedClass => "unsupported_class",
+            UpdateFailure::Malformed => "malformed",
+            UpdateFailure::Write(_) => "write",
+            UpdateFailure::Standby => "standby",
+            UpdateFailure::Forward(_) => "forward",
+            UpdateFailure::Primary(_) => "primary",
+            UpdateFailure::Client => "client",
+            UpdateFailure::Policy => "policy",
+            UpdateFailure::RateLimit => "rate_limit",
+        }
+    }
 }
 
-fn handle_update_query(
-    dnsr: Arc<crate::service::Dnsr>,
-    message: Message<Bytes>,
-) -> HandlerResult<()> {
-    // if there is no authority part then no update is made
-    if message.authority()?.next().is_none() {
-        log::info!(target: "update", "no authority part -- skipping zone update");
-        return Ok(());
+/// A failed update along with the record which failed the check, if any.
+#[derive(Debug)]
+struct Rejection {
+    failure: UpdateFailure,
+    record: Option<String>,
+}
+
+impl Rejection {
+    fn new(failure: UpdateFailure) -> Self {
+        Self {
+            failure,
+            record: None,
+        }
     }
 
-    let authority = message.authority()?;
-    let records: HashMap<(Rtype, Ttl), Vec<StoredRecordData>> = HashMap::new();
+    fn with_record<N>(mut self, owner: N, class: Class, rtype: Rtype) -> Self
+    where
+        N: std::fmt::Display,
+    {
+        self.record = Some(format!("{} {} {}", owner, class, rtype));
+        self
+    }
+}
 
-    let question = message.sole_question().unwrap();
-    let records = Arc::new(Mutex::new(records));
-    let cloned_records = records.clone();
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.record {
+            Some(record) => write!(
+                f,
+                "{} check failed for record {}",
+                self.failure.reason(),
+                record
+            ),
+            None => write!(f, "{} check failed", self.failure.reason()),
+        }
+    }
+}
+
+impl From<ParseError> for Rejection {
+    fn from(_: ParseError) -> Self {
+        Rejection::new(UpdateFailure::Malformed)
+    }
+}
 
-    let op = Box::new(move |_owner: Name<Bytes>, rrset: &Rrset| {
-        let mut records = cloned_records.lock().unwrap();
+/// Logs the TSIG verification failure of `message`, with the reason of the
+/// failure when its key could not be loaded in the keystore, and returns the
+/// kind of this reason.
+fn log_tsig_error(
+    keystore: &KeyStore,
+    message: &Message<Vec<u8>>,
+    error: impl std::fmt::Display,
+) -> Option<ErrorKind> {
+    let key_name = message.additional().ok().and_then(|records| {
         records
-            .entry((rrset.rtype(), rrset.ttl()))
-            .or_default()
-            .extend(rrset.data().to_vec());
+            .filter_map(Result::ok)
+            .find(|record| record.rtype() == Rtype::TSIG)
+            .map(|record| record.owner().to_bytes())
     });
 
-    dnsr.zones.find_zone_walk(question.qname(), |zone| {
-        if let Some(zone) = zone {
-            zone.walk(op);
+    match key_name.and_then(|name| keystore.unavailable_reason(&name).map(|r| (name, r))) {
+        Some((name, reason)) => {
+            log::error!(target: "tsig", "tsig key {} is unavailable, signed request rejected: {}", name, reason);
+            Some(reason.kind)
         }
+        None => {
+            log::error!(target: "tsig", "tsig transaction error: {}", error);
+            None
+        }
+    }
+}
+
+/// Builds the response of a request whose signature was not verified.
+///
+/// The response is a NOTAUTH carrying the TSIG error of RFC 8945 section 5.2
+/// in its TSIG record: BADKEY for an unknown key, BADSIG for a wrong MAC and
+/// BADTIME along with the server time for a skewed clock, so that the clients
+/// report why their update was rejected.
+///
+/// A request signed with a key which could not be loaded is answered NOTAUTH
+/// with the code of the error behind it in an extended error instead, as the
+/// OPT record carrying it cannot follow the TSIG record, always the last one.
+fn tsig_failure_response<Target>(
+    message: &Message<Vec<u8>>,
+    error: ServerError<Arc<Key>>,
+    kind: Option<ErrorKind>,
+) -> AdditionalBuilder<StreamTarget<Target>>
+where
+    Target: Composer + Default,
+{
+    if kind.is_some() {
+        return failure_response(message, Rcode::NOTAUTH, kind);
+    }
+    error
+        .build_message(message, mk_builder_for_target())
+        .unwrap_or_else(|e| {
+            log::error!(target: "tsig", "failed to build the tsig error response: {}", e);
+            failure_response(message, Rcode::REFUSED, None)
+        })
+}
+
+/// Builds the response of a failed request. The code of the error behind the
+/// failure, if any, is sent as the extra text of an extended DNS error to the
+/// clients supporting EDNS, see RFC 8914.
+fn failure_response<Target>(
+    message: &Message<Vec<u8>>,
+    rcode: Rcode,
+    error: Option<ErrorKind>,
+) -> AdditionalBuilder<StreamTarget<Target>>
+where
+    Target: Composer + Default,
+{
+    let mut response = Answer::new(rcode).to_message(message, mk_builder_for_target());
+    if let (Some(kind), Some(_)) = (error, message.opt()) {
+        let text = Str::from_string(kind.code().to_string());
+        if let Err(e) =
+            response.opt(|opt| opt.extended_error(ExtendedErrorCode::OTHER, Some(&text)))
+        {
+            log::warn!(target: "svc", "failed to add the extended error {}: {}", kind.code(), e);
+        }
+    }
+    response
+}
+
+/// The key authenticating an update.
+enum Signer<'a> {
+    /// A TSIG key, handling the domains listed under its name
+    Tsig(&'a Key),
+    /// The name of a SIG(0) key, trusted by the domains listing it
+    Sig0(&'a str),
+}
+
+impl std::fmt::Display for Signer<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Signer::Tsig(key) => write!(f, "{}", key.name()),
+            Signer::Sig0(name) => write!(f, "sig(0) {}", name),
+        }
+    }
+}
+
+impl Signer<'_> {
+    /// The name of the key, the identity of the update policy rules and of
+    /// the update rate limits.
+    fn name(&self) -> String {
+        match self {
+            Signer::Tsig(key) => key.name().to_string(),
+            Signer::Sig0(name) => name.to_string(),
+        }
+    }
+}
+
+/// Verifies the SIG(0) record of the wire `message` of the zone `dname` with
+/// the keys trusted by the domain of the zone and returns the name of the key
+/// which signed it, `None` if the message is not signed with SIG(0).
+fn verify_sig0(
+    dnsr: &crate::service::Dnsr,
+    dname: &Name<Bytes>,
+    message: &[u8],
+) -> Result<Option<String>, crate::error::Error> {
+    let domain = DomainName::from_name(dname);
+    let provisioned = dnsr.provisioned.read().unwrap();
+    let trusted = [&dnsr.config.keys, &provisioned]
+        .into_iter()
+        .flat_map(|keys| keys.values())
+        .filter_map(|domains| domains.get(&domain))
+        .flat_map(|info| info.sig0_keys())
+        .collect::<Vec<_>>();
+    let now = crate::time::unix_secs(std::time::SystemTime::now());
+    let fudge = dnsr.config.tsig_config().fudge().into();
+    crate::sig0::verify(message, &trusted, now, fudge)
+}
+
+/// Validates and applies the update of the zone `dname` signed by `signer`,
+/// the messages of other opcodes are left untouched.
+///
+/// A failed update is logged along with the failed check and counted in the
+/// metrics, nothing is written in the zone in this case.
+async fn apply_update(
+    dnsr: &Arc<crate::service::Dnsr>,
+    stats: &RwLock<Stats>,
+    signer: &Signer<'_>,
+    dname: &Name<Bytes>,
+    client: IpAddr,
+    message: Message<Bytes>,
+) -> Result<(), UpdateFailure> {
+    // The signed queries and transfers are answered as is, they are neither
+    // applied, forwarded nor counted against the update rates
+    if message.header().opcode() != Opcode::UPDATE {
+        return Ok(());
+    }
+
+    let client_id = client_id(&message, dnsr.config.client_id_option());
+    let client_id = client_id.as_deref().unwrap_or("-");
+
+    if dnsr.is_standby() {
+        if let Some(config) = dnsr.config.update_forwarding_config() {
+            return forward_to_primary(&config, dname, &message, client_id)
+                .await
+                .inspect_err(|failure| {
+                    stats
+                        .write()
+                        .unwrap()
+                        .record_update_failure(failure.reason())
+                });
+        }
+        log::warn!(target: "update", "[{}] update of {} refused: this instance is a standby", client_id, dname);
+        stats
+            .write()
+            .unwrap()
+            .record_update_failure(UpdateFailure::Standby.reason());
+        return Err(UpdateFailure::Standby);
+    }
+
+    let scope = {
+        let provisioned = dnsr.provisioned.read().unwrap();
+        validate_key_scope(
+            &[&dnsr.config.keys, &provisioned],
+            signer,
+            dname,
+            client,
+            &message,
+        )
+    };
+
+    // Only the updates the key may apply count against its rate
+    let scope = scope.and_then(|()| match &dnsr.update_limiter {
+        Some(limiter) if !limiter.allows(&signer.name(), &dname.to_string(), Instant::now()) => {
+            Err(Rejection::new(UpdateFailure::RateLimit))
+        }
+        _ => Ok(()),
     });
 
-    let mutex = Arc::try_unwrap(records).unwrap();
-    let mut records = mutex.into_inner().unwrap();
+    let result = match scope {
+        Ok(()) => handle_update_query(dnsr, stats, &message, client_id).await,
+        Err(rejection) => Err(rejection),
+    };
+    result.map_err(|rejection| {
+        log::error!(target: "update", "[{}] update of {} with key {} rejected: {}", client_id, dname, signer, rejection);
+        stats
+            .write()
+            .unwrap()
+            .record_update_failure(rejection.failure.reason());
+        rejection.failure
+    })
+}
+
+/// Forwards the update `message` of the zone `dname` received by a standby to
+/// the primary of `config`, the zone is updated by the primary only.
+async fn forward_to_primary(
+    config: &UpdateForwardingConfig,
+    dname: &Name<Bytes>,
+    message: &Message<Bytes>,
+    client_id: &str,
+) -> Result<(), UpdateFailure> {
+    match forward::forward_update(config, message).await {
+        Ok(Rcode::NOERROR) => {
+            log::info!(target: "update", "[{}] update of {} forwarded to {}", client_id, dname, config.primary());
+            Ok(())
+        }
+        Ok(rcode) => {
+            log::warn!(target: "update", "[{}] update of {} failed by {} with {}", client_id, dname, config.primary(), rcode);
+            Err(UpdateFailure::Primary(rcode))
+        }
+        Err(e) => {
+            log::error!(target: "update", "[{}] update of {} not forwarded: {}", client_id, dname, e);
+            Err(UpdateFailure::Forward(e.kind))
+        }
+    }
+}
+
+/// Checks that `signer` handles the zone `dname` and that the update policy
+/// of its domain allows the `client` address and every record of the update
+/// section of `message`.
+fn validate_key_scope(
+    keys: &[&Keys],
+    signer: &Signer<'_>,
+    dname: &Name<Bytes>,
+    client: IpAddr,
+    message: &Message<Bytes>,
+) -> Result<(), Rejection> {
+    let domain = DomainName::from_name(dname);
+
+    let info = match signer {
+        Signer::Tsig(key) => {
+            let key_file = key.name().into();
+            keys.iter()
+                .find_map(|keys| keys.get(&key_file))
+                .and_then(|d| d.get(&domain))
+        }
+        Signer::Sig0(name) => keys
+            .iter()
+            .flat_map(|keys| keys.values())
+            .filter_map(|d| d.get(&domain))
+            .find(|info| info.sig0_keys().iter().any(|key| key.name() == *name)),
+    };
+    let Some(info) = info else {
+        return Err(Rejection::new(UpdateFailure::Scope));
+    };
+    // Only the challenge zone can be updated, not the zone of the domain itself
+    if !domain
+        .challenge_apex()
+        .is_ok_and(|apex| apex.name_eq(dname))
+    {
+        return Err(Rejection::new(UpdateFailure::Scope));
+    }
+    // A leaked secret is useless out of the networks of its owner
+    if !info.allows_client(client) {
+        return Err(Rejection::new(UpdateFailure::Client));
+    }
+
+    for record in message.authority()? {
+        let record = record?;
+        let owner = record.owner();
+
+        let failure = if !owner.ends_with(dname) {
+            UpdateFailure::NotZone
+        } else if !info.allows_type(record.rtype()) {
+            UpdateFailure::Type
+        } else if !info.allows_name(&owner) {
+            UpdateFailure::Name
+        } else if !info.allows_update(&signer.name(), dname, &owner, record.rtype()) {
+            UpdateFailure::Policy
+        } else {
+            continue;
+        };
+        return Err(Rejection::new(failure).with_record(owner, record.class(), record.rtype()));
+    }
+
+    Ok(())
+}
+
+async fn handle_update_query(
+    dnsr: &Arc<crate::service::Dnsr>,
+    stats: &RwLock<Stats>,
+    message: &Message<Bytes>,
+    client_id: &str,
+) -> Result<(), Rejection> {
+    // if there is no authority part then no update is made
+    if message.authority()?.next().is_none() {
+        log::info!(target: "update", "[{}] no authority part -- skipping zone update", client_id);
+        return Ok(());
+    }
+
+    let authority = message.authority()?;
+    let question = message.sole_question()?;
+    // A concurrent update of the zone waits until this one is written
+    let _changes = dnsr.zones.lock_changes(question.qname()).await;
+    let mut records = dnsr.zones.records(question.qname());
+    let before = records.clone();
 
     log::debug!("{:?}", records);
 
@@ -261,48 +711,120 @@ fn handle_update_query(
         if let Some(record) = a {
             let data: ZoneRecordData<Bytes, Name<Bytes>> = match record.data() {
                 AllRecordData::Txt(txt) => txt.clone().into(),
-                _ => unimplemented!(),
+                _ => {
+                    return Err(Rejection::new(UpdateFailure::UnsupportedType).with_record(
+                        record.owner(),
+                        record.class(),
+                        record.rtype(),
+                    ));
+                }
             };
 
             match record.class() {
-                Class::IN => {
-                    records
-                        .entry((record.rtype(), record.ttl()))
-                        .or_default()
-                        .push(data);
-                }
+                Class::IN => add_record(&mut records, record.rtype(), record.ttl(), data),
                 Class::NONE => {
                     // Here we don't take ttl as a key because in delete
-                    // queries ttl is 0
+                    // queries ttl is 0, only the matching value is removed
                     for ((rtype, _), entry) in records.iter_mut() {
                         if rtype == &record.rtype() {
-                            if let Some(index) = entry.iter().position(|r| r == &data) {
-                                entry.remove(index);
-                            }
+                            entry.retain(|r| r != &data);
                         }
                     }
                 }
-                _ => unreachable!(),
+                _ => {
+                    return Err(Rejection::new(UpdateFailure::UnsupportedClass).with_record(
+                        record.owner(),
+                        record.class(),
+                        record.rtype(),
+                    ));
+                }
             };
         }
     }
 
-    // TODO: handle this lot of unwraps
-    if let Some(zone) = dnsr.zones.find_zone(&question.qname()) {
-        let mut writer = zone.write().now_or_never().unwrap();
-        let open = writer.open().now_or_never().unwrap().unwrap();
-
-        records.into_iter().for_each(|((rtype, ttl), data)| {
-            let mut rset = Rrset::new(rtype, ttl);
-            data.into_iter().for_each(|data| rset.push_data(data));
-            open.update_rrset(rset.into_shared())
-                .now_or_never()
-                .unwrap()
-                .unwrap();
-        });
-        writer.commit().now_or_never().unwrap().unwrap();
+    dnsr.commit_records(question.qname(), &before, &mut records)
+        .await
+        .map_err(|e| {
+            log::error!(target: "update", "[{}] failed to write the zone records: {}", client_id, e);
+            Rejection::new(UpdateFailure::Write(e.kind))
+        })?;
+
+    let record_count = records
+        .iter()
+        .filter(|((rtype, _), _)| *rtype != Rtype::SOA)
+        .map(|(_, data)| data.len())
+        .sum();
+    let alerts = dnsr.monitor.record_update(question.qname(), record_count);
+    if !alerts.is_empty() {
+        let mut stats = stats.write().unwrap();
+        for alert in alerts {
+            stats.record_alert(alert.kind());
+        }
     }
 
-    log::info!(target: "update", "successfully updated the zone");
+    log::info!(target: "update", "[{}] successfully updated the zone {}", client_id, question.qname());
     Ok(())
 }
+
+/// Returns the correlation id sent by the client in the private EDNS option `code`.
+///
+/// Control characters are dropped and the id is truncated so that it can be
+/// logged as is.
+fn client_id(message: &Message<Bytes>, code: u16) -> Option<String> {
+    let opt = message.opt()?;
+    let option = opt
+        .opt()
+        .iter::<UnknownOptData<_>>()
+        .filter_map(Result::ok)
+        .find(|option| option.code().to_int() == code)?;
+
+    let id = String::from_utf8_lossy(option.data().as_ref())
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_CLIENT_ID_LEN)
+        .collect();
+    Some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use domain::base::Ttl;
+    use domain::rdata::Txt;
+    use domain::zonetree::types::StoredRecordData;
+
+    use super::*;
+    use crate::store::ZoneRecords;
+
+    fn txt(text: &str) -> StoredRecordData {
+        Txt::<Bytes>::build_from_slice(text.as_bytes())
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn concurrent_challenges_share_an_rrset() {
+        let mut records = ZoneRecords::new();
+        add_record(&mut records, Rtype::TXT, Ttl::from_secs(60), txt("token-1"));
+        add_record(
+            &mut records,
+            Rtype::TXT,
+            Ttl::from_secs(120),
+            txt("token-2"),
+        );
+        add_record(
+            &mut records,
+            Rtype::TXT,
+            Ttl::from_secs(120),
+            txt("token-2"),
+        );
+
+        assert_eq!(
+            records.get(&(Rtype::TXT, Ttl::from_secs(120))),
+            Some(&vec![txt("token-1"), txt("token-2")])
+        );
+        assert_eq!(
+            records.get(&(Rtype::TXT, Ttl::from_secs(60))),
+            Some(&Vec::new())
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use core::future::{ready, Ready};
+
+use std::sync::{Arc, RwLock};
+
+use bytes::Bytes;
+use domain::base::iana::{Class, Rcode};
+use domain::base::message_builder::AdditionalBuilder;
+use domain::base::wire::Composer;
+use domain::base::{Rtype, StreamTarget, ToName, Ttl};
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use domain::net::server::service::{CallResult, Service, ServiceResult};
+use domain::net::server::util::mk_builder_for_target;
+use domain::rdata::Txt;
+use domain::zonetree::types::StoredName;
+use futures::stream::{once, Once};
+
+use crate::config::StatsZoneConfig;
+use crate::service::middleware::Stats;
+
+/// Answers the queries of the `_stats.<instance>` control zone with the per
+/// zone counters as TXT records, if enabled.
+///
+/// The apex of the control zone holds one `<zone> queries=.. nxdomain=..
+/// updates=..` record per zone and `<zone>._stats.<instance>` holds the
+/// counters of a single zone.
+#[derive(Clone)]
+pub struct StatsZoneMiddlewareSvc<Svc> {
+    apex: Option<StoredName>,
+    stats: Arc<RwLock<Stats>>,
+    svc: Svc,
+}
+
+impl<Svc> StatsZoneMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc, stats: Arc<RwLock<Stats>>, config: Option<&StatsZoneConfig>) -> Self {
+        let apex = config.and_then(|config| match config.apex() {
+            Ok(apex) => Some(apex),
+            Err(e) => {
+                log::error!(target: "stats_zone", "the stats zone is disabled: {}", e);
+                None
+            }
+        });
+
+        Self { svc, stats, apex }
+    }
+
+    /// Returns the TXT records of `qname`, `None` if the name does not exist.
+    fn texts<N>(&self, apex: &StoredName, qname: &N) -> Option<Vec<String>>
+    where
+        N: ToName,
+    {
+        let stats = self.stats.read().unwrap();
+        if qname.name_eq(apex) {
+            let texts = stats
+                .zone_counters()
+                .map(|(zone, counters)| format!("{} {}", zone, counters))
+                .collect();
+            return Some(texts);
+        }
+
+        let qname = qname.to_bytes().to_string();
+        stats
+            .zone_counters()
+            .find(|(zone, _)| format!("{}.{}", zone, apex).eq_ignore_ascii_case(&qname))
+            .map(|(_, counters)| vec![counters])
+    }
+
+    fn answer<RequestOctets>(
+        &self,
+        request: &Request<RequestOctets>,
+    ) -> Option<AdditionalBuilder<StreamTarget<Svc::Target>>>
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+        Svc: Service<RequestOctets>,
+        Svc::Target: Composer + Default,
+    {
+        let apex = self.apex.as_ref()?;
+        let question = request.message().sole_question().ok()?;
+        let qname = question.qname();
+        if !qname.ends_with(apex) {
+            return None;
+        }
+
+        let texts = self.texts(apex, &qname);
+        let rcode = match texts {
+            Some(_) => Rcode::NOERROR,
+            None => Rcode::NXDOMAIN,
+        };
+
+        let builder = mk_builder_for_target();
+        let mut answer = builder.start_answer(request.message(), rcode).ok()?;
+        answer.header_mut().set_aa(true);
+        if matches!(question.qtype(), Rtype::TXT | Rtype::ANY) {
+            for text in texts.into_iter().flatten() {
+                let txt = Txt::<Bytes>::build_from_slice(text.as_bytes()).ok()?;
+                answer
+                    .push((qname.clone(), Class::IN, Ttl::from_secs(0), txt))
+                    .ok()?;
+            }
+        }
+        Some(answer.additional())
+    }
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for StatsZoneMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Target: Composer + Default,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, ()>,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        if let Some(response) = self.answer(&request) {
+            return ready(MiddlewareStream::Result(once(ready(Ok(CallResult::new(
+                response,
+            ))))));
+        }
+
+        let svc_call_fut = self.svc.call(request.clone());
+        let map = PostprocessingStream::new(svc_call_fut, request, (), |_, item, _| item);
+        ready(MiddlewareStream::Map(map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ingest::Transport;
+    use crate::service::middleware_chain;
+    use crate::service::testing::{call_svc, dnsr_from, query, texts, CLIENT, CONFIG};
+
+    #[test]
+    fn counters_are_served_as_txt_records() {
+        let dnsr = dnsr_from(&format!(
+            "stats_zone:\n  instance: ns1.example.fr{}",
+            CONFIG
+        ));
+        let svc = middleware_chain(dnsr, Stats::new_shared());
+        let call = |qname: &str, qtype: Rtype| {
+            let mut responses = call_svc(&svc, &query(qname, qtype), CLIENT, Transport::Udp);
+            responses.remove(0)
+        };
+
+        call("_acme-challenge.example.fr.", Rtype::SOA);
+
+        let response = call("_stats.ns1.example.fr.", Rtype::TXT);
+        assert_eq!(response.header().rcode(), Rcode::NOERROR);
+        assert!(response.header().aa());
+        assert_eq!(
+            texts(&response),
+            ["_acme-challenge.example.fr queries=1 nxdomain=0 updates=0"]
+        );
+
+        let response = call(
+            "_acme-challenge.example.fr._stats.ns1.example.fr.",
+            Rtype::TXT,
+        );
+        assert_eq!(texts(&response), ["queries=1 nxdomain=0 updates=0"]);
+
+        let response = call("unknown.fr._stats.ns1.example.fr.", Rtype::TXT);
+        assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
+        assert!(texts(&response).is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use std::sync::Arc;
+
+use domain::base::Header;
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::service::{Service, ServiceResult};
+use futures::Stream;
+
+use crate::telemetry::{self, Trace, Tracer, Value};
+
+/// Traces the handling of the requests, if the telemetry is enabled.
+///
+/// The trace of a request is current while its future and stream are polled,
+/// so that the stages of the inner services are recorded as its children.
+#[derive(Clone)]
+pub struct TracingMiddlewareSvc<Svc> {
+    tracer: Option<Arc<Tracer>>,
+    svc: Svc,
+}
+
+impl<Svc> TracingMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc, tracer: Option<Arc<Tracer>>) -> Self {
+        Self { svc, tracer }
+    }
+
+    fn start_trace<RequestOctets>(&self, request: &Request<RequestOctets>) -> Option<Trace>
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+    {
+        let mut trace = self.tracer.as_ref()?.start_trace("dns.request")?;
+
+        trace.set_attribute(
+            "client.address",
+            Value::Str(request.client_addr().ip().to_string()),
+        );
+        let transport = if request.transport_ctx().is_udp() {
+            "udp"
+        } else {
+            "tcp"
+        };
+        trace.set_attribute("network.transport", Value::Str(transport.into()));
+        if let Ok(question) = request.message().sole_question() {
+            trace.set_attribute(
+                "dns.question.name",
+                Value::Str(question.qname().to_string()),
+            );
+            trace.set_attribute(
+                "dns.question.type",
+                Value::Str(question.qtype().to_string()),
+            );
+        }
+
+        Some(trace)
+    }
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for TracingMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Target: AsRef<[u8]>,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = TracedStream<Svc::Stream>;
+    type Future = TracedFuture<Svc::Future>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        let mut trace = self.start_trace(&request);
+        let fut = telemetry::with_trace(&mut trace, || self.svc.call(request));
+
+        TracedFuture {
+            fut,
+            trace: Traced {
+                tracer: self.tracer.clone(),
+                trace,
+            },
+        }
+    }
+}
+
+/// The trace of a request, finished when dropped.
+struct Traced {
+    tracer: Option<Arc<Tracer>>,
+    trace: Option<Trace>,
+}
+
+impl Drop for Traced {
+    fn drop(&mut self) {
+        if let (Some(tracer), Some(trace)) = (self.tracer.as_ref(), self.trace.take()) {
+            tracer.finish_trace(trace);
+        }
+    }
+}
+
+pub struct TracedFuture<F> {
+    fut: F,
+    trace: Traced,
+}
+
+impl<F> Future for TracedFuture<F>
+where
+    F: Future + Unpin,
+{
+    type Output = TracedStream<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let poll =
+            telemetry::with_trace(&mut this.trace.trace, || Pin::new(&mut this.fut).poll(cx));
+
+        poll.map(|stream| TracedStream {
+            stream,
+            trace: Traced {
+                tracer: this.trace.tracer.clone(),
+                trace: this.trace.trace.take(),
+            },
+        })
+    }
+}
+
+pub struct TracedStream<S> {
+    stream: S,
+    trace: Traced,
+}
+
+impl<S, Target> Stream for TracedStream<S>
+where
+    S: Stream<Item = ServiceResult<Target>> + Unpin,
+    Target: AsRef<[u8]>,
+{
+    type Item = ServiceResult<Target>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let poll = telemetry::with_trace(&mut this.trace.trace, || {
+            Pin::new(&mut this.stream).poll_next(cx)
+        });
+
+        if let (Poll::Ready(Some(Ok(cr))), Some(trace)) = (&poll, this.trace.trace.as_mut()) {
+            if let Some(response) = cr.response() {
+                let rcode = Header::for_message_slice(response.as_slice()).rcode();
+                trace.set_attribute("dns.response.code", Value::Int(rcode.to_int() as i64));
+            }
+        }
+        poll
+    }
+}
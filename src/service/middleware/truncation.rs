@@ -0,0 +1,347 @@
+use core::future::{ready, Ready};
+
+use domain::base::iana::OptionCode;
+use domain::base::message_builder::AdditionalBuilder;
+use domain::base::opt::UnknownOptData;
+use domain::base::wire::Composer;
+use domain::base::{Message, ParsedName, Rtype, StreamTarget};
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use domain::net::server::service::{Service, ServiceResult};
+use domain::net::server::util::mk_builder_for_target;
+use domain::rdata::AllRecordData;
+use futures::stream::Empty;
+
+use crate::config::EdnsConfig;
+
+/// The largest UDP response to a request without EDNS, see RFC 1035 section 4.2.1.
+const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+
+/// The length of the code and length fields of an EDNS option.
+const OPTION_HEADER_LEN: usize = 4;
+
+/// Advertises the configured EDNS UDP payload size and truncates the UDP
+/// responses larger than the size negotiated with the client.
+///
+/// A truncated response only holds the header with the TC bit set, the question
+/// and the OPT record so that the client retries over TCP. This runs before the
+/// responses are signed so that the TSIG record covers the truncated response.
+///
+/// When a padding block size is configured, the responses to the requests
+/// carrying a padding option are padded as described in RFC 7830.
+#[derive(Clone)]
+pub struct TruncationMiddlewareSvc<Svc> {
+    udp_payload_size: u16,
+    padding_block_size: Option<u16>,
+    svc: Svc,
+}
+
+/// The limits negotiated with the client of a request.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// The largest response, `None` over TCP
+    max_len: Option<usize>,
+    /// The UDP payload size to advertise, `None` if the request has no OPT record
+    advertised: Option<u16>,
+    /// The block size to pad the response to, `None` if it is not padded
+    padding_block_size: Option<u16>,
+}
+
+impl<Svc> TruncationMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc, config: EdnsConfig) -> Self {
+        Self {
+            svc,
+            udp_payload_size: config.udp_payload_size(),
+            padding_block_size: config.padding_block_size(),
+        }
+    }
+
+    fn limits<RequestOctets>(&self, request: &Request<RequestOctets>) -> Limits
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+    {
+        // Sizes lower than 512 are treated as 512, see RFC 6891 section 6.2.5
+        let opt = request.message().opt();
+        let requested = opt.as_ref().map(|opt| opt.udp_payload_size());
+        let max_len = request.transport_ctx().is_udp().then(|| {
+            requested.map_or(MIN_UDP_PAYLOAD_SIZE, |size| {
+                size.clamp(MIN_UDP_PAYLOAD_SIZE, self.udp_payload_size)
+            }) as usize
+        });
+
+        // Only the responses to padded requests are padded, see RFC 7830 section 4
+        let padded = opt.is_some_and(|opt| {
+            opt.opt()
+                .iter::<UnknownOptData<_>>()
+                .flatten()
+                .any(|option| option.code() == OptionCode::PADDING)
+        });
+
+        Limits {
+            max_len,
+            advertised: requested.map(|_| self.udp_payload_size),
+            padding_block_size: self.padding_block_size.filter(|_| padded),
+        }
+    }
+
+    fn map_stream_item<RequestOctets>(
+        _request: Request<RequestOctets>,
+        mut stream_item: ServiceResult<Svc::Target>,
+        limits: Limits,
+    ) -> ServiceResult<Svc::Target>
+    where
+        RequestOctets: Octets + Send + Sync + Unpin,
+        Svc: Service<RequestOctets>,
+        Svc::Target: Composer + Default,
+    {
+        if let Ok(cr) = &mut stream_item {
+            if let Some(response) = cr.response_mut() {
+                if let Some(rebuilt) = limits.apply(response.as_slice()) {
+                    *response = rebuilt;
+                }
+            }
+        }
+        stream_item
+    }
+}
+
+impl Limits {
+    /// Returns the response to send instead of `response`, if any.
+    fn apply<Target>(&self, response: &[u8]) -> Option<AdditionalBuilder<StreamTarget<Target>>>
+    where
+        Target: Composer + Default,
+    {
+        let message = Message::from_octets(response).ok()?;
+
+        let truncate = self.max_len.is_some_and(|max_len| response.len() > max_len);
+        let advertised = message.opt().map(|opt| opt.udp_payload_size());
+        if !truncate
+            && self.padding_block_size.is_none()
+            && (self.advertised.is_none() || advertised == self.advertised)
+        {
+            return None;
+        }
+
+        let mut rebuilt = rebuild(&message, self.advertised, truncate, None);
+        if let (Some(unpadded), Some(block_size)) = (&rebuilt, self.padding_block_size) {
+            let padding = self.padding_len(unpadded.as_slice().len(), block_size);
+            rebuilt = rebuild(&message, self.advertised, truncate, padding);
+        }
+
+        if rebuilt.is_none() {
+            log::warn!(target: "edns", "failed to rebuild a response of {} bytes", response.len());
+        } else if truncate {
+            log::debug!(target: "edns", "truncated a response of {} bytes", response.len());
+        }
+        rebuilt
+    }
+
+    /// Returns the length of the padding option data bringing a response of
+    /// `len` bytes to a multiple of `block_size` without exceeding the limit.
+    fn padding_len(&self, len: usize, block_size: u16) -> Option<usize> {
+        let block_size = usize::from(block_size);
+        let mut padded_len = (len + OPTION_HEADER_LEN).div_ceil(block_size) * block_size;
+        if let Some(max_len) = self.max_len {
+            padded_len = padded_len.min(max_len);
+        }
+        padded_len.checked_sub(len + OPTION_HEADER_LEN)
+    }
+}
+
+/// Copies `message` with an OPT record advertising `udp_payload_size` and a
+/// padding option of `padding` bytes, only the header and the question are
+/// kept if `truncate` is set.
+fn rebuild<Target>(
+    message: &Message<&[u8]>,
+    udp_payload_size: Option<u16>,
+    truncate: bool,
+    padding: Option<usize>,
+) -> Option<AdditionalBuilder<StreamTarget<Target>>>
+where
+    Target: Composer + Default,
+{
+    let mut builder = mk_builder_for_target();
+    *builder.header_mut() = message.header();
+    builder.header_mut().set_tc(truncate);
+
+    let mut builder = builder.question();
+    for question in message.question() {
+        builder.push(question.ok()?).ok()?;
+    }
+
+    let mut builder = builder.answer();
+    if !truncate {
+        for record in message
+            .answer()
+            .ok()?
+            .limit_to::<AllRecordData<_, ParsedName<_>>>()
+        {
+            builder.push(record.ok()?).ok()?;
+        }
+    }
+
+    let mut builder = builder.authority();
+    if !truncate {
+        for record in message
+            .authority()
+            .ok()?
+            .limit_to::<AllRecordData<_, ParsedName<_>>>()
+        {
+            builder.push(record.ok()?).ok()?;
+        }
+    }
+
+    let mut builder = builder.additional();
+    if !truncate {
+        for record in message
+            .additional()
+            .ok()?
+            .limit_to::<AllRecordData<_, ParsedName<_>>>()
+        {
+            let record = record.ok()?;
+            if record.rtype() != Rtype::OPT {
+                builder.push(record).ok()?;
+            }
+        }
+    }
+
+    if let Some(udp_payload_size) = udp_payload_size {
+        let opt = message.opt();
+        let padding = padding
+            .map(|len| UnknownOptData::new(OptionCode::PADDING, vec![0u8; len]))
+            .transpose()
+            .ok()?;
+        builder
+            .opt(|builder| {
+                builder.set_udp_payload_size(udp_payload_size);
+                if let Some(opt) = &opt {
+                    builder.set_rcode(opt.rcode(message.header()));
+                    builder.set_version(opt.version());
+                    builder.set_dnssec_ok(opt.dnssec_ok());
+                    for option in opt.opt().iter::<UnknownOptData<_>>().flatten() {
+                        if option.code() != OptionCode::PADDING {
+                            builder.push(&option)?;
+                        }
+                    }
+                }
+                if let Some(padding) = &padding {
+                    builder.push(padding)?;
+                }
+                Ok(())
+            })
+            .ok()?;
+    }
+
+    Some(builder)
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for TruncationMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Target: Composer + Default,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, Limits>,
+        Empty<ServiceResult<Self::Target>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        let limits = self.limits(&request);
+        let svc_call_fut = self.svc.call(request.clone());
+        let map = PostprocessingStream::new(svc_call_fut, request, limits, Self::map_stream_item);
+        ready(MiddlewareStream::Map(map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use domain::base::iana::Class;
+    use domain::base::{MessageBuilder, Name, Ttl};
+    use domain::rdata::Txt;
+
+    use super::*;
+
+    /// Builds a response with `count` TXT records and an OPT record.
+    fn response(count: usize) -> Vec<u8> {
+        let name = Name::<Vec<u8>>::from_str("_acme-challenge.example.fr.").unwrap();
+        let mut builder = MessageBuilder::new_vec();
+        builder.header_mut().set_qr(true);
+
+        let mut question = builder.question();
+        question.push((name.clone(), Rtype::TXT)).unwrap();
+        let mut answer = question.answer();
+        let txt = Txt::<Vec<u8>>::build_from_slice("t".repeat(100).as_bytes()).unwrap();
+        for _ in 0..count {
+            answer
+                .push((name.clone(), Class::IN, Ttl::from_secs(60), txt.clone()))
+                .unwrap();
+        }
+
+        let mut additional = answer.additional();
+        additional
+            .opt(|opt| {
+                opt.set_udp_payload_size(4096);
+                Ok(())
+            })
+            .unwrap();
+        additional.finish()
+    }
+
+    fn limits(max_len: Option<usize>, padding_block_size: Option<u16>) -> Limits {
+        Limits {
+            max_len,
+            advertised: Some(1232),
+            padding_block_size,
+        }
+    }
+
+    #[test]
+    fn responses_stay_within_the_limit() {
+        let large = response(20);
+        assert!(large.len() > 1232);
+
+        let truncated = limits(Some(1232), None).apply::<Vec<u8>>(&large).unwrap();
+        let truncated = Message::from_octets(truncated.as_slice()).unwrap();
+        assert!(truncated.header().tc());
+        assert_eq!(truncated.header_counts().ancount(), 0);
+        assert_eq!(truncated.opt().unwrap().udp_payload_size(), 1232);
+
+        // Over TCP only the advertised size is rewritten
+        let rebuilt = limits(None, None).apply::<Vec<u8>>(&large).unwrap();
+        assert_eq!(rebuilt.as_slice().len(), large.len());
+        assert!(!Message::from_octets(rebuilt.as_slice())
+            .unwrap()
+            .header()
+            .tc());
+    }
+
+    #[test]
+    fn responses_are_padded_to_the_block_size() {
+        let small = response(1);
+        let padded = limits(Some(1232), Some(468))
+            .apply::<Vec<u8>>(&small)
+            .unwrap();
+        assert_eq!(padded.as_slice().len(), 468);
+
+        // The padding never exceeds the negotiated size
+        let medium = response(4);
+        let padded = limits(Some(800), Some(468))
+            .apply::<Vec<u8>>(&medium)
+            .unwrap();
+        assert_eq!(padded.as_slice().len(), 800);
+        let padded = limits(None, Some(468)).apply::<Vec<u8>>(&medium).unwrap();
+        assert_eq!(padded.as_slice().len(), 936);
+    }
+}
@@ -0,0 +1,112 @@
+use core::future::{ready, Ready};
+
+use domain::base::iana::{Opcode, Rcode};
+use domain::base::message_builder::AdditionalBuilder;
+use domain::base::wire::{Composer, ParseError};
+use domain::base::{Message, StreamTarget};
+use domain::dep::octseq::Octets;
+use domain::net::server::message::Request;
+use domain::net::server::middleware::stream::{MiddlewareStream, PostprocessingStream};
+use domain::net::server::service::{CallResult, Service, ServiceResult};
+use domain::net::server::util::mk_builder_for_target;
+use futures::stream::{once, Once};
+
+/// Answers FORMERR to the malformed requests before they reach the inner
+/// service, so that it only handles messages with well formed sections and a
+/// single question.
+///
+/// The responses are left to the mandatory middleware, which drops them.
+#[derive(Clone)]
+pub struct ValidationMiddlewareSvc<Svc> {
+    svc: Svc,
+}
+
+impl<Svc> ValidationMiddlewareSvc<Svc> {
+    /// Creates an instance of this processor.
+    #[must_use]
+    pub fn new(svc: Svc) -> Self {
+        Self { svc }
+    }
+}
+
+/// Returns whether the sections of the request `message` can be parsed and it
+/// holds a single question, as expected by the queries, the updates and the
+/// notifies.
+fn is_well_formed<Octs>(message: &Message<Octs>) -> bool
+where
+    Octs: Octets,
+{
+    let header = message.header();
+    if header.qr() {
+        return true;
+    }
+    if matches!(
+        header.opcode(),
+        Opcode::QUERY | Opcode::NOTIFY | Opcode::UPDATE
+    ) && message.header_counts().qdcount() != 1
+    {
+        return false;
+    }
+    parse_sections(message).is_ok()
+}
+
+fn parse_sections<Octs>(message: &Message<Octs>) -> Result<(), ParseError>
+where
+    Octs: Octets,
+{
+    // Reaching the additional section parses the question and skips over the
+    // records of the answer and authority sections
+    for record in message.additional()? {
+        record?;
+    }
+    Ok(())
+}
+
+/// Builds a FORMERR response without any question, the question of the
+/// request may not even be parsable.
+fn formerr<Octs, Target>(message: &Message<Octs>) -> AdditionalBuilder<StreamTarget<Target>>
+where
+    Octs: Octets,
+    Target: Composer + Default,
+{
+    let mut builder = mk_builder_for_target();
+    let header = builder.header_mut();
+    header.set_id(message.header().id());
+    header.set_qr(true);
+    header.set_opcode(message.header().opcode());
+    header.set_rd(message.header().rd());
+    header.set_rcode(Rcode::FORMERR);
+    builder.additional()
+}
+
+impl<RequestOctets, Svc> Service<RequestOctets> for ValidationMiddlewareSvc<Svc>
+where
+    RequestOctets: Octets + Send + Sync + 'static + Unpin,
+    Svc: Service<RequestOctets>,
+    Svc::Target: Composer + Default,
+    Svc::Future: Unpin,
+{
+    type Target = Svc::Target;
+    type Stream = MiddlewareStream<
+        Svc::Future,
+        Svc::Stream,
+        PostprocessingStream<RequestOctets, Svc::Future, Svc::Stream, ()>,
+        Once<Ready<ServiceResult<Self::Target>>>,
+        ServiceResult<Self::Target>,
+    >;
+    type Future = Ready<Self::Stream>;
+
+    fn call(&self, request: Request<RequestOctets>) -> Self::Future {
+        if !is_well_formed(request.message()) {
+            log::info!(target: "svc", "malformed request from {}", request.client_addr());
+            let response = formerr(request.message());
+            return ready(MiddlewareStream::Result(once(ready(Ok(CallResult::new(
+                response,
+            ))))));
+        }
+
+        let svc_call_fut = self.svc.call(request.clone());
+        let map = PostprocessingStream::new(svc_call_fut, request, (), |_, item, _| item);
+        ready(MiddlewareStream::Map(map))
+    }
+}
@@ -1,46 +1,178 @@
 use core::future::{ready, Future};
 
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
+use std::time::{Duration, Instant};
 
-use domain::base::iana::Opcode;
+use arc_swap::ArcSwap;
+use bytes::Bytes;
 use domain::base::iana::{Class, Rcode};
-use domain::base::message_builder::AdditionalBuilder;
 use domain::base::Message;
 use domain::base::Name;
-use domain::base::{Rtype, ToName};
-use domain::dep::octseq::OctetsBuilder;
+use domain::base::{CharStr, Rtype, ToName, Ttl};
 use domain::net::server::message::Request;
+use domain::net::server::middleware::edns::EdnsMiddlewareSvc;
+use domain::net::server::middleware::mandatory::MandatoryMiddlewareSvc;
 use domain::net::server::service::CallResult;
-use domain::net::server::service::{Service, ServiceResult};
-use domain::net::server::util::mk_builder_for_target;
-use domain::zonetree::Rrset;
-use domain::zonetree::{Answer, ReadableZone, Zone};
+use domain::net::server::service::{Service, ServiceError, ServiceResult};
+use domain::rdata::{Hinfo, Txt, ZoneRecordData};
+use domain::tsig::ServerSequence;
+use domain::zonetree::types::{StoredName, StoredRecordData};
+use domain::zonetree::{Answer, AnswerAuthority, AnswerContent, ReadableZone, Zone};
+use domain::zonetree::{Rrset, SharedRrset};
 use futures::channel::mpsc::unbounded;
-use futures::channel::mpsc::UnboundedSender;
+use futures::lock::{Mutex as AsyncMutex, OwnedMutexGuard};
 use futures::stream::{once, Stream};
 
-use crate::config::Config;
+use crate::blocklist::Blocklist;
+use crate::config::{Config, WebhookEvent};
+use crate::dname::DomainName;
+use crate::error;
 use crate::error::Error;
+use crate::geo::GeoDb;
 use crate::key;
+use crate::store::{RedisStore, S3Store, ZoneRecords};
+use crate::telemetry::Tracer;
+use crate::tsig::verification_time;
+use crate::vault::Vault;
 use crate::zone::ZoneTree;
 
+use self::capture::WireCapture;
+use self::expiry::ChallengeExpiry;
+use self::flush::CacheFlusher;
 use self::handler::{HandleDNS, HandlerResult};
+use self::journal::Journal;
+use self::middleware::{
+    AclMiddlewareSvc, BlocklistMiddlewareSvc, CaptureMiddlewareSvc, CatalogMiddlewareSvc,
+    ChaosMiddlewareSvc, GeoMiddlewareSvc, MetricsMiddlewareSvc, RateLimitMiddlewareSvc,
+    Rfc2136MiddlewareSvc, Stats, StatsZoneMiddlewareSvc, TracingMiddlewareSvc,
+    TruncationMiddlewareSvc, UpdateLimiter, ValidationMiddlewareSvc,
+};
+use self::monitor::ChangeMonitor;
+use self::profiling::Stage;
+use self::response::{CnameChain, Response, ResponseBuilder, ResponseSender};
+use self::secondary::SecondaryNotifier;
 pub use self::watcher::Watcher;
+use self::webhook::Webhooks;
 
+#[cfg(all(test, feature = "acme-e2e"))]
+mod acme;
+pub mod capture;
+#[cfg(test)]
+mod conformance;
+mod expiry;
+pub mod export;
+mod flush;
+mod forward;
 mod handler;
+pub mod ingest;
+pub mod journal;
 pub mod middleware;
+mod monitor;
+pub mod profiling;
+mod response;
+mod secondary;
+#[cfg(test)]
+mod testing;
 mod watcher;
+mod webhook;
+mod zone_files;
 
 pub type KeyStore = Arc<RwLock<key::KeyStore>>;
 
+/// The longest chain of CNAME records followed in an answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The full middleware chain served over UDP and TCP.
+pub type DnsrSvc = TracingMiddlewareSvc<
+    CaptureMiddlewareSvc<
+        MetricsMiddlewareSvc<
+            BlocklistMiddlewareSvc<
+                RateLimitMiddlewareSvc<
+                    ValidationMiddlewareSvc<
+                        GeoMiddlewareSvc<
+                            AclMiddlewareSvc<
+                                Rfc2136MiddlewareSvc<
+                                    Vec<u8>,
+                                    TruncationMiddlewareSvc<
+                                        ChaosMiddlewareSvc<
+                                            CatalogMiddlewareSvc<
+                                                StatsZoneMiddlewareSvc<
+                                                    MandatoryMiddlewareSvc<
+                                                        Vec<u8>,
+                                                        EdnsMiddlewareSvc<Vec<u8>, Arc<Dnsr>>,
+                                                    >,
+                                                >,
+                                            >,
+                                        >,
+                                    >,
+                                >,
+                            >,
+                        >,
+                    >,
+                >,
+            >,
+        >,
+    >,
+>;
+
+/// Wraps `dnsr` in the middlewares applied to every request.
+pub fn middleware_chain(dnsr: Arc<Dnsr>, stats: Arc<RwLock<Stats>>) -> DnsrSvc {
+    let svc = EdnsMiddlewareSvc::new(dnsr.clone());
+    let svc = MandatoryMiddlewareSvc::new(svc);
+    let svc = StatsZoneMiddlewareSvc::new(svc, stats.clone(), dnsr.config.stats_zone_config());
+    let svc = CatalogMiddlewareSvc::new(svc, dnsr.clone(), dnsr.config.catalog_config());
+    let svc = ChaosMiddlewareSvc::new(svc, dnsr.clone(), dnsr.config.chaos_config());
+    let svc = TruncationMiddlewareSvc::new(svc, dnsr.config.edns_config());
+    let svc = Rfc2136MiddlewareSvc::new(dnsr.clone(), svc, stats.clone());
+    let svc = AclMiddlewareSvc::new(
+        svc,
+        dnsr.config.acl_config(),
+        dnsr.geo.clone(),
+        dnsr.zones.clone(),
+    );
+    let svc = GeoMiddlewareSvc::new(svc, dnsr.geo.clone(), stats.clone());
+    let svc = ValidationMiddlewareSvc::new(svc);
+    let svc = RateLimitMiddlewareSvc::new(svc, dnsr.config.rate_limit_config());
+    let svc = BlocklistMiddlewareSvc::new(svc, dnsr.blocklist.clone());
+    let svc = MetricsMiddlewareSvc::new(svc, stats, dnsr.zones.clone());
+    let svc = CaptureMiddlewareSvc::new(svc, dnsr.capture.clone());
+    TracingMiddlewareSvc::new(svc, dnsr.tracer.clone())
+}
+
 #[derive(Debug, Clone)]
 pub struct Dnsr {
     pub config: Arc<Config>,
     pub zones: Arc<Zones>,
     pub keystore: KeyStore,
+    pub store: Option<Arc<RedisStore>>,
+    pub snapshots: Option<Arc<S3Store>>,
+    pub monitor: Arc<ChangeMonitor>,
+    pub journal: Arc<Journal>,
+    pub capture: Option<Arc<WireCapture>>,
+    pub tracer: Option<Arc<Tracer>>,
+    pub geo: Option<Arc<GeoDb>>,
+    pub flusher: Option<Arc<CacheFlusher>>,
+    pub notifier: Option<Arc<SecondaryNotifier>>,
+    pub blocklist: Arc<Blocklist>,
+    pub expiry: Option<Arc<ChallengeExpiry>>,
+    pub webhooks: Option<Arc<Webhooks>>,
+    pub update_limiter: Option<Arc<UpdateLimiter>>,
+
+    /// The keys and domains provisioned through the admin API
+    pub provisioned: Arc<RwLock<key::Keys>>,
+    /// The key and the entry of the domains removed from the configuration
+    /// whose zones are retained, provisioned again when restored
+    pub retained: Arc<Mutex<HashMap<DomainName, (key::KeyFile, key::DomainInfo)>>>,
+    /// Whether this instance is a read-only replica
+    standby: Arc<AtomicBool>,
+    /// The last iteration of the configuration watcher
+    watcher_heartbeat: Arc<Mutex<Option<Instant>>>,
 }
 
 impl Service<Vec<u8>> for Dnsr {
@@ -64,10 +196,28 @@ impl Service<Vec<u8>> for Dnsr {
                 return Box::pin(immediate_result) as Self::Stream;
             }
 
+            // AXFR is only defined over TCP, see RFC 5936 section 4.2
+            if request.transport_ctx().is_udp() {
+                let additional =
+                    ResponseBuilder::new(request.message().clone()).error(Rcode::NOTIMP);
+                let immediate_result = once(ready(Ok(CallResult::new(additional))));
+                return Box::pin(immediate_result) as Self::Stream;
+            }
+
             let (sender, receiver) = unbounded();
+            let transfer = move || {
+                if let Err(e) = dnsr.handle_axfr(request, sender.clone()) {
+                    let _ = sender.unbounded_send(Err(e));
+                }
+            };
 
-            if let Err(e) = dnsr.handle_axfr(request, sender.clone()) {
-                let _ = sender.unbounded_send(Err(e));
+            // The walk of a large zone would stall the executor, it runs on
+            // the blocking threads and its responses are streamed as they
+            // are built
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => drop(runtime.spawn_blocking(transfer)),
+                // Outside of a runtime, e.g. while ingesting captured messages
+                Err(_) => transfer(),
             }
 
             Box::pin(receiver) as Self::Stream
@@ -78,29 +228,83 @@ impl Service<Vec<u8>> for Dnsr {
 impl HandleDNS for Dnsr {
     fn handle_non_axfr(&self, request: Request<Vec<u8>>) -> HandlerResult<CallResult<Vec<u8>>> {
         let answer = {
-            let question = request.message().sole_question().unwrap();
-            self.zones
-                .find_zone_read(question.qname(), |zone| match zone {
-                    Some(zone) => {
-                        let qname = question.qname().to_bytes();
-                        let qtype = question.qtype();
-                        zone.query(qname, qtype).unwrap()
-                    }
-                    None => Answer::new(Rcode::NXDOMAIN),
-                })
+            let Ok(question) = request.message().sole_question() else {
+                return Err(ServiceError::FormatError);
+            };
+            // The names out of the zones are not ours to deny, see RFC 8906
+            // section 3.1.3.1
+            if self.config.refuse_out_of_zone()
+                && self.zones.enclosing_apex_name(question.qname()).is_none()
+            {
+                let response =
+                    ResponseBuilder::new(request.message().clone()).error(Rcode::REFUSED);
+                return Ok(CallResult::new(response));
+            }
+            let mut answer = profiling::time(Stage::Lookup, || {
+                self.zones
+                    .find_zone_read(question.qname(), |zone| match zone {
+                        Some(zone) => {
+                            let qname = question.qname().to_bytes();
+                            let qtype = question.qtype();
+                            zone.query(qname, qtype).unwrap_or_else(|e| {
+                                log::error!(target: "svc", "lookup of {} {} failed: {}", question.qname(), qtype, Error::from(e));
+                                Answer::new(Rcode::SERVFAIL)
+                            })
+                        }
+                        None => Answer::new(Rcode::NXDOMAIN),
+                    })
+            });
+
+            // The CNAME records are followed within the zone, see RFC 1034
+            // section 4.3.2
+            if !matches!(question.qtype(), Rtype::CNAME | Rtype::ANY) {
+                let chain =
+                    self.zones
+                        .follow_cnames(question.qname(), question.qtype(), &mut answer);
+                if let Some(chain) = chain {
+                    let soa = match is_negative(&answer) {
+                        true => self.zones.negative_soa(&chain.owner),
+                        false => None,
+                    };
+                    return profiling::time(Stage::Build, || {
+                        ResponseBuilder::new(request.message().clone())
+                            .cname_chain(&chain, &answer, soa)
+                    })
+                    .map(CallResult::new);
+                }
+            }
+
+            // ANY queries of an existing name get a single synthesized HINFO
+            // record instead of every RRset, see RFC 8482 section 4.2
+            if question.qtype() == Rtype::ANY && answer.rcode() == Rcode::NOERROR {
+                return minimal_any_response(&request).map(CallResult::new);
+            }
+
+            // The existence of the name is checked apart from the one of the
+            // type: a name without records of the queried type, an empty
+            // non-terminal or a name above a zone is answered NODATA rather
+            // than NXDOMAIN, see RFC 2308 section 2.2 and RFC 8020
+            if answer.rcode() == Rcode::NXDOMAIN && self.zones.name_exists(question.qname()) {
+                answer = Answer::new(Rcode::NOERROR);
+            }
+
+            // Negative answers carry the SOA of the zone, see RFC 2308 section 3
+            if is_negative(&answer) {
+                if let Some(authority) = self.zones.negative_authority(question.qname()) {
+                    answer.add_authority(authority);
+                }
+            }
+            answer
         };
 
-        let builder = mk_builder_for_target();
-        let additional = answer.to_message(request.message(), builder);
+        let additional = profiling::time(Stage::Build, || {
+            ResponseBuilder::new(request.message().clone()).answer(&answer)
+        });
 
         Ok(CallResult::new(additional))
     }
 
-    fn handle_axfr(
-        &self,
-        request: Request<Vec<u8>>,
-        sender: UnboundedSender<HandlerResult<CallResult<Vec<u8>>>>,
-    ) -> HandlerResult<()> {
+    fn handle_axfr(&self, request: Request<Vec<u8>>, sender: ResponseSender) -> HandlerResult<()> {
         let mut message = request.message().clone();
         let message = Arc::make_mut(&mut message);
 
@@ -111,24 +315,44 @@ impl HandleDNS for Dnsr {
             request.transport_ctx().to_owned(),
         );
 
+        let responses = ResponseBuilder::new(request.message().clone()).transfer();
+
         // Look up the zone for the queried name.
-        let question = request.message().sole_question().unwrap();
+        let Ok(question) = request.message().sole_question() else {
+            return Err(ServiceError::FormatError);
+        };
 
-        if question.qclass() == Class::IN {
-            let answer = Answer::new(Rcode::NXDOMAIN);
-            add_to_stream(answer, request.message(), &sender);
+        if question.qclass() != Class::IN {
+            responses.send_answer(&Answer::new(Rcode::NXDOMAIN), &sender);
             return Ok(());
         }
 
-        let zone = self.zones.find_zone(question.qname());
+        let zone = self.zones.get_zone(question.qname());
 
-        // If not found, return an NXDOMAIN error response.
+        // If not found, return a REFUSED or NXDOMAIN error response.
         let Some(zone) = zone else {
-            let answer = Answer::new(Rcode::NXDOMAIN);
-            add_to_stream(answer, request.message(), &sender);
+            let rcode = if self.config.refuse_out_of_zone() {
+                Rcode::REFUSED
+            } else {
+                Rcode::NXDOMAIN
+            };
+            responses.send_answer(&Answer::new(rcode), &sender);
             return Ok(());
         };
 
+        // Refuse the transfer if the client does not match the transfer policy
+        let domain = DomainName::from_name(question.qname());
+        let client = request.client_addr().ip();
+        let signed = || self.has_valid_signature(request.message(), client, &domain);
+        let acl = self.config.acl_config();
+        if !acl.allows_transfer(request.client_addr().ip(), &domain, signed) {
+            log::info!(target: "acl", "refused transfer of {} to {}", domain, request.client_addr().ip());
+            responses.send_answer(&Answer::new(Rcode::REFUSED), &sender);
+            return Ok(());
+        }
+        let responses =
+            responses.with_transfer_ttl(acl.transfer_ttl(request.client_addr().ip(), &domain));
+
         // https://datatracker.ietf.org/doc/html/rfc5936#section-2.2
         // 2.2: AXFR Response
         //
@@ -148,14 +372,20 @@ impl HandleDNS for Dnsr {
         // record. If not found, return a SERVFAIL error response.
         let qname = question.qname().to_bytes();
         let zone = zone.read();
-        let Ok(soa_answer) = zone.query(qname, Rtype::SOA) else {
-            let answer = Answer::new(Rcode::SERVFAIL);
-            add_to_stream(answer, request.message(), &sender);
-            return Ok(());
+        let soa = match zone
+            .query(qname, Rtype::SOA)
+            .map(|answer| answer.content().clone())
+        {
+            Ok(AnswerContent::Data(soa)) => soa,
+            _ => {
+                responses.send_answer(&Answer::new(Rcode::SERVFAIL), &sender);
+                return Ok(());
+            }
         };
+        let soa_response = || responses.rrset(&question.qname(), soa.ttl(), soa.data());
 
         // Push the begin SOA response message into the stream
-        add_to_stream(soa_answer.clone(), request.message(), &sender);
+        response::send(soa_response()?, &sender);
 
         // "The AXFR protocol treats the zone contents as an unordered
         //  collection (or to use the mathematical term, a "set") of
@@ -184,133 +414,569 @@ impl HandleDNS for Dnsr {
         //  detect such clients, this typically requires manual
         //  configuration at the server."
 
+        let single_record = acl.single_record_transfer(request.client_addr().ip(), &domain);
+
         let sender = Arc::new(Mutex::new(sender));
         let cloned_sender = sender.clone();
-        let cloned_msg = request.message().clone();
+        let cloned_responses = responses.clone();
 
         let op = Box::new(move |owner: Name<_>, rrset: &Rrset| {
-            if rrset.rtype() != Rtype::SOA {
-                let builder = mk_builder_for_target();
-                let mut answer = builder.start_answer(&cloned_msg, Rcode::NOERROR).unwrap();
-                for item in rrset.data() {
-                    answer.push((owner.clone(), rrset.ttl(), item)).unwrap();
-                }
+            if rrset.rtype() == Rtype::SOA {
+                return;
+            }
 
-                let additional = answer.additional();
-                let sender = cloned_sender.lock().unwrap();
-                add_additional_to_stream(additional, &cloned_msg, &sender);
+            let Ok(sender) = cloned_sender.lock() else {
+                return;
+            };
+            let records = rrset.data();
+            // Either the whole RRset in a message or one message per record
+            let per_message = if single_record { 1 } else { records.len() };
+            for chunk in records.chunks(per_message.max(1)) {
+                match cloned_responses.rrset(&owner, rrset.ttl(), chunk) {
+                    Ok(response) => response::send(response, &sender),
+                    Err(e) => {
+                        let _ = sender.unbounded_send(Err(e));
+                    }
+                }
             }
         });
-        zone.walk(op);
+        profiling::time(Stage::AxfrWalk, || zone.walk(op));
 
-        let mutex = Arc::try_unwrap(sender).unwrap();
-        let sender = mutex.into_inner().unwrap();
+        let sender = sender
+            .lock()
+            .map_err(|_| ServiceError::InternalError)?
+            .clone();
 
         // Push the end SOA response message into the stream
-        add_to_stream(soa_answer, request.message(), &sender);
+        response::send(soa_response()?, &sender);
 
         Ok(())
     }
 }
 
-fn add_to_stream(
-    answer: Answer,
-    msg: &Message<Vec<u8>>,
-    sender: &UnboundedSender<HandlerResult<CallResult<Vec<u8>>>>,
-) {
-    let builder = mk_builder_for_target();
-    let additional = answer.to_message(msg, builder);
-    add_additional_to_stream(additional, msg, sender);
+impl Dnsr {
+    /// Returns whether this instance is a standby, refusing the writes.
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::Acquire)
+    }
+
+    /// Returns the time elapsed since the last iteration of the configuration
+    /// watcher, `None` if it is not started yet.
+    pub fn watcher_heartbeat(&self) -> Option<Duration> {
+        let heartbeat = self.watcher_heartbeat.lock().unwrap();
+        heartbeat.map(|heartbeat| heartbeat.elapsed())
+    }
+
+    /// Drops the zones removed from the configuration for longer than the
+    /// retention, along with the entries of their domains.
+    pub fn purge_removed_zones(&self) {
+        self.zones
+            .purge_disabled_zones(self.config.removed_zone_retention());
+        let disabled = self.zones.disabled_zones();
+        self.retained.lock().unwrap().retain(|domain, _| {
+            disabled
+                .iter()
+                .any(|(apex, _)| DomainName::from_name(apex) == *domain)
+        });
+    }
+
+    /// Promotes this standby to primary, returns false if it already was.
+    pub fn promote(&self) -> bool {
+        self.standby.swap(false, Ordering::AcqRel)
+    }
+
+    /// Writes the `records` of the zone `apex` with a new serial, `before`
+    /// being its records before the change, then records the change in the
+    /// journal and propagates it to the store, the caches, the secondaries and
+    /// the webhooks.
+    ///
+    /// The records must have been read under the change lock of the zone,
+    /// held until this returns.
+    pub async fn commit_records<N>(
+        &self,
+        apex: &N,
+        before: &ZoneRecords,
+        records: &mut ZoneRecords,
+    ) -> Result<(), Error>
+    where
+        N: ToName,
+    {
+        crate::serial::bump_soa_serial(records, self.config.serial_policy());
+        let Some(serial) = crate::serial::soa_serial(records) else {
+            return Err(error!(DomainZone => "the zone {} has no SOA record", apex.to_bytes()));
+        };
+        // The secondaries ignore a change whose serial does not follow the
+        // previous one in the sequence space of RFC 1982
+        if let Some(from_serial) = crate::serial::soa_serial(before) {
+            if !crate::serial::is_newer(serial, from_serial) {
+                return Err(
+                    error!(DomainZone => "the serial {} of the zone {} does not follow {}", serial, apex.to_bytes(), from_serial),
+                );
+            }
+        }
+        self.zones.write_records(apex, records.clone()).await?;
+        self.journal.record(apex, before, records);
+
+        if let Some(store) = &self.store {
+            store.publish(apex, records);
+        }
+        if let Some(flusher) = &self.flusher {
+            flusher.flush(apex);
+        }
+        if let Some(notifier) = &self.notifier {
+            notifier.notify(apex, serial);
+        }
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.send(WebhookEvent::RecordsUpdated, &apex.to_bytes());
+        }
+        Ok(())
+    }
+
+    /// Publishes the challenge `value` as a TXT record of the zone `apex`, as
+    /// an update adding it would.
+    pub async fn present_challenge(
+        &self,
+        apex: &StoredName,
+        value: &str,
+        ttl: Ttl,
+    ) -> Result<(), Error> {
+        let data = challenge_txt(value)?;
+        self.change_records(apex, |records| add_record(records, Rtype::TXT, ttl, data))
+            .await
+    }
+
+    /// Removes the challenge `value` from the TXT records of the zone `apex`,
+    /// the other challenges are left.
+    pub async fn clean_up_challenge(&self, apex: &StoredName, value: &str) -> Result<(), Error> {
+        let data = challenge_txt(value)?;
+        self.change_records(apex, |records| {
+            for ((rtype, _), entry) in records.iter_mut() {
+                if *rtype == Rtype::TXT {
+                    entry.retain(|r| r != &data);
+                }
+            }
+        })
+        .await
+    }
+
+    /// Applies `change` to the records of the zone `apex` and commits them,
+    /// nothing is written if they are left unchanged.
+    async fn change_records<F>(&self, apex: &StoredName, change: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut ZoneRecords),
+    {
+        let _changes = self.zones.lock_changes(apex).await;
+        let mut records = self.zones.records(apex);
+        let before = records.clone();
+        change(&mut records);
+        if records == before {
+            return Ok(());
+        }
+        self.commit_records(apex, &before, &mut records).await
+    }
+
+    /// Returns whether `message` is signed by the key of the secondary at
+    /// `client` or by the key handling `domain`, the signature is checked
+    /// again when the response is signed.
+    fn has_valid_signature(
+        &self,
+        message: &Message<Vec<u8>>,
+        client: IpAddr,
+        domain: &DomainName,
+    ) -> bool {
+        let mut message = message.clone();
+        let now = verification_time(&message, self.config.tsig_config().fudge());
+        let key_file: key::KeyFile = {
+            let keystore = self.keystore.read().unwrap();
+            match ServerSequence::request::<key::KeyStore, Vec<u8>>(&keystore, &mut message, now) {
+                Ok(Some(sequence)) => sequence.key().name().into(),
+                _ => return false,
+            }
+        };
+
+        // The other keys of the keystore only sign the transfers of the zones
+        // they handle
+        if self.config.is_secondary_key(client, &key_file) {
+            return true;
+        }
+        let provisioned = self.provisioned.read().unwrap();
+        [&self.config.keys, &*provisioned]
+            .into_iter()
+            .filter_map(|keys| keys.find_domain(domain))
+            .any(|(key, _)| key == &key_file)
+    }
 }
 
-fn add_additional_to_stream(
-    mut additional: AdditionalBuilder<domain::base::StreamTarget<Vec<u8>>>,
-    msg: &Message<Vec<u8>>,
-    sender: &UnboundedSender<HandlerResult<CallResult<Vec<u8>>>>,
-) {
-    set_axfr_header(msg, &mut additional);
-    let item = Ok(CallResult::new(additional));
-    sender.unbounded_send(item).unwrap();
+/// Adds `data` to the rrset of `rtype`, e.g. the second challenge of a
+/// wildcard certificate next to the first one.
+///
+/// The whole rrset takes the TTL `ttl` of the new record and a value already
+/// present is not duplicated, see RFC 2136 section 3.4.2.2.
+pub fn add_record(records: &mut ZoneRecords, rtype: Rtype, ttl: Ttl, data: StoredRecordData) {
+    let mut rrset = Vec::new();
+    for ((t, _), entry) in records.iter_mut() {
+        if *t == rtype {
+            // The emptied entries are left so that their rrset is rewritten
+            rrset.append(entry);
+        }
+    }
+    if !rrset.contains(&data) {
+        rrset.push(data);
+    }
+    records.insert((rtype, ttl), rrset);
 }
 
-fn set_axfr_header<Target>(msg: &Message<Vec<u8>>, additional: &mut AdditionalBuilder<Target>)
-where
-    Target: AsMut<[u8]>,
-    Target: OctetsBuilder,
-{
-    // https://datatracker.ietf.org/doc/html/rfc5936#section-2.2.1
-    // 2.2.1: Header Values
-    //
-    // "These are the DNS message header values for AXFR responses.
-    //
-    //     ID          MUST be copied from request -- see Note a)
-    //
-    //     QR          MUST be 1 (Response)
-    //
-    //     OPCODE      MUST be 0 (Standard Query)
-    //
-    //     Flags:
-    //        AA       normally 1 -- see Note b)
-    //        TC       MUST be 0 (Not truncated)
-    //        RD       RECOMMENDED: copy request's value; MAY be set to 0
-    //        RA       SHOULD be 0 -- see Note c)
-    //        Z        "mbz" -- see Note d)
-    //        AD       "mbz" -- see Note d)
-    //        CD       "mbz" -- see Note d)"
-    let header = additional.header_mut();
-    header.set_id(msg.header().id());
-    header.set_qr(true);
-    header.set_opcode(Opcode::QUERY);
-    header.set_aa(true);
-    header.set_tc(false);
-    header.set_rd(msg.header().rd());
-    header.set_ra(false);
-    header.set_z(false);
-    header.set_ad(false);
-    header.set_cd(false);
+fn challenge_txt(value: &str) -> Result<StoredRecordData, Error> {
+    let txt = Txt::<Bytes>::build_from_slice(value.as_bytes())
+        .map_err(|_| error!(DomainZone => "invalid challenge value {:?}", value))?;
+    Ok(txt.into())
+}
+
+/// Builds the answer to an ANY query with the HINFO record `"RFC8482" ""`.
+fn minimal_any_response(request: &Request<Vec<u8>>) -> HandlerResult<Response> {
+    let qname = request
+        .message()
+        .sole_question()
+        .map_err(|_| ServiceError::FormatError)?
+        .into_qname();
+    let hinfo = Hinfo::new(
+        CharStr::from_octets(Bytes::from_static(b"RFC8482")).unwrap(),
+        CharStr::from_octets(Bytes::new()).unwrap(),
+    );
+
+    ResponseBuilder::new(request.message().clone()).records([(qname, Class::IN, Ttl::HOUR, hinfo)])
+}
+
+/// Returns whether `answer` is a NXDOMAIN or a NODATA answer.
+fn is_negative(answer: &Answer) -> bool {
+    match answer.rcode() {
+        Rcode::NXDOMAIN => true,
+        Rcode::NOERROR => matches!(answer.content(), AnswerContent::NoData),
+        _ => false,
+    }
 }
 
 impl From<Arc<Config>> for Dnsr {
     fn from(config: Arc<Config>) -> Self {
-        let zones = Arc::new(Arc::new(RwLock::new(ZoneTree::new())).into());
-        let keystore = key::KeyStore::new_shared();
+        let tree = ZoneTree::with_max_zones(config.limits_config().max_zones());
+        let zones = Arc::new(tree.into());
+        let keystore = key::KeyStore::new_shared(config.vault_config().map(Vault::new));
+        let store = config.redis_config().map(|c| Arc::new(RedisStore::new(c)));
+        let snapshots = config.s3_config().map(|c| Arc::new(S3Store::new(c)));
+        let monitor = Arc::new(ChangeMonitor::new(config.alert_config()));
+        let journal = Arc::new(Journal::new(config.journal_config()));
+        let capture = config
+            .capture_config()
+            .map(|c| Arc::new(WireCapture::new(c.size())));
+        let tracer = config.telemetry_config().map(|c| Arc::new(Tracer::new(c)));
+        let geo = config
+            .geoip_config()
+            .and_then(|c| match GeoDb::open(&c.path()) {
+                Ok(geo) => Some(Arc::new(geo)),
+                Err(e) => {
+                    log::error!(target: "geo", "the geoip tagging is disabled: {}", e);
+                    None
+                }
+            });
+        let flusher = config
+            .cache_flush_config()
+            .map(|c| Arc::new(CacheFlusher::new(c.clone())));
+        let notifier = (!config.secondaries().is_empty()).then(|| {
+            let secondaries = config.secondaries().to_vec();
+            Arc::new(SecondaryNotifier::new(secondaries, keystore.clone()))
+        });
+        let blocklist = Arc::new(Blocklist::default());
+        if let Err(e) = blocklist.reload(config.blocklist_config()) {
+            log::error!(target: "blocklist", "no client is blocked: {}", e);
+        }
+        let expiry = config
+            .challenge_expiry_config()
+            .map(|c| Arc::new(ChallengeExpiry::new(c)));
+        let webhooks = (!config.webhooks().is_empty())
+            .then(|| Arc::new(Webhooks::new(config.webhooks().to_vec())));
+        let update_limiter = config
+            .update_rate_limit_config()
+            .map(|c| Arc::new(UpdateLimiter::new(c)));
+        let standby = Arc::new(AtomicBool::new(config.standby()));
 
         Dnsr {
             config,
             zones,
             keystore,
+            store,
+            snapshots,
+            monitor,
+            journal,
+            capture,
+            tracer,
+            geo,
+            flusher,
+            notifier,
+            blocklist,
+            expiry,
+            webhooks,
+            update_limiter,
+            provisioned: Arc::default(),
+            retained: Arc::default(),
+            standby,
+            watcher_heartbeat: Arc::default(),
         }
     }
 }
 
+/// The served zones.
+///
+/// The queries read an immutable snapshot of the tree without any lock, so
+/// that they never wait for a reload. The writers are serialized, each one
+/// publishes an updated copy of the tree which the following reads load.
+///
+/// The records of a zone are read, changed and written back under its change
+/// lock, so that a concurrent change is never overwritten with a stale copy.
 #[derive(Debug, Clone)]
-pub struct Zones(Arc<RwLock<ZoneTree>>);
+pub struct Zones {
+    tree: Arc<ArcSwap<ZoneTree>>,
+    writer: Arc<Mutex<()>>,
+    changes: Arc<Mutex<HashMap<StoredName, Arc<AsyncMutex<()>>>>>,
+}
 
 impl Zones {
-    fn find_zone<N>(&self, qname: &N) -> Option<Zone>
+    /// Returns the zone whose apex is exactly `name`.
+    fn get_zone<N>(&self, name: &N) -> Option<Zone>
     where
         N: ToName,
     {
-        let zones = self.0.read().unwrap();
-        zones.find_zone(qname).cloned()
+        let zones = self.tree.load();
+        zones.get_zone(name).cloned()
     }
 
+    /// Reads the zone serving `qname`, the closest one enclosing it.
     fn find_zone_read<N, F>(&self, qname: &N, f: F) -> Answer
     where
         N: ToName,
         F: FnOnce(Option<Box<dyn ReadableZone>>) -> Answer,
     {
-        let zones = self.0.read().unwrap();
+        let zones = self.tree.load();
         f(zones.find_zone(qname).map(|z| z.read()))
     }
 
-    fn find_zone_walk<N, F>(&self, qname: &N, f: F)
+    /// Follows the CNAME RRset of `answer`, the answer to `qtype` for `qname`,
+    /// within the zone of `qname`. `answer` is replaced with the answer of the
+    /// name the chain ends with, `None` is returned if it is not a CNAME.
+    ///
+    /// The chain ends with a CNAME answer at a target out of the zone, at a
+    /// loop or after `MAX_CNAME_CHAIN` links.
+    fn follow_cnames<N>(&self, qname: &N, qtype: Rtype, answer: &mut Answer) -> Option<CnameChain>
+    where
+        N: ToName,
+    {
+        if !matches!(answer.content(), AnswerContent::Cname(_)) {
+            return None;
+        }
+        let zones = self.tree.load();
+        let zone = zones.find_zone(qname)?;
+        let apex = zone.apex_name().clone();
+        let zone = zone.read();
+
+        let mut chain = CnameChain {
+            links: Vec::new(),
+            owner: qname.to_bytes(),
+        };
+        while let AnswerContent::Cname(cname) = answer.content() {
+            let cname = cname.clone();
+            let Some(ZoneRecordData::Cname(target)) = cname.data().first() else {
+                break;
+            };
+            let target = target.cname().clone();
+            let seen = target == chain.owner || chain.links.iter().any(|(name, _)| name == &target);
+            if seen || chain.links.len() == MAX_CNAME_CHAIN || !target.ends_with(&apex) {
+                break;
+            }
+            let Ok(next) = zone.query(target.clone(), qtype) else {
+                break;
+            };
+            let owner = std::mem::replace(&mut chain.owner, target);
+            chain.links.push((owner, cname));
+            *answer = next;
+        }
+        Some(chain)
+    }
+
+    fn get_zone_walk<N, F>(&self, name: &N, f: F)
     where
         N: ToName,
         F: FnOnce(Option<Box<dyn ReadableZone>>),
     {
-        let zones = self.0.read().unwrap();
-        f(zones.find_zone(qname).map(|z| z.read()))
+        let zones = self.tree.load();
+        f(zones.get_zone(name).map(|z| z.read()))
+    }
+
+    /// Returns the apex of the zone serving `qname`, the closest one
+    /// enclosing it.
+    pub fn enclosing_apex_name<N>(&self, qname: &N) -> Option<StoredName>
+    where
+        N: ToName,
+    {
+        let zones = self.tree.load();
+        zones.find_zone(qname).map(|z| z.apex_name().clone())
+    }
+
+    /// Returns whether `qname` exists whatever its records: a name of a zone,
+    /// empty non-terminals included, or a name above a zone.
+    fn name_exists<N>(&self, qname: &N) -> bool
+    where
+        N: ToName,
+    {
+        let zones = self.tree.load();
+        if zones.has_zone_below(qname) {
+            return true;
+        }
+        zones.find_zone(qname).is_some_and(|zone| {
+            zone.read()
+                .query(qname.to_bytes(), Rtype::ANY)
+                .is_ok_and(|answer| answer.rcode() != Rcode::NXDOMAIN)
+        })
+    }
+
+    /// Returns the SOA of the zone enclosing `qname` for the authority section
+    /// of its negative answers. Its TTL is capped to the SOA minimum, which
+    /// resolvers use as the negative caching TTL.
+    fn negative_authority<N>(&self, qname: &N) -> Option<AnswerAuthority>
+    where
+        N: ToName,
+    {
+        let (apex, soa) = self.negative_soa(qname)?;
+        Some(AnswerAuthority::new(apex, Some(soa), None, None))
+    }
+
+    /// Returns the apex and the SOA RRset of the negative answers of `qname`,
+    /// see `negative_authority`.
+    fn negative_soa<N>(&self, qname: &N) -> Option<(StoredName, SharedRrset)>
+    where
+        N: ToName,
+    {
+        // Only the SOA RRset of the apex is read, not the whole zone
+        let zones = self.tree.load();
+        let zone = zones.find_zone(qname)?;
+        let apex = zone.apex_name().clone();
+        let answer = zone.read().query(apex.clone(), Rtype::SOA).ok()?;
+        let AnswerContent::Data(rrset) = answer.content() else {
+            return None;
+        };
+        let ZoneRecordData::Soa(soa) = rrset.data().first()?.clone() else {
+            return None;
+        };
+
+        let mut rrset = Rrset::new(Rtype::SOA, rrset.ttl().min(soa.minimum()));
+        rrset.push_data(soa.into());
+        Some((apex, rrset.into_shared()))
+    }
+
+    pub fn apex_names(&self) -> Vec<StoredName> {
+        let zones = self.tree.load();
+        zones.iter_zones().map(|z| z.apex_name().clone()).collect()
+    }
+
+    /// Collects the records of the zone matching `qname` grouped by type and ttl.
+    pub fn records<N>(&self, qname: &N) -> ZoneRecords
+    where
+        N: ToName,
+    {
+        let records = Arc::new(Mutex::new(ZoneRecords::new()));
+        let cloned_records = records.clone();
+
+        let op = Box::new(move |_owner: Name<Bytes>, rrset: &Rrset| {
+            let mut records = cloned_records.lock().unwrap();
+            records
+                .entry((rrset.rtype(), rrset.ttl()))
+                .or_default()
+                .extend(rrset.data().to_vec());
+        });
+
+        self.get_zone_walk(qname, |zone| {
+            if let Some(zone) = zone {
+                zone.walk(op);
+            }
+        });
+
+        let mutex = Arc::try_unwrap(records).unwrap();
+        mutex.into_inner().unwrap()
+    }
+
+    /// Takes the change lock of the zone `apex`, held until the guard is
+    /// dropped. The records read under the lock are not changed by anyone
+    /// else until they are written back.
+    pub async fn lock_changes<N>(&self, apex: &N) -> OwnedMutexGuard<()>
+    where
+        N: ToName,
+    {
+        let lock = self
+            .changes
+            .lock()
+            .unwrap()
+            .entry(apex.to_bytes())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// Drops the change locks of the zones no longer served, except the ones
+    /// held or awaited by a change in progress. A zone served again gets a
+    /// new lock on its next change.
+    fn prune_changes(&self) {
+        let zones = self.tree.load();
+        self.changes
+            .lock()
+            .unwrap()
+            .retain(|apex, lock| Arc::strong_count(lock) > 1 || zones.get_zone(apex).is_some());
+    }
+
+    /// Writes `records` at the apex of the zone matching `qname`.
+    ///
+    /// Entries without any data remove the rrset of their type unless another
+    /// entry of the same type still holds some. The write waits for the one of
+    /// the zone in progress, if any.
+    pub async fn write_records<N>(&self, qname: &N, records: ZoneRecords) -> Result<(), Error>
+    where
+        N: ToName,
+    {
+        let Some(zone) = self.get_zone(qname) else {
+            return Ok(());
+        };
+
+        let (removed, updated): (Vec<_>, Vec<_>) =
+            records.into_iter().partition(|(_, data)| data.is_empty());
+
+        let mut writer = zone.write().await;
+        let open = writer.open().await?;
+
+        for ((rtype, _), _) in removed {
+            if !updated.iter().any(|((t, _), _)| t == &rtype) {
+                open.remove_rrset(rtype).await?;
+            }
+        }
+        for ((rtype, ttl), data) in updated {
+            let mut rset = Rrset::new(rtype, ttl);
+            data.into_iter().for_each(|data| rset.push_data(data));
+            open.update_rrset(rset.into_shared()).await?;
+        }
+        writer.commit().await?;
+
+        Ok(())
+    }
+
+    /// Replaces every non SOA record of the zone matching `qname` with `records`.
+    pub async fn replace_records<N>(&self, qname: &N, mut records: ZoneRecords) -> Result<(), Error>
+    where
+        N: ToName,
+    {
+        let _changes = self.lock_changes(qname).await;
+        for (rtype, ttl) in self.records(qname).into_keys() {
+            if rtype != Rtype::SOA {
+                records.entry((rtype, ttl)).or_default();
+            }
+        }
+
+        self.write_records(qname, records).await
     }
 
     fn has_zone<N>(&self, qname: &N, class: Class) -> bool
@@ -321,8 +987,8 @@ impl Zones {
             return false;
         }
 
-        let zones = self.0.read().unwrap();
-        zones.find_zone(qname).is_some()
+        let zones = self.tree.load();
+        zones.get_zone(qname).is_some()
     }
 
     pub fn insert_zone(&self, zone: Zone) -> Result<(), Error> {
@@ -332,8 +998,24 @@ impl Zones {
         }
 
         log::info!(target: "zone_change", "adding zone {}", zone.apex_name());
-        let mut zones = self.0.write().unwrap();
-        zones.insert_zone(zone)
+        self.update(|zones| zones.insert_zone(zone))
+    }
+
+    /// Serves `zone` in place of the zone `old`, if any, in a single update of
+    /// the tree. The tree is left as it was if `zone` cannot be inserted, e.g.
+    /// if its apex is already served by another zone.
+    pub fn replace_zone(&self, old: Option<&StoredName>, zone: Zone) -> Result<(), Error> {
+        log::info!(target: "zone_change", "replacing zone {}", zone.apex_name());
+
+        self.try_update(|zones| {
+            if let Some(old) = old {
+                zones.remove_zone(old)?;
+            }
+            zones.insert_zone(zone)
+        })?;
+
+        self.prune_changes();
+        Ok(())
     }
 
     pub fn remove_zone<N>(&self, name: &N, class: Class) -> Result<(), Error>
@@ -342,24 +1024,125 @@ impl Zones {
     {
         log::info!(target: "zone_change", "removing zone {} {}", name.to_bytes(), class);
 
-        let mut zones = self.0.write().unwrap();
+        self.update(|zones| {
+            for z in zones.iter_zones() {
+                log::debug!(target: "zone_change", "zones present {} {}", z.apex_name(), z.class());
+            }
 
-        for z in zones.iter_zones() {
-            log::debug!(target: "zone_change", "zones present {} {}", z.apex_name(), z.class());
-        }
+            zones.remove_zone(name)?;
 
-        zones.remove_zone(name)?;
+            for z in zones.iter_zones() {
+                log::info!(target: "zone_change", "zones present {} {}", z.apex_name(), z.class());
+            }
 
-        for z in zones.iter_zones() {
-            log::info!(target: "zone_change", "zones present {} {}", z.apex_name(), z.class());
-        }
+            Ok::<_, Error>(())
+        })?;
 
+        self.prune_changes();
         Ok(())
     }
+
+    /// Publishes the tree updated by `f`, the reads in progress keep the
+    /// snapshot they loaded.
+    fn update<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut ZoneTree) -> T,
+    {
+        let _writer = self.writer.lock().unwrap();
+        let mut tree = ZoneTree::clone(&self.tree.load());
+        let result = f(&mut tree);
+        self.tree.store(Arc::new(tree));
+        result
+    }
+
+    /// Publishes the tree updated by `f` if it succeeds, the tree is left
+    /// untouched otherwise.
+    fn try_update<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut ZoneTree) -> Result<T, Error>,
+    {
+        let _writer = self.writer.lock().unwrap();
+        let mut tree = ZoneTree::clone(&self.tree.load());
+        let result = f(&mut tree)?;
+        self.tree.store(Arc::new(tree));
+        Ok(result)
+    }
 }
 
-impl From<Arc<RwLock<ZoneTree>>> for Zones {
-    fn from(value: Arc<RwLock<ZoneTree>>) -> Self {
-        Zones(value)
+impl Zones {
+    /// Stops serving a zone while retaining it so that it can be restored.
+    pub fn disable_zone<N>(&self, name: &N) -> Result<(), Error>
+    where
+        N: ToName,
+    {
+        log::info!(target: "zone_change", "disabling zone {}", name.to_bytes());
+
+        self.update(|zones| zones.disable_zone(name))?;
+
+        self.prune_changes();
+        Ok(())
+    }
+
+    pub fn restore_zone<N>(&self, name: &N) -> Result<(), Error>
+    where
+        N: ToName,
+    {
+        self.update(|zones| zones.restore_zone(name))?;
+
+        log::info!(target: "zone_change", "restored zone {}", name.to_bytes());
+        Ok(())
+    }
+
+    /// Returns the disabled zones and the time elapsed since they were disabled.
+    pub fn disabled_zones(&self) -> Vec<(StoredName, Duration)> {
+        let zones = self.tree.load();
+        zones
+            .disabled_zones()
+            .map(|(name, elapsed)| (name.clone(), elapsed))
+            .collect()
+    }
+
+    /// Drops the zones disabled for longer than `retention`.
+    pub fn purge_disabled_zones(&self, retention: Duration) {
+        for name in self.update(|zones| zones.purge_disabled_zones(retention)) {
+            log::info!(target: "zone_change", "purged disabled zone {}", name);
+        }
+        self.prune_changes();
+    }
+}
+
+impl From<ZoneTree> for Zones {
+    fn from(value: ZoneTree) -> Self {
+        Zones {
+            tree: Arc::new(ArcSwap::from_pointee(value)),
+            writer: Arc::default(),
+            changes: Arc::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::testing::{dnsr, ZONE};
+    use super::*;
+
+    #[test]
+    fn the_change_locks_of_the_zones_no_longer_served_are_dropped() {
+        let dnsr = dnsr();
+        let apex = StoredName::bytes_from_str(ZONE).unwrap();
+
+        drop(block_on(dnsr.zones.lock_changes(&apex)));
+        assert!(dnsr.zones.changes.lock().unwrap().contains_key(&apex));
+
+        // A lock held by a change in progress outlives its zone
+        let guard = block_on(dnsr.zones.lock_changes(&apex));
+        dnsr.zones.disable_zone(&apex).unwrap();
+        assert!(dnsr.zones.changes.lock().unwrap().contains_key(&apex));
+
+        drop(guard);
+        dnsr.zones.purge_disabled_zones(Duration::ZERO);
+        assert!(dnsr.zones.changes.lock().unwrap().is_empty());
     }
 }
@@ -0,0 +1,202 @@
+//! Detection of anomalous changes of the zones.
+//!
+//! Every zone keeps a rolling baseline, an exponentially weighted moving
+//! average, of its number of updates per window and of its number of records.
+//! An update pushing one of them well above its baseline is reported, which
+//! catches runaway ACME clients or a leaked key early.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use domain::base::ToName;
+use domain::zonetree::types::StoredName;
+
+use crate::config::AlertConfig;
+
+/// The weight of the last observation in the baselines.
+const SMOOTHING: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alert {
+    UpdateRate { updates: u32, baseline: f64 },
+    RecordCount { records: usize, baseline: f64 },
+}
+
+impl Alert {
+    /// Returns the kind of the alert, its counter in the metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Alert::UpdateRate { .. } => "update_rate",
+            Alert::RecordCount { .. } => "record_count",
+        }
+    }
+}
+
+impl std::fmt::Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Alert::UpdateRate { updates, baseline } => write!(
+                f,
+                "{} updates in the current window against a baseline of {:.1}",
+                updates, baseline
+            ),
+            Alert::RecordCount { records, baseline } => write!(
+                f,
+                "{} records against a baseline of {:.1}",
+                records, baseline
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ChangeMonitor {
+    config: AlertConfig,
+    zones: Mutex<HashMap<StoredName, ZoneActivity>>,
+}
+
+#[derive(Debug)]
+struct ZoneActivity {
+    window_start: Instant,
+    updates: u32,
+    rate_alerted: bool,
+    update_baseline: Option<f64>,
+    record_baseline: Option<f64>,
+}
+
+impl ChangeMonitor {
+    pub fn new(config: AlertConfig) -> Self {
+        Self {
+            config,
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records an update of the zone `apex` which now holds `records` records
+    /// and logs a warning for every anomaly detected, which are returned.
+    pub fn record_update<N>(&self, apex: &N, records: usize) -> Vec<Alert>
+    where
+        N: ToName,
+    {
+        let alerts = self.observe(apex, records, Instant::now());
+        for alert in &alerts {
+            log::warn!(target: "alert", "anomalous change of zone {}: {}", apex.to_bytes(), alert);
+        }
+        alerts
+    }
+
+    fn observe<N>(&self, apex: &N, records: usize, now: Instant) -> Vec<Alert>
+    where
+        N: ToName,
+    {
+        let window = self.config.window();
+        let mut zones = self.zones.lock().unwrap();
+        let activity = zones.entry(apex.to_bytes()).or_insert(ZoneActivity {
+            window_start: now,
+            updates: 0,
+            rate_alerted: false,
+            update_baseline: None,
+            record_baseline: None,
+        });
+
+        // Fold the elapsed windows in the baseline, the windows without any
+        // update count as zero
+        let elapsed = now.saturating_duration_since(activity.window_start);
+        let windows = (elapsed.as_secs_f64() / window.as_secs_f64()).floor();
+        if windows >= 1.0 {
+            let baseline = ewma(activity.update_baseline, activity.updates as f64);
+            activity.update_baseline = Some(baseline * (1.0 - SMOOTHING).powf(windows - 1.0));
+            activity.window_start += window.mul_f64(windows);
+            activity.updates = 0;
+            activity.rate_alerted = false;
+        }
+
+        let mut alerts = Vec::new();
+        activity.updates += 1;
+        if let Some(baseline) = activity.update_baseline {
+            if !activity.rate_alerted
+                && activity.updates >= self.config.min_updates()
+                && activity.updates as f64 > self.config.factor() * baseline.max(1.0)
+            {
+                activity.rate_alerted = true;
+                alerts.push(Alert::UpdateRate {
+                    updates: activity.updates,
+                    baseline,
+                });
+            }
+        }
+
+        if let Some(baseline) = activity.record_baseline {
+            if records >= self.config.min_records()
+                && records as f64 > self.config.factor() * baseline.max(1.0)
+            {
+                alerts.push(Alert::RecordCount { records, baseline });
+            }
+        }
+        activity.record_baseline = Some(ewma(activity.record_baseline, records as f64));
+
+        alerts
+    }
+}
+
+fn ewma(baseline: Option<f64>, value: f64) -> f64 {
+    match baseline {
+        Some(baseline) => baseline + SMOOTHING * (value - baseline),
+        None => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use domain::base::Name;
+
+    use super::*;
+
+    fn monitor() -> ChangeMonitor {
+        let config =
+            serde_yaml::from_str("{ window: 60, factor: 5, min_updates: 10, min_records: 20 }");
+        ChangeMonitor::new(config.unwrap())
+    }
+
+    #[test]
+    fn update_burst_is_reported_once() {
+        let monitor = monitor();
+        let apex = Name::<Vec<u8>>::from_str("_acme-challenge.example.fr.").unwrap();
+        let start = Instant::now();
+
+        // A steady rate of two updates per window
+        for i in 0..20 {
+            let now = start + Duration::from_secs(30 * i);
+            assert!(monitor.observe(&apex, 1, now).is_empty());
+        }
+
+        let now = start + Duration::from_secs(30 * 20);
+        let alerts = (0..20)
+            .flat_map(|_| monitor.observe(&apex, 1, now))
+            .collect::<Vec<_>>();
+        assert_eq!(alerts.len(), 1);
+        assert!(matches!(alerts[0], Alert::UpdateRate { updates: 11, .. }));
+    }
+
+    #[test]
+    fn record_count_jump_is_reported() {
+        let monitor = monitor();
+        let apex = Name::<Vec<u8>>::from_str("_acme-challenge.example.fr.").unwrap();
+        let start = Instant::now();
+
+        for i in 0..10 {
+            let now = start + Duration::from_secs(600 * i);
+            assert!(monitor.observe(&apex, 2, now).is_empty());
+        }
+
+        let alerts = monitor.observe(&apex, 50, start + Duration::from_secs(6000));
+        assert!(matches!(
+            alerts[..],
+            [Alert::RecordCount { records: 50, .. }]
+        ));
+    }
+}
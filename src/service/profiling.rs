@@ -0,0 +1,134 @@
+//! Timers of the request handling stages.
+//!
+//! The timers are only compiled in with the `profiling` feature. Without it
+//! [`time`] simply calls its closure, so the default builds pay nothing for
+//! the instrumentation. The timings are reported with the server metrics.
+//!
+//! Independently of the feature, the stages of a traced request are recorded
+//! as spans of its trace when the telemetry is enabled.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Parsing of the request message.
+    Parse,
+    /// Verification of the TSIG signature of the request.
+    TsigVerify,
+    /// Lookup of the answer in the zones.
+    Lookup,
+    /// Building of the response message.
+    Build,
+    /// TSIG signing of the response.
+    Sign,
+    /// Walk of the zone for a transfer.
+    AxfrWalk,
+}
+
+impl Stage {
+    #[cfg(feature = "profiling")]
+    const ALL: [Stage; 6] = [
+        Stage::Parse,
+        Stage::TsigVerify,
+        Stage::Lookup,
+        Stage::Build,
+        Stage::Sign,
+        Stage::AxfrWalk,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Parse => "parse",
+            Stage::TsigVerify => "tsig_verify",
+            Stage::Lookup => "lookup",
+            Stage::Build => "build",
+            Stage::Sign => "sign",
+            Stage::AxfrWalk => "axfr_walk",
+        }
+    }
+}
+
+/// Runs `f` and records its duration under `stage`.
+#[inline(always)]
+pub fn time<T, F>(stage: Stage, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    #[cfg(feature = "profiling")]
+    {
+        let start = std::time::Instant::now();
+        let result = crate::telemetry::in_span(stage.name(), f);
+        timings::record(stage, start.elapsed());
+        result
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    crate::telemetry::in_span(stage.name(), f)
+}
+
+#[cfg(feature = "profiling")]
+pub use timings::Report;
+
+#[cfg(feature = "profiling")]
+mod timings {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    use super::Stage;
+
+    struct Timing {
+        count: AtomicU64,
+        total_ns: AtomicU64,
+        max_ns: AtomicU64,
+    }
+
+    impl Timing {
+        const fn new() -> Self {
+            Self {
+                count: AtomicU64::new(0),
+                total_ns: AtomicU64::new(0),
+                max_ns: AtomicU64::new(0),
+            }
+        }
+    }
+
+    static TIMINGS: [Timing; 6] = [
+        Timing::new(),
+        Timing::new(),
+        Timing::new(),
+        Timing::new(),
+        Timing::new(),
+        Timing::new(),
+    ];
+
+    pub(super) fn record(stage: Stage, duration: Duration) {
+        let timing = &TIMINGS[stage as usize];
+        let ns = duration.as_nanos().min(u64::MAX as u128) as u64;
+
+        timing.count.fetch_add(1, Ordering::Relaxed);
+        timing.total_ns.fetch_add(ns, Ordering::Relaxed);
+        timing.max_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    /// Displays the mean and max duration of every stage.
+    pub struct Report;
+
+    impl std::fmt::Display for Report {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Stages [")?;
+            for (i, stage) in Stage::ALL.iter().enumerate() {
+                let timing = &TIMINGS[*stage as usize];
+                let count = timing.count.load(Ordering::Relaxed);
+                let total = timing.total_ns.load(Ordering::Relaxed);
+                let max = timing.max_ns.load(Ordering::Relaxed);
+
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                match total.checked_div(count) {
+                    Some(mean) => write!(f, "{}={}μs/{}μs", stage.name(), mean / 1000, max / 1000)?,
+                    None => write!(f, "{}=-", stage.name())?,
+                }
+            }
+            write!(f, "]")
+        }
+    }
+}
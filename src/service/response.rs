@@ -0,0 +1,206 @@
+//! Building of the responses of the zones.
+//!
+//! Every response is built from the request it answers by a
+//! [`ResponseBuilder`]: it copies the question and the header of the request,
+//! then runs its hooks on the finished message. The hooks adjust the responses
+//! of a kind as a whole, e.g. the header values of the zone transfers or a
+//! TSIG signature, so that a new kind of response only fills its sections.
+
+use std::sync::Arc;
+
+use domain::base::iana::{Opcode, Rcode};
+use domain::base::message_builder::AdditionalBuilder;
+use domain::base::rdata::{ComposeRecordData, RecordData};
+use domain::base::record::ComposeRecord;
+use domain::base::{Message, StreamTarget, ToName, Ttl};
+use domain::net::server::service::{CallResult, ServiceError};
+use domain::net::server::util::mk_builder_for_target;
+use domain::zonetree::types::StoredName;
+use domain::zonetree::{Answer, AnswerContent, SharedRrset};
+use futures::channel::mpsc::UnboundedSender;
+
+use super::handler::HandlerResult;
+use crate::config::TransferTtl;
+
+pub type Response = AdditionalBuilder<StreamTarget<Vec<u8>>>;
+
+/// A hook run on every response with the request it answers.
+pub type Hook = Arc<dyn Fn(&Message<Vec<u8>>, &mut Response) + Send + Sync>;
+
+/// The sender of the responses of a multi-message answer, e.g. a transfer.
+pub type ResponseSender = UnboundedSender<HandlerResult<CallResult<Vec<u8>>>>;
+
+/// The CNAME RRsets followed from the name of a question.
+pub struct CnameChain {
+    /// The CNAME RRsets along with their owner, in the order followed
+    pub links: Vec<(StoredName, SharedRrset)>,
+    /// The name the chain ends with, the owner of the final answer
+    pub owner: StoredName,
+}
+
+#[derive(Clone)]
+pub struct ResponseBuilder {
+    request: Arc<Message<Vec<u8>>>,
+    hooks: Vec<Hook>,
+    /// The bounds of the TTLs of the RRsets
+    ttl: Option<TransferTtl>,
+}
+
+impl ResponseBuilder {
+    pub fn new(request: Arc<Message<Vec<u8>>>) -> Self {
+        Self {
+            request,
+            hooks: Vec::new(),
+            ttl: None,
+        }
+    }
+
+    /// Runs `hook` on every response built, after the previous hooks.
+    pub fn with_hook(mut self, hook: Hook) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Sets the header values of a zone transfer on every response built.
+    pub fn transfer(self) -> Self {
+        self.with_hook(Arc::new(set_transfer_header))
+    }
+
+    /// Clamps the TTLs of the RRsets to the bounds of `ttl`, if any, for the
+    /// secondaries mishandling some TTLs.
+    pub fn with_transfer_ttl(mut self, ttl: Option<TransferTtl>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Builds the response holding `answer`.
+    pub fn answer(&self, answer: &Answer) -> Response {
+        let response = answer.to_message(&self.request, mk_builder_for_target());
+        self.finish(response)
+    }
+
+    /// Builds the authoritative response holding the CNAME RRsets of `chain`
+    /// followed by `answer`, the answer of the name the chain ends with, and
+    /// the SOA of its zone in the authority section if the answer is negative.
+    pub fn cname_chain(
+        &self,
+        chain: &CnameChain,
+        answer: &Answer,
+        soa: Option<(StoredName, SharedRrset)>,
+    ) -> HandlerResult<Response> {
+        let mut builder = mk_builder_for_target()
+            .start_answer(&self.request, answer.rcode())
+            .map_err(|_| ServiceError::InternalError)?;
+        builder.header_mut().set_aa(true);
+
+        let last = match answer.content() {
+            AnswerContent::Data(rrset) | AnswerContent::Cname(rrset) => Some((&chain.owner, rrset)),
+            AnswerContent::NoData => None,
+        };
+        let rrsets = chain.links.iter().map(|(owner, rrset)| (owner, rrset));
+        for (owner, rrset) in rrsets.chain(last) {
+            for data in rrset.data() {
+                builder
+                    .push((owner, rrset.ttl(), data))
+                    .map_err(|_| ServiceError::InternalError)?;
+            }
+        }
+
+        let mut authority = builder.authority();
+        if let Some((apex, soa)) = &soa {
+            for data in soa.data() {
+                authority
+                    .push((apex, soa.ttl(), data))
+                    .map_err(|_| ServiceError::InternalError)?;
+            }
+        }
+        Ok(self.finish(authority.additional()))
+    }
+
+    /// Builds an empty response with `rcode`.
+    pub fn error(&self, rcode: Rcode) -> Response {
+        self.answer(&Answer::new(rcode))
+    }
+
+    /// Builds an authoritative response holding `records` in its answer
+    /// section.
+    pub fn records<I>(&self, records: I) -> HandlerResult<Response>
+    where
+        I: IntoIterator,
+        I::Item: ComposeRecord,
+    {
+        let mut answer = mk_builder_for_target()
+            .start_answer(&self.request, Rcode::NOERROR)
+            .map_err(|_| ServiceError::InternalError)?;
+        answer.header_mut().set_aa(true);
+        for record in records {
+            answer
+                .push(record)
+                .map_err(|_| ServiceError::InternalError)?;
+        }
+        Ok(self.finish(answer.additional()))
+    }
+
+    /// Builds an authoritative response holding the `records` of the RRset of
+    /// `owner` and `ttl`, the TTL is clamped to the bounds of the builder.
+    pub fn rrset<N, I>(&self, owner: &N, ttl: Ttl, records: I) -> HandlerResult<Response>
+    where
+        N: ToName,
+        I: IntoIterator,
+        I::Item: RecordData + ComposeRecordData,
+    {
+        let ttl = self.ttl.as_ref().map_or(ttl, |bounds| bounds.clamp(ttl));
+        self.records(records.into_iter().map(|data| (owner, ttl, data)))
+    }
+
+    /// Builds the response holding `answer` and sends it to `sender`.
+    pub fn send_answer(&self, answer: &Answer, sender: &ResponseSender) {
+        send(self.answer(answer), sender);
+    }
+
+    fn finish(&self, mut response: Response) -> Response {
+        for hook in &self.hooks {
+            hook(&self.request, &mut response);
+        }
+        response
+    }
+}
+
+/// Sends `response` to the stream of `sender`, the stream may already be
+/// closed by the server.
+pub fn send(response: Response, sender: &ResponseSender) {
+    let _ = sender.unbounded_send(Ok(CallResult::new(response)));
+}
+
+fn set_transfer_header(msg: &Message<Vec<u8>>, response: &mut Response) {
+    // https://datatracker.ietf.org/doc/html/rfc5936#section-2.2.1
+    // 2.2.1: Header Values
+    //
+    // "These are the DNS message header values for AXFR responses.
+    //
+    //     ID          MUST be copied from request -- see Note a)
+    //
+    //     QR          MUST be 1 (Response)
+    //
+    //     OPCODE      MUST be 0 (Standard Query)
+    //
+    //     Flags:
+    //        AA       normally 1 -- see Note b)
+    //        TC       MUST be 0 (Not truncated)
+    //        RD       RECOMMENDED: copy request's value; MAY be set to 0
+    //        RA       SHOULD be 0 -- see Note c)
+    //        Z        "mbz" -- see Note d)
+    //        AD       "mbz" -- see Note d)
+    //        CD       "mbz" -- see Note d)"
+    let header = response.header_mut();
+    header.set_id(msg.header().id());
+    header.set_qr(true);
+    header.set_opcode(Opcode::QUERY);
+    header.set_aa(true);
+    header.set_tc(false);
+    header.set_rd(msg.header().rd());
+    header.set_ra(false);
+    header.set_z(false);
+    header.set_ad(false);
+    header.set_cd(false);
+}
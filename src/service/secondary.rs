@@ -0,0 +1,117 @@
+//! The NOTIFY of the updated zones to the secondaries.
+//!
+//! Once a zone is updated, a NOTIFY of the zone is sent to every configured
+//! secondary, signed with its TSIG key if any, so that it transfers the new
+//! challenge records at once instead of waiting for the refresh timer of the
+//! SOA. The notifications run on their own thread, a NOTIFY which is not
+//! acknowledged is sent again up to the `retries` of the secondary with a
+//! wait of its `backoff` doubled on every retry in between, as in RFC 1996
+//! section 3.6. The changes queued
+//! while notifying are coalesced: a single NOTIFY is sent per zone, and none
+//! for a serial which is not newer than the last one notified.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Sender};
+
+use domain::base::ToName;
+use domain::tsig::{Key, KeyName};
+use domain::zonetree::types::StoredName;
+
+use super::flush;
+use super::KeyStore;
+use crate::config::SecondaryConfig;
+use crate::error::Result;
+use crate::serial::is_newer;
+
+#[derive(Debug)]
+pub struct SecondaryNotifier {
+    sender: Sender<(StoredName, u32)>,
+}
+
+impl SecondaryNotifier {
+    /// Starts the thread notifying the `secondaries`, their keys are looked
+    /// up in `keystore` when a NOTIFY is sent.
+    pub fn new(secondaries: Vec<SecondaryConfig>, keystore: KeyStore) -> Self {
+        let (sender, receiver) = channel::<(StoredName, u32)>();
+        std::thread::spawn(move || {
+            let mut notified = HashMap::<StoredName, u32>::new();
+            while let Ok(change) = receiver.recv() {
+                let mut pending = HashMap::new();
+                for (apex, serial) in std::iter::once(change).chain(receiver.try_iter()) {
+                    let newest = pending.entry(apex).or_insert(serial);
+                    if is_newer(serial, *newest) {
+                        *newest = serial;
+                    }
+                }
+
+                for (apex, serial) in pending {
+                    if notified
+                        .get(&apex)
+                        .is_some_and(|last| !is_newer(serial, *last))
+                    {
+                        continue;
+                    }
+                    notified.insert(apex.clone(), serial);
+                    notify_all(&secondaries, &keystore, &apex);
+                }
+            }
+        });
+
+        Self { sender }
+    }
+
+    /// Queues the NOTIFY of the zone `apex`, updated to `serial`, to every
+    /// secondary.
+    pub fn notify<N>(&self, apex: &N, serial: u32)
+    where
+        N: ToName,
+    {
+        let _ = self.sender.send((apex.to_bytes(), serial));
+    }
+}
+
+/// Sends the NOTIFY of `apex` to every secondary.
+fn notify_all(secondaries: &[SecondaryConfig], keystore: &KeyStore, apex: &StoredName) {
+    for secondary in secondaries {
+        let addr = secondary.address();
+        let key = match secondary.key().map(KeyName::try_from).transpose() {
+            Ok(Some(name)) => match keystore.read().unwrap().find_key(&name) {
+                Some(key) => Some(key),
+                None => {
+                    log::error!(target: "secondary", "notify of {} to {} not sent: tsig key {} unavailable", apex, addr, name);
+                    continue;
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                log::error!(target: "secondary", "notify of {} to {} not sent: {}", apex, addr, e);
+                continue;
+            }
+        };
+
+        match notify(secondary, apex, key.as_deref()) {
+            Ok(()) => log::debug!(target: "secondary", "notified {} of {}", addr, apex),
+            Err(e) => {
+                log::warn!(target: "secondary", "failed to notify {} of {}: {}", addr, apex, e)
+            }
+        }
+    }
+}
+
+/// Sends the NOTIFY of `apex` to `secondary` until it is acknowledged or the
+/// retries of the secondary are exhausted.
+fn notify(secondary: &SecondaryConfig, apex: &StoredName, key: Option<&Key>) -> Result<()> {
+    let mut backoff = secondary.backoff();
+    let mut retries = secondary.retries();
+    loop {
+        match flush::notify(secondary.address(), apex, secondary.timeout(), key) {
+            Err(e) if retries > 0 => {
+                log::debug!(target: "secondary", "notify of {} to {} failed, retrying in {:?}: {}", apex, secondary.address(), backoff, e);
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                retries -= 1;
+            }
+            result => return result,
+        }
+    }
+}
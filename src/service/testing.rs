@@ -0,0 +1,129 @@
+//! Fixtures shared by the tests of the service: a server built from a
+//! configuration, its keys and the messages exchanged with it.
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use domain::base::iana::Class;
+use domain::base::{Message, MessageBuilder, Name, Rtype};
+use domain::rdata::Txt;
+use domain::tsig::{Algorithm, Key, KeyName};
+use futures::executor::block_on;
+
+use super::ingest::{ingest, Transport};
+use super::middleware::Stats;
+use super::{middleware_chain, Dnsr, DnsrSvc};
+use crate::config::Config;
+use crate::key::build_zone;
+
+pub const CONFIG: &str = "
+keys:
+  key1:
+    example.fr:
+      mname: ns-acme.example.fr.
+      rname: postmaster.example.fr.
+  key2:
+    another-example.fr:
+      mname: ns-acme.another-example.fr.
+      rname: postmaster.another-example.fr.
+";
+
+pub const ZONE: &str = "_acme-challenge.example.fr.";
+
+/// The address of the client of `call`.
+pub const CLIENT: &str = "127.0.0.1:53000";
+
+pub fn dnsr() -> Arc<Dnsr> {
+    dnsr_from(CONFIG)
+}
+
+/// Builds a server from `config` serving the zones of its domains.
+pub fn dnsr_from(config: &str) -> Arc<Dnsr> {
+    let config = Config::try_from(&config.as_bytes().to_vec()).unwrap();
+    let dnsr = Arc::new(Dnsr::from(Arc::new(config)));
+    let clock = UNIX_EPOCH + Duration::from_secs(1722353587);
+
+    for (name, info) in dnsr.config.keys.domains() {
+        let zone = build_zone(name, info, &clock).unwrap();
+        dnsr.zones.insert_zone(zone).unwrap();
+    }
+
+    dnsr
+}
+
+/// Registers a new TSIG key in the keystore and returns a copy for the client.
+pub fn register_key(dnsr: &Dnsr, name: &str) -> Key {
+    let rng = ring::rand::SystemRandom::new();
+    let name = KeyName::from_str(name).unwrap();
+    let (key, secret) = Key::generate(Algorithm::Sha512, &rng, name.clone(), None, None).unwrap();
+
+    dnsr.keystore.write().unwrap().insert_key(key);
+    Key::new(Algorithm::Sha512, &secret, name, None, None).unwrap()
+}
+
+/// Runs `message` through the middleware chain and returns every response.
+pub fn call(
+    dnsr: &Arc<Dnsr>,
+    message: Message<Vec<u8>>,
+    transport: Transport,
+) -> Vec<Message<Vec<u8>>> {
+    let svc = middleware_chain(dnsr.clone(), Stats::new_shared());
+    call_svc(&svc, &message, CLIENT, transport)
+}
+
+/// Runs `message` sent from `client_addr` through `svc`, which keeps its
+/// state between the calls, and returns every response.
+pub fn call_svc(
+    svc: &DnsrSvc,
+    message: &Message<Vec<u8>>,
+    client_addr: &str,
+    transport: Transport,
+) -> Vec<Message<Vec<u8>>> {
+    let client_addr = SocketAddr::from_str(client_addr).unwrap();
+
+    block_on(ingest(svc, message.as_slice(), client_addr, transport))
+        .unwrap()
+        .into_iter()
+        .map(|response| Message::from_octets(response).unwrap())
+        .collect()
+}
+
+pub fn query(qname: &str, qtype: Rtype) -> Message<Vec<u8>> {
+    class_query(qname, qtype, Class::IN)
+}
+
+/// Builds a query like `query` of the records of `class`.
+pub fn class_query(qname: &str, qtype: Rtype, class: Class) -> Message<Vec<u8>> {
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_id(0xbeef);
+    builder.header_mut().set_rd(true);
+
+    let mut question = builder.question();
+    question
+        .push((Name::<Vec<u8>>::from_str(qname).unwrap(), qtype, class))
+        .unwrap();
+    question.into_message()
+}
+
+pub fn answer_types(message: &Message<Vec<u8>>) -> Vec<Rtype> {
+    message
+        .answer()
+        .unwrap()
+        .map(|record| record.unwrap().rtype())
+        .collect()
+}
+
+/// Returns the TXT records of the answer of `message`, one string each.
+pub fn texts(message: &Message<Vec<u8>>) -> Vec<String> {
+    message
+        .answer()
+        .unwrap()
+        .limit_to::<Txt<_>>()
+        .map(|record| {
+            let text = record.unwrap().data().iter().flatten().copied().collect();
+            String::from_utf8(text).unwrap()
+        })
+        .collect()
+}
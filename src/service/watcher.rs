@@ -1,19 +1,44 @@
+use std::ffi::OsString;
 use std::fs::File;
-use std::path::Path;
-use std::sync::mpsc::channel;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+use domain::zonetree::types::StoredName;
+use domain::zonetree::Zone;
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher as NotifyWatcher};
 
-use crate::error::Result;
-use crate::key::{DomainInfo, DomainName, KeyFile, Keys, TryInto};
+use crate::config::WebhookEvent;
+use crate::dname::DomainName;
+use crate::error::{ErrorKind, Result};
+use crate::key::{DomainInfo, KeyFile, Keys, TryInto};
+use crate::vault::Vault;
+
+use super::zone_files::ZoneFiles;
+
+/// The interval between two attempts to load the unavailable keys.
+const KEY_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The interval between two checks of the key files against the keys.
+const KEY_FILES_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The key files modified more recently are never reported as orphaned, they
+/// may belong to a key being provisioned through the admin API.
+const KEY_FILE_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+/// The longest burst of events debounced, in debounce windows, so that files
+/// changed continuously are still reloaded.
+const MAX_DEBOUNCE_WINDOWS: u32 = 10;
 
 pub trait Watcher {
-    fn watch_lock(&self) -> Result<()>;
+    /// Loads the zones of the configuration and watches its changes,
+    /// `on_loaded` is called once the zones are loaded.
+    fn watch_lock(&self, on_loaded: impl FnOnce()) -> Result<()>;
 }
 
 impl Watcher for super::Dnsr {
-    fn watch_lock(&self) -> Result<()> {
+    fn watch_lock(&self, on_loaded: impl FnOnce()) -> Result<()> {
         // Retrieve path
         let file_path = crate::config::Config::config_file_path();
         let path = Path::new(&file_path);
@@ -21,14 +46,120 @@ impl Watcher for super::Dnsr {
         // Initialize the watcher
         let (tx, rx) = channel();
         let mut watcher = Box::new(RecommendedWatcher::new(tx, Config::default())?);
-        watcher.watch(path, RecursiveMode::NonRecursive)?;
+        // A change of an included file or of the blocklist file reloads the
+        // whole configuration
+        let blocklist_file = self.config.blocklist_config().and_then(|c| c.file());
+        let mut config_files = ConfigFiles::new(
+            std::iter::once(path)
+                .chain(self.config.includes().iter().map(PathBuf::as_path))
+                .chain(blocklist_file),
+        );
+        for dir in config_files.dirs() {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        }
 
         // Initialize the dns zones
-        initialize_dns_zones(&self.config, &self.zones, &self.keystore)?;
+        initialize_dns_zones(&self.config, &self.zones, &self.keystore, &self.provisioned)?;
+        if let Some(snapshots) = &self.snapshots {
+            if let Err(e) = snapshots.restore(&self.zones, &self.journal) {
+                log::error!(target: "s3", "failed to restore zones snapshot: {}", e);
+            }
+        }
+        let mut zone_files = self.config.zones_dir().map(ZoneFiles::new);
+        if let Some(zone_files) = &mut zone_files {
+            zone_files.load_all(&self.zones)?;
+            watcher.watch(zone_files.dir(), RecursiveMode::NonRecursive)?;
+        }
+        on_loaded();
+        // The key files created, rotated or deleted by external tools
+        let key_dir = self.config.tsig_path();
+        watcher.watch(key_dir, RecursiveMode::NonRecursive)?;
         let mut keys = self.config.keys.clone();
+        check_key_files(self, &keys);
+        let mut last_check = Instant::now();
+        let mut last_vault_check = Instant::now();
+        #[cfg(feature = "kubernetes")]
+        let mut configmaps = self
+            .config
+            .kubernetes_config()
+            .and_then(|config| crate::kubernetes::version(config).ok());
+        let mut kv_keys = self
+            .config
+            .kv_config()
+            .and_then(|config| crate::kv::version(config).ok());
+
+        loop {
+            *self.watcher_heartbeat.lock().unwrap() = Some(Instant::now());
+            match rx.recv_timeout(KEY_RETRY_INTERVAL) {
+                Ok(event) => {
+                    let events = debounce(&rx, event, self.config.reload_debounce());
+                    let zones_dir = zone_files.as_ref().map(ZoneFiles::dir);
+                    let changes =
+                        Changes::from_events(&events, &mut config_files, key_dir, zones_dir);
 
-        while rx.recv().is_ok() {
-            keys = handle_file_change(&keys, path, &self.keystore, &self.zones)?;
+                    if changes.config {
+                        keys = handle_file_change(self, &keys, path)?;
+                    }
+                    if !changes.key_files.is_empty() {
+                        reload_key_files(self, &keys, &changes.key_files);
+                    }
+                    if let Some(zone_files) = &mut zone_files {
+                        zone_files.reload(&self.zones, &changes.zone_files);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    reload_unavailable(&self.keystore);
+                    if last_check.elapsed() >= KEY_FILES_CHECK_INTERVAL {
+                        check_key_files(self, &keys);
+                        last_check = Instant::now();
+                    }
+                    let vault = self.keystore.read().unwrap().vault();
+                    if let Some(vault) = vault {
+                        if last_vault_check.elapsed() >= vault.interval() {
+                            reload_vault_keys(self, &keys, &vault);
+                            last_vault_check = Instant::now();
+                        }
+                    }
+                    // The ConfigMaps are polled, a failure keeps the loaded domains
+                    #[cfg(feature = "kubernetes")]
+                    if let Some(config) = self.config.kubernetes_config() {
+                        match crate::kubernetes::version(config) {
+                            Ok(version) if configmaps.as_ref() != Some(&version) => {
+                                configmaps = Some(version);
+                                match handle_file_change(self, &keys, path) {
+                                    Ok(loaded) => keys = loaded,
+                                    Err(e) => {
+                                        log::error!(target: "kubernetes", "configmaps not reloaded: {}", e)
+                                    }
+                                }
+                            }
+                            Ok(_) => (),
+                            Err(e) => {
+                                log::warn!(target: "kubernetes", "failed to list the configmaps: {}", e)
+                            }
+                        }
+                    }
+                    // The keys of etcd or Consul are polled as well
+                    if let Some(config) = self.config.kv_config() {
+                        match crate::kv::version(config) {
+                            Ok(version) if kv_keys.as_ref() != Some(&version) => {
+                                kv_keys = Some(version);
+                                match handle_file_change(self, &keys, path) {
+                                    Ok(loaded) => keys = loaded,
+                                    Err(e) => {
+                                        log::error!(target: "kv", "keys not reloaded: {}", e)
+                                    }
+                                }
+                            }
+                            Ok(_) => (),
+                            Err(e) => {
+                                log::warn!(target: "kv", "failed to list the keys: {}", e)
+                            }
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
         }
 
         Ok(())
@@ -39,6 +170,7 @@ fn initialize_dns_zones(
     config: &Arc<crate::config::Config>,
     zones: &super::Zones,
     keystore: &super::KeyStore,
+    provisioned: &RwLock<Keys>,
 ) -> Result<()> {
     {
         // Create the key folder if it does not exist
@@ -48,29 +180,48 @@ fn initialize_dns_zones(
         }
     }
 
-    for (k, v) in config.keys.iter() {
-        v.try_into_t()?.into_iter().try_for_each(|z| {
-            {
-                let mut keystore = keystore.write().unwrap();
-                keystore.add_key(k)?;
-            }
+    for path in config.import_keys() {
+        let mut keystore = keystore.write().unwrap();
+        keystore.import_keys(path)?;
+    }
 
-            zones.insert_zone(z)
+    let provisioned_path = config.provisioned_keys_path();
+    if provisioned_path.is_file() {
+        let mut provisioned = provisioned.write().unwrap();
+        *provisioned = serde_yaml::from_reader(File::open(provisioned_path)?)?;
+    }
+
+    let provisioned = provisioned.read().unwrap();
+    for (k, v) in config.keys.iter().chain(provisioned.iter()) {
+        v.try_into_t()?.into_iter().try_for_each(|z| {
+            // The zone is served even if its key is unavailable
+            add_key(keystore, k);
+            insert_within_limit(zones, z)
         })?;
     }
 
     Ok(())
 }
 
-fn handle_file_change(
-    keys: &Keys,
-    config_path: &Path,
-    keystore: &super::KeyStore,
-    zones: &super::Zones,
-) -> Result<Keys> {
-    let new_config =
-        serde_yaml::from_reader::<File, crate::config::Config>(File::open(config_path)?)?;
+/// Inserts `zone`, it is skipped if the limit of zones is reached so that the
+/// other zones are still loaded.
+fn insert_within_limit(zones: &super::Zones, zone: Zone) -> Result<()> {
+    match zones.insert_zone(zone) {
+        Err(e) if e.kind == ErrorKind::Limit => {
+            log::error!(target: "watcher", "zone not loaded: {}", e);
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+fn handle_file_change(dnsr: &super::Dnsr, keys: &Keys, config_path: &Path) -> Result<Keys> {
+    let new_config = crate::config::Config::from_file(config_path)?;
     log::debug!(target: "config_file", "new config loaded {:?}", new_config);
+    let retention = new_config.removed_zone_retention();
+    if let Err(e) = dnsr.blocklist.reload(new_config.blocklist_config()) {
+        log::error!(target: "blocklist", "blocklist not reloaded: {}", e);
+    }
     let loaded_keys = new_config.keys;
 
     let new_domains = loaded_keys.domains();
@@ -78,8 +229,15 @@ fn handle_file_change(
     let new_keys = loaded_keys.keys();
     let old_keys = keys.keys();
 
-    handle_keys_change(keystore, &old_keys, &new_keys)?;
-    handle_domains_change(zones, &old_domains, &new_domains)?;
+    // The imported keys are loaded first so that the added keys found in the
+    // BIND key files are not generated
+    for path in new_config.import_keys() {
+        if let Err(e) = dnsr.keystore.write().unwrap().import_keys(path) {
+            log::error!(target: "tsig_file", "failed to import the tsig keys of {}: {}", path.display(), e);
+        }
+    }
+    handle_keys_change(&dnsr.keystore, &old_keys, &new_keys)?;
+    handle_domains_change(dnsr, keys, &old_domains, &new_domains, retention)?;
 
     Ok(loaded_keys)
 }
@@ -90,29 +248,368 @@ fn handle_keys_change(
     new_keys: &[&KeyFile],
 ) -> Result<()> {
     let mut deleted_keys = old_keys.iter().filter(|k| !new_keys.contains(k));
-    let mut added_keys = new_keys.iter().filter(|k| !old_keys.contains(k));
+    let added_keys = new_keys.iter().filter(|k| !old_keys.contains(k));
 
     deleted_keys.try_for_each(|&k| -> Result<()> {
-        let mut keystore = keystore.write().unwrap();
-        keystore.remove_key(k)?;
+        let removed = keystore.write().unwrap().remove_key(k)?;
+        // The secret is deleted once the keystore is released
+        if removed {
+            keystore.read().unwrap().secrets().delete(k)?;
+        }
 
         Ok(())
     })?;
 
-    added_keys.try_for_each(|&k| -> Result<()> {
-        let mut keystore = keystore.write().unwrap();
-        keystore.add_key(k)?;
-
-        Ok(())
-    })?;
+    added_keys.for_each(|&k| add_key(keystore, k));
 
     Ok(())
 }
 
+/// Adds `key` to the keystore, a key which cannot be loaded is left
+/// unavailable and retried later instead of stopping the server.
+fn add_key(keystore: &super::KeyStore, key: &KeyFile) {
+    if let Err(e) = fetch_key(keystore, key) {
+        log::error!(target: "tsig_file", "tsig key {} is unavailable, its signed requests are refused: {}", key, e);
+    }
+}
+
+/// Generates `key`, or loads it if its secret exists, and inserts it. The
+/// keystore is only locked to insert the key, so that the signed requests are
+/// not blocked while Vault answers.
+fn fetch_key(keystore: &super::KeyStore, key: &KeyFile) -> Result<()> {
+    let secrets = {
+        let keystore = keystore.read().unwrap();
+        // Keep the key already loaded, e.g. imported from a BIND key file
+        if keystore.is_loaded(key) {
+            log::info!(target: "tsig_file", "tsig key {} already loaded - skipping", key);
+            return Ok(());
+        }
+        keystore.secrets()
+    };
+    let fetched = secrets.fetch(key);
+    keystore.write().unwrap().set_key(key, fetched)
+}
+
+/// Loads `key` again from its file or Vault, e.g. rotated by an external
+/// tool. The key is unavailable until its secret is back if it was deleted.
+fn reload_key(keystore: &super::KeyStore, key: &KeyFile) -> Result<()> {
+    let secrets = keystore.read().unwrap().secrets();
+    let loaded = secrets.load(key);
+    keystore.write().unwrap().set_key(key, loaded)
+}
+
+/// Tries to load the unavailable keys again.
+fn reload_unavailable(keystore: &super::KeyStore) {
+    let retried = keystore.read().unwrap().retried_keys();
+    for key in retried {
+        match fetch_key(keystore, &key) {
+            Ok(()) => log::info!(target: "tsig_file", "tsig key {} is available again", key),
+            Err(e) => {
+                log::debug!(target: "tsig_file", "tsig key {} is still unavailable: {}", key, e)
+            }
+        }
+    }
+}
+
+/// Collects the events following `first` until none is received for `window`,
+/// or for at most `MAX_DEBOUNCE_WINDOWS` windows, so that a burst of events is
+/// handled at once.
+fn debounce<T>(rx: &Receiver<T>, first: T, window: Duration) -> Vec<T> {
+    let deadline = Instant::now() + window * MAX_DEBOUNCE_WINDOWS;
+    let mut events = vec![first];
+    while Instant::now() < deadline {
+        match rx.recv_timeout(window) {
+            Ok(event) => events.push(event),
+            Err(_) => break,
+        }
+    }
+    events
+}
+
+/// The files changed by a burst of events.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Changes {
+    /// Whether the configuration file or one of its includes changed
+    config: bool,
+    key_files: Vec<PathBuf>,
+    zone_files: Vec<PathBuf>,
+}
+
+impl Changes {
+    /// Sorts the files changed by `events` between the configuration files,
+    /// the key folder `key_dir` and the zone files folder `zones_dir`.
+    fn from_events(
+        events: &[notify::Result<notify::Event>],
+        config_files: &mut ConfigFiles,
+        key_dir: &Path,
+        zones_dir: Option<&Path>,
+    ) -> Self {
+        let mut changes = Changes::default();
+        for event in events {
+            // The configuration is reloaded on an error, a change may be lost
+            changes.config |= event
+                .as_ref()
+                .map_or(true, |event| config_files.changed(event));
+            let key_files = changed_files(event, key_dir);
+            let zone_files = zones_dir.map_or_else(Vec::new, |dir| changed_files(event, dir));
+            changes.key_files.extend(key_files);
+            changes.zone_files.extend(zone_files);
+        }
+
+        changes.key_files.sort();
+        changes.key_files.dedup();
+        changes.zone_files.sort();
+        changes.zone_files.dedup();
+        changes
+    }
+}
+
+/// The configuration file and its includes.
+///
+/// They are watched through their folders so that the watches survive the
+/// files being replaced by a rename, e.g. by an editor or an update of a
+/// Kubernetes ConfigMap, whose files are symlinks swapped at once.
+#[derive(Debug)]
+struct ConfigFiles {
+    /// The folder and name of every file, with the file it resolves to
+    files: Vec<(PathBuf, OsString, Option<PathBuf>)>,
+}
+
+impl ConfigFiles {
+    fn new<'a>(paths: impl Iterator<Item = &'a Path>) -> Self {
+        let files = paths
+            .filter_map(|path| {
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                    _ => PathBuf::from("."),
+                };
+                let name = path.file_name()?.to_os_string();
+                Some((dir, name, std::fs::canonicalize(path).ok()))
+            })
+            .collect();
+        Self { files }
+    }
+
+    /// The folders to watch.
+    fn dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = self
+            .files
+            .iter()
+            .map(|(dir, _, _)| dir.clone())
+            .collect::<Vec<_>>();
+        dirs.sort();
+        dirs.dedup();
+        dirs
+    }
+
+    /// Returns whether `event` changed one of the files, either directly or
+    /// by replacing a symlink it resolves through, e.g. the `..data` symlink
+    /// of a ConfigMap. The files are resolved again.
+    fn changed(&mut self, event: &notify::Event) -> bool {
+        let direct = event.paths.iter().any(|path| {
+            self.files.iter().any(|(dir, name, _)| {
+                path.parent() == Some(dir.as_path()) && path.file_name() == Some(name.as_os_str())
+            })
+        });
+
+        let mut retargeted = false;
+        for (dir, name, resolved) in self.files.iter_mut() {
+            let current = std::fs::canonicalize(dir.join(&*name)).ok();
+            if current != *resolved {
+                *resolved = current;
+                retargeted = true;
+            }
+        }
+
+        direct || retargeted
+    }
+}
+
+/// Returns the paths of the files of the folder `dir` changed by `event`, the
+/// hidden files, e.g. the lock and temporary files of the key generation, are
+/// skipped.
+fn changed_files(event: &notify::Result<notify::Event>, dir: &Path) -> Vec<PathBuf> {
+    let Ok(event) = event else {
+        return Vec::new();
+    };
+    event
+        .paths
+        .iter()
+        .filter(|path| path.parent() == Some(dir))
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| !name.to_string_lossy().starts_with('.'))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Loads again the configured and provisioned keys whose file is one of
+/// `files`, the other files are left to the key files check.
+fn reload_key_files(dnsr: &super::Dnsr, keys: &Keys, files: &[PathBuf]) {
+    let provisioned = dnsr.provisioned.read().unwrap();
+    for key in keys.keys().into_iter().chain(provisioned.keys()) {
+        let changed = files
+            .iter()
+            .any(|file| file.file_name() == Some(key.to_string().as_ref()));
+        if dnsr.keystore.read().unwrap().is_imported(key) || !changed {
+            continue;
+        }
+        match reload_key(&dnsr.keystore, key) {
+            Ok(()) => {
+                log::info!(target: "tsig_file", "tsig key {} reloaded from its file", key);
+                if let Some(webhooks) = &dnsr.webhooks {
+                    webhooks.send(WebhookEvent::KeyRotated, key);
+                }
+            }
+            Err(e) => {
+                log::error!(target: "tsig_file", "tsig key {} is unavailable, its signed requests are refused: {}", key, e)
+            }
+        }
+    }
+}
+
+/// Loads again the configured and provisioned keys whose secret was rotated in
+/// `vault`.
+fn reload_vault_keys(dnsr: &super::Dnsr, keys: &Keys, vault: &Vault) {
+    let provisioned = dnsr.provisioned.read().unwrap();
+    for key in keys.keys().into_iter().chain(provisioned.keys()) {
+        if dnsr.keystore.read().unwrap().is_imported(key) {
+            continue;
+        }
+        match vault.is_rotated(key) {
+            Ok(true) => (),
+            Ok(false) => continue,
+            Err(e) => {
+                log::warn!(target: "vault", "failed to check the rotation of tsig key {}: {}", key, e);
+                continue;
+            }
+        }
+        match reload_key(&dnsr.keystore, key) {
+            Ok(()) => {
+                log::info!(target: "vault", "tsig key {} reloaded from vault", key);
+                if let Some(webhooks) = &dnsr.webhooks {
+                    webhooks.send(WebhookEvent::KeyRotated, key);
+                }
+            }
+            Err(e) => {
+                log::error!(target: "vault", "tsig key {} is unavailable, its signed requests are refused: {}", key, e)
+            }
+        }
+    }
+}
+
+/// The differences between the key folder and the keys of the configuration.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct KeyFilesReport {
+    /// The files matching no key, e.g. left behind by a crash
+    orphaned: Vec<PathBuf>,
+    /// The keys, other than the imported ones, without a file
+    missing: Vec<KeyFile>,
+}
+
+/// Reports the orphaned key files and the keys without a file of the
+/// configured and provisioned keys, the orphaned files are deleted if pruning
+/// is enabled.
+fn check_key_files(dnsr: &super::Dnsr, keys: &Keys) {
+    // The secrets stored in Vault have no key file
+    if dnsr.keystore.read().unwrap().vault().is_some() {
+        return;
+    }
+
+    let provisioned = dnsr.provisioned.read().unwrap();
+    let expected = keys
+        .keys()
+        .into_iter()
+        .chain(provisioned.keys())
+        .collect::<Vec<_>>();
+
+    let report = {
+        let keystore = dnsr.keystore.read().unwrap();
+        scan_key_files(dnsr.config.tsig_path(), &expected, |key| {
+            keystore.is_imported(key)
+        })
+    };
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => {
+            log::error!(target: "tsig_file", "failed to check the key files: {}", e);
+            return;
+        }
+    };
+
+    for key in report.missing {
+        log::warn!(target: "tsig_file", "tsig key {} has no key file", key);
+    }
+    for path in report.orphaned {
+        if !dnsr.config.prune_orphaned_key_files() {
+            log::warn!(target: "tsig_file", "key file {} matches no key", path.display());
+            continue;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                log::info!(target: "tsig_file", "pruned orphaned key file {}", path.display())
+            }
+            Err(e) => {
+                log::error!(target: "tsig_file", "failed to prune key file {}: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+/// Compares the files of the key folder `dir` with `keys`, `imported` tells
+/// whether a key was imported from a BIND key file and has no file.
+fn scan_key_files<F>(dir: &Path, keys: &[&KeyFile], imported: F) -> Result<KeyFilesReport>
+where
+    F: Fn(&KeyFile) -> bool,
+{
+    let mut report = KeyFilesReport::default();
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry.file_name();
+        // The lock and temporary files of the key generation
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if keys
+            .iter()
+            .any(|key| name.to_str() == Some(key.to_string().as_str()))
+        {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        if modified
+            .elapsed()
+            .is_ok_and(|age| age >= KEY_FILE_GRACE_PERIOD)
+        {
+            report.orphaned.push(entry.path());
+        }
+    }
+    report.orphaned.sort();
+
+    report.missing = keys
+        .iter()
+        .filter(|key| !imported(key) && !dir.join(key.to_string()).is_file())
+        .map(|&key| key.clone())
+        .collect();
+
+    Ok(report)
+}
+
+/// Serves the zones of the domains added to the configuration and removes the
+/// zones of the domains removed from it, `keys` being the previous keys. The
+/// key and entry of a removed domain are kept while its zones are retained, so
+/// that restoring them provisions the domain again.
 fn handle_domains_change(
-    zones: &super::Zones,
+    dnsr: &super::Dnsr,
+    keys: &Keys,
     old_domains: &[(&DomainName, &DomainInfo)],
     new_domains: &[(&DomainName, &DomainInfo)],
+    retention: Duration,
 ) -> Result<()> {
     let mut deleted_domains = old_domains.iter().filter(|d| !new_domains.contains(d));
     let mut added_domains = new_domains.iter().filter(|d| !old_domains.contains(d));
@@ -120,24 +617,159 @@ fn handle_domains_change(
         .iter()
         .filter(|(n, _)| old_domains.iter().any(|(o, _)| n == o));
 
+    let zones = &dnsr.zones;
+    let announce = |kind, apex: &StoredName| {
+        if let Some(webhooks) = &dnsr.webhooks {
+            webhooks.send(kind, apex);
+        }
+    };
+
     deleted_domains.try_for_each(|d| -> Result<()> {
-        let z = d.try_into_t()?;
-        zones.remove_zone(z.apex_name(), z.class())?;
+        let zones_of_domain: Vec<Zone> = d.try_into_t()?;
+        if !retention.is_zero() {
+            if let Some((key, info)) = keys.find_domain(d.0) {
+                let entry = (key.clone(), info.clone());
+                dnsr.retained.lock().unwrap().insert(d.0.clone(), entry);
+            }
+        }
+        for z in zones_of_domain {
+            if retention.is_zero() {
+                zones.remove_zone(z.apex_name(), z.class())?;
+            } else {
+                zones.disable_zone(z.apex_name())?;
+            }
+            announce(WebhookEvent::ZoneRemoved, z.apex_name());
+        }
         Ok(())
     })?;
 
+    // Domains added back while their zone is still retained get their records back
     added_domains.try_for_each(|d| -> Result<()> {
-        let z = d.try_into_t()?;
-        zones.insert_zone(z)?;
+        let zones_of_domain: Vec<Zone> = d.try_into_t()?;
+        dnsr.retained.lock().unwrap().remove(d.0);
+        for z in zones_of_domain {
+            let apex = z.apex_name().clone();
+            if zones.restore_zone(&apex).is_err() {
+                insert_within_limit(zones, z)?;
+            }
+            announce(WebhookEvent::ZoneAdded, &apex);
+        }
         Ok(())
     })?;
 
     modified_domains.try_for_each(|d| -> Result<()> {
-        let z = d.try_into_t()?;
-        zones.remove_zone(z.apex_name(), z.class())?;
-        zones.insert_zone(z)?;
+        let zones_of_domain: Vec<Zone> = d.try_into_t()?;
+        for z in zones_of_domain {
+            zones.remove_zone(z.apex_name(), z.class())?;
+            zones.insert_zone(z)?;
+        }
         Ok(())
     })?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[test]
+    fn orphaned_key_files_and_missing_keys_are_reported() {
+        let dir = std::env::temp_dir().join(format!("dnsr-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys = serde_yaml::from_str::<Vec<KeyFile>>("[key1, key2, key3]").unwrap();
+        let keys = keys.iter().collect::<Vec<_>>();
+
+        let old = SystemTime::now() - 2 * KEY_FILE_GRACE_PERIOD;
+        for name in ["key1", "orphan", "recent"] {
+            let file = File::create(dir.join(name)).unwrap();
+            if name != "recent" {
+                file.set_modified(old).unwrap();
+            }
+        }
+
+        let imported = |key: &KeyFile| key.to_string() == "key3";
+        let report = scan_key_files(&dir, &keys, imported).unwrap();
+        assert_eq!(report.orphaned, [dir.join("orphan")]);
+        assert_eq!(report.missing, [keys[1].clone()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_files_of_the_folder_are_reloaded() {
+        let dir = Path::new("/etc/dnsr/keys");
+        let event = notify::Event::new(notify::EventKind::Any)
+            .add_path(dir.join(".key1.tmp"))
+            .add_path(dir.join("key1"))
+            .add_path(PathBuf::from("/etc/dnsr/config.yml"));
+        assert_eq!(changed_files(&Ok(event), dir), [dir.join("key1")]);
+
+        let event = notify::Event::new(notify::EventKind::Any).add_path(dir.join(".key2.lock"));
+        assert!(changed_files(&Ok(event), dir).is_empty());
+    }
+
+    #[test]
+    fn bursts_of_events_are_coalesced() {
+        let key_dir = Path::new("/etc/dnsr/keys");
+        let zones_dir = Path::new("/etc/dnsr/zones.d");
+        let event = |path: PathBuf| Ok(notify::Event::new(notify::EventKind::Any).add_path(path));
+
+        let (tx, rx) = channel();
+        for path in [
+            key_dir.join("key1"),
+            zones_dir.join("example.net.zone"),
+            key_dir.join("key1"),
+            PathBuf::from("/etc/dnsr/config.yml"),
+        ] {
+            tx.send(event(path)).unwrap();
+        }
+        let first = rx.recv().unwrap();
+        let events = debounce(&rx, first, Duration::from_millis(10));
+        assert_eq!(events.len(), 4);
+
+        let mut config_files = ConfigFiles::new([Path::new("/etc/dnsr/config.yml")].into_iter());
+        let changes = Changes::from_events(&events, &mut config_files, key_dir, Some(zones_dir));
+        assert_eq!(
+            changes,
+            Changes {
+                config: true,
+                key_files: vec![key_dir.join("key1")],
+                zone_files: vec![zones_dir.join("example.net.zone")],
+            }
+        );
+
+        let changes = Changes::from_events(&events[..1], &mut config_files, key_dir, None);
+        assert!(!changes.config);
+    }
+
+    #[test]
+    fn replaced_config_files_are_detected() {
+        let dir = std::env::temp_dir().join(format!("dnsr-config-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("v1")).unwrap();
+        std::fs::create_dir_all(dir.join("v2")).unwrap();
+        std::fs::write(dir.join("v1/config.yml"), "keys: {}").unwrap();
+        std::fs::write(dir.join("v2/config.yml"), "keys: {}").unwrap();
+        // The layout of a ConfigMap volume
+        std::os::unix::fs::symlink("v1", dir.join("..data")).unwrap();
+        std::os::unix::fs::symlink("..data/config.yml", dir.join("config.yml")).unwrap();
+
+        let path = dir.join("config.yml");
+        let mut config_files = ConfigFiles::new([path.as_path()].into_iter());
+        assert_eq!(config_files.dirs(), [dir.clone()]);
+        let event = |path: PathBuf| notify::Event::new(notify::EventKind::Any).add_path(path);
+
+        assert!(config_files.changed(&event(dir.join("config.yml"))));
+        assert!(!config_files.changed(&event(dir.join("provisioned.yml"))));
+
+        // The symlink is swapped by a rename
+        std::os::unix::fs::symlink("v2", dir.join("..data_tmp")).unwrap();
+        std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+        assert!(config_files.changed(&event(dir.join("..data"))));
+        assert!(!config_files.changed(&event(dir.join("..data"))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
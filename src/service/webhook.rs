@@ -0,0 +1,99 @@
+//! The webhooks posting the changes of the zones and keys.
+//!
+//! Every event is posted as a JSON object to the configured urls accepting it,
+//! e.g. to announce the new zones on a chat or to keep an inventory of the
+//! served domains. The posts run on their own thread and are not retried, a
+//! failure is only logged.
+
+use std::fmt::Display;
+use std::sync::mpsc::{channel, Sender};
+use std::time::SystemTime;
+
+use crate::config::{WebhookConfig, WebhookEvent};
+use crate::telemetry::escape;
+
+#[derive(Debug)]
+pub struct Webhooks {
+    sender: Sender<(WebhookEvent, String)>,
+}
+
+impl Webhooks {
+    /// Starts the thread posting the events to the `webhooks`.
+    pub fn new(webhooks: Vec<WebhookConfig>) -> Self {
+        let (sender, receiver) = channel::<(WebhookEvent, String)>();
+        std::thread::spawn(move || {
+            for (kind, subject) in receiver {
+                let body = body(kind, &subject, SystemTime::now());
+                for webhook in webhooks.iter().filter(|webhook| webhook.accepts(kind)) {
+                    let url = webhook.url();
+                    match ureq::post(url)
+                        .timeout(webhook.timeout())
+                        .set("content-type", "application/json")
+                        .send_string(&body)
+                    {
+                        Ok(_) => {
+                            log::debug!(target: "webhook", "posted {} of {} to {}", name(kind), subject, url)
+                        }
+                        Err(ureq::Error::Status(code, _)) => {
+                            log::warn!(target: "webhook", "post of {} of {} to {} failed with status {}", name(kind), subject, url, code)
+                        }
+                        Err(e) => {
+                            log::warn!(target: "webhook", "post of {} of {} to {} failed: {}", name(kind), subject, url, e)
+                        }
+                    }
+                }
+            }
+        });
+
+        Self { sender }
+    }
+
+    /// Queues the event `kind` of `subject`, a zone apex or a key name.
+    pub fn send<S>(&self, kind: WebhookEvent, subject: &S)
+    where
+        S: Display + ?Sized,
+    {
+        let _ = self.sender.send((kind, subject.to_string()));
+    }
+}
+
+fn name(kind: WebhookEvent) -> &'static str {
+    match kind {
+        WebhookEvent::ZoneAdded => "zone_added",
+        WebhookEvent::ZoneRemoved => "zone_removed",
+        WebhookEvent::RecordsUpdated => "records_updated",
+        WebhookEvent::KeyRotated => "key_rotated",
+    }
+}
+
+/// Builds the JSON body of the event `kind` of `subject` happened at `time`.
+fn body(kind: WebhookEvent, subject: &str, time: SystemTime) -> String {
+    let field = match kind {
+        WebhookEvent::KeyRotated => "key",
+        _ => "zone",
+    };
+    format!(
+        r#"{{"event":"{}","{}":"{}","time":"{}"}}"#,
+        name(kind),
+        field,
+        escape(subject),
+        crate::time::rfc3339(time)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn events_are_posted_as_json() {
+        let time = UNIX_EPOCH + Duration::from_secs(1722353587);
+        assert_eq!(
+            body(WebhookEvent::ZoneAdded, "_acme-challenge.example.fr", time),
+            r#"{"event":"zone_added","zone":"_acme-challenge.example.fr","time":"2024-07-30T15:33:07.000000Z"}"#
+        );
+        assert!(body(WebhookEvent::KeyRotated, "key\"1", time).contains(r#""key":"key\"1""#));
+    }
+}
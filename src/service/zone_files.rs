@@ -0,0 +1,170 @@
+//! The zones loaded from a folder of zone files.
+//!
+//! Every file of the folder holds a zone in the presentation format of RFC
+//! 1035, its origin defaults to the name of the file without its `.zone`
+//! extension. The zones are loaded, reloaded and removed as their files are
+//! created, modified and deleted. They are served as is and cannot be updated.
+//! A file whose zone is already served, by the configuration or by another
+//! file, is not loaded.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use domain::base::iana::Class;
+use domain::zonefile::inplace;
+use domain::zonetree::types::StoredName;
+use domain::zonetree::Zone;
+
+use super::Zones;
+use crate::error;
+use crate::error::Result;
+use crate::key::TryInto;
+
+#[derive(Debug)]
+pub struct ZoneFiles {
+    dir: PathBuf,
+    /// The apex of the zone loaded from each file
+    loaded: HashMap<PathBuf, StoredName>,
+}
+
+impl ZoneFiles {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            loaded: HashMap::new(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Loads the zone of every file of the folder.
+    pub fn load_all(&mut self, zones: &Zones) -> Result<()> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+        self.reload(zones, &paths);
+        Ok(())
+    }
+
+    /// Loads again the zones of the files `paths` of the folder, the zone of a
+    /// deleted file is removed. A zone whose file is invalid or whose apex is
+    /// already served keeps being served as it was.
+    pub fn reload(&mut self, zones: &Zones, paths: &[PathBuf]) {
+        for path in paths {
+            let hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if hidden || path.parent() != Some(self.dir.as_path()) {
+                continue;
+            }
+
+            if !path.is_file() {
+                if let Some(apex) = self.loaded.remove(path) {
+                    log::info!(target: "zone_file", "zone file {} deleted", path.display());
+                    if let Err(e) = zones.remove_zone(&apex, Class::IN) {
+                        log::error!(target: "zone_file", "failed to remove zone {}: {}", apex, e);
+                    }
+                }
+                continue;
+            }
+
+            let zone = match load_zone_file(path) {
+                Ok(zone) => zone,
+                Err(e) => {
+                    log::error!(target: "zone_file", "zone file {} not loaded: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let apex = zone.apex_name().clone();
+            match zones.replace_zone(self.loaded.get(path), zone) {
+                Ok(()) => {
+                    log::info!(target: "zone_file", "zone {} loaded from {}", apex, path.display());
+                    self.loaded.insert(path.clone(), apex);
+                }
+                Err(e) => {
+                    log::error!(target: "zone_file", "zone file {} not loaded: {}", path.display(), e)
+                }
+            }
+        }
+    }
+}
+
+/// Parses the zone file at `path`.
+fn load_zone_file(path: &Path) -> Result<Zone> {
+    let mut reader = inplace::Zonefile::load(&mut BufReader::new(File::open(path)?))?;
+    if let Some(origin) = default_origin(path) {
+        reader.set_origin(origin.try_into_t()?);
+    }
+    Zone::try_from(reader).map_err(|e| error!(DomainZone => "invalid zone file: {}", e))
+}
+
+/// Returns the origin of the zone file `path` if it sets none, the name of
+/// the file without its `.zone` extension.
+fn default_origin(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let origin = name.strip_suffix(".zone").unwrap_or(name);
+    Some(format!("{}.", origin.trim_end_matches('.')))
+}
+
+#[cfg(test)]
+mod tests {
+    use domain::zonetree::ZoneBuilder;
+
+    use super::*;
+    use crate::zone::ZoneTree;
+
+    const SOA: &str = "@ 3600 IN SOA ns.example.fr. admin.example.fr. 1 7200 3600 1209600 3600\n";
+
+    #[test]
+    fn zone_files_never_replace_a_served_zone() {
+        let dir = std::env::temp_dir().join(format!("dnsr-zones-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let configured = StoredName::bytes_from_str("example.fr").unwrap();
+        let loaded = StoredName::bytes_from_str("example.net").unwrap();
+        let zones = Zones::from(ZoneTree::default());
+        zones
+            .insert_zone(ZoneBuilder::new(configured.clone(), Class::IN).build())
+            .unwrap();
+
+        std::fs::write(dir.join("example.fr.zone"), SOA).unwrap();
+        std::fs::write(dir.join("example.net.zone"), SOA).unwrap();
+        let mut files = ZoneFiles::new(&dir);
+        files.load_all(&zones).unwrap();
+        assert_eq!(files.loaded.values().collect::<Vec<_>>(), [&loaded]);
+        assert!(zones.records(&configured).is_empty());
+
+        // An invalid file keeps its zone served
+        std::fs::write(dir.join("example.net.zone"), "not a zone").unwrap();
+        files.reload(&zones, &[dir.join("example.net.zone")]);
+        assert!(!zones.records(&loaded).is_empty());
+
+        std::fs::remove_file(dir.join("example.fr.zone")).unwrap();
+        files.reload(&zones, &[dir.join("example.fr.zone")]);
+        assert!(zones.apex_names().contains(&configured));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_origin_defaults_to_the_file_name() {
+        let origin = |path: &str| default_origin(Path::new(path));
+
+        assert_eq!(
+            origin("/etc/dnsr/zones.d/example.fr.zone").as_deref(),
+            Some("example.fr.")
+        );
+        assert_eq!(
+            origin("/etc/dnsr/zones.d/example.fr").as_deref(),
+            Some("example.fr.")
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! The SIG(0) signatures of the updates, see RFC 2931.
+//!
+//! A client which cannot share a TSIG secret signs its updates with the
+//! private key of a key pair instead, closing the message with a SIG record
+//! owned by the root. The public keys are trusted per domain in its
+//! `sig0_keys`, a signature is accepted if one of the keys of its signer name
+//! and algorithm verifies it within its validity period.
+
+use base64::Engine;
+use bytes::Bytes;
+use domain::base::{Message, Name, Rtype, ToName};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+use crate::error::Result;
+
+/// The length of the fixed fields of the SIG record data, before the name of
+/// the signer.
+const FIXED_LEN: usize = 18;
+
+/// A public key trusted to sign the updates of a domain.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Sig0Key {
+    /// The name of the key, the signer name of its SIG records
+    name: String,
+    /// The DNSSEC algorithm number of the key
+    algorithm: u8,
+    /// The public key in base64, as in the KEY record of the key
+    public_key: String,
+}
+
+impl Sig0Key {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_named<N>(&self, name: &N) -> bool
+    where
+        N: ToName,
+    {
+        Name::bytes_from_str(&self.name).is_ok_and(|own: Name<Bytes>| own.name_eq(name))
+    }
+
+    /// Verifies the `signature` of `data` made by the private key.
+    fn verify(&self, data: &[u8], sig: &[u8]) -> Result<()> {
+        let key = base64::engine::general_purpose::STANDARD.decode(&self.public_key)?;
+        let verified = match self.algorithm {
+            // RSASHA256 and RSASHA512
+            8 | 10 => {
+                let params = if self.algorithm == 8 {
+                    &signature::RSA_PKCS1_2048_8192_SHA256
+                } else {
+                    &signature::RSA_PKCS1_2048_8192_SHA512
+                };
+                let components = rsa_components(&key)
+                    .ok_or_else(|| error!(Sig0 => "invalid RSA public key {}", self.name))?;
+                components.verify(params, data, sig)
+            }
+            // ECDSAP256SHA256 and ECDSAP384SHA384, the point is stored
+            // without its uncompressed prefix
+            13 | 14 => {
+                let algorithm = if self.algorithm == 13 {
+                    &signature::ECDSA_P256_SHA256_FIXED
+                } else {
+                    &signature::ECDSA_P384_SHA384_FIXED
+                };
+                let point = [&[4], key.as_slice()].concat();
+                UnparsedPublicKey::new(algorithm, point).verify(data, sig)
+            }
+            // ED25519
+            15 => UnparsedPublicKey::new(&signature::ED25519, key).verify(data, sig),
+            algorithm => {
+                return Err(
+                    error!(Sig0 => "unsupported algorithm {} of key {}", algorithm, self.name),
+                )
+            }
+        };
+        verified.map_err(|_| error!(Sig0 => "invalid signature of key {}", self.name))
+    }
+}
+
+/// Verifies the SIG(0) record closing the wire `message` with the `trusted`
+/// keys at the UNIX time `now`, its validity period extended by `fudge`
+/// seconds, and returns the name of the key which signed it. Returns `None`
+/// if the message is not signed with SIG(0).
+pub fn verify(
+    message: &[u8],
+    trusted: &[&Sig0Key],
+    now: u64,
+    fudge: u64,
+) -> Result<Option<String>> {
+    let parsed = Message::from_octets(message).map_err(|_| error!(Sig0 => "message too short"))?;
+    let additional = parsed
+        .additional()
+        .map_err(|_| error!(Sig0 => "invalid message"))?;
+    let Some(record) = additional.filter_map(Result::ok).last() else {
+        return Ok(None);
+    };
+    if record.rtype() != Rtype::SIG {
+        return Ok(None);
+    }
+
+    // The record owned by the root closes the message: its owner, type,
+    // class, TTL and data length take 11 bytes
+    let rdlen = usize::from(record.rdlen());
+    let start = message
+        .len()
+        .checked_sub(11 + rdlen)
+        .filter(|start| message[*start..*start + 3] == [0, 0, 24])
+        .ok_or_else(|| error!(Sig0 => "the SIG record does not close the message"))?;
+    let rdata = &message[start + 11..];
+    let signer_len = rdata
+        .get(FIXED_LEN..)
+        .and_then(name_len)
+        .ok_or_else(|| error!(Sig0 => "invalid signer name"))?;
+    let (signed_rdata, sig) = rdata.split_at(FIXED_LEN + signer_len);
+    let signer = Name::from_octets(Bytes::copy_from_slice(&signed_rdata[FIXED_LEN..]))
+        .map_err(|_| error!(Sig0 => "invalid signer name"))?;
+
+    let field = |at: usize| u64::from(u32::from_be_bytes(rdata[at..at + 4].try_into().unwrap()));
+    let (expiration, inception) = (field(8), field(12));
+    if now + fudge < inception || now > expiration + fudge {
+        return Err(error!(Sig0 => "signature of {} out of its validity period", signer));
+    }
+
+    // The signature covers its record data and the message before the SIG
+    // record was added, see RFC 2931 section 3.1
+    let mut data = signed_rdata.to_vec();
+    data.extend_from_slice(&message[..start]);
+    let arcount = u16::from_be_bytes([message[10], message[11]]) - 1;
+    data[signed_rdata.len() + 10..signed_rdata.len() + 12].copy_from_slice(&arcount.to_be_bytes());
+
+    let algorithm = rdata[2];
+    let mut candidates = trusted
+        .iter()
+        .filter(|key| key.algorithm == algorithm && key.is_named(&signer))
+        .peekable();
+    if candidates.peek().is_none() {
+        return Err(error!(Sig0 => "no trusted key {} with algorithm {}", signer, algorithm));
+    }
+    let mut result = Ok(None);
+    for key in candidates {
+        match key.verify(&data, sig) {
+            Ok(()) => return Ok(Some(key.name.clone())),
+            Err(e) => result = Err(e),
+        }
+    }
+    result
+}
+
+/// Returns the length of the uncompressed name starting `bytes`.
+fn name_len(bytes: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    loop {
+        let len = usize::from(*bytes.get(pos)?);
+        // The name of the signer is never compressed
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        pos += 1 + len;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+/// Splits a RSA public key in the format of RFC 3110 into its modulus and
+/// exponent.
+fn rsa_components(key: &[u8]) -> Option<RsaPublicKeyComponents<&[u8]>> {
+    let (exponent_len, rest) = match key.split_first()? {
+        (0, rest) if rest.len() >= 2 => (
+            usize::from(u16::from_be_bytes([rest[0], rest[1]])),
+            &rest[2..],
+        ),
+        (0, _) => return None,
+        (len, rest) => (usize::from(*len), rest),
+    };
+    if rest.len() <= exponent_len {
+        return None;
+    }
+    let (e, n) = rest.split_at(exponent_len);
+    Some(RsaPublicKeyComponents { n, e })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use domain::base::MessageBuilder;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair};
+
+    use super::*;
+
+    const NOW: u64 = 1722353587;
+
+    fn update() -> Vec<u8> {
+        let mut builder = MessageBuilder::new_vec();
+        builder.header_mut().set_id(0xcafe);
+        let mut question = builder.question();
+        let zone = Name::<Vec<u8>>::from_str("_acme-challenge.example.fr.").unwrap();
+        question.push((zone, Rtype::SOA)).unwrap();
+        question.into_message().into_octets()
+    }
+
+    /// Closes `message` with a SIG(0) record of `signer` made by `sign`.
+    fn sign(
+        mut message: Vec<u8>,
+        signer: &str,
+        algorithm: u8,
+        sign: impl Fn(&[u8]) -> Vec<u8>,
+    ) -> Vec<u8> {
+        let mut rdata = vec![0, 0, algorithm, 0, 0, 0, 0, 0];
+        rdata.extend((NOW as u32 + 300).to_be_bytes());
+        rdata.extend((NOW as u32 - 300).to_be_bytes());
+        rdata.extend([0, 0]);
+        rdata.extend(Name::<Vec<u8>>::from_str(signer).unwrap().as_slice());
+        rdata.extend(sign(&[rdata.as_slice(), message.as_slice()].concat()));
+
+        let arcount = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&arcount.to_be_bytes());
+        message.push(0);
+        message.extend(24u16.to_be_bytes());
+        message.extend(255u16.to_be_bytes());
+        message.extend(0u32.to_be_bytes());
+        message.extend((rdata.len() as u16).to_be_bytes());
+        message.extend(rdata);
+        message
+    }
+
+    fn trusted(name: &str, algorithm: u8, public_key: &[u8]) -> Sig0Key {
+        Sig0Key {
+            name: name.into(),
+            algorithm,
+            public_key: base64::engine::general_purpose::STANDARD.encode(public_key),
+        }
+    }
+
+    #[test]
+    fn ed25519_signatures_are_verified() {
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let key = trusted("client.example.fr.", 15, pair.public_key().as_ref());
+
+        let signed = sign(update(), "client.example.fr", 15, |data| {
+            pair.sign(data).as_ref().to_vec()
+        });
+        assert_eq!(
+            verify(&signed, &[&key], NOW, 300).unwrap().as_deref(),
+            Some("client.example.fr.")
+        );
+        assert_eq!(verify(&update(), &[&key], NOW, 300).unwrap(), None);
+
+        // Expired, tampered or signed by an untrusted key
+        assert!(verify(&signed, &[&key], NOW + 3600, 300).is_err());
+        let mut tampered = signed.clone();
+        tampered[0] ^= 1;
+        assert!(verify(&tampered, &[&key], NOW, 300).is_err());
+        let other = trusted("other.example.fr.", 15, pair.public_key().as_ref());
+        assert!(verify(&signed, &[&other], NOW, 300).is_err());
+    }
+
+    #[test]
+    fn ecdsa_signatures_are_verified() {
+        let rng = SystemRandom::new();
+        let algorithm = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(algorithm, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(algorithm, pkcs8.as_ref(), &rng).unwrap();
+        // The public key of the KEY record has no uncompressed prefix
+        let key = trusted("client.example.fr", 13, &pair.public_key().as_ref()[1..]);
+
+        let signed = sign(update(), "Client.Example.fr", 13, |data| {
+            pair.sign(&rng, data).unwrap().as_ref().to_vec()
+        });
+        assert!(verify(&signed, &[&key], NOW, 300).unwrap().is_some());
+    }
+}
@@ -0,0 +1,305 @@
+//! The cert-manager webhook solver.
+//!
+//! cert-manager solves the DNS-01 challenges of its issuers through webhook
+//! solvers, served as a Kubernetes aggregated API: the kube-apiserver proxies
+//! the `ChallengeReview` of an issuer to `POST /apis/<group>/v1alpha1/<solver>`
+//! over TLS, authenticated with a client certificate signed by its
+//! requestheader CA. A `Present` publishes the challenge record in the challenge zone
+//! of its resolved name and a `CleanUp` removes it, so that cert-manager drives
+//! dnsr without any TSIG key.
+//!
+//! Routes:
+//!
+//! - `GET /healthz`: answers `ok`, for the probes of the deployment,
+//! - `GET /apis/<group>/v1alpha1`: the discovery of the solver resource,
+//! - `POST /apis/<group>/v1alpha1/<solver>`: handles a `ChallengeReview`.
+
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+use domain::zonetree::types::StoredName;
+use futures::executor::block_on;
+use rustls::pki_types::CertificateDer;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use serde::Deserialize;
+
+use crate::admin::{Request, Response};
+use crate::config::{parse_json, CertManagerConfig};
+use crate::dname::CHALLENGE_PREFIX;
+use crate::error;
+use crate::error::Result;
+use crate::key::TryInto;
+use crate::service::Dnsr;
+use crate::telemetry::escape;
+
+/// The API version of the challenge reviews.
+const API_VERSION: &str = "acme.cert-manager.io/v1alpha1";
+
+pub struct SolverServer {
+    dnsr: Arc<Dnsr>,
+    config: CertManagerConfig,
+}
+
+impl SolverServer {
+    pub fn new(dnsr: Arc<Dnsr>, config: &CertManagerConfig) -> Self {
+        Self {
+            dnsr,
+            config: config.clone(),
+        }
+    }
+
+    /// Serves the solver until the listener fails.
+    pub fn run(&self) -> Result<()> {
+        let tls = tls_config(&self.config)?;
+        let listener = TcpListener::bind(self.config.listen())?;
+        log::info!(target: "solver", "cert-manager solver listening on {}", self.config.listen());
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::error!(target: "solver", "failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.handle(stream, tls.clone()) {
+                log::error!(target: "solver", "failed to handle request: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream, tls: Arc<ServerConfig>) -> Result<()> {
+        stream.set_read_timeout(Some(self.config.timeout()))?;
+        stream.set_write_timeout(Some(self.config.timeout()))?;
+        let connection =
+            ServerConnection::new(tls).map_err(|e| error!(Solver => "tls error: {}", e))?;
+        let mut reader = BufReader::new(StreamOwned::new(connection, stream));
+
+        let response = match Request::read(&mut reader) {
+            Ok(request) => {
+                log::debug!(target: "solver", "{} {}", request.method, request.path);
+                self.route(&request)
+            }
+            Err(e) => {
+                log::debug!(target: "solver", "invalid request: {}", e);
+                Response::new(400, "bad request")
+            }
+        };
+
+        let stream = reader.get_mut();
+        response.write(stream)?;
+        stream.conn.send_close_notify();
+        stream.flush()?;
+        Ok(())
+    }
+
+    fn route(&self, request: &Request) -> Response {
+        let segments = request
+            .path
+            .trim_matches('/')
+            .split('/')
+            .collect::<Vec<_>>();
+        let group = self.config.group_name();
+        let solver = self.config.solver_name();
+
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["healthz"]) => Response::new(200, "ok"),
+            ("GET", ["apis", g, "v1alpha1"]) if *g == group => {
+                Response::new(200, resource_list(group, solver))
+                    .with_content_type("application/json")
+            }
+            ("POST", ["apis", g, "v1alpha1", s]) if *g == group && *s == solver => {
+                self.review(&request.body)
+            }
+            _ => Response::new(404, "not found"),
+        }
+    }
+
+    /// Handles a `ChallengeReview`, its failures are reported in the review
+    /// so that cert-manager retries the challenge.
+    fn review(&self, body: &[u8]) -> Response {
+        let review: ChallengeReview = match parse_json(body) {
+            Ok(review) => review,
+            Err(e) => return Response::new(400, e.to_string()),
+        };
+        if review.api_version != API_VERSION || review.kind != "ChallengeReview" {
+            return Response::new(400, "expected a ChallengeReview");
+        }
+        let Some(request) = review.request else {
+            return Response::new(400, "missing request");
+        };
+
+        let result = self.solve(&request);
+        match &result {
+            Ok(()) => {
+                log::info!(target: "solver", "{} of the challenge of {} done", request.action, request.resolved_fqdn)
+            }
+            Err(e) => {
+                log::error!(target: "solver", "{} of the challenge of {} failed: {}", request.action, request.resolved_fqdn, e)
+            }
+        }
+
+        Response::new(200, review_response(&request.uid, &result))
+            .with_content_type("application/json")
+    }
+
+    fn solve(&self, request: &ChallengeRequest) -> Result<()> {
+        if self.dnsr.is_standby() {
+            return Err(error!(Solver => "standby instance"));
+        }
+        if request.challenge_type != "dns-01" {
+            return Err(error!(Solver => "unsupported challenge type {}", request.challenge_type));
+        }
+
+        // Only the served challenge zones are written, not the zones of the
+        // domains themselves
+        let apex: StoredName = request.resolved_fqdn.as_str().try_into_t()?;
+        let is_challenge = apex
+            .to_string()
+            .to_ascii_lowercase()
+            .starts_with(CHALLENGE_PREFIX);
+        if !is_challenge || !self.dnsr.zones.apex_names().contains(&apex) {
+            return Err(error!(Solver => "the challenge name {} is not served", apex));
+        }
+
+        match request.action.as_str() {
+            "Present" => block_on(self.dnsr.present_challenge(
+                &apex,
+                &request.key,
+                self.config.ttl(),
+            )),
+            "CleanUp" => block_on(self.dnsr.clean_up_challenge(&apex, &request.key)),
+            action => Err(error!(Solver => "unknown action {}", action)),
+        }
+    }
+}
+
+/// Loads the certificate chain and the private key of `config`, the clients
+/// must present a certificate signed by its client CA.
+fn tls_config(config: &CertManagerConfig) -> Result<Arc<ServerConfig>> {
+    // Any client reaching the solver could otherwise present the challenges
+    // of every domain
+    let client_ca = config
+        .client_ca()
+        .ok_or_else(|| error!(Solver => "no client_ca configured, the solver is not started"))?;
+    let certs = read_certs(config.cert())?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(config.key())?))?
+        .ok_or_else(|| error!(Solver => "no private key in {}", config.key().display()))?;
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(client_ca)? {
+        roots
+            .add(cert)
+            .map_err(|e| error!(Solver => "invalid client CA {}: {}", client_ca.display(), e))?;
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| error!(Solver => "invalid client CA {}: {}", client_ca.display(), e))?;
+    let tls = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| {
+            builder
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(certs, key)
+        })
+        .map_err(|e| error!(Solver => "invalid certificate: {}", e))?;
+    Ok(Arc::new(tls))
+}
+
+/// Reads the certificates of the PEM file `path`.
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(error!(Solver => "no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChallengeReview {
+    api_version: String,
+    kind: String,
+    request: Option<ChallengeRequest>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChallengeRequest {
+    uid: String,
+    action: String,
+    #[serde(rename = "type")]
+    challenge_type: String,
+    #[serde(rename = "resolvedFQDN")]
+    resolved_fqdn: String,
+    /// The value of the challenge record
+    key: String,
+}
+
+/// Describes the solver resource for the discovery of the API group.
+fn resource_list(group: &str, solver: &str) -> String {
+    format!(
+        r#"{{"kind":"APIResourceList","apiVersion":"v1","groupVersion":"{}/v1alpha1","resources":[{{"name":"{}","singularName":"{}","namespaced":false,"kind":"ChallengePayload","verbs":["create"]}}]}}"#,
+        escape(group),
+        escape(solver),
+        escape(solver)
+    )
+}
+
+/// Builds the `ChallengeReview` answering the request `uid`.
+fn review_response(uid: &str, result: &Result<()>) -> String {
+    let response = match result {
+        Ok(()) => format!(r#"{{"uid":"{}","success":true}}"#, escape(uid)),
+        Err(e) => format!(
+            r#"{{"uid":"{}","success":false,"status":{{"status":"Failure","message":"{}"}}}}"#,
+            escape(uid),
+            escape(&e.to_string())
+        ),
+    };
+    format!(
+        r#"{{"apiVersion":"{}","kind":"ChallengeReview","response":{}}}"#,
+        API_VERSION, response
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reviews_are_parsed_and_answered() {
+        let body = r#"{"apiVersion":"acme.cert-manager.io/v1alpha1","kind":"ChallengeReview","request":{"uid":"6b4f","action":"Present","type":"dns-01","dnsName":"example.fr","key":"token","resourceNamespace":"default","resolvedFQDN":"_acme-challenge.example.fr.","resolvedZone":"example.fr.","allowAmbientCredentials":false,"config":null}}"#;
+        let review: ChallengeReview = parse_json(body).unwrap();
+        let request = review.request.unwrap();
+        assert_eq!(request.action, "Present");
+        assert_eq!(request.resolved_fqdn, "_acme-challenge.example.fr.");
+        assert_eq!(request.key, "token");
+
+        assert_eq!(
+            review_response(&request.uid, &Ok(())),
+            r#"{"apiVersion":"acme.cert-manager.io/v1alpha1","kind":"ChallengeReview","response":{"uid":"6b4f","success":true}}"#
+        );
+        let failure = review_response("6b4f", &Err(error!(Solver => "standby instance")));
+        assert!(failure.contains(r#""success":false"#));
+        assert!(failure.contains(r#""message":"[E026] standby instance""#));
+    }
+
+    #[test]
+    fn the_solver_requires_a_client_ca() {
+        let config: CertManagerConfig = serde_yaml::from_str(
+            "{ group_name: acme.example.fr, cert: /nonexistent/tls.crt, key: /nonexistent/tls.key }",
+        )
+        .unwrap();
+        let e = tls_config(&config).unwrap_err();
+        assert_eq!(e.kind, crate::error::ErrorKind::Solver);
+        assert!(e.text().contains("client_ca"));
+    }
+}
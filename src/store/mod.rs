@@ -0,0 +1,279 @@
+//! Shared backends used to replicate zone data between several dnsr instances.
+//!
+//! Records are exchanged in a small line based format: one record per line
+//! holding the record type, the ttl and the base64 encoded wire rdata. Snapshots
+//! hold several zones, each one starting with a `zone <apex>` line. Journals
+//! hold the entries of the zones after their `zone <apex>` line, each one as a
+//! `serial <from> <to> <unix time>` line followed by its removed (`-`) and
+//! added (`+`) records.
+
+use std::collections::HashMap;
+use std::time::{Duration, UNIX_EPOCH};
+
+use base64::Engine;
+use bytes::Bytes;
+use domain::base::name::FlattenInto;
+use domain::base::rdata::{ComposeRecordData, ParseRecordData};
+use domain::base::{ParsedName, Rtype, Ttl};
+use domain::dep::octseq::Parser;
+use domain::rdata::ZoneRecordData;
+use domain::zonetree::types::{StoredName, StoredRecordData};
+
+use crate::error;
+use crate::error::Result;
+use crate::service::journal::{Journal, JournalEntry, RrsetChange};
+use crate::service::Zones;
+
+pub use self::redis::RedisStore;
+pub use self::s3::S3Store;
+
+mod redis;
+mod s3;
+
+/// The records of a zone apex grouped by record type and ttl.
+pub type ZoneRecords = HashMap<(Rtype, Ttl), Vec<StoredRecordData>>;
+
+pub fn encode_records(records: &ZoneRecords) -> Vec<u8> {
+    let mut lines = Vec::new();
+
+    for ((rtype, ttl), data) in records.iter() {
+        if *rtype == Rtype::SOA {
+            continue;
+        }
+
+        for item in data {
+            let mut rdata = Vec::new();
+            let Ok(()) = item.compose_rdata(&mut rdata);
+            let rdata = base64::engine::general_purpose::STANDARD.encode(&rdata);
+            lines.push(format!("{} {} {}", rtype.to_int(), ttl.as_secs(), rdata));
+        }
+    }
+
+    // Sort the lines so that the same set of records always gives the same payload
+    lines.sort();
+    lines.join("\n").into_bytes()
+}
+
+pub fn decode_records(payload: &[u8]) -> Result<ZoneRecords> {
+    let mut records = ZoneRecords::new();
+
+    for line in std::str::from_utf8(payload)?.lines() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let (Some(rtype), Some(ttl), Some(rdata), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(error!(Store => "invalid record line: {}", line));
+        };
+
+        let rtype = rtype
+            .parse::<u16>()
+            .map(Rtype::from_int)
+            .map_err(|e| error!(Store => "invalid record type {}: {}", rtype, e))?;
+        let ttl = ttl
+            .parse::<u32>()
+            .map(Ttl::from_secs)
+            .map_err(|e| error!(Store => "invalid record ttl {}: {}", ttl, e))?;
+        let rdata = Bytes::from(base64::engine::general_purpose::STANDARD.decode(rdata)?);
+
+        let mut parser = Parser::from_ref(&rdata);
+        let data = ZoneRecordData::<Bytes, ParsedName<Bytes>>::parse_rdata(rtype, &mut parser)
+            .map_err(|e| error!(Store => "invalid {} record data: {}", rtype, e))?
+            .ok_or_else(|| error!(Store => "unsupported record type {}", rtype))?;
+        let data: StoredRecordData = data
+            .try_flatten_into()
+            .map_err(|_| error!(Store => "invalid {} record data", rtype))?;
+
+        records.entry((rtype, ttl)).or_default().push(data);
+    }
+
+    Ok(records)
+}
+
+pub fn encode_snapshot(zones: &Zones) -> Vec<u8> {
+    let mut apex_names = zones.apex_names();
+    apex_names.sort();
+
+    let mut payload = Vec::new();
+    for apex in apex_names {
+        payload.extend_from_slice(format!("zone {}\n", apex).as_bytes());
+        payload.extend_from_slice(&encode_records(&zones.records(&apex)));
+        payload.push(b'\n');
+    }
+    payload
+}
+
+pub fn decode_snapshot(payload: &[u8]) -> Result<Vec<(StoredName, ZoneRecords)>> {
+    let mut zones: Vec<(StoredName, ZoneRecords)> = Vec::new();
+
+    for line in std::str::from_utf8(payload)?.lines() {
+        if let Some(apex) = line.strip_prefix("zone ") {
+            zones.push((StoredName::bytes_from_str(apex)?, ZoneRecords::new()));
+            continue;
+        }
+
+        let Some((_, records)) = zones.last_mut() else {
+            return Err(error!(Store => "record line outside of a zone: {}", line));
+        };
+        for (key, data) in decode_records(line.as_bytes())? {
+            records.entry(key).or_default().extend(data);
+        }
+    }
+
+    Ok(zones)
+}
+
+pub fn encode_journal(journal: &Journal) -> Vec<u8> {
+    let mut apex_names = journal.apex_names();
+    apex_names.sort();
+
+    let mut payload = Vec::new();
+    for apex in apex_names {
+        payload.extend_from_slice(format!("zone {}\n", apex).as_bytes());
+        for entry in journal.entries(&apex) {
+            let committed_at = crate::time::unix_secs(entry.committed_at);
+            payload.extend_from_slice(
+                format!(
+                    "serial {} {} {}\n",
+                    entry.from_serial, entry.serial, committed_at
+                )
+                .as_bytes(),
+            );
+            for change in entry.changes {
+                for (sign, data) in [("-", change.removed), ("+", change.added)] {
+                    let records = ZoneRecords::from([((change.rtype, change.ttl), data)]);
+                    for line in encode_records(&records).split(|b| *b == b'\n') {
+                        if !line.is_empty() {
+                            payload.extend_from_slice(format!("{} ", sign).as_bytes());
+                            payload.extend_from_slice(line);
+                            payload.push(b'\n');
+                        }
+                    }
+                }
+            }
+        }
+    }
+    payload
+}
+
+pub fn decode_journal(payload: &[u8]) -> Result<Vec<(StoredName, Vec<JournalEntry>)>> {
+    let mut zones: Vec<(StoredName, Vec<JournalEntry>)> = Vec::new();
+
+    for line in std::str::from_utf8(payload)?.lines() {
+        if let Some(apex) = line.strip_prefix("zone ") {
+            zones.push((StoredName::bytes_from_str(apex)?, Vec::new()));
+            continue;
+        }
+
+        let Some((_, entries)) = zones.last_mut() else {
+            return Err(error!(Store => "journal line outside of a zone: {}", line));
+        };
+        if let Some(serials) = line.strip_prefix("serial ") {
+            let fields = serials
+                .split_whitespace()
+                .map(str::parse::<u64>)
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| error!(Store => "invalid journal entry {}: {}", line, e))?;
+            let [from_serial, serial, committed_at] = fields[..] else {
+                return Err(error!(Store => "invalid journal entry: {}", line));
+            };
+            entries.push(JournalEntry {
+                from_serial: from_serial as u32,
+                serial: serial as u32,
+                committed_at: UNIX_EPOCH + Duration::from_secs(committed_at),
+                changes: Vec::new(),
+            });
+            continue;
+        }
+
+        let Some(entry) = entries.last_mut() else {
+            return Err(error!(Store => "record line outside of a journal entry: {}", line));
+        };
+        let (removed, record) = match line.split_once(' ') {
+            Some(("-", record)) => (true, record),
+            Some(("+", record)) => (false, record),
+            _ => return Err(error!(Store => "invalid journal line: {}", line)),
+        };
+        for ((rtype, ttl), data) in decode_records(record.as_bytes())? {
+            let index = match entry
+                .changes
+                .iter()
+                .position(|change| change.rtype == rtype && change.ttl == ttl)
+            {
+                Some(index) => index,
+                None => {
+                    entry.changes.push(RrsetChange {
+                        rtype,
+                        ttl,
+                        removed: Vec::new(),
+                        added: Vec::new(),
+                    });
+                    entry.changes.len() - 1
+                }
+            };
+            let change = &mut entry.changes[index];
+            match removed {
+                true => change.removed.extend(data),
+                false => change.added.extend(data),
+            }
+        }
+    }
+
+    Ok(zones)
+}
+
+#[cfg(test)]
+mod tests {
+    use domain::base::Serial;
+    use domain::rdata::{Soa, Txt};
+
+    use super::*;
+    use crate::config::JournalConfig;
+
+    fn records(serial: u32, texts: &[&str]) -> ZoneRecords {
+        let name = StoredName::bytes_from_str("ns-acme.example.fr").unwrap();
+        let soa = Soa::new(
+            name.clone(),
+            name,
+            Serial::from(serial),
+            Ttl::HOUR,
+            Ttl::HOUR,
+            Ttl::HOUR,
+            Ttl::HOUR,
+        );
+
+        let mut records = ZoneRecords::new();
+        records.insert((Rtype::SOA, Ttl::HOUR), vec![soa.into()]);
+        records.insert(
+            (Rtype::TXT, Ttl::from_secs(60)),
+            texts
+                .iter()
+                .map(|text| Txt::build_from_slice(text.as_bytes()).unwrap().into())
+                .collect(),
+        );
+        records
+    }
+
+    #[test]
+    fn journals_are_decoded_as_encoded() {
+        let journal = Journal::new(JournalConfig::default());
+        let apex = StoredName::bytes_from_str("_acme-challenge.example.fr").unwrap();
+        journal.record(&apex, &records(1, &[]), &records(2, &["a", "b"]));
+        journal.record(&apex, &records(2, &["a", "b"]), &records(3, &["c"]));
+
+        let zones = decode_journal(&encode_journal(&journal)).unwrap();
+        assert_eq!(zones.len(), 1);
+        assert_eq!(zones[0].0, apex);
+
+        // The commit times are kept to the second
+        let entries = journal.entries(&apex);
+        assert_eq!(zones[0].1.len(), entries.len());
+        for (decoded, entry) in zones[0].1.iter().zip(&entries) {
+            assert_eq!(decoded.to_string(), entry.to_string());
+            assert_eq!(decoded.changes, entry.changes);
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! A minimal Redis backed zone store.
+//!
+//! This only implements the few commands needed by dnsr over the RESP protocol
+//! to avoid pulling a full featured client in the dependencies.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use domain::base::ToName;
+use domain::zonetree::types::StoredName;
+use futures::executor::block_on;
+
+use super::{decode_records, encode_records, ZoneRecords};
+use crate::config::RedisConfig;
+use crate::error;
+use crate::error::Result;
+use crate::service::Zones;
+
+#[derive(Debug)]
+pub struct RedisStore {
+    client: RedisClient,
+    prefix: String,
+    sync_interval: Duration,
+
+    /// The last payload seen for each zone, used to skip unchanged zones
+    applied: Mutex<HashMap<StoredName, Vec<u8>>>,
+    /// The records queued for the store thread, so that the updates never
+    /// wait for redis
+    sender: Sender<(StoredName, Vec<u8>)>,
+    receiver: Mutex<Receiver<(StoredName, Vec<u8>)>>,
+}
+
+impl RedisStore {
+    pub fn new(config: &RedisConfig) -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            client: RedisClient::new(config.url(), config.timeout()),
+            prefix: config.prefix().to_string(),
+            sync_interval: config.sync_interval(),
+            applied: Mutex::new(HashMap::new()),
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+
+    fn zone_key<N>(&self, apex: &N) -> String
+    where
+        N: ToName,
+    {
+        format!("{}:zone:{}", self.prefix, apex.to_bytes())
+    }
+
+    /// Queues the records of a zone to be published by the store thread so
+    /// that every other instance serves them.
+    pub fn publish<N>(&self, apex: &N, records: &ZoneRecords)
+    where
+        N: ToName,
+    {
+        let _ = self.sender.send((apex.to_bytes(), encode_records(records)));
+    }
+
+    /// Runs the store thread: publishes the queued records as they come and
+    /// synchronizes the zones every sync interval once the queue is empty.
+    pub fn run(&self, zones: &Zones) {
+        let receiver = self.receiver.lock().unwrap();
+        let mut next_sync = Instant::now() + self.sync_interval;
+        loop {
+            match receiver.recv_timeout(next_sync.saturating_duration_since(Instant::now())) {
+                Ok((apex, payload)) => {
+                    if let Err(e) = self.send(&apex, payload) {
+                        log::error!(target: "redis", "failed to publish the zone records: {}", e);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = self.sync(zones) {
+                        log::error!(target: "redis", "failed to synchronize zones: {}", e);
+                    }
+                    next_sync = Instant::now() + self.sync_interval;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn send(&self, apex: &StoredName, payload: Vec<u8>) -> Result<()> {
+        let key = self.zone_key(apex);
+        self.client
+            .command(&[b"SET", key.as_bytes(), payload.as_slice()])?;
+        log::debug!(target: "redis", "published records of zone {}", apex);
+
+        let mut applied = self.applied.lock().unwrap();
+        applied.insert(apex.clone(), payload);
+
+        Ok(())
+    }
+
+    /// Fetches the records of every local zone and applies the ones which changed.
+    pub fn sync(&self, zones: &Zones) -> Result<()> {
+        for apex in zones.apex_names() {
+            let key = self.zone_key(&apex);
+            let Some(payload) = self.client.command(&[b"GET", key.as_bytes()])? else {
+                continue;
+            };
+
+            let mut applied = self.applied.lock().unwrap();
+            if applied.get(&apex) == Some(&payload) {
+                continue;
+            }
+
+            let records = decode_records(&payload)?;
+            block_on(zones.replace_records(&apex, records))?;
+            log::info!(target: "redis", "synchronized records of zone {}", apex);
+
+            applied.insert(apex, payload);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct RedisClient {
+    url: String,
+    timeout: Duration,
+    conn: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl RedisClient {
+    fn new(url: &str, timeout: Duration) -> Self {
+        Self {
+            url: url.to_string(),
+            timeout,
+            conn: Mutex::new(None),
+        }
+    }
+
+    fn connect(&self) -> Result<BufReader<TcpStream>> {
+        let url = RedisUrl::parse(&self.url)?;
+        let addr = url
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| error!(Store => "unable to resolve redis address {}", url.addr))?;
+
+        let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut conn = BufReader::new(stream);
+
+        match (&url.username, &url.password) {
+            (Some(username), Some(password)) => {
+                execute(
+                    &mut conn,
+                    &[b"AUTH", username.as_bytes(), password.as_bytes()],
+                )?;
+            }
+            (None, Some(password)) => {
+                execute(&mut conn, &[b"AUTH", password.as_bytes()])?;
+            }
+            _ => (),
+        }
+        if let Some(db) = &url.db {
+            execute(&mut conn, &[b"SELECT", db.as_bytes()])?;
+        }
+
+        log::debug!(target: "redis", "connected to redis at {}", addr);
+        Ok(conn)
+    }
+
+    /// Runs a command, reconnecting first if the previous connection was lost.
+    fn command(&self, args: &[&[u8]]) -> Result<Option<Vec<u8>>> {
+        let mut conn = self.conn.lock().unwrap();
+
+        let mut stream = match conn.take() {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+
+        let reply = execute(&mut stream, args);
+        if reply.is_ok() {
+            *conn = Some(stream);
+        }
+        reply
+    }
+}
+
+#[derive(Debug)]
+struct RedisUrl {
+    addr: String,
+    username: Option<String>,
+    password: Option<String>,
+    db: Option<String>,
+}
+
+impl RedisUrl {
+    /// Parses urls of the form `redis://[[username]:password@]host[:port][/db]`
+    fn parse(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("redis://") else {
+            return Err(error!(Store => "unsupported redis url {}", url));
+        };
+
+        let (auth, rest) = match rest.rsplit_once('@') {
+            Some((auth, rest)) => (Some(auth), rest),
+            None => (None, rest),
+        };
+        let (username, password) = match auth.map(|a| a.split_once(':')) {
+            Some(Some((username, password))) if !username.is_empty() => {
+                (Some(username.to_string()), Some(password.to_string()))
+            }
+            Some(Some((_, password))) => (None, Some(password.to_string())),
+            Some(None) => (None, auth.map(str::to_string)),
+            None => (None, None),
+        };
+
+        let (host, db) = match rest.split_once('/') {
+            Some((host, db)) if !db.is_empty() => (host, Some(db.to_string())),
+            Some((host, _)) => (host, None),
+            None => (rest, None),
+        };
+        let addr = if host
+            .rsplit_once(':')
+            .is_some_and(|(_, p)| !p.ends_with(']'))
+        {
+            host.to_string()
+        } else {
+            format!("{}:6379", host)
+        };
+
+        Ok(Self {
+            addr,
+            username,
+            password,
+            db,
+        })
+    }
+}
+
+fn execute(conn: &mut BufReader<TcpStream>, args: &[&[u8]]) -> Result<Option<Vec<u8>>> {
+    conn.get_mut().write_all(&encode_command(args)?)?;
+
+    read_reply(conn)
+}
+
+/// Encodes a command as a RESP array of bulk strings.
+fn encode_command(args: &[&[u8]]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    write!(buf, "*{}\r\n", args.len())?;
+    for arg in args {
+        write!(buf, "${}\r\n", arg.len())?;
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    Ok(buf)
+}
+
+/// Reads a single RESP reply, arrays are not supported as no command used returns one.
+fn read_reply<R>(reader: &mut R) -> Result<Option<Vec<u8>>>
+where
+    R: BufRead,
+{
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    let Some(line) = line.strip_suffix(b"\r\n") else {
+        return Err(error!(Store => "unexpected end of redis reply"));
+    };
+    let Some((kind, value)) = line.split_first() else {
+        return Err(error!(Store => "empty redis reply"));
+    };
+
+    match kind {
+        b'+' | b':' => Ok(Some(value.to_vec())),
+        b'-' => Err(error!(Store => "redis error: {}", String::from_utf8_lossy(value))),
+        b'$' => {
+            let len = std::str::from_utf8(value)?
+                .parse::<i64>()
+                .map_err(|e| error!(Store => "invalid redis bulk length: {}", e))?;
+            let Ok(len) = usize::try_from(len) else {
+                return Ok(None);
+            };
+
+            let mut data = vec![0; len + 2];
+            reader.read_exact(&mut data)?;
+            data.truncate(len);
+            Ok(Some(data))
+        }
+        _ => Err(error!(Store => "unsupported redis reply type {}", *kind as char)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+        read_reply(&mut &bytes[..])
+    }
+
+    #[test]
+    fn commands_are_encoded_as_arrays_of_bulk_strings() {
+        let command = encode_command(&[b"SET", b"dnsr:zone", b""]).unwrap();
+        assert_eq!(command, b"*3\r\n$3\r\nSET\r\n$9\r\ndnsr:zone\r\n$0\r\n\r\n");
+    }
+
+    #[test]
+    fn replies_are_decoded() {
+        assert_eq!(reply(b"+OK\r\n").unwrap(), Some(b"OK".to_vec()));
+        assert_eq!(reply(b":42\r\n").unwrap(), Some(b"42".to_vec()));
+
+        // The bulk strings are binary safe
+        assert_eq!(
+            reply(b"$4\r\na\r\nb\r\n").unwrap(),
+            Some(b"a\r\nb".to_vec())
+        );
+        assert_eq!(reply(b"$0\r\n\r\n").unwrap(), Some(Vec::new()));
+        assert_eq!(reply(b"$-1\r\n").unwrap(), None);
+
+        assert!(reply(b"-ERR unknown command\r\n").is_err());
+        assert!(reply(b"$5\r\nab").is_err());
+        assert!(reply(b"+OK").is_err());
+    }
+
+    #[test]
+    fn array_replies_are_unsupported() {
+        assert!(reply(b"*1\r\n$2\r\nOK\r\n").is_err());
+        assert!(reply(b"*-1\r\n").is_err());
+    }
+
+    #[test]
+    fn urls_are_parsed() {
+        let url = RedisUrl::parse("redis://localhost").unwrap();
+        assert_eq!(url.addr, "localhost:6379");
+        assert_eq!(url.username, None);
+        assert_eq!(url.password, None);
+        assert_eq!(url.db, None);
+
+        let url = RedisUrl::parse("redis://dnsr:s3cr:et@10.0.0.1:6380/2").unwrap();
+        assert_eq!(url.addr, "10.0.0.1:6380");
+        assert_eq!(url.username.as_deref(), Some("dnsr"));
+        assert_eq!(url.password.as_deref(), Some("s3cr:et"));
+        assert_eq!(url.db.as_deref(), Some("2"));
+
+        // A password alone, with or without the colon
+        let url = RedisUrl::parse("redis://:secret@localhost/").unwrap();
+        assert_eq!(url.username, None);
+        assert_eq!(url.password.as_deref(), Some("secret"));
+        assert_eq!(url.db, None);
+        let url = RedisUrl::parse("redis://secret@localhost").unwrap();
+        assert_eq!(url.password.as_deref(), Some("secret"));
+
+        assert_eq!(RedisUrl::parse("redis://[::1]").unwrap().addr, "[::1]:6379");
+        assert_eq!(
+            RedisUrl::parse("redis://[::1]:6380").unwrap().addr,
+            "[::1]:6380"
+        );
+
+        assert!(RedisUrl::parse("rediss://localhost").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! Notifications of the service manager, see `sd_notify(3)`.
+//!
+//! dnsr notifies systemd once it is ready to answer and then sends the watchdog
+//! keep-alives, so that `Type=notify` units with a `WatchdogSec` work. Nothing
+//! is sent when dnsr is not started by systemd, i.e. when `NOTIFY_SOCKET` is
+//! not set.
+
+use std::ffi::OsStr;
+use std::io::Result;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// Notifies that the listeners are bound and the zones loaded, and starts the
+/// watchdog keep-alives if the watchdog is enabled.
+///
+/// The keep-alives are sent from the runtime so that they stop if it hangs.
+pub fn notify_ready() {
+    notify("READY=1");
+
+    if let Some(interval) = watchdog_interval() {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                notify("WATCHDOG=1");
+            }
+        });
+    }
+}
+
+/// Returns the interval of the watchdog keep-alives, half of the watchdog
+/// timeout as recommended.
+fn watchdog_interval() -> Option<Duration> {
+    // The watchdog may be meant for another process, e.g. a wrapper script
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
+
+fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    if let Err(e) = send(&path, state) {
+        log::warn!(target: "systemd", "failed to notify {}: {}", state, e);
+    }
+}
+
+fn send(path: &OsStr, state: &str) -> Result<()> {
+    // A path starting with @ is in the abstract namespace
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_are_sent_to_the_notify_socket() {
+        let path = std::env::temp_dir().join(format!("dnsr-notify-{}", std::process::id()));
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+//! OpenTelemetry tracing of the requests.
+//!
+//! Every sampled request is traced with a root span covering its handling
+//! and a child span for each stage timed by [`crate::service::profiling`].
+//! The traces are buffered and periodically exported to an OTLP collector
+//! using the OTLP/HTTP JSON encoding, which is accepted by Jaeger, Tempo and
+//! the OpenTelemetry collector.
+//!
+//! The trace of a request is only accessible from the thread polling the
+//! request, it is moved in a thread local for the duration of each poll.
+
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::rand::SecureRandom;
+
+use crate::config::TelemetryConfig;
+use crate::error;
+use crate::error::Result;
+
+/// The maximum number of traces waiting for an export.
+const MAX_PENDING_TRACES: usize = 10_000;
+
+/// The OTLP kinds of the root spans and of their children.
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+}
+
+#[derive(Debug)]
+pub struct Span {
+    span_id: [u8; 8],
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+#[derive(Debug)]
+pub struct Trace {
+    trace_id: [u8; 16],
+    root: Span,
+    children: Vec<Span>,
+}
+
+impl Trace {
+    pub fn set_attribute(&mut self, key: &'static str, value: Value) {
+        match self.root.attributes.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.root.attributes.push((key, value)),
+        }
+    }
+
+    fn child_id(&self) -> [u8; 8] {
+        let id = u64::from_be_bytes(self.root.span_id);
+        id.wrapping_add(self.children.len() as u64 + 1)
+            .to_be_bytes()
+    }
+}
+
+#[derive(Debug)]
+pub struct Tracer {
+    config: TelemetryConfig,
+    pending: Mutex<Vec<Trace>>,
+    dropped: AtomicU64,
+}
+
+impl Tracer {
+    pub fn new(config: &TelemetryConfig) -> Self {
+        Self {
+            config: config.clone(),
+            pending: Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval()
+    }
+
+    /// Starts the trace of a request named `name`, returns `None` if the
+    /// request is not sampled.
+    pub fn start_trace(&self, name: &'static str) -> Option<Trace> {
+        let mut ids = [0u8; 24];
+        ring::rand::SystemRandom::new().fill(&mut ids).ok()?;
+
+        // The sampling decision is derived from the trace id as recommended
+        // for the trace id ratio based samplers
+        let sample = u64::from_be_bytes(ids[8..16].try_into().unwrap()) as f64 / u64::MAX as f64;
+        if sample >= self.config.sample_ratio() {
+            return None;
+        }
+
+        let now = SystemTime::now();
+        Some(Trace {
+            trace_id: ids[..16].try_into().unwrap(),
+            root: Span {
+                span_id: ids[16..].try_into().unwrap(),
+                name,
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+            },
+            children: Vec::new(),
+        })
+    }
+
+    /// Ends the root span of `trace` and queues it for the next export.
+    pub fn finish_trace(&self, mut trace: Trace) {
+        trace.root.end = SystemTime::now();
+
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING_TRACES {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        pending.push(trace);
+    }
+
+    /// Exports the pending traces to the collector.
+    pub fn export(&self) -> Result<()> {
+        let traces = std::mem::take(&mut *self.pending.lock().unwrap());
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!(target: "telemetry", "dropped {} traces, the export is too slow", dropped);
+        }
+        if traces.is_empty() {
+            return Ok(());
+        }
+
+        let url = format!("{}/v1/traces", self.config.endpoint().trim_end_matches('/'));
+        let request = ureq::post(&url)
+            .timeout(self.config.timeout())
+            .set("content-type", "application/json");
+
+        match request.send_string(&self.encode(&traces)) {
+            Ok(_) => {
+                log::debug!(target: "telemetry", "exported {} traces", traces.len());
+                Ok(())
+            }
+            Err(ureq::Error::Status(code, response)) => Err(
+                error!(Telemetry => "otlp export failed with status {}: {}", code, response.into_string().unwrap_or_default()),
+            ),
+            Err(e) => Err(error!(Telemetry => "otlp export failed: {}", e)),
+        }
+    }
+
+    /// Encodes `traces` as an OTLP/HTTP JSON `ExportTraceServiceRequest`.
+    fn encode(&self, traces: &[Trace]) -> String {
+        let mut spans = Vec::new();
+        for trace in traces {
+            let trace_id = hex(&trace.trace_id);
+            spans.push(encode_span(&trace_id, &trace.root, None));
+            for child in trace.children.iter() {
+                spans.push(encode_span(&trace_id, child, Some(&trace.root.span_id)));
+            }
+        }
+
+        format!(
+            r#"{{"resourceSpans":[{{"resource":{{"attributes":[{}]}},"scopeSpans":[{{"scope":{{"name":"dnsr"}},"spans":[{}]}}]}}]}}"#,
+            encode_attribute(
+                "service.name",
+                &Value::Str(self.config.service_name().into())
+            ),
+            spans.join(",")
+        )
+    }
+}
+
+/// Moves `trace` in the thread local of the current trace while `f` runs.
+pub fn with_trace<T, F>(trace: &mut Option<Trace>, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    if trace.is_none() {
+        return f();
+    }
+
+    let previous = CURRENT.with(|current| current.replace(trace.take()));
+    let result = f();
+    *trace = CURRENT.with(|current| current.replace(previous));
+    result
+}
+
+/// Runs `f` and records it as a child span named `name` of the current trace.
+pub fn in_span<T, F>(name: &'static str, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    if CURRENT.with(|current| current.borrow().is_none()) {
+        return f();
+    }
+
+    let start = SystemTime::now();
+    let result = f();
+    let end = SystemTime::now();
+
+    CURRENT.with(|current| {
+        if let Some(trace) = current.borrow_mut().as_mut() {
+            let span = Span {
+                span_id: trace.child_id(),
+                name,
+                start,
+                end,
+                attributes: Vec::new(),
+            };
+            trace.children.push(span);
+        }
+    });
+    result
+}
+
+fn encode_span(trace_id: &str, span: &Span, parent: Option<&[u8; 8]>) -> String {
+    let mut json = format!(
+        r#"{{"traceId":"{}","spanId":"{}","name":"{}","kind":{},"startTimeUnixNano":"{}","endTimeUnixNano":"{}""#,
+        trace_id,
+        hex(&span.span_id),
+        escape(span.name),
+        if parent.is_some() {
+            SPAN_KIND_INTERNAL
+        } else {
+            SPAN_KIND_SERVER
+        },
+        unix_nanos(span.start),
+        unix_nanos(span.end)
+    );
+    if let Some(parent) = parent {
+        let _ = write!(json, r#","parentSpanId":"{}""#, hex(parent));
+    }
+
+    let attributes = span
+        .attributes
+        .iter()
+        .map(|(key, value)| encode_attribute(key, value))
+        .collect::<Vec<_>>();
+    let _ = write!(json, r#","attributes":[{}]}}"#, attributes.join(","));
+    json
+}
+
+fn encode_attribute(key: &str, value: &Value) -> String {
+    let value = match value {
+        Value::Str(s) => format!(r#"{{"stringValue":"{}"}}"#, escape(s)),
+        // 64 bits integers are encoded as strings in the OTLP JSON encoding
+        Value::Int(i) => format!(r#"{{"intValue":"{}"}}"#, i),
+    };
+    format!(r#"{{"key":"{}","value":{}}}"#, escape(key), value)
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracer() -> Tracer {
+        let config = serde_yaml::from_str("{ endpoint: http://127.0.0.1:4318 }").unwrap();
+        Tracer::new(&config)
+    }
+
+    #[test]
+    fn stages_are_recorded_in_the_current_trace() {
+        let tracer = tracer();
+        let mut trace = tracer.start_trace("dns.request");
+
+        // Spans outside of a trace are ignored
+        assert_eq!(in_span("lookup", || 1), 1);
+        let result = with_trace(&mut trace, || in_span("lookup", || in_span("build", || 2)));
+        assert_eq!(result, 2);
+
+        let trace = trace.unwrap();
+        let names = trace.children.iter().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(names, ["build", "lookup"]);
+        assert_ne!(trace.children[0].span_id, trace.children[1].span_id);
+        assert!(CURRENT.with(|current| current.borrow().is_none()));
+    }
+
+    #[test]
+    fn traces_are_encoded_as_otlp_json() {
+        let tracer = tracer();
+        let mut trace = tracer.start_trace("dns.request").unwrap();
+        trace.set_attribute("dns.question.name", Value::Str("a\"b".into()));
+        trace.set_attribute("dns.response.code", Value::Int(3));
+
+        let json = tracer.encode(&[trace]);
+        assert!(json.starts_with(r#"{"resourceSpans":[{"resource":{"attributes":[{"key":"service.name","value":{"stringValue":"dnsr"}}]}"#));
+        assert!(json.contains(r#"{"key":"dns.question.name","value":{"stringValue":"a\"b"}}"#));
+        assert!(json.contains(r#"{"key":"dns.response.code","value":{"intValue":"3"}}"#));
+        assert!(!json.contains("parentSpanId"));
+    }
+}
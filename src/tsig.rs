@@ -1,8 +1,13 @@
 use std::ffi::OsStr;
 use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
 
 use base64::Engine;
-use domain::tsig::{Key, KeyName};
+use domain::base::{Message, ParsedName};
+use domain::dep::octseq::Octets;
+use domain::rdata::tsig::{Time48, Tsig};
+use domain::tsig::{Algorithm, Key, KeyName};
 
 use crate::error;
 use crate::error::Result;
@@ -20,12 +25,20 @@ where
     Ok(())
 }
 
+/// Generates a new TSIG key and writes its secret at `fpath`.
+///
+/// The generation holds an advisory lock next to the file, so that concurrent
+/// generations of the same key, from this process or from another replica
+/// sharing the volume, result in a single key. The secret is written to a
+/// temporary file renamed over `fpath` so that it is never read partially
+/// written.
 pub fn generate_new_tsig<P, N>(fpath: &P, name: N) -> Result<Key>
 where
     P: AsRef<OsStr>,
     N: TryInto<KeyName, Error = error::Error>,
 {
     let path = std::path::Path::new(fpath);
+    let _lock = lock_key_file(path)?;
 
     // Check if a file already exists at this path if so we return an error
     if path.is_file() {
@@ -41,12 +54,37 @@ where
     let (key, secret) = Key::generate(domain::tsig::Algorithm::Sha512, &rng, name, None, None)?;
     let secret = base64::engine::general_purpose::STANDARD.encode(&secret);
 
-    let mut file = std::fs::File::create(path)?;
+    let tmp = sibling(path, "tmp");
+    let mut file = std::fs::File::create(&tmp)?;
     write!(file, "{}", secret)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
 
     Ok(key)
 }
 
+/// Takes the advisory lock of the key file at `path`, released when the
+/// returned file is dropped.
+///
+/// The lock file is left in place, removing it would let a waiting generation
+/// lock an unlinked file.
+fn lock_key_file(path: &Path) -> Result<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(sibling(path, "lock"))?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// Returns the path of the hidden `.<file name>.<extension>` file next to
+/// `path`, which the key files checks ignore.
+fn sibling(path: &Path, extension: &str) -> std::path::PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}", name, extension))
+}
+
 pub fn load_tsig<P, N>(fpath: &P, name: N) -> Result<Key>
 where
     P: AsRef<OsStr>,
@@ -71,3 +109,253 @@ where
         None,
     )?)
 }
+
+/// Returns the time the signature of `message` is verified at, given the
+/// accepted clock skew `fudge` in seconds.
+///
+/// A request signed within `fudge` of the server clock is verified at its own
+/// signing time, so that it is accepted whatever the fudge announced by the
+/// client. Any other request fails with BADTIME, see RFC 8945 section 5.2.3:
+/// it is verified at the server time, sent along the error so that the client
+/// can resync its clock, or just past the fudge announced by the client when
+/// the server time is still within it.
+pub fn verification_time<Octs>(message: &Message<Octs>, fudge: u16) -> Time48
+where
+    Octs: Octets,
+{
+    let now = Time48::now();
+    let signed = message.additional().ok().and_then(|records| {
+        records
+            .limit_to::<Tsig<_, ParsedName<_>>>()
+            .filter_map(Result::ok)
+            .last()
+            .map(|record| (record.data().time_signed(), record.data().fudge()))
+    });
+    match signed {
+        Some((signed, _)) if signed.is_within(now, fudge.into()) => signed,
+        Some((signed, client_fudge)) if signed.is_within(now, client_fudge.into()) => {
+            let (signed, past) = (signed.into_int(), u64::from(client_fudge) + 1);
+            match now.into_int() >= signed {
+                true => Time48::from_u64(signed + past),
+                false => Time48::from_u64(signed.saturating_sub(past)),
+            }
+        }
+        _ => now,
+    }
+}
+
+/// Loads the keys defined by the BIND `key` statements of a file.
+///
+/// This reads both `named.conf` style files, where every other statement is
+/// ignored, and the output of `tsig-keygen`.
+pub fn load_bind_keys<P>(fpath: &P) -> Result<Vec<Key>>
+where
+    P: AsRef<Path>,
+{
+    let content = std::fs::read_to_string(fpath)?;
+    parse_bind_keys(&content).map_err(
+        |e| error!(TSIGKey => "invalid bind key file {}: {}", fpath.as_ref().display(), e.text()),
+    )
+}
+
+fn parse_bind_keys(content: &str) -> Result<Vec<Key>> {
+    let mut tokens = tokenize(content)?.into_iter().peekable();
+    let statements = parse_statements(&mut tokens)?;
+    if tokens.next().is_some() {
+        return Err(error!(TSIGKey => "unexpected closing brace"));
+    }
+
+    statements
+        .iter()
+        .filter(|s| s.args.first().is_some_and(|a| a == "key"))
+        .map(|s| {
+            let (Some(name), Some(block)) = (s.args.get(1), &s.block) else {
+                return Err(error!(TSIGKey => "invalid key statement"));
+            };
+            let value = |option: &str| {
+                block
+                    .iter()
+                    .find(|s| s.args.first().is_some_and(|a| a == option))
+                    .and_then(|s| s.args.get(1))
+                    .ok_or_else(|| error!(TSIGKey => "missing {} in key {}", option, name))
+            };
+
+            let algorithm = value("algorithm")?;
+            let algorithm = Algorithm::from_str(algorithm.trim_end_matches('.'))
+                .map_err(|_| error!(TSIGKey => "unsupported algorithm {}", algorithm))?;
+            let secret = base64::engine::general_purpose::STANDARD.decode(value("secret")?)?;
+
+            Ok(Key::new(
+                algorithm,
+                &secret,
+                KeyName::from_str(name)?,
+                None,
+                None,
+            )?)
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Open,
+    Close,
+    End,
+}
+
+#[derive(Debug)]
+struct Statement {
+    args: Vec<String>,
+    block: Option<Vec<Statement>>,
+}
+
+fn tokenize(content: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => tokens.push(Token::Open),
+            '}' => tokens.push(Token::Close),
+            ';' => tokens.push(Token::End),
+            '#' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '/' if chars.next_if_eq(&'/').is_some() => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+            }
+            '/' if chars.next_if_eq(&'*').is_some() => loop {
+                match chars.next() {
+                    Some('*') if chars.next_if_eq(&'/').is_some() => break,
+                    Some(_) => (),
+                    None => return Err(error!(TSIGKey => "unterminated comment")),
+                }
+            },
+            '"' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(error!(TSIGKey => "unterminated string")),
+                    }
+                }
+                tokens.push(Token::Word(word));
+            }
+            c if c.is_whitespace() => (),
+            c => {
+                let mut word = String::from(c);
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !matches!(c, '{' | '}' | ';' | '"'))
+                {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Parses statements until the end of the tokens or an unmatched closing brace.
+fn parse_statements<I>(tokens: &mut std::iter::Peekable<I>) -> Result<Vec<Statement>>
+where
+    I: Iterator<Item = Token>,
+{
+    let mut statements = Vec::new();
+    let mut args = Vec::new();
+    let mut block = None;
+
+    while let Some(token) = tokens.next_if(|t| *t != Token::Close) {
+        match token {
+            Token::Word(word) if block.is_none() => args.push(word),
+            Token::Open if block.is_none() => {
+                block = Some(parse_statements(tokens)?);
+                if tokens.next() != Some(Token::Close) {
+                    return Err(error!(TSIGKey => "unterminated block"));
+                }
+            }
+            Token::End => statements.push(Statement {
+                args: std::mem::take(&mut args),
+                block: block.take(),
+            }),
+            _ => return Err(error!(TSIGKey => "missing semicolon")),
+        }
+    }
+
+    if !args.is_empty() || block.is_some() {
+        return Err(error!(TSIGKey => "missing semicolon"));
+    }
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn concurrent_generations_write_a_single_key() {
+        let dir = std::env::temp_dir().join(format!("dnsr-tsig-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("key1");
+        let name: crate::key::KeyFile = serde_yaml::from_str("key1").unwrap();
+
+        let handles = (0..4)
+            .map(|_| {
+                let (path, name) = (path.clone(), name.clone());
+                std::thread::spawn(move || generate_new_tsig(&path, &name))
+            })
+            .collect::<Vec<_>>();
+        let results = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results.iter().all(|r| match r {
+            Ok(_) => true,
+            Err(e) => e.kind == ErrorKind::TSIGFileAlreadyExist,
+        }));
+        let key = load_tsig(&path, &name).unwrap();
+        assert_eq!(key.name(), &KeyName::from_str("key1").unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bind_keys_are_parsed() {
+        let content = r#"
+# generated by tsig-keygen
+key "key1" {
+	algorithm hmac-sha256;
+	secret "c2VjcmV0LWtleS0x";
+};
+
+options {
+    directory "/var/cache/bind"; // not a key
+};
+
+/* a second key */
+key key2. { algorithm "hmac-sha512"; secret "c2VjcmV0LWtleS0y"; };
+"#;
+
+        let keys = parse_bind_keys(content).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].name(), &KeyName::from_str("key1").unwrap());
+        assert_eq!(keys[0].algorithm(), Algorithm::Sha256);
+        assert_eq!(keys[1].name(), &KeyName::from_str("key2").unwrap());
+        assert_eq!(keys[1].algorithm(), Algorithm::Sha512);
+    }
+
+    #[test]
+    fn invalid_bind_keys_are_rejected() {
+        assert!(parse_bind_keys(r#"key "key1" { algorithm hmac-sha256; };"#).is_err());
+        assert!(
+            parse_bind_keys(r#"key "key1" { algorithm hmac-md5; secret "c2VjcmV0"; };"#).is_err()
+        );
+        assert!(
+            parse_bind_keys(r#"key "key1" { algorithm hmac-sha256; secret "c2VjcmV0"; }"#).is_err()
+        );
+    }
+}
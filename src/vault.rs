@@ -0,0 +1,245 @@
+//! The TSIG secrets stored in HashiCorp Vault.
+//!
+//! With a `vault` section, the secrets are stored in a KV version 2 secrets
+//! engine, one secret per key under the configured path, instead of the files
+//! of the key folder. A new key is written with a check-and-set of 0 so that
+//! replicas generating the same key concurrently end with a single secret, and
+//! the version of every loaded secret is kept to reload the secrets rotated in
+//! Vault.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use base64::Engine;
+use domain::tsig::{Algorithm, Key, KeyName};
+use serde::Deserialize;
+
+use crate::config::{parse_json, VaultConfig};
+use crate::error;
+use crate::error::Result;
+use crate::key::KeyFile;
+
+#[derive(Debug)]
+pub struct Vault {
+    config: VaultConfig,
+    agent: ureq::Agent,
+    /// The version of the secret of every loaded key
+    loaded: Mutex<HashMap<KeyFile, u64>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SecretResponse {
+    data: SecretData,
+}
+
+#[derive(Debug, Deserialize)]
+struct SecretData {
+    data: Secret,
+    metadata: Version,
+}
+
+#[derive(Debug, Deserialize)]
+struct Secret {
+    secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct VersionResponse {
+    data: Version,
+}
+
+#[derive(Debug, Deserialize)]
+struct Version {
+    version: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataResponse {
+    data: Metadata,
+}
+
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    current_version: u64,
+}
+
+impl Vault {
+    pub fn new(config: &VaultConfig) -> Self {
+        Self {
+            config: config.clone(),
+            agent: ureq::AgentBuilder::new().timeout(config.timeout()).build(),
+            loaded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn interval(&self) -> std::time::Duration {
+        self.config.interval()
+    }
+
+    /// Generates a new key and writes its secret, the secret is returned
+    /// encoded in base64.
+    pub fn generate(&self, key: &KeyFile) -> Result<(Key, String)> {
+        let rng = ring::rand::SystemRandom::new();
+        let (k, secret) =
+            Key::generate(Algorithm::Sha512, &rng, KeyName::try_from(key)?, None, None)?;
+        let secret = base64::engine::general_purpose::STANDARD.encode(&secret);
+
+        // The check-and-set of 0 only writes a secret which does not exist
+        let body = format!(
+            r#"{{"options":{{"cas":0}},"data":{{"secret":"{}"}}}}"#,
+            secret
+        );
+        let response = self
+            .agent
+            .post(&self.url("data", key))
+            .set("x-vault-token", &self.config.token())
+            .set("content-type", "application/json")
+            .send_string(&body);
+        let version = match response {
+            Ok(response) => parse_version(&response.into_string()?)?,
+            Err(ureq::Error::Status(400, response))
+                if response
+                    .into_string()
+                    .is_ok_and(|body| body.contains("check-and-set")) =>
+            {
+                return Err(
+                    error!(TSIGFileAlreadyExist => "TSIG secret of {} already exists in vault", key),
+                );
+            }
+            Err(e) => return Err(request_error(e)),
+        };
+
+        self.loaded.lock().unwrap().insert(key.clone(), version);
+        Ok((k, secret))
+    }
+
+    /// Reads the latest version of the secret of `key`.
+    pub fn load(&self, key: &KeyFile) -> Result<Key> {
+        let response = self
+            .agent
+            .get(&self.url("data", key))
+            .set("x-vault-token", &self.config.token())
+            .call();
+        let (secret, version) = match response {
+            Ok(response) => parse_secret(&response.into_string()?)?,
+            Err(ureq::Error::Status(404, _)) => {
+                self.loaded.lock().unwrap().remove(key);
+                return Err(
+                    error!(TSIGFileNotFound => "TSIG secret of {} not found in vault", key),
+                );
+            }
+            Err(e) => return Err(request_error(e)),
+        };
+
+        let k = Key::new(
+            Algorithm::Sha512,
+            &secret,
+            KeyName::try_from(key)?,
+            None,
+            None,
+        )?;
+        self.loaded.lock().unwrap().insert(key.clone(), version);
+        Ok(k)
+    }
+
+    /// Deletes every version of the secret of `key`.
+    pub fn delete(&self, key: &KeyFile) -> Result<()> {
+        let response = self
+            .agent
+            .delete(&self.url("metadata", key))
+            .set("x-vault-token", &self.config.token())
+            .call();
+        match response {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => {
+                self.loaded.lock().unwrap().remove(key);
+                Ok(())
+            }
+            Err(e) => Err(request_error(e)),
+        }
+    }
+
+    /// Returns whether the secret of `key` was rotated, created or deleted
+    /// since it was last loaded.
+    pub fn is_rotated(&self, key: &KeyFile) -> Result<bool> {
+        let response = self
+            .agent
+            .get(&self.url("metadata", key))
+            .set("x-vault-token", &self.config.token())
+            .call();
+        let current = match response {
+            Ok(response) => Some(parse_current_version(&response.into_string()?)?),
+            Err(ureq::Error::Status(404, _)) => None,
+            Err(e) => return Err(request_error(e)),
+        };
+        Ok(current != self.loaded.lock().unwrap().get(key).copied())
+    }
+
+    fn url(&self, endpoint: &str, key: &KeyFile) -> String {
+        format!(
+            "{}/v1/{}/{}/{}/{}",
+            self.config.url(),
+            self.config.mount(),
+            endpoint,
+            self.config.path(),
+            key
+        )
+    }
+}
+
+fn request_error(e: ureq::Error) -> error::Error {
+    match e {
+        ureq::Error::Status(code, _) => error!(Vault => "request failed with status {}", code),
+        e => error!(Vault => "request failed: {}", e),
+    }
+}
+
+fn parse_secret(body: &str) -> Result<(Vec<u8>, u64)> {
+    let response: SecretResponse =
+        parse_json(body).map_err(|e| error!(Vault => "invalid secret: {}", e))?;
+    let secret = base64::engine::general_purpose::STANDARD.decode(response.data.data.secret)?;
+    Ok((secret, response.data.metadata.version))
+}
+
+fn parse_version(body: &str) -> Result<u64> {
+    let response: VersionResponse =
+        parse_json(body).map_err(|e| error!(Vault => "invalid write response: {}", e))?;
+    Ok(response.data.version)
+}
+
+fn parse_current_version(body: &str) -> Result<u64> {
+    let response: MetadataResponse =
+        parse_json(body).map_err(|e| error!(Vault => "invalid metadata: {}", e))?;
+    Ok(response.data.current_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_and_versions_are_parsed() {
+        let secret = r#"{"request_id":"2b3c","lease_id":"","renewable":false,"lease_duration":0,
+            "data":{"data":{"secret":"c2VjcmV0"},"metadata":{"created_time":"2024-07-30T15:33:07.000000Z","custom_metadata":null,"deletion_time":"","destroyed":false,"version":3}},
+            "wrap_info":null,"warnings":null,"auth":null}"#;
+        assert_eq!(parse_secret(secret).unwrap(), (b"secret".to_vec(), 3));
+
+        let written = r#"{"data":{"created_time":"2024-07-30T15:33:07.000000Z","deletion_time":"","destroyed":false,"version":1}}"#;
+        assert_eq!(parse_version(written).unwrap(), 1);
+
+        let metadata = r#"{"data":{"cas_required":false,"current_version":4,"oldest_version":0,"versions":{}}}"#;
+        assert_eq!(parse_current_version(metadata).unwrap(), 4);
+    }
+
+    #[test]
+    fn secrets_are_addressed_by_key() {
+        let config =
+            serde_yaml::from_str("{ url: 'https://vault.example.fr:8200/', path: /dnsr/keys/ }")
+                .unwrap();
+        let vault = Vault::new(&config);
+        let key = serde_yaml::from_str("key1").unwrap();
+        assert_eq!(
+            vault.url("data", &key),
+            "https://vault.example.fr:8200/v1/secret/data/dnsr/keys/key1"
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! The UDP workers, scaled on the queue delay of the requests.
+//!
+//! Every worker has its own `SO_REUSEPORT` socket so that the kernel balances
+//! the datagrams between them. A worker is added when the mean delay between
+//! the reception and the handling of the requests exceeds the configured
+//! threshold and one is stopped once the delay stayed low for a few intervals,
+//! always within the configured bounds. With a batch size on Linux, the
+//! workers read and write their datagrams in batches, see `crate::mmsg`.
+
+use core::time::Duration;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use domain::net::server::buf::VecBufSource;
+use domain::net::server::dgram::{self, DgramServer};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::config::{Config, UdpWorkersConfig};
+#[cfg(target_os = "linux")]
+use crate::mmsg::BatchedServer;
+use crate::service::middleware::Stats;
+use crate::service::DnsrSvc;
+
+/// The number of consecutive idle intervals before a worker is stopped.
+const IDLE_INTERVALS: u32 = 3;
+
+type UdpServer = DgramServer<Arc<UdpSocket>, VecBufSource, DnsrSvc>;
+
+/// The running UDP workers.
+pub struct UdpWorkers {
+    addr: SocketAddr,
+    svc: DnsrSvc,
+    max_response_size: u16,
+    scaler: Scaler,
+    workers: Vec<Worker>,
+}
+
+/// A running worker.
+enum Worker {
+    Server(Arc<UdpServer>),
+    #[cfg(target_os = "linux")]
+    Batched(tokio::task::JoinHandle<()>),
+}
+
+impl Worker {
+    fn stop(self) {
+        match self {
+            Worker::Server(server) => {
+                if let Err(e) = server.shutdown() {
+                    log::error!(target: "udp_workers", "failed to stop a worker: {}", e);
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Worker::Batched(task) => task.abort(),
+        }
+    }
+}
+
+impl UdpWorkers {
+    pub fn new(addr: SocketAddr, svc: DnsrSvc, config: &Config) -> Self {
+        Self {
+            addr,
+            svc,
+            max_response_size: config.edns_config().udp_payload_size(),
+            scaler: Scaler::new(config.udp_workers_config()),
+            workers: Vec::new(),
+        }
+    }
+
+    /// Starts the minimum number of workers.
+    pub fn start(&mut self) -> std::io::Result<()> {
+        #[cfg(not(target_os = "linux"))]
+        if self.scaler.config.batch().is_some() {
+            log::warn!(target: "udp_workers", "the batched UDP workers are only available on Linux");
+        }
+        while self.workers.len() < self.scaler.config.min() {
+            self.spawn()?;
+        }
+        Ok(())
+    }
+
+    /// Scales the workers on the queue delay recorded in `stats`, forever.
+    pub async fn run(mut self, stats: Arc<RwLock<Stats>>) {
+        let mut interval = tokio::time::interval(self.scaler.config.interval());
+        loop {
+            interval.tick().await;
+            let delay = stats.write().unwrap().take_udp_queue_delay();
+
+            match self.scaler.next(self.workers.len(), delay) {
+                Scaling::Up => match self.spawn() {
+                    Ok(()) => {
+                        log::info!(target: "udp_workers", "scaled up to {} workers, queue delay {:?}", self.workers.len(), delay.unwrap_or_default());
+                    }
+                    Err(e) => {
+                        log::error!(target: "udp_workers", "failed to bind UDP socket on {}: {}", self.addr, e);
+                    }
+                },
+                Scaling::Down => {
+                    if let Some(worker) = self.workers.pop() {
+                        worker.stop();
+                        log::info!(target: "udp_workers", "scaled down to {} workers", self.workers.len());
+                    }
+                }
+                Scaling::Keep => (),
+            }
+        }
+    }
+
+    fn spawn(&mut self) -> std::io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some(batch) = self.scaler.config.batch() {
+            let sock = bind_udp_reuseport(self.addr)?.into_std()?;
+            let server = BatchedServer::new(sock, self.svc.clone(), batch, self.max_response_size)?;
+            self.workers
+                .push(Worker::Batched(tokio::spawn(server.run())));
+            return Ok(());
+        }
+
+        let sock = Arc::new(bind_udp_reuseport(self.addr)?);
+        // Responses larger than the advertised payload size are truncated
+        let mut config = dgram::Config::new();
+        config.set_max_response_size(Some(self.max_response_size));
+
+        let server = Arc::new(DgramServer::with_config(
+            sock,
+            VecBufSource,
+            self.svc.clone(),
+            config,
+        ));
+        let running = server.clone();
+        tokio::spawn(async move { running.run().await });
+        self.workers.push(Worker::Server(server));
+        Ok(())
+    }
+}
+
+/// What to do with the workers after an interval.
+#[derive(Debug, PartialEq, Eq)]
+enum Scaling {
+    Up,
+    Down,
+    Keep,
+}
+
+/// Decides the scaling of the workers from the queue delay of every interval.
+struct Scaler {
+    config: UdpWorkersConfig,
+    idle_intervals: u32,
+}
+
+impl Scaler {
+    fn new(config: UdpWorkersConfig) -> Self {
+        Self {
+            config,
+            idle_intervals: 0,
+        }
+    }
+
+    /// Returns the scaling of `workers` given the mean queue delay of the last
+    /// interval, `None` if no request was received.
+    fn next(&mut self, workers: usize, delay: Option<Duration>) -> Scaling {
+        let threshold = self.config.queue_delay();
+        let delay = delay.unwrap_or_default();
+
+        if delay > threshold {
+            self.idle_intervals = 0;
+            return if workers < self.config.max() {
+                Scaling::Up
+            } else {
+                Scaling::Keep
+            };
+        }
+
+        // Some margin below the threshold so that the workers do not flap
+        if delay >= threshold / 4 {
+            self.idle_intervals = 0;
+            return Scaling::Keep;
+        }
+
+        self.idle_intervals += 1;
+        if self.idle_intervals >= IDLE_INTERVALS && workers > self.config.min() {
+            self.idle_intervals = 0;
+            Scaling::Down
+        } else {
+            Scaling::Keep
+        }
+    }
+}
+
+/// Binds a UDP socket with `SO_REUSEPORT`, so that the kernel balances the
+/// datagrams between the sockets of the workers instead of them contending on
+/// a single receive queue.
+fn bind_udp_reuseport(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workers_scale_within_the_bounds() {
+        let config = serde_yaml::from_str("{ min: 1, max: 2, queue_delay: 8 }").unwrap();
+        let mut scaler = Scaler::new(config);
+        let ms = |ms| Some(Duration::from_millis(ms));
+
+        assert_eq!(scaler.next(1, ms(20)), Scaling::Up);
+        assert_eq!(scaler.next(2, ms(20)), Scaling::Keep);
+
+        // The workers are only stopped after a few idle intervals
+        assert_eq!(scaler.next(2, ms(1)), Scaling::Keep);
+        assert_eq!(scaler.next(2, ms(5)), Scaling::Keep);
+        assert_eq!(scaler.next(2, ms(1)), Scaling::Keep);
+        assert_eq!(scaler.next(2, None), Scaling::Keep);
+        assert_eq!(scaler.next(2, ms(1)), Scaling::Down);
+
+        for _ in 0..IDLE_INTERVALS {
+            assert_eq!(scaler.next(1, None), Scaling::Keep);
+        }
+    }
+}
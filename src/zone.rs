@@ -1,34 +1,115 @@
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use domain::base::{name::Name, ToName};
 use domain::zonetree::Zone;
 
+use crate::error;
 use crate::error::Result;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct ZoneTree {
     zones: HashMap<Name<Bytes>, Zone>,
+    /// The largest number of zones served, unlimited if `None`
+    max_zones: Option<usize>,
+
+    /// The zones removed from the configuration along with their removal time
+    disabled: HashMap<Name<Bytes>, (Zone, Instant)>,
+    /// The number of served zones strictly below each name
+    zones_below: HashMap<Name<Bytes>, usize>,
 }
 
 impl ZoneTree {
-    pub fn new() -> Self {
-        Default::default()
+    pub fn with_max_zones(max_zones: Option<usize>) -> Self {
+        Self {
+            max_zones,
+            ..Default::default()
+        }
+    }
+
+    /// Fails if serving one more zone would exceed the limit.
+    fn check_limit(&self) -> Result<()> {
+        match self.max_zones {
+            Some(max) if self.zones.len() >= max => {
+                Err(error!(Limit => "the limit of {} zones is reached", max))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Serves `zone`, returns the zone it replaces if any.
+    fn serve(&mut self, zone: Zone) -> Option<Zone> {
+        let apex = zone.apex_name().clone();
+        let replaced = self.zones.insert(apex.clone(), zone);
+        if replaced.is_none() {
+            let mut name = apex.parent();
+            while let Some(current) = name {
+                name = current.parent();
+                *self.zones_below.entry(current).or_default() += 1;
+            }
+        }
+        replaced
+    }
+
+    /// Stops serving the zone `name`, returns it if it was served.
+    fn unserve(&mut self, name: &Name<Bytes>) -> Option<Zone> {
+        let removed = self.zones.remove(name)?;
+        let mut parent = name.parent();
+        while let Some(current) = parent {
+            parent = current.parent();
+            if let Some(count) = self.zones_below.get_mut(&current) {
+                *count -= 1;
+                if *count == 0 {
+                    self.zones_below.remove(&current);
+                }
+            }
+        }
+        Some(removed)
+    }
+
+    /// Returns whether a served zone is strictly below `name`, which then
+    /// exists even if no zone holds it.
+    pub fn has_zone_below<N>(&self, name: &N) -> bool
+    where
+        N: ToName,
+    {
+        self.zones_below.contains_key(&name.to_name::<Bytes>())
     }
 
     pub fn iter_zones(&self) -> impl Iterator<Item = &Zone> {
         self.zones.values()
     }
 
+    /// Returns the zone whose apex is exactly `name`.
+    pub fn get_zone<N>(&self, name: &N) -> Option<&Zone>
+    where
+        N: ToName,
+    {
+        self.zones.get(&name.to_name::<Bytes>())
+    }
+
+    /// Returns the zone serving `qname`, the one whose apex is the longest
+    /// suffix of `qname`.
     pub fn find_zone<N>(&self, qname: &N) -> Option<&Zone>
     where
         N: ToName,
     {
-        self.zones.get(&qname.to_name::<Bytes>())
+        let mut name = Some(qname.to_name::<Bytes>());
+        while let Some(current) = name {
+            if let Some(zone) = self.zones.get(&current) {
+                return Some(zone);
+            }
+            name = current.parent();
+        }
+        None
     }
 
     pub fn insert_zone(&mut self, zone: Zone) -> Result<()> {
-        match self.zones.insert(zone.apex_name().clone(), zone) {
+        if !self.zones.contains_key(zone.apex_name()) {
+            self.check_limit()?;
+        }
+        match self.serve(zone) {
             None => Ok(()),
             Some(_) => Err(domain::zonetree::error::ZoneTreeModificationError::ZoneExists.into()),
         }
@@ -38,11 +119,161 @@ impl ZoneTree {
     where
         N: ToName,
     {
-        match self.zones.remove(&name.to_name::<Bytes>()) {
+        match self.unserve(&name.to_name::<Bytes>()) {
             None => {
                 Err(domain::zonetree::error::ZoneTreeModificationError::ZoneDoesNotExist.into())
             }
             Some(_) => Ok(()),
         }
     }
+
+    /// Moves a zone out of the served zones, it can be restored until purged.
+    pub fn disable_zone<N>(&mut self, name: &N) -> Result<()>
+    where
+        N: ToName,
+    {
+        let name = name.to_name::<Bytes>();
+        match self.unserve(&name) {
+            None => {
+                Err(domain::zonetree::error::ZoneTreeModificationError::ZoneDoesNotExist.into())
+            }
+            Some(zone) => {
+                self.disabled.insert(name, (zone, Instant::now()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Serves a disabled zone again with the records it had when disabled.
+    pub fn restore_zone<N>(&mut self, name: &N) -> Result<()>
+    where
+        N: ToName,
+    {
+        let name = name.to_name::<Bytes>();
+        if self.zones.contains_key(&name) {
+            return Err(domain::zonetree::error::ZoneTreeModificationError::ZoneExists.into());
+        }
+        if self.disabled.contains_key(&name) {
+            self.check_limit()?;
+        }
+
+        match self.disabled.remove(&name) {
+            None => {
+                Err(domain::zonetree::error::ZoneTreeModificationError::ZoneDoesNotExist.into())
+            }
+            Some((zone, _)) => {
+                self.serve(zone);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the name of every disabled zone and the time elapsed since it was disabled.
+    pub fn disabled_zones(&self) -> impl Iterator<Item = (&Name<Bytes>, Duration)> {
+        self.disabled
+            .iter()
+            .map(|(name, (_, disabled_at))| (name, disabled_at.elapsed()))
+    }
+
+    /// Drops the zones disabled for longer than `retention`.
+    pub fn purge_disabled_zones(&mut self, retention: Duration) -> Vec<Name<Bytes>> {
+        let expired = self
+            .disabled_zones()
+            .filter(|(_, elapsed)| *elapsed >= retention)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+
+        for name in &expired {
+            self.disabled.remove(name);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use domain::base::iana::Class;
+    use domain::zonetree::ZoneBuilder;
+
+    use super::*;
+    use crate::error::ErrorKind;
+
+    fn zone(apex: &str) -> Zone {
+        let apex = Name::bytes_from_str(apex).unwrap();
+        ZoneBuilder::new(apex, Class::IN).build()
+    }
+
+    #[test]
+    fn names_are_served_by_the_closest_enclosing_zone() {
+        let mut tree = ZoneTree::default();
+        tree.insert_zone(zone("example.fr")).unwrap();
+        tree.insert_zone(zone("_acme-challenge.sub.example.fr"))
+            .unwrap();
+        let apex = |qname: &str| {
+            let qname = Name::bytes_from_str(qname).unwrap();
+            tree.find_zone(&qname).map(|z| z.apex_name().to_string())
+        };
+
+        assert_eq!(apex("example.fr").as_deref(), Some("example.fr"));
+        assert_eq!(apex("sub.example.fr").as_deref(), Some("example.fr"));
+        assert_eq!(
+            apex("token._acme-challenge.sub.example.fr").as_deref(),
+            Some("_acme-challenge.sub.example.fr")
+        );
+        assert_eq!(apex("example.com"), None);
+
+        let name = Name::bytes_from_str("sub.example.fr").unwrap();
+        assert!(tree.get_zone(&name).is_none());
+    }
+
+    #[test]
+    fn names_above_the_served_zones_are_tracked() {
+        let mut tree = ZoneTree::default();
+        let name = |name: &str| Name::bytes_from_str(name).unwrap();
+        tree.insert_zone(zone("_acme-challenge.example.fr"))
+            .unwrap();
+        tree.insert_zone(zone("_acme-challenge.sub.example.fr"))
+            .unwrap();
+
+        assert!(tree.has_zone_below(&name("example.fr")));
+        assert!(tree.has_zone_below(&name("sub.example.fr")));
+        assert!(tree.has_zone_below(&name(".")));
+        assert!(!tree.has_zone_below(&name("_acme-challenge.example.fr")));
+        assert!(!tree.has_zone_below(&name("www.example.fr")));
+
+        tree.disable_zone(&name("_acme-challenge.sub.example.fr"))
+            .unwrap();
+        assert!(!tree.has_zone_below(&name("sub.example.fr")));
+        assert!(tree.has_zone_below(&name("example.fr")));
+        tree.restore_zone(&name("_acme-challenge.sub.example.fr"))
+            .unwrap();
+        assert!(tree.has_zone_below(&name("sub.example.fr")));
+
+        tree.remove_zone(&name("_acme-challenge.sub.example.fr"))
+            .unwrap();
+        tree.remove_zone(&name("_acme-challenge.example.fr"))
+            .unwrap();
+        assert!(!tree.has_zone_below(&name("example.fr")));
+    }
+
+    #[test]
+    fn zones_over_the_limit_are_rejected() {
+        let mut tree = ZoneTree::with_max_zones(Some(1));
+        tree.insert_zone(zone("_acme-challenge.example.fr"))
+            .unwrap();
+
+        let e = tree
+            .insert_zone(zone("_acme-challenge.example.com"))
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Limit);
+
+        tree.disable_zone(&Name::bytes_from_str("_acme-challenge.example.fr").unwrap())
+            .unwrap();
+        tree.insert_zone(zone("_acme-challenge.example.com"))
+            .unwrap();
+        let e = tree
+            .restore_zone(&Name::bytes_from_str("_acme-challenge.example.fr").unwrap())
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Limit);
+    }
 }